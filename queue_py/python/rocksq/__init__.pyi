@@ -0,0 +1,109 @@
+import os
+from enum import Enum
+from typing import List, Optional, Union
+
+def version() -> str: ...
+
+class QueueInUseError(RuntimeError): ...
+
+def remove_queue(queue_name: Union[str, os.PathLike], force: bool = False, secure_wipe: bool = False, options: Optional[QueueOptions] = None): ...
+
+def remove_mpmc_queue(queue_name: Union[str, os.PathLike], force: bool = False, secure_wipe: bool = False, options: Optional[QueueOptions] = None): ...
+
+def pack_parts(parts: List[bytes]) -> bytes: ...
+
+def unpack_parts(element: bytes) -> List[bytes]: ...
+
+def discover(root_dir: Union[str, os.PathLike]) -> List[dict]: ...
+
+def set_log_level(level: str = "INFO"): ...
+
+class StartPosition(Enum):
+    Oldest=0
+    Newest=1
+
+class Payload:
+    def __len__(self) -> int: ...
+
+    def __buffer__(self, flags: int) -> memoryview: ...
+
+class SharedResources:
+    @property
+    def cache_usage(self) -> int: ...
+
+    def __init__(self, block_cache_mb: Optional[int] = None, background_threads: Optional[int] = None, max_open_files: Optional[int] = None): ...
+
+class QueueOptions:
+    write_buffer_size: Optional[int]
+    max_write_buffers: Optional[int]
+    compression: Optional[str]
+    block_cache_mb: Optional[int]
+    sync_writes: bool
+    enable_blob_files: bool
+    min_blob_size: Optional[int]
+    blob_file_size: Optional[int]
+    enable_statistics: bool
+    background_write_bytes_per_sec: Optional[int]
+    auto_tune_background_writes: bool
+    low_priority_compactions: bool
+    push_items_per_sec: Optional[float]
+    push_bytes_per_sec: Optional[float]
+    push_rate_fail_fast: bool
+    pop_items_per_sec: Optional[float]
+    pop_bytes_per_sec: Optional[float]
+    pop_rate_fail_fast: bool
+    resources: Optional[SharedResources]
+    verify_on_open: bool
+    repair_on_open: bool
+    index_space: Optional[int]
+    strict_ttl: bool
+    retention_idle_secs: Optional[float]
+    retention_max_disk_size: Optional[int]
+    prefetch: int
+    reader_persist_elements: Optional[int]
+    reader_persist_interval_secs: Optional[float]
+    start_position: Optional[StartPosition]
+    start_position_prefixes: Optional[dict[str, StartPosition]]
+    disk_quota: Optional[int]
+    disk_alert_percents: Optional[list[int]]
+    disk_alert_bytes: Optional[list[int]]
+    disk_alert_available_bytes: Optional[list[int]]
+
+    def __init__(self, write_buffer_size: Optional[int] = None, max_write_buffers: Optional[int] = None, compression: Optional[str] = None, block_cache_mb: Optional[int] = None, sync_writes: bool = False, enable_blob_files: bool = False, min_blob_size: Optional[int] = None, blob_file_size: Optional[int] = None, enable_statistics: bool = False, background_write_bytes_per_sec: Optional[int] = None, auto_tune_background_writes: bool = False, low_priority_compactions: bool = False, push_items_per_sec: Optional[float] = None, push_bytes_per_sec: Optional[float] = None, push_rate_fail_fast: bool = False, pop_items_per_sec: Optional[float] = None, pop_bytes_per_sec: Optional[float] = None, pop_rate_fail_fast: bool = False, resources: Optional[SharedResources] = None, verify_on_open: bool = False, repair_on_open: bool = False, index_space: Optional[int] = None, strict_ttl: bool = False, retention_idle_secs: Optional[float] = None, retention_max_disk_size: Optional[int] = None, prefetch: int = 0, reader_persist_elements: Optional[int] = None, reader_persist_interval_secs: Optional[float] = None, start_position: Optional[StartPosition] = None, start_position_prefixes: Optional[dict[str, StartPosition]] = None, disk_quota: Optional[int] = None, disk_alert_percents: Optional[list[int]] = None, disk_alert_bytes: Optional[list[int]] = None, disk_alert_available_bytes: Optional[list[int]] = None): ...
+
+class DbStats:
+    @property
+    def compaction_pending(self) -> bool: ...
+
+    @property
+    def running_compactions(self) -> int: ...
+
+    @property
+    def pending_compaction_bytes(self) -> int: ...
+
+    @property
+    def write_stopped(self) -> bool: ...
+
+    @property
+    def delayed_write_rate(self) -> int: ...
+
+    @property
+    def memtables_size(self) -> int: ...
+
+    @property
+    def block_cache_usage(self) -> int: ...
+
+    @property
+    def estimated_keys(self) -> int: ...
+
+    @property
+    def stall_micros(self) -> Optional[int]: ...
+
+    @property
+    def block_cache_hits(self) -> Optional[int]: ...
+
+    @property
+    def block_cache_misses(self) -> Optional[int]: ...
+
+    @property
+    def block_cache_hit_rate(self) -> Optional[float]: ...
@@ -0,0 +1,132 @@
+import os
+from typing import Iterator, Optional, Union
+from rocksq import DbStats, QueueOptions, StartPosition
+
+class PersistentQueueWithCapacity:
+    def __init__(self, path: Union[str, os.PathLike], max_elements: int = 1_000_000_000, options: Optional[QueueOptions] = None): ...
+
+    def push(self, items: list[bytes], no_gil: bool = True): ...
+
+    def push_pair(self, meta: bytes, payload: bytes, no_gil: bool = True): ...
+
+    def push_with_sequence(self, producer_id: str, seq: int, items: list[bytes], no_gil: bool = True) -> bool: ...
+
+    def push_with_key(self, key: bytes, item: bytes, no_gil: bool = True): ...
+
+    def find_by_key(self, key: bytes) -> list[bytes]: ...
+
+    def delete_by_key(self, key: bytes) -> int: ...
+
+    def producer_sequence(self, producer_id: str) -> Optional[int]: ...
+
+    def remove_producer_session(self, producer_id: str): ...
+
+    def register_epoch(self, producer_id: str, epoch: int): ...
+
+    def producer_epoch(self, producer_id: str) -> Optional[int]: ...
+
+    def push_with_epoch(self, producer_id: str, epoch: int, items: list[bytes], no_gil: bool = True): ...
+
+    def push_partial(self, items: list[bytes], no_gil: bool = True) -> int: ...
+
+    def push_front(self, items: list[bytes], no_gil: bool = True): ...
+
+    def pop(self, max_elements: int = 1, no_gil: bool = True, as_memoryview: bool = False) -> list[bytes] | list[memoryview]: ...
+
+    def pop_pairs(self, max_elements: int = 1, no_gil: bool = True) -> list[tuple[bytes, bytes]]: ...
+
+    def pop_exact(self, n: int, timeout: float, no_gil: bool = True, as_memoryview: bool = False) -> list[bytes] | list[memoryview]: ...
+
+    def pop_all(self, max_bytes: Optional[int] = None, no_gil: bool = True, as_memoryview: bool = False) -> list[bytes] | list[memoryview]: ...
+
+    def pop_back(self, max_elements: int = 1, no_gil: bool = True, as_memoryview: bool = False) -> list[bytes] | list[memoryview]: ...
+
+    @property
+    def is_empty(self) -> bool: ...
+
+    def stats(self) -> dict: ...
+
+    def stats_snapshot(self) -> dict: ...
+
+    def oldest_age(self) -> Optional[float]: ...
+
+    def estimate_disk_for(self, elements: int, avg_size: int) -> int: ...
+
+    def estimate_remaining_capacity(self) -> Optional[int]: ...
+
+    def db_stats(self) -> DbStats: ...
+
+    @property
+    def disk_size(self) -> int: ...
+
+    @property
+    def payload_size(self) -> int: ...
+
+    @property
+    def len(self) -> int: ...
+
+class MpmcQueue:
+    def __init__(self, path: Union[str, os.PathLike], ttl: int, options: Optional[QueueOptions] = None): ...
+
+    def add(self, items: list[bytes], no_gil: bool = True): ...
+
+    def add_with_timestamps(self, items: list[bytes], timestamps: list[int], no_gil: bool = True): ...
+
+    def add_with_key(self, key: bytes, item: bytes, no_gil: bool = True): ...
+
+    def get_by_key(self, key: bytes) -> Optional[bytes]: ...
+
+    def next(self, label: str, start_position: StartPosition, max_elements: int = 1, no_gil: bool = True, as_memoryview: bool = False) -> tuple[list[bytes] | list[memoryview], bool]: ...
+
+    def next_exact(self, label: str, start_position: StartPosition, n: int, timeout: float, no_gil: bool = True, as_memoryview: bool = False) -> tuple[list[bytes] | list[memoryview], bool]: ...
+
+    def subscribe(self, label: str, start_position: StartPosition, batch: int = 1, poll_timeout: float = 0.1, as_memoryview: bool = False) -> Subscription: ...
+
+    @property
+    def is_empty(self) -> bool: ...
+
+    def stats(self) -> dict: ...
+
+    def stats_snapshot(self) -> dict: ...
+
+    def oldest_age(self) -> Optional[float]: ...
+
+    def estimate_disk_for(self, elements: int, avg_size: int) -> int: ...
+
+    def estimate_remaining_capacity(self) -> Optional[int]: ...
+
+    def db_stats(self) -> DbStats: ...
+
+    @property
+    def disk_size(self) -> int: ...
+
+    @property
+    def payload_size(self) -> int: ...
+
+    @property
+    def len(self) -> int: ...
+
+    @property
+    def labels(self) -> list[str]: ...
+
+    def read_range(self, from_timestamp: int, to_timestamp: int, max_elements: int = 1, no_gil: bool = True) -> list[bytes]: ...
+
+    def purge_before(self, timestamp: int, no_gil: bool = True) -> int: ...
+
+    def remove_label(self, label: str) -> bool: ...
+
+    def export_labels(self) -> bytes: ...
+
+    def import_labels(self, data: bytes) -> None: ...
+
+    def join_group(self, label: str, group: str) -> None: ...
+
+    def leave_group(self, label: str) -> bool: ...
+
+class Expired:
+    ...
+
+class Subscription(Iterator[list[bytes] | list[memoryview] | Expired]):
+    def __iter__(self) -> Subscription: ...
+
+    def __next__(self) -> list[bytes] | list[memoryview] | Expired: ...
@@ -0,0 +1,135 @@
+import asyncio
+import os
+from typing import Callable, Generator, Optional, Union
+from rocksq import QueueOptions, StartPosition
+
+class ResponseVariant:
+    @property
+    def index_range(self) -> Optional[tuple[int, int]]: ...
+
+    @property
+    def data(self) -> Optional[list[bytes]]: ...
+
+    @property
+    def len(self) -> Optional[int]: ...
+
+    @property
+    def is_empty(self) -> Optional[bool]: ...
+
+    @property
+    def size(self) -> Optional[int]: ...
+
+
+class Response:
+    @property
+    def is_ready(self) -> bool: ...
+
+    def try_get(self) -> Optional[ResponseVariant]: ...
+
+    def get(self) -> ResponseVariant: ...
+
+    def as_future(self, event_loop: Optional[asyncio.AbstractEventLoop] = None) -> asyncio.Future[ResponseVariant]: ...
+
+    def __await__(self) -> Generator[None, None, ResponseVariant]: ...
+
+    def add_done_callback(self, callback: Callable[[Response], None]) -> None: ...
+
+
+class PersistentQueueWithCapacity:
+    def __init__(self, path: Union[str, os.PathLike], max_elements: int = 1_000_000_000, max_inflight_ops: int = 1_000, options: Optional[QueueOptions] = None): ...
+
+    def push(self, items: list[bytes], no_gil: bool = True) -> Response: ...
+
+    @property
+    def inflight_ops(self) -> int: ...
+
+    @property
+    def is_healthy(self) -> bool: ...
+
+    @property
+    def last_error(self) -> Optional[str]: ...
+
+    def pop(self, max_elements = 1, no_gil: bool = True) -> Response: ...
+
+    def pop_all(self, max_bytes: Optional[int] = None, no_gil: bool = True) -> Response: ...
+
+    @property
+    def disk_size(self) -> Response: ...
+
+    @property
+    def payload_size(self) -> Response: ...
+
+    @property
+    def len(self) -> Response: ...
+
+    @property
+    def is_empty(self) -> Response: ...
+
+class MpmcResponseVariant:
+    @property
+    def index_range(self) -> Optional[tuple[int, int]]: ...
+
+    @property
+    def data(self) -> Optional[tuple[list[bytes], bool]]: ...
+
+    @property
+    def labels(self) -> Optional[list[str]]: ...
+
+    @property
+    def removed_label(self) -> Optional[bool]: ...
+
+    @property
+    def len(self) -> Optional[int]: ...
+
+    @property
+    def is_empty(self) -> Optional[bool]: ...
+
+    @property
+    def size(self) -> Optional[int]: ...
+
+class MpmcResponse:
+    @property
+    def is_ready(self) -> bool: ...
+
+    def try_get(self) -> Optional[MpmcResponseVariant]: ...
+
+    def get(self) -> MpmcResponseVariant: ...
+
+    def as_future(self, event_loop: Optional[asyncio.AbstractEventLoop] = None) -> asyncio.Future[MpmcResponseVariant]: ...
+
+    def __await__(self) -> Generator[None, None, MpmcResponseVariant]: ...
+
+    def add_done_callback(self, callback: Callable[[MpmcResponse], None]) -> None: ...
+
+class MpmcQueue:
+    def __init__(self, path: Union[str, os.PathLike], ttl: int, max_inflight_ops: int = 1_000, options: Optional[QueueOptions] = None): ...
+
+    def add(self, items: list[bytes], no_gil: bool = True) -> MpmcResponse: ...
+
+    @property
+    def inflight_ops(self) -> int: ...
+
+    @property
+    def is_healthy(self) -> bool: ...
+
+    @property
+    def last_error(self) -> Optional[str]: ...
+
+    def next(self, label: str, start_position: StartPosition, max_elements = 1, no_gil: bool = True) -> MpmcResponse: ...
+
+    @property
+    def disk_size(self) -> MpmcResponse: ...
+
+    @property
+    def len(self) -> MpmcResponse: ...
+
+    @property
+    def is_empty(self) -> MpmcResponse: ...
+
+    @property
+    def labels(self) -> MpmcResponse: ...
+
+    def remove_label(self, label: str) -> MpmcResponse: ...
+
+
+def wait_all(responses: list[Union[Response, MpmcResponse]], timeout: Optional[float] = None) -> list[Union[ResponseVariant, MpmcResponseVariant]]: ...
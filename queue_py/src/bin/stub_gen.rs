@@ -0,0 +1,10 @@
+//! Generates the `.pyi` stubs of the `rocksq` package into `python/rocksq`:
+//!
+//! ```sh
+//! cargo run -p rocksq --bin stub_gen --no-default-features --features stub-gen
+//! ```
+//!
+fn main() -> pyo3_stub_gen::Result<()> {
+    rocksq::stub_info()?.generate()?;
+    Ok(())
+}
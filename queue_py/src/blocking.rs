@@ -1,42 +1,49 @@
-use crate::{pylist_to_vec_of_byte_vec, value_as_slice, StartPosition};
-use pyo3::exceptions::PyRuntimeError;
+use crate::{items_to_py, ByteBuffers, DbStats, QueueOptions, StartPosition};
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyList};
+use pyo3::types::{PyBytes, PyDict, PyList};
+use queue_rs::alerts::DiskThreshold;
 use queue_rs::mpmc;
-use rocksdb::Options;
+use queue_rs::stats::{SizeStats, StatsSnapshot};
+use queue_rs::QueueError;
+use std::path::PathBuf;
 use std::time::Duration;
 
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::{gen_stub_pyclass, gen_stub_pymethods};
+
 /// A persistent queue with a fixed capacity. This is a blocking implementation.
 ///
 /// Parameters
 /// ----------
-/// path : str
+/// path : str or os.PathLike
 ///   The path to the queue.
 /// max_elements : int
 ///   The maximum number of elements the queue can hold. Default is ``1_000_000_000``.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The RocksDB options of the queue. Default is the RocksDB defaults.
 ///
 /// Raises
 /// ------
 /// PyRuntimeError
 ///   If the queue could not be created.
 ///
-#[pyclass]
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.blocking")]
 pub struct PersistentQueueWithCapacity(queue_rs::blocking::PersistentQueueWithCapacity);
 
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl PersistentQueueWithCapacity {
     #[new]
-    #[pyo3(signature=(path, max_elements = 1_000_000_000))]
-    fn new(path: &str, max_elements: usize) -> PyResult<Self> {
-        let queue = queue_rs::blocking::PersistentQueueWithCapacity::new(
-            path,
-            max_elements,
-            Options::default(),
-        )
-        .map_err(|e| {
-            PyRuntimeError::new_err(format!("Failed to create persistent queue: {}", e))
-        })?;
-        Ok(Self(queue))
+    #[pyo3(signature=(path, max_elements = 1_000_000_000, options = None))]
+    fn new(path: PathBuf, max_elements: usize, options: Option<QueueOptions>) -> PyResult<Self> {
+        let queue = options
+            .unwrap_or_default()
+            .open_queue(&path, max_elements)?;
+        Ok(Self(queue_rs::blocking::PersistentQueueWithCapacity::from(
+            queue,
+        )))
     }
 
     /// Adds items to the queue.
@@ -45,8 +52,8 @@ impl PersistentQueueWithCapacity {
     ///
     /// Parameters
     /// ----------
-    /// items : list of bytes
-    ///   The items to add to the queue.
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
@@ -54,6 +61,8 @@ impl PersistentQueueWithCapacity {
     /// ------
     /// PyRuntimeError
     ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
     ///
     /// Returns
     /// -------
@@ -61,8 +70,8 @@ impl PersistentQueueWithCapacity {
     ///
     #[pyo3(signature = (items, no_gil = true))]
     fn push(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<()> {
-        let items = pylist_to_vec_of_byte_vec(items);
-        let data = value_as_slice(&items);
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
         Python::with_gil(|py| {
             let f = || {
                 self.0
@@ -78,14 +87,18 @@ impl PersistentQueueWithCapacity {
         })
     }
 
-    /// Retrieves items from the queue.
+    /// Adds an item of two parts: the small metadata stored as the item and the large payload
+    /// stored separately, so :py:meth:`pop` reads the metadata only and drops the payload, while
+    /// :py:meth:`pop_pairs` returns both parts.
     ///
     /// **GIL**: the method can optionally be called without the GIL.
     ///
     /// Parameters
     /// ----------
-    /// max_elements : int
-    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// meta : bytes
+    ///   The metadata of the item.
+    /// payload : bytes
+    ///   The payload of the item.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
@@ -96,130 +109,90 @@ impl PersistentQueueWithCapacity {
     ///
     /// Returns
     /// -------
-    /// items : list of bytes
-    ///   The items retrieved from the queue.
+    /// None
     ///
-    #[pyo3(signature = (max_elements = 1, no_gil = true))]
-    fn pop(&self, max_elements: usize, no_gil: bool) -> PyResult<Vec<PyObject>> {
+    #[pyo3(signature = (meta, payload, no_gil = true))]
+    fn push_pair(&self, meta: &[u8], payload: &[u8], no_gil: bool) -> PyResult<()> {
         Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .push_pairs(&[(meta, payload)])
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push item: {}", e)))
+            };
+
             if no_gil {
-                py.allow_threads(|| self.0.pop(max_elements))
+                py.allow_threads(f)
             } else {
-                self.0.pop(max_elements)
+                f()
             }
-            .map(|results| {
-                results
-                    .into_iter()
-                    .map(|r| {
-                        PyBytes::new_bound_with(py, r.len(), |b: &mut [u8]| {
-                            b.copy_from_slice(&r);
-                            Ok(())
-                        })
-                        .map(PyObject::from)
-                    })
-                    .collect::<PyResult<Vec<_>>>()
-            })
-            .map_err(|_| PyRuntimeError::new_err("Failed to pop item"))
-        })?
+        })
     }
 
-    /// Checks if the queue is empty.
-    ///
-    /// Returns
-    /// -------
-    /// bool
-    ///   ``True`` if the queue is empty, ``False`` otherwise.
+    /// Adds items of a producer session unless its sequence number has already been added, so
+    /// the retries after a crash or a reconnect are deduplicated. The sequence numbers of a
+    /// producer must grow, the last one is stored with the items.
     ///
-    #[getter]
-    fn is_empty(&self) -> bool {
-        self.0.is_empty()
-    }
-
-    /// Returns the disk size of the queue in bytes.
+    /// **GIL**: the method can optionally be called without the GIL.
     ///
-    /// Returns
-    /// -------
-    /// size : int
+    /// Parameters
+    /// ----------
+    /// producer_id : str
+    ///   The identifier of the producer session.
+    /// seq : int
+    ///   The sequence number of the items in the session.
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
     /// Raises
     /// ------
     /// PyRuntimeError
     ///   If the method fails.
-    ///
-    #[getter]
-    fn disk_size(&self) -> PyResult<usize> {
-        Python::with_gil(|py| {
-            py.allow_threads(|| {
-                self.0.disk_size().map_err(|e| {
-                    PyRuntimeError::new_err(format!("Failed to get queue size: {}", e))
-                })
-            })
-        })
-    }
-
-    /// Returns the size of the queue in bytes (only payload).
-    ///
-    /// Returns
-    /// -------
-    /// size : int
-    ///
-    #[getter]
-    fn payload_size(&self) -> u64 {
-        self.0.payload_size()
-    }
-
-    /// Returns the number of elements in the queue.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
     ///
     /// Returns
     /// -------
-    /// int
-    ///   The number of elements in the queue.
+    /// bool
+    ///   ``False`` if the sequence number has already been added, ``True`` otherwise.
     ///
-    #[getter]
-    fn len(&self) -> usize {
-        self.0.len()
-    }
-}
-
-/// A persistent queue with a ttl that supports multiple consumers marked with labels. This is a
-/// blocking implementation.
-///
-/// Parameters
-/// ----------
-/// path : str
-///   The path to the queue.
-/// ttl : int
-///   The amount of seconds after which the element in the queue will be removed. TTL is not strict.
-///   It means that the element will remain in the queue for TTL seconds after insertion and the
-///   queue will make efforts to remove the element after TTL seconds but it is not guaranteed to be
-///   done immediately. Thus, consumers can retrieve expired but not removed elements.
-///
-/// Raises
-/// ------
-/// PyRuntimeError
-///   If the queue could not be created.
-///
-#[pyclass]
-pub struct MpmcQueue(queue_rs::blocking::MpmcQueue);
+    #[pyo3(signature = (producer_id, seq, items, no_gil = true))]
+    fn push_with_sequence(
+        &self,
+        producer_id: &str,
+        seq: u64,
+        items: &Bound<'_, PyList>,
+        no_gil: bool,
+    ) -> PyResult<bool> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .push_with_sequence(producer_id, seq, &data)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push items: {}", e)))
+            };
 
-#[pymethods]
-impl MpmcQueue {
-    #[new]
-    #[pyo3(signature=(path, ttl))]
-    fn new(path: &str, ttl: u32) -> PyResult<Self> {
-        let queue = queue_rs::blocking::MpmcQueue::new(path, Duration::from_secs(ttl as u64))
-            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create mpmc queue: {}", e)))?;
-        Ok(Self(queue))
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
     }
 
-    /// Adds items to the queue.
+    /// Adds an item with its key, so it can be found with :py:meth:`find_by_key` and deleted
+    /// with :py:meth:`delete_by_key` before it is retrieved. The keys don't have to be unique.
     ///
     /// **GIL**: the method can optionally be called without the GIL.
     ///
     /// Parameters
     /// ----------
-    /// items : list of bytes
-    ///   The items to add to the queue.
+    /// key : bytes
+    ///   The key of the item.
+    /// item : bytes
+    ///   The item to add to the queue.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
@@ -232,15 +205,13 @@ impl MpmcQueue {
     /// -------
     /// None
     ///
-    #[pyo3(signature = (items, no_gil = true))]
-    fn add(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<()> {
-        let items = pylist_to_vec_of_byte_vec(items);
-        let data = value_as_slice(&items);
+    #[pyo3(signature = (key, item, no_gil = true))]
+    fn push_with_key(&self, key: &[u8], item: &[u8], no_gil: bool) -> PyResult<()> {
         Python::with_gil(|py| {
             let f = || {
                 self.0
-                    .add(&data)
-                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to add items: {}", e)))
+                    .push_with_keys(&[(key, item)])
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push item: {}", e)))
             };
 
             if no_gil {
@@ -251,25 +222,13 @@ impl MpmcQueue {
         })
     }
 
-    /// Retrieves items from the queue.
-    ///
-    /// **GIL**: the method can optionally be called without the GIL.
+    /// Returns the items added with the key which are not retrieved or deleted yet, oldest
+    /// first, without retrieving them.
     ///
     /// Parameters
     /// ----------
-    /// label: str
-    ///   The consumer label that determines the start position in the queue to retrieve elements.
-    ///   If the label does not exist the start position is determined by ``option` parameter. If
-    ///   the label exists the start position is the next element after the last call of this
-    ///   method. If some elements are expired between the last and this call next non-expired
-    ///   elements will be retrieved.
-    /// start_position: StartPosition
-    ///    The option that determines the start position in the queue to retrieve elements if the
-    ///    consumer label does not exist.
-    /// max_elements : int
-    ///   The maximum number of elements to retrieve. Default is ``1``.
-    /// no_gil : bool
-    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// key : bytes
+    ///   The key of the items.
     ///
     /// Raises
     /// ------
@@ -279,131 +238,168 @@ impl MpmcQueue {
     /// Returns
     /// -------
     /// items : list of bytes
-    ///   The items retrieved from the queue.
-    /// expired : bool
-    ///   True if some elements are expired between the last and this call of the method.
+    ///   The items of the key.
     ///
-    #[pyo3(signature = (label, start_position, max_elements = 1, no_gil = true))]
-    fn next(
-        &self,
-        label: &str,
-        start_position: StartPosition,
-        max_elements: usize,
-        no_gil: bool,
-    ) -> PyResult<(Vec<PyObject>, bool)> {
+    fn find_by_key(&self, key: &[u8]) -> PyResult<Vec<PyObject>> {
         Python::with_gil(|py| {
-            let start_position = match start_position {
-                StartPosition::Oldest => mpmc::StartPosition::Oldest,
-                StartPosition::Newest => mpmc::StartPosition::Newest,
-            };
-            if no_gil {
-                py.allow_threads(|| self.0.next(max_elements, label, start_position))
-            } else {
-                self.0.next(max_elements, label, start_position)
-            }
-            .map(|result| {
-                result
-                    .0
-                    .into_iter()
-                    .map(|r| {
-                        PyBytes::new_bound_with(py, r.len(), |b: &mut [u8]| {
-                            b.copy_from_slice(&r);
-                            Ok(())
-                        })
-                        .map(PyObject::from)
-                    })
-                    .collect::<PyResult<Vec<_>>>()
-                    .map(|e| (e, result.1))
-            })
-            .map_err(|_| PyRuntimeError::new_err("Failed to retrieve items"))
+            self.0
+                .find_by_key(key)
+                .map(|results| items_to_py(py, results, false))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to find items: {}", e)))
         })?
     }
 
-    /// Checks if the queue is empty.
+    /// Deletes the items added with the key which are not retrieved yet. The deleted items count
+    /// towards the length of the queue until they are reached by the retrieval.
+    ///
+    /// Parameters
+    /// ----------
+    /// key : bytes
+    ///   The key of the items.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
     ///
     /// Returns
     /// -------
-    /// bool
-    ///   ``True`` if the queue is empty, ``False`` otherwise.
+    /// int
+    ///   The number of the deleted items.
     ///
-    #[getter]
-    fn is_empty(&self) -> bool {
-        self.0.is_empty()
+    fn delete_by_key(&self, key: &[u8]) -> PyResult<usize> {
+        self.0
+            .delete_by_key(key)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to delete items: {}", e)))
     }
 
-    /// Returns the disk size of the queue in bytes.
+    /// Returns the last added sequence number of the producer session.
+    ///
+    /// Parameters
+    /// ----------
+    /// producer_id : str
+    ///   The identifier of the producer session.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
     ///
     /// Returns
     /// -------
-    /// size : int
+    /// int or None
+    ///   The last sequence number or ``None`` if the session is unknown.
+    ///
+    fn producer_sequence(&self, producer_id: &str) -> PyResult<Option<u64>> {
+        self.0
+            .producer_sequence(producer_id)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to read producer session: {}", e)))
+    }
+
+    /// Removes the producer session, so its sequence numbers are not checked anymore.
+    ///
+    /// Parameters
+    /// ----------
+    /// producer_id : str
+    ///   The identifier of the producer session.
     ///
     /// Raises
     /// ------
     /// PyRuntimeError
     ///   If the method fails.
     ///
-    #[getter]
-    fn disk_size(&self) -> PyResult<usize> {
-        Python::with_gil(|py| {
-            py.allow_threads(|| {
-                self.0.disk_size().map_err(|e| {
-                    PyRuntimeError::new_err(format!("Failed to get queue size: {}", e))
-                })
-            })
+    fn remove_producer_session(&self, producer_id: &str) -> PyResult<()> {
+        self.0.remove_producer_session(producer_id).map_err(|e| {
+            PyRuntimeError::new_err(format!("Failed to remove producer session: {}", e))
         })
     }
 
-    /// Returns the number of elements in the queue.
+    /// Registers the epoch of the producer, e.g. a restarted producer registers an incremented
+    /// one. The items with older epochs are rejected afterwards, see ``push_with_epoch()``.
     ///
-    /// Returns
-    /// -------
-    /// int
-    ///   The number of elements in the queue.
+    /// Parameters
+    /// ----------
+    /// producer_id : str
+    ///   The identifier of the producer.
+    /// epoch : int
+    ///   The epoch of the producer.
     ///
-    #[getter]
-    fn len(&self) -> usize {
-        self.0.len()
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If a newer epoch is registered or the method fails.
+    ///
+    fn register_epoch(&self, producer_id: &str, epoch: u64) -> PyResult<()> {
+        self.0
+            .register_epoch(producer_id, epoch)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to register epoch: {}", e)))
     }
 
-    /// Returns the consumer labels.
+    /// Returns the registered epoch of the producer.
+    ///
+    /// Parameters
+    /// ----------
+    /// producer_id : str
+    ///   The identifier of the producer.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
     ///
     /// Returns
     /// -------
-    /// labels: list of str
-    ///   The consumer labels.
+    /// int or None
+    ///   The epoch or ``None`` if no epoch is registered.
     ///
-    #[getter]
-    fn labels(&self) -> Vec<String> {
-        self.0.get_labels()
+    fn producer_epoch(&self, producer_id: &str) -> PyResult<Option<u64>> {
+        self.0
+            .producer_epoch(producer_id)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to read epoch: {}", e)))
     }
 
-    /// Removes the consumer label from the queue.
+    /// Adds items unless a newer epoch of the producer is registered, so a stale instance of a
+    /// restarted producer can't add items anymore.
     ///
     /// **GIL**: the method can optionally be called without the GIL.
     ///
     /// Parameters
     /// ----------
-    /// label : str
-    ///   The consumer label to remove.
+    /// producer_id : str
+    ///   The identifier of the producer.
+    /// epoch : int
+    ///   The epoch of the producer.
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
     /// Raises
     /// ------
     /// PyRuntimeError
-    ///   If the method fails.
+    ///   If the producer is fenced or the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
     ///
     /// Returns
     /// -------
     /// None
     ///
-    #[pyo3(signature = (label, no_gil = true))]
-    fn remove_label(&self, label: &str, no_gil: bool) -> PyResult<bool> {
+    #[pyo3(signature = (producer_id, epoch, items, no_gil = true))]
+    fn push_with_epoch(
+        &self,
+        producer_id: &str,
+        epoch: u64,
+        items: &Bound<'_, PyList>,
+        no_gil: bool,
+    ) -> PyResult<()> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
         Python::with_gil(|py| {
             let f = || {
                 self.0
-                    .remove_label(label)
-                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove label: {}", e)))
+                    .push_with_epoch(producer_id, epoch, &data)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push items: {}", e)))
             };
 
             if no_gil {
@@ -413,4 +409,1429 @@ impl MpmcQueue {
             }
         })
     }
+
+    /// Adds as many of the items, in order, as the capacity of the queue allows instead of
+    /// rejecting all of them when the queue can't hold them all.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The number of the added items, the rest of the items starts at it.
+    ///
+    #[pyo3(signature = (items, no_gil = true))]
+    fn push_partial(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<usize> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .push_partial(&data)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push items: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Adds items to the front of the queue, so they are retrieved first and in order, e.g. to
+    /// return the failed items for an immediate retry.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
+    ///
+    /// Returns
+    /// -------
+    /// None
+    ///
+    #[pyo3(signature = (items, no_gil = true))]
+    fn push_front(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<()> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .push_front(&data)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to push item: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Retrieves items from the queue.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// max_elements : int
+    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   The items retrieved from the queue.
+    ///
+    #[pyo3(signature = (max_elements = 1, no_gil = true, as_memoryview = false))]
+    fn pop(
+        &self,
+        max_elements: usize,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop(max_elements))
+            } else {
+                self.0.pop(max_elements)
+            }
+            .map(|results| items_to_py(py, results, as_memoryview))
+            .map_err(|_| PyRuntimeError::new_err("Failed to pop item"))
+        })?
+    }
+
+    /// Retrieves items with their payloads, see :py:meth:`push_pair`.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// max_elements : int
+    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of tuple of bytes
+    ///   The metadata and the payloads of the items, the payloads of the plain items are empty.
+    ///
+    #[pyo3(signature = (max_elements = 1, no_gil = true))]
+    fn pop_pairs(&self, max_elements: usize, no_gil: bool) -> PyResult<Vec<(PyObject, PyObject)>> {
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop_pairs(max_elements))
+            } else {
+                self.0.pop_pairs(max_elements)
+            }
+            .map(|results| {
+                results
+                    .into_iter()
+                    .map(|(meta, payload)| {
+                        (
+                            PyBytes::new_bound(py, &meta).into(),
+                            PyBytes::new_bound(py, &payload).into(),
+                        )
+                    })
+                    .collect()
+            })
+            .map_err(|_| PyRuntimeError::new_err("Failed to pop item"))
+        })
+    }
+
+    /// Retrieves exactly ``n`` items, waiting up to ``timeout`` for them, or no items after the
+    /// timeout, never a partial batch. The waiting call is woken up as soon as items are added.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// n : int
+    ///   The number of items to retrieve, can't be greater than the capacity of the queue.
+    /// timeout : float
+    ///   The time in seconds to wait for the items.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// ValueError
+    ///   If ``timeout`` is invalid.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   ``n`` items retrieved from the queue or an empty list.
+    ///
+    #[pyo3(signature = (n, timeout, no_gil = true, as_memoryview = false))]
+    fn pop_exact(
+        &self,
+        n: usize,
+        timeout: f64,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {}", e)))?;
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop_exact(n, timeout))
+            } else {
+                self.0.pop_exact(n, timeout)
+            }
+            .map(|results| items_to_py(py, results, as_memoryview))
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
+        })?
+    }
+
+    /// Retrieves all the items from the queue. The items are popped in large batches, which is
+    /// much faster than draining the queue with ``pop()``.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// max_bytes : int, optional
+    ///   If set, stops once the total size of the retrieved items reaches it, so the result can
+    ///   exceed it by the size of the last item. Default is ``None``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   The items retrieved from the queue.
+    ///
+    #[pyo3(signature = (max_bytes = None, no_gil = true, as_memoryview = false))]
+    fn pop_all(
+        &self,
+        max_bytes: Option<u64>,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop_all(max_bytes))
+            } else {
+                self.0.pop_all(max_bytes)
+            }
+            .map(|results| items_to_py(py, results, as_memoryview))
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
+        })?
+    }
+
+    /// Retrieves the newest items from the queue, newest first, so the queue can be consumed as
+    /// a stack while the older items stay stored.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// max_elements : int
+    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   The items retrieved from the queue.
+    ///
+    #[pyo3(signature = (max_elements = 1, no_gil = true, as_memoryview = false))]
+    fn pop_back(
+        &self,
+        max_elements: usize,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop_back(max_elements))
+            } else {
+                self.0.pop_back(max_elements)
+            }
+            .map(|results| items_to_py(py, results, as_memoryview))
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
+        })?
+    }
+
+    /// Checks if the queue is empty.
+    ///
+    /// Returns
+    /// -------
+    /// bool
+    ///   ``True`` if the queue is empty, ``False`` otherwise.
+    ///
+    #[getter]
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the state of the queue read at once.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// dict
+    ///   ``len``, ``payload_size`` and ``disk_size`` of the queue, the numbers of the ``pushed``
+    ///   and ``popped`` items since the queue was opened and ``sizes``, the distribution of the
+    ///   sizes of the items pushed since the queue was created: ``count``, ``min``, ``max``,
+    ///   ``avg`` and ``histogram``, the item ``i`` of which counts the sizes of ``i`` bits, and
+    ///   ``disk_alerts``, the reached disk thresholds as ``(kind, value)`` tuples, the kind is
+    ///   ``"bytes"``, ``"percent"`` or ``"available"``.
+    ///
+    fn stats(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            let stats = py
+                .allow_threads(|| self.0.stats())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get stats: {}", e)))?;
+            let dict = PyDict::new_bound(py);
+            dict.set_item("len", stats.len)?;
+            dict.set_item("payload_size", stats.payload_size)?;
+            dict.set_item("disk_size", stats.disk_size)?;
+            dict.set_item("pushed", stats.pushed)?;
+            dict.set_item("popped", stats.popped)?;
+            dict.set_item("sizes", size_stats(py, &stats.sizes)?)?;
+            dict.set_item("disk_alerts", disk_alerts(&stats.disk_alerts))?;
+            Ok(dict.into())
+        })
+    }
+
+    /// Returns the state of the queue read against a single RocksDB snapshot, so the numbers
+    /// are consistent with each other.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// dict
+    ///   ``len`` and ``payload_size`` of the queue, ``start_index``, the index of the oldest
+    ///   item, ``write_index``, the index of the next pushed item, and ``labels``, always empty.
+    ///
+    fn stats_snapshot(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            let snapshot = py
+                .allow_threads(|| self.0.stats_snapshot())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get stats: {}", e)))?;
+            Ok(snapshot_stats(py, &snapshot)?.into())
+        })
+    }
+
+    /// Returns the time since the oldest item was pushed.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// float
+    ///   The age in seconds, ``None`` if the queue is empty or the item was pushed by a version
+    ///   not storing the push times.
+    ///
+    fn oldest_age(&self) -> PyResult<Option<f64>> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.oldest_age())
+                .map(|age| age.map(|age| age.as_secs_f64()))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get oldest age: {}", e)))
+        })
+    }
+
+    /// Estimates the disk bytes the items take in the queue from the compression ratio and the
+    /// space amplification observed by RocksDB.
+    ///
+    /// Parameters
+    /// ----------
+    /// elements : int
+    ///   The number of the items.
+    /// avg_size : int
+    ///   The average size of the items in bytes.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The estimated disk bytes.
+    ///
+    fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> PyResult<u64> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.estimate_disk_for(elements, avg_size))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to estimate disk: {}", e)))
+        })
+    }
+
+    /// Estimates the number of the items which can be pushed yet, limited by the capacity and by
+    /// the space available on the disk. The items are expected to be of the average size of the
+    /// pushed ones.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The estimated number of the items, ``None`` if no items were pushed.
+    ///
+    fn estimate_remaining_capacity(&self) -> PyResult<Option<u64>> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.estimate_remaining_capacity())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to estimate capacity: {}", e)))
+        })
+    }
+
+    /// Returns the RocksDB internals of the queue, e.g. to find out why the writes stall.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`DbStats`
+    ///   The RocksDB internals.
+    ///
+    fn db_stats(&self) -> PyResult<DbStats> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| {
+                self.0.db_stats().map(DbStats).map_err(|e| {
+                    PyRuntimeError::new_err(format!("Failed to get database stats: {}", e))
+                })
+            })
+        })
+    }
+
+    /// Returns the disk size of the queue in bytes.
+    ///
+    /// Returns
+    /// -------
+    /// size : int
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    #[getter]
+    fn disk_size(&self) -> PyResult<usize> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| {
+                self.0.disk_size().map_err(|e| {
+                    PyRuntimeError::new_err(format!("Failed to get queue size: {}", e))
+                })
+            })
+        })
+    }
+
+    /// Returns the size of the queue in bytes (only payload).
+    ///
+    /// Returns
+    /// -------
+    /// size : int
+    ///
+    #[getter]
+    fn payload_size(&self) -> u64 {
+        self.0.payload_size()
+    }
+
+    /// Returns the number of elements in the queue.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The number of elements in the queue.
+    ///
+    #[getter]
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+}
+
+/// A persistent queue with a ttl that supports multiple consumers marked with labels. This is a
+/// blocking implementation.
+///
+/// Parameters
+/// ----------
+/// path : str or os.PathLike
+///   The path to the queue.
+/// ttl : int
+///   The amount of seconds after which the element in the queue will be removed. TTL is not strict.
+///   It means that the element will remain in the queue for TTL seconds after insertion and the
+///   queue will make efforts to remove the element after TTL seconds but it is not guaranteed to be
+///   done immediately. Thus, consumers can retrieve expired but not removed elements.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The RocksDB options of the queue. Default is the RocksDB defaults.
+///
+/// Raises
+/// ------
+/// PyRuntimeError
+///   If the queue could not be created.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.blocking")]
+pub struct MpmcQueue(queue_rs::blocking::MpmcQueue);
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl MpmcQueue {
+    #[new]
+    #[pyo3(signature=(path, ttl, options = None))]
+    fn new(path: PathBuf, ttl: u32, options: Option<QueueOptions>) -> PyResult<Self> {
+        let queue = options
+            .unwrap_or_default()
+            .open_mpmc_queue(&path, Duration::from_secs(ttl as u64))?;
+        Ok(Self(queue_rs::blocking::MpmcQueue::from(queue)))
+    }
+
+    /// Adds items to the queue.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
+    ///
+    /// Returns
+    /// -------
+    /// None
+    ///
+    #[pyo3(signature = (items, no_gil = true))]
+    fn add(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<()> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .add(&data)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to add items: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Adds items with their add times, e.g. so the replayed historical data keeps its times
+    /// for the time positions, ``read_range``, ``purge_before`` and the strict TTL.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
+    /// timestamps : list of int
+    ///   The add times of the items, nanoseconds since the epoch. They must not decrease, must
+    ///   not be earlier than the last add of a non-empty queue or in the future, the last one
+    ///   must differ from the last add time.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// ValueError
+    ///   If the timestamps are invalid.
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
+    ///
+    #[pyo3(signature = (items, timestamps, no_gil = true))]
+    fn add_with_timestamps(
+        &self,
+        items: &Bound<'_, PyList>,
+        timestamps: Vec<u64>,
+        no_gil: bool,
+    ) -> PyResult<()> {
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .add_with_timestamps(&data, &timestamps)
+                    .map_err(|e| match e {
+                        QueueError::InvalidArgument(_) => PyValueError::new_err(e.to_string()),
+                        e => PyRuntimeError::new_err(format!("Failed to add items: {}", e)),
+                    })
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Adds an item with its key, it supersedes the item added with the same key before, which
+    /// is removed, so the queue keeps the latest item per key. The consumers skip the removed
+    /// items, :py:meth:`get_by_key` returns the latest item of a key.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// key : bytes
+    ///   The key of the item.
+    /// item : bytes
+    ///   The item to add to the queue.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// None
+    ///
+    #[pyo3(signature = (key, item, no_gil = true))]
+    fn add_with_key(&self, key: &[u8], item: &[u8], no_gil: bool) -> PyResult<()> {
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .add_with_keys(&[(key, item)])
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to add item: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Returns the latest item added with the key by :py:meth:`add_with_key`.
+    ///
+    /// Parameters
+    /// ----------
+    /// key : bytes
+    ///   The key of the item.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// bytes
+    ///   The item, ``None`` if it has expired or there is no such key.
+    ///
+    fn get_by_key(&self, key: &[u8]) -> PyResult<Option<PyObject>> {
+        Python::with_gil(|py| {
+            let item = py
+                .allow_threads(|| self.0.get_by_key(key))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get item: {}", e)))?;
+            Ok(item.map(|item| PyBytes::new_bound(py, &item).into()))
+        })
+    }
+
+    /// Retrieves items from the queue.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// label: str
+    ///   The consumer label that determines the start position in the queue to retrieve elements.
+    ///   If the label does not exist the start position is determined by ``option` parameter. If
+    ///   the label exists the start position is the next element after the last call of this
+    ///   method. If some elements are expired between the last and this call next non-expired
+    ///   elements will be retrieved.
+    /// start_position: StartPosition
+    ///    The option that determines the start position in the queue to retrieve elements if the
+    ///    consumer label does not exist.
+    /// max_elements : int
+    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   The items retrieved from the queue.
+    /// expired : bool
+    ///   True if some elements are expired between the last and this call of the method.
+    ///
+    #[pyo3(signature = (label, start_position, max_elements = 1, no_gil = true, as_memoryview = false))]
+    fn next(
+        &self,
+        label: &str,
+        start_position: StartPosition,
+        max_elements: usize,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<(Vec<PyObject>, bool)> {
+        Python::with_gil(|py| {
+            let start_position = match start_position {
+                StartPosition::Oldest => mpmc::StartPosition::Oldest,
+                StartPosition::Newest => mpmc::StartPosition::Newest,
+            };
+            if no_gil {
+                py.allow_threads(|| self.0.next(max_elements, label, start_position))
+            } else {
+                self.0.next(max_elements, label, start_position)
+            }
+            .map(|result| items_to_py(py, result.0, as_memoryview).map(|e| (e, result.1)))
+            .map_err(|_| PyRuntimeError::new_err("Failed to retrieve items"))
+        })?
+    }
+
+    /// Retrieves exactly ``n`` items, waiting up to ``timeout`` for them, or no items
+    /// after the timeout, never a partial batch. The consumer stays at its position until the
+    /// batch is full.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// label: str
+    ///   The consumer label, see ``next()``.
+    /// start_position: StartPosition
+    ///    The option that determines the start position in the queue to retrieve elements if the
+    ///    consumer label does not exist.
+    /// n : int
+    ///   The number of items to retrieve.
+    /// timeout : float
+    ///   The time in seconds to wait for the items.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects over the retrieved
+    ///   data instead of ``bytes`` copies. Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    /// ValueError
+    ///   If ``timeout`` is invalid.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes or list of memoryview
+    ///   ``n`` items retrieved from the queue or an empty list.
+    /// expired : bool
+    ///   True if some elements are expired between the last and this call of the method.
+    ///
+    #[pyo3(signature = (label, start_position, n, timeout, no_gil = true, as_memoryview = false))]
+    fn next_exact(
+        &self,
+        label: &str,
+        start_position: StartPosition,
+        n: usize,
+        timeout: f64,
+        no_gil: bool,
+        as_memoryview: bool,
+    ) -> PyResult<(Vec<PyObject>, bool)> {
+        let timeout = Duration::try_from_secs_f64(timeout)
+            .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {}", e)))?;
+        Python::with_gil(|py| {
+            let start_position = match start_position {
+                StartPosition::Oldest => mpmc::StartPosition::Oldest,
+                StartPosition::Newest => mpmc::StartPosition::Newest,
+            };
+            if no_gil {
+                py.allow_threads(|| self.0.next_exact(n, label, start_position, timeout))
+            } else {
+                self.0.next_exact(n, label, start_position, timeout)
+            }
+            .map(|result| items_to_py(py, result.0, as_memoryview).map(|e| (e, result.1)))
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to retrieve items: {}", e)))
+        })?
+    }
+
+    /// Subscribes to the queue with the consumer label. The returned iterator yields batches of
+    /// items as they arrive, waiting for new items when the queue has no more items for the label.
+    ///
+    /// **GIL**: the iterator releases the GIL while reading and waiting.
+    ///
+    /// Parameters
+    /// ----------
+    /// label: str
+    ///   The consumer label, see ``next()``.
+    /// start_position: StartPosition
+    ///   The start position in the queue if the consumer label does not exist.
+    /// batch : int
+    ///   The maximum number of items in a batch. Default is ``1``.
+    /// poll_timeout : float
+    ///   The time in seconds to wait for new items before handling the pending signals, e.g.
+    ///   ``KeyboardInterrupt``, and waiting again. The iterator is woken up as soon as new items
+    ///   are added. Default is ``0.1``.
+    /// as_memoryview : bool
+    ///   If True, the items are returned as read-only ``memoryview`` objects, see ``next()``.
+    ///   Default is ``False``.
+    ///
+    /// Raises
+    /// ------
+    /// ValueError
+    ///   If ``batch`` is zero or ``poll_timeout`` is invalid.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`Subscription`
+    ///   The iterator over the batches.
+    ///
+    #[pyo3(signature = (label, start_position, batch = 1, poll_timeout = 0.1, as_memoryview = false))]
+    fn subscribe(
+        &self,
+        label: &str,
+        start_position: StartPosition,
+        batch: usize,
+        poll_timeout: f64,
+        as_memoryview: bool,
+    ) -> PyResult<Subscription> {
+        if batch == 0 {
+            return Err(PyValueError::new_err(
+                "Batch size must be greater than zero",
+            ));
+        }
+        let poll_timeout = Duration::try_from_secs_f64(poll_timeout)
+            .map_err(|e| PyValueError::new_err(format!("Invalid poll timeout: {}", e)))?;
+        let start_position = match start_position {
+            StartPosition::Oldest => mpmc::StartPosition::Oldest,
+            StartPosition::Newest => mpmc::StartPosition::Newest,
+        };
+        Ok(Subscription {
+            queue: self.0.clone(),
+            label: label.to_string(),
+            start_position,
+            batch,
+            poll_timeout,
+            as_memoryview,
+            pending: None,
+        })
+    }
+
+    /// Checks if the queue is empty.
+    ///
+    /// Returns
+    /// -------
+    /// bool
+    ///   ``True`` if the queue is empty, ``False`` otherwise.
+    ///
+    #[getter]
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the state of the queue read at once, the expired items are accounted first.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// dict
+    ///   ``len`` and ``disk_size`` of the queue, ``newest_timestamp`` (the time of the last add in
+    ///   nanoseconds since the epoch or ``None`` if the queue is empty), the numbers of the
+    ///   ``added`` and ``expired`` items since the queue was opened, ``labels``, the indices
+    ///   of the next items of the consumers by their labels, ``sizes``, the distribution of
+    ///   the sizes of the added items, and ``disk_alerts`` like in
+    ///   ``PersistentQueueWithCapacity.stats``.
+    ///
+    fn stats(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            let stats = py
+                .allow_threads(|| self.0.stats())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get stats: {}", e)))?;
+            let dict = PyDict::new_bound(py);
+            dict.set_item("len", stats.len)?;
+            dict.set_item("disk_size", stats.disk_size)?;
+            dict.set_item("newest_timestamp", stats.newest_timestamp)?;
+            dict.set_item("added", stats.added)?;
+            dict.set_item("expired", stats.expired)?;
+            dict.set_item("labels", stats.labels)?;
+            dict.set_item("sizes", size_stats(py, &stats.sizes)?)?;
+            dict.set_item("disk_alerts", disk_alerts(&stats.disk_alerts))?;
+            Ok(dict.into())
+        })
+    }
+
+    /// Returns the state of the queue read against a single RocksDB snapshot, so the numbers
+    /// are consistent with each other, e.g. the length and the lags of the consumers.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// dict
+    ///   ``len`` of the queue, ``payload_size``, always ``None``, ``start_index``, the index of
+    ///   the oldest item, ``write_index``, the index of the next added item, and ``labels``,
+    ///   the positions of the consumers by their labels: dicts of ``index``, the index of the
+    ///   next item, and ``lag``, the number of the items the consumer hasn't read.
+    ///
+    fn stats_snapshot(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            let snapshot = py
+                .allow_threads(|| self.0.stats_snapshot())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get stats: {}", e)))?;
+            Ok(snapshot_stats(py, &snapshot)?.into())
+        })
+    }
+
+    /// Returns the time since the oldest not expired item was added.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// float
+    ///   The age in seconds, ``None`` if the queue is empty or the item was added by a version
+    ///   not storing the add times.
+    ///
+    fn oldest_age(&self) -> PyResult<Option<f64>> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.oldest_age())
+                .map(|age| age.map(|age| age.as_secs_f64()))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get oldest age: {}", e)))
+        })
+    }
+
+    /// Estimates the disk bytes the items take in the queue from the compression ratio and the
+    /// space amplification observed by RocksDB.
+    ///
+    /// Parameters
+    /// ----------
+    /// elements : int
+    ///   The number of the items.
+    /// avg_size : int
+    ///   The average size of the items in bytes.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The estimated disk bytes.
+    ///
+    fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> PyResult<u64> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.estimate_disk_for(elements, avg_size))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to estimate disk: {}", e)))
+        })
+    }
+
+    /// Estimates the number of the items which can be added before the disk is full, the
+    /// expired items are accounted first. The items are expected to be of the average size of
+    /// the added ones.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The estimated number of the items, ``None`` if no items were added.
+    ///
+    fn estimate_remaining_capacity(&self) -> PyResult<Option<u64>> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.estimate_remaining_capacity())
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to estimate capacity: {}", e)))
+        })
+    }
+
+    /// Returns the RocksDB internals of the queue, e.g. to find out why the writes stall.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`DbStats`
+    ///   The RocksDB internals.
+    ///
+    fn db_stats(&self) -> PyResult<DbStats> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| {
+                self.0.db_stats().map(DbStats).map_err(|e| {
+                    PyRuntimeError::new_err(format!("Failed to get database stats: {}", e))
+                })
+            })
+        })
+    }
+
+    /// Returns the disk size of the queue in bytes.
+    ///
+    /// Returns
+    /// -------
+    /// size : int
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    #[getter]
+    fn disk_size(&self) -> PyResult<usize> {
+        Python::with_gil(|py| {
+            py.allow_threads(|| {
+                self.0.disk_size().map_err(|e| {
+                    PyRuntimeError::new_err(format!("Failed to get queue size: {}", e))
+                })
+            })
+        })
+    }
+
+    /// Returns the number of elements in the queue.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The number of elements in the queue.
+    ///
+    #[getter]
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Returns the consumer labels.
+    ///
+    /// Returns
+    /// -------
+    /// labels: list of str
+    ///   The consumer labels.
+    ///
+    #[getter]
+    fn labels(&self) -> Vec<String> {
+        self.0.get_labels()
+    }
+
+    /// Retrieves the items added in the time range without moving the consumers.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// from_timestamp : int
+    ///   The start of the range, nanoseconds since the epoch, inclusive.
+    /// to_timestamp : int
+    ///   The end of the range, nanoseconds since the epoch, exclusive.
+    /// max_elements : int
+    ///   The maximum number of elements to retrieve. Default is ``1``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// items : list of bytes
+    ///   The items added in the range, oldest first.
+    ///
+    #[pyo3(signature = (from_timestamp, to_timestamp, max_elements = 1, no_gil = true))]
+    fn read_range(
+        &self,
+        from_timestamp: u64,
+        to_timestamp: u64,
+        max_elements: usize,
+        no_gil: bool,
+    ) -> PyResult<Vec<PyObject>> {
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .read_range(from_timestamp, to_timestamp, max_elements)
+            };
+            let results = if no_gil { py.allow_threads(f) } else { f() };
+            results
+                .map(|results| items_to_py(py, results, false))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to read items: {}", e)))
+        })?
+    }
+
+    /// Removes the items added before the timestamp without waiting for them to expire. The
+    /// consumers behind them get the expiration flag.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// timestamp : int
+    ///   Nanoseconds since the epoch.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///   The number of the removed items.
+    ///
+    #[pyo3(signature = (timestamp, no_gil = true))]
+    fn purge_before(&self, timestamp: u64, no_gil: bool) -> PyResult<usize> {
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .purge_before(timestamp)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to purge items: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Removes the consumer label from the queue.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// label : str
+    ///   The consumer label to remove.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// None
+    ///
+    #[pyo3(signature = (label, no_gil = true))]
+    fn remove_label(&self, label: &str, no_gil: bool) -> PyResult<bool> {
+        Python::with_gil(|py| {
+            let f = || {
+                self.0
+                    .remove_label(label)
+                    .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove label: {}", e)))
+            };
+
+            if no_gil {
+                py.allow_threads(f)
+            } else {
+                f()
+            }
+        })
+    }
+
+    /// Exports the positions of all consumers, e.g. to restore them with the backup of the
+    /// data by :py:meth:`import_labels`.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// bytes
+    ///   The serialized positions.
+    ///
+    fn export_labels(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            let bytes = py
+                .allow_threads(|| self.0.export_labels().and_then(|c| c.to_bytes()))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to export labels: {}", e)))?;
+            Ok(PyBytes::new_bound(py, &bytes).into())
+        })
+    }
+
+    /// Restores the positions of the consumers exported by :py:meth:`export_labels`. The
+    /// consumers with the same labels are replaced, the others are kept.
+    ///
+    /// Parameters
+    /// ----------
+    /// data : bytes
+    ///   The serialized positions.
+    ///
+    /// Raises
+    /// ------
+    /// PyValueError
+    ///   If the data is malformed or the positions don't fit the queue.
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    fn import_labels(&self, data: &[u8]) -> PyResult<()> {
+        let checkpoint = mpmc::LabelCheckpoint::from_bytes(data)
+            .map_err(|e| PyValueError::new_err(format!("Invalid label checkpoint: {}", e)))?;
+        Python::with_gil(|py| {
+            py.allow_threads(|| self.0.import_labels(&checkpoint))
+                .map_err(|e| match e {
+                    QueueError::InvalidArgument(_) => {
+                        PyValueError::new_err(format!("Invalid label checkpoint: {}", e))
+                    }
+                    e => PyRuntimeError::new_err(format!("Failed to import labels: {}", e)),
+                })
+        })
+    }
+
+    /// Makes the consumer a member of the group, the members share the position of the group
+    /// so any member's read moves it, e.g. for the cooperating threads of a process. The
+    /// membership is not stored, the consumers join again after the queue is opened.
+    ///
+    /// Parameters
+    /// ----------
+    /// label : str
+    ///   The consumer label.
+    /// group : str
+    ///   The label of the group.
+    ///
+    /// Raises
+    /// ------
+    /// PyValueError
+    ///   If the groups would be nested.
+    ///
+    fn join_group(&self, label: &str, group: &str) -> PyResult<()> {
+        self.0
+            .join_group(label, group)
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Removes the consumer from its group, it continues from its own position.
+    ///
+    /// Parameters
+    /// ----------
+    /// label : str
+    ///   The consumer label.
+    ///
+    /// Returns
+    /// -------
+    /// bool
+    ///   True if the consumer was a member of a group.
+    ///
+    fn leave_group(&self, label: &str) -> bool {
+        self.0.leave_group(label)
+    }
+}
+
+/// The marker yielded by :py:class:`Subscription` when some elements have expired before being
+/// read by the consumer. The next batch continues with the first non-expired element. The object
+/// is created only by the library, there is no public constructor.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.blocking", frozen)]
+pub struct Expired;
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl Expired {
+    fn __repr__(&self) -> &'static str {
+        "Expired"
+    }
+}
+
+/// The iterator returned by ``MpmcQueue.subscribe()``. It yields lists of items or
+/// :py:class:`Expired` markers and never stops, break the loop to unsubscribe. The read position
+/// is kept by the consumer label, so another subscription with the same label continues from it.
+/// The object is created only by the library, there is no public constructor.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.blocking")]
+pub struct Subscription {
+    queue: queue_rs::blocking::MpmcQueue,
+    label: String,
+    start_position: mpmc::StartPosition,
+    batch: usize,
+    poll_timeout: Duration,
+    as_memoryview: bool,
+    pending: Option<Vec<Vec<u8>>>,
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl Subscription {
+    fn __iter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __next__(&mut self, py: Python) -> PyResult<PyObject> {
+        if let Some(items) = self.pending.take() {
+            return items_to_py(py, items, self.as_memoryview).map(|items| items.into_py(py));
+        }
+        loop {
+            let (items, expired) = py
+                .allow_threads(|| {
+                    self.queue.next_wait(
+                        self.batch,
+                        &self.label,
+                        self.start_position,
+                        self.poll_timeout,
+                    )
+                })
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to retrieve items: {}", e)))?;
+            if expired {
+                if !items.is_empty() {
+                    self.pending = Some(items);
+                }
+                return Ok(Expired.into_py(py));
+            }
+            if !items.is_empty() {
+                return items_to_py(py, items, self.as_memoryview).map(|items| items.into_py(py));
+            }
+            // the signals, e.g. KeyboardInterrupt, are handled between the waits
+            py.check_signals()?;
+        }
+    }
+}
+
+fn disk_alerts(thresholds: &[DiskThreshold]) -> Vec<(&'static str, u64)> {
+    thresholds
+        .iter()
+        .map(|threshold| match threshold {
+            DiskThreshold::Bytes(bytes) => ("bytes", *bytes),
+            DiskThreshold::Percent(percent) => ("percent", *percent as u64),
+            DiskThreshold::Available(bytes) => ("available", *bytes),
+        })
+        .collect()
+}
+
+fn size_stats<'py>(py: Python<'py>, sizes: &SizeStats) -> PyResult<Bound<'py, PyDict>> {
+    let dict = PyDict::new_bound(py);
+    dict.set_item("count", sizes.count)?;
+    dict.set_item("min", sizes.min)?;
+    dict.set_item("max", sizes.max)?;
+    dict.set_item("avg", sizes.avg())?;
+    dict.set_item("histogram", sizes.histogram.to_vec())?;
+    Ok(dict)
+}
+
+pub(crate) fn snapshot_stats<'py>(
+    py: Python<'py>,
+    snapshot: &StatsSnapshot,
+) -> PyResult<Bound<'py, PyDict>> {
+    let labels = PyDict::new_bound(py);
+    for (label, position) in &snapshot.labels {
+        let dict = PyDict::new_bound(py);
+        dict.set_item("index", position.index)?;
+        dict.set_item("lag", position.lag)?;
+        labels.set_item(label, dict)?;
+    }
+    let dict = PyDict::new_bound(py);
+    dict.set_item("len", snapshot.len)?;
+    dict.set_item("payload_size", snapshot.payload_size)?;
+    dict.set_item("start_index", snapshot.start_index)?;
+    dict.set_item("write_index", snapshot.write_index)?;
+    dict.set_item("labels", labels)?;
+    Ok(dict)
 }
@@ -1,10 +1,39 @@
-use pyo3::exceptions::PyRuntimeError;
+use pyo3::buffer::PyBuffer;
+use pyo3::create_exception;
+use pyo3::exceptions::{PyBufferError, PyRuntimeError, PyTypeError, PyValueError};
+use pyo3::ffi;
 use pyo3::prelude::*;
-use pyo3::types::{PyDict, PyList};
+use pyo3::types::{PyBytes, PyDict, PyList, PyMemoryView};
 use pyo3::wrap_pymodule;
+use queue_rs::discovery::{QueueHealth, QueueKind};
+use queue_rs::options::RemoveOptions;
+use queue_rs::QueueError;
+use std::ffi::CString;
+use std::os::raw::{c_int, c_void};
+use std::path::PathBuf;
+
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::{gen_stub_pyclass, gen_stub_pyclass_enum, gen_stub_pyfunction};
 
 mod blocking;
+mod logging;
 mod nonblocking;
+mod options;
+mod stats;
+
+pub use options::{QueueOptions, SharedResources};
+pub use stats::DbStats;
+
+// collects the stubs of the annotated items, see `src/bin/stub_gen.rs`
+#[cfg(feature = "stub-gen")]
+pyo3_stub_gen::define_stub_info_gatherer!(stub_info);
+
+create_exception!(
+    rocksq,
+    QueueInUseError,
+    PyRuntimeError,
+    "The queue is open, e.g. by another process."
+);
 
 /// Returns the version of the underlying queue_rs library.
 ///
@@ -13,6 +42,7 @@ mod nonblocking;
 /// version : str
 ///   The version of the underlying queue_rs library.
 ///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
 #[pyfunction]
 pub fn version() -> String {
     queue_rs::version().to_string()
@@ -22,39 +52,217 @@ pub fn version() -> String {
 ///
 /// Parameters
 /// ----------
-/// path : str
+/// path : str or os.PathLike
 ///   The path to the queue to remove.
+/// force : bool
+///   If True, the queue is removed even if it is open, e.g. by another process, which loses
+///   its data. Default is ``False``.
+/// secure_wipe : bool
+///   If True, the data files are overwritten with zeros and synced before they are removed, so
+///   the elements can't be recovered from the freed blocks. Default is ``False``.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The options the queue was created with.
 ///
 /// Raises
 /// ------
+/// QueueInUseError
+///   If the queue is open and ``force`` is not set.
 /// PyRuntimeError
 ///   If the queue could not be removed.
 ///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
 #[pyfunction]
-fn remove_queue(path: &str) -> PyResult<()> {
-    queue_rs::PersistentQueueWithCapacity::remove_db(path)
-        .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove persistent queue: {}", e)))
+#[pyo3(signature = (path, force = false, secure_wipe = false, options = None))]
+fn remove_queue(
+    path: PathBuf,
+    force: bool,
+    secure_wipe: bool,
+    options: Option<QueueOptions>,
+) -> PyResult<()> {
+    let options = remove_options(force, secure_wipe, options)?;
+    queue_rs::PersistentQueueWithCapacity::remove_db_with(path, &options)
+        .map_err(|e| remove_error("persistent queue", e))
 }
 
 /// Removes ``MpmcQueue`` at the given path. The queue must be closed.
 ///
 /// Parameters
 /// ----------
-/// path : str
+/// path : str or os.PathLike
 ///   The path to the queue to remove.
+/// force : bool
+///   If True, the queue is removed even if it is open, e.g. by another process, which loses
+///   its data. Default is ``False``.
+/// secure_wipe : bool
+///   If True, the data files are overwritten with zeros and synced before they are removed, so
+///   the elements can't be recovered from the freed blocks. Default is ``False``.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The options the queue was created with.
 ///
 /// Raises
 /// ------
+/// QueueInUseError
+///   If the queue is open and ``force`` is not set.
 /// PyRuntimeError
 ///   If the queue could not be removed.
 ///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
+#[pyfunction]
+#[pyo3(signature = (path, force = false, secure_wipe = false, options = None))]
+fn remove_mpmc_queue(
+    path: PathBuf,
+    force: bool,
+    secure_wipe: bool,
+    options: Option<QueueOptions>,
+) -> PyResult<()> {
+    let options = remove_options(force, secure_wipe, options)?;
+    queue_rs::mpmc::MpmcQueue::remove_db_with(path, &options)
+        .map_err(|e| remove_error("mpmc queue", e))
+}
+
+/// Packs the parts into one element, e.g. a Savant message serialized with
+/// ``savant_rs.utils.serialization.save_message_to_bytes`` and the frame content.
+///
+/// Parameters
+/// ----------
+/// parts : list of bytes
+///   The parts of the element.
+///
+/// Returns
+/// -------
+/// bytes
+///   The element to push.
+///
+/// Raises
+/// ------
+/// ValueError
+///   If a part is longer than 4 GiB.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
+#[pyfunction]
+fn pack_parts(py: Python<'_>, parts: Vec<Vec<u8>>) -> PyResult<PyObject> {
+    let parts = parts.iter().map(|p| p.as_slice()).collect::<Vec<_>>();
+    let element =
+        queue_rs::multipart::pack(&parts).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(PyBytes::new_bound(py, &element).into())
+}
+
+/// Returns the parts of the element packed with ``pack_parts``.
+///
+/// Parameters
+/// ----------
+/// element : bytes
+///   The popped element.
+///
+/// Returns
+/// -------
+/// list of bytes
+///   The parts of the element.
+///
+/// Raises
+/// ------
+/// ValueError
+///   If the element is not a multi-part one.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
 #[pyfunction]
-fn remove_mpmc_queue(path: &str) -> PyResult<()> {
-    queue_rs::mpmc::MpmcQueue::remove_db(path)
-        .map_err(|e| PyRuntimeError::new_err(format!("Failed to remove mpmc queue: {}", e)))
+fn unpack_parts(py: Python<'_>, element: &[u8]) -> PyResult<Vec<PyObject>> {
+    let parts =
+        queue_rs::multipart::unpack(element).map_err(|e| PyValueError::new_err(e.to_string()))?;
+    Ok(parts
+        .into_iter()
+        .map(|p| PyBytes::new_bound(py, p).into())
+        .collect())
 }
 
-#[pyclass(eq, eq_int)]
+/// Finds the queues in the directory tree, e.g. to inventory the queues left on a node. The
+/// databases are opened read-only, so the open queues are found too, with the state last
+/// written by them.
+///
+/// Parameters
+/// ----------
+/// root_dir : str or os.PathLike
+///   The directory to scan.
+///
+/// Raises
+/// ------
+/// PyRuntimeError
+///   If the directory could not be scanned.
+///
+/// Returns
+/// -------
+/// list[dict]
+///   The queues ordered by their paths: ``path``, ``kind`` (``"persistent"`` or ``"mpmc"``),
+///   ``format_version``, ``disk_size``, ``stats`` (as returned by ``stats_snapshot()``, None if
+///   the queue can't be read), ``health`` (``"healthy"``, ``"newer_format"``,
+///   ``"inconsistent"`` or ``"unreadable"``) and ``problems`` (the descriptions of the health).
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
+#[pyfunction]
+fn discover(py: Python<'_>, root_dir: PathBuf) -> PyResult<Vec<PyObject>> {
+    let queues = py
+        .allow_threads(|| queue_rs::discovery::discover(root_dir))
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to discover queues: {}", e)))?;
+    let mut res = Vec::with_capacity(queues.len());
+    for queue in queues {
+        let (health, problems) = match queue.health {
+            QueueHealth::Healthy => ("healthy", vec![]),
+            QueueHealth::NewerFormat(version) => (
+                "newer_format",
+                vec![format!("The format version {} is not supported", version)],
+            ),
+            QueueHealth::Inconsistent(problems) => (
+                "inconsistent",
+                problems.iter().map(|p| format!("{:?}", p)).collect(),
+            ),
+            QueueHealth::Unreadable(error) => ("unreadable", vec![error]),
+        };
+        let dict = PyDict::new_bound(py);
+        dict.set_item("path", queue.path)?;
+        dict.set_item(
+            "kind",
+            match queue.kind {
+                QueueKind::Persistent => "persistent",
+                QueueKind::Mpmc => "mpmc",
+            },
+        )?;
+        dict.set_item("format_version", queue.format_version)?;
+        dict.set_item("disk_size", queue.disk_size)?;
+        let stats = queue
+            .stats
+            .map(|stats| blocking::snapshot_stats(py, &stats))
+            .transpose()?;
+        dict.set_item("stats", stats)?;
+        dict.set_item("health", health)?;
+        dict.set_item("problems", problems)?;
+        res.push(dict.into());
+    }
+    Ok(res)
+}
+
+fn remove_options(
+    force: bool,
+    secure_wipe: bool,
+    options: Option<QueueOptions>,
+) -> PyResult<RemoveOptions> {
+    let mut remove = RemoveOptions::default()
+        .force(force)
+        .secure_wipe(secure_wipe);
+    if let Some(options) = options {
+        remove = remove.db_options(options.db_options()?);
+    }
+    Ok(remove)
+}
+
+fn remove_error(kind: &str, e: QueueError) -> PyErr {
+    match e {
+        QueueError::InUse { .. } => QueueInUseError::new_err(e.to_string()),
+        _ => PyRuntimeError::new_err(format!("Failed to remove {}: {}", kind, e)),
+    }
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass_enum)]
+#[pyclass(module = "rocksq", eq, eq_int)]
 #[derive(PartialEq, Copy, Clone)]
 enum StartPosition {
     Oldest = 0,
@@ -65,6 +273,8 @@ enum StartPosition {
 fn rocksq_blocking(_: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<blocking::PersistentQueueWithCapacity>()?;
     m.add_class::<blocking::MpmcQueue>()?;
+    m.add_class::<blocking::Subscription>()?;
+    m.add_class::<blocking::Expired>()?;
     Ok(())
 }
 
@@ -78,6 +288,8 @@ fn rocksq_nonblocking(_: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_class::<nonblocking::MpmcResponse>()?;
     m.add_class::<nonblocking::MpmcQueue>()?;
 
+    m.add_function(wrap_pyfunction!(nonblocking::wait_all, m)?)?;
+
     Ok(())
 }
 
@@ -86,11 +298,20 @@ fn rocksq(py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     m.add_function(wrap_pyfunction!(version, m)?)?;
     m.add_function(wrap_pyfunction!(remove_queue, m)?)?;
     m.add_function(wrap_pyfunction!(remove_mpmc_queue, m)?)?;
+    m.add_function(wrap_pyfunction!(pack_parts, m)?)?;
+    m.add_function(wrap_pyfunction!(unpack_parts, m)?)?;
+    m.add_function(wrap_pyfunction!(discover, m)?)?;
+    m.add("QueueInUseError", py.get_type_bound::<QueueInUseError>())?;
+    m.add_function(wrap_pyfunction!(logging::set_log_level, m)?)?;
 
     m.add_wrapped(wrap_pymodule!(rocksq_blocking))?;
     m.add_wrapped(wrap_pymodule!(rocksq_nonblocking))?;
 
     m.add_class::<StartPosition>()?;
+    m.add_class::<Payload>()?;
+    m.add_class::<QueueOptions>()?;
+    m.add_class::<SharedResources>()?;
+    m.add_class::<DbStats>()?;
 
     let sys = PyModule::import_bound(py, "sys")?;
     let attr_modules = sys.getattr("modules")?;
@@ -102,13 +323,126 @@ fn rocksq(py: Python, m: &Bound<'_, PyModule>) -> PyResult<()> {
     Ok(())
 }
 
-fn pylist_to_vec_of_byte_vec(items: &Bound<'_, PyList>) -> Vec<Vec<u8>> {
-    items
-        .iter()
-        .map(|e| e.extract::<Vec<u8>>().unwrap())
-        .collect::<Vec<_>>()
+/// The items borrowed from the objects implementing the buffer protocol (``bytes``,
+/// ``bytearray``, ``memoryview``, numpy arrays, etc.), so they are not copied before being
+/// written to the queue. The objects must not be modified until the operation completes.
+///
+struct ByteBuffers(Vec<PyBuffer<u8>>);
+
+impl ByteBuffers {
+    fn from_list(items: &Bound<'_, PyList>) -> PyResult<Self> {
+        items
+            .iter()
+            .map(|item| {
+                let buffer = PyBuffer::<u8>::get_bound(&item)
+                    .or_else(|_| {
+                        // buffers of other item types are viewed as bytes
+                        let view = PyMemoryView::from_bound(&item)?.call_method1("cast", ("B",))?;
+                        PyBuffer::<u8>::get_bound(&view)
+                    })
+                    .map_err(|_| {
+                        PyTypeError::new_err(format!(
+                            "Items must support the buffer protocol, got {}",
+                            item.get_type()
+                        ))
+                    })?;
+                if !buffer.is_c_contiguous() {
+                    return Err(PyTypeError::new_err("Items must be C-contiguous buffers"));
+                }
+                Ok(buffer)
+            })
+            .collect::<PyResult<Vec<_>>>()
+            .map(Self)
+    }
+
+    fn as_slices(&self) -> Vec<&[u8]> {
+        self.0
+            .iter()
+            .map(|buffer| {
+                // SAFETY: the buffer is contiguous and stays valid while it is held
+                unsafe {
+                    std::slice::from_raw_parts(buffer.buf_ptr() as *const u8, buffer.len_bytes())
+                }
+            })
+            .collect()
+    }
 }
 
-fn value_as_slice<T>(items: &[Vec<T>]) -> Vec<&[T]> {
-    items.iter().map(|e| e.as_slice()).collect::<Vec<_>>()
+/// A read-only item retrieved from the queue. The object owns the bytes read from the queue and
+/// exposes them with the buffer protocol, so ``memoryview(payload)`` does not copy them. The
+/// object is created only by the library, there is no public constructor.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq", frozen)]
+pub struct Payload(Vec<u8>);
+
+#[pymethods]
+impl Payload {
+    fn __len__(&self) -> usize {
+        self.0.len()
+    }
+
+    unsafe fn __getbuffer__(
+        slf: Bound<'_, Self>,
+        view: *mut ffi::Py_buffer,
+        flags: c_int,
+    ) -> PyResult<()> {
+        if view.is_null() {
+            return Err(PyBufferError::new_err("View is null"));
+        }
+        if (flags & ffi::PyBUF_WRITABLE) == ffi::PyBUF_WRITABLE {
+            return Err(PyBufferError::new_err("Object is not writable"));
+        }
+
+        let data = &slf.get().0;
+        (*view).buf = data.as_ptr() as *mut c_void;
+        (*view).len = data.len() as isize;
+        (*view).readonly = 1;
+        (*view).itemsize = 1;
+        (*view).format = if (flags & ffi::PyBUF_FORMAT) == ffi::PyBUF_FORMAT {
+            CString::new("B").unwrap().into_raw()
+        } else {
+            std::ptr::null_mut()
+        };
+        (*view).ndim = 1;
+        (*view).shape = if (flags & ffi::PyBUF_ND) == ffi::PyBUF_ND {
+            &mut (*view).len
+        } else {
+            std::ptr::null_mut()
+        };
+        (*view).strides = if (flags & ffi::PyBUF_STRIDES) == ffi::PyBUF_STRIDES {
+            &mut (*view).itemsize
+        } else {
+            std::ptr::null_mut()
+        };
+        (*view).suboffsets = std::ptr::null_mut();
+        (*view).internal = std::ptr::null_mut();
+        // the view keeps the payload alive
+        (*view).obj = slf.into_any().into_ptr();
+
+        Ok(())
+    }
+
+    unsafe fn __releasebuffer__(&self, view: *mut ffi::Py_buffer) {
+        if !(*view).format.is_null() {
+            drop(CString::from_raw((*view).format));
+        }
+    }
+}
+
+/// Converts the items retrieved from the queue to Python objects. The items are either copied
+/// to ``bytes`` or moved to ``Payload`` objects and returned as ``memoryview`` without copying.
+///
+fn items_to_py(py: Python, items: Vec<Vec<u8>>, as_memoryview: bool) -> PyResult<Vec<PyObject>> {
+    items
+        .into_iter()
+        .map(|item| {
+            if as_memoryview {
+                let payload = Bound::new(py, Payload(item))?;
+                PyMemoryView::from_bound(&payload).map(PyObject::from)
+            } else {
+                Ok(PyBytes::new_bound(py, &item).into())
+            }
+        })
+        .collect()
 }
@@ -0,0 +1,129 @@
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Mutex, Once};
+use std::thread;
+
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::gen_stub_pyfunction;
+
+const LEVELS: [(&str, LevelFilter); 7] = [
+    ("CRITICAL", LevelFilter::Error),
+    ("ERROR", LevelFilter::Error),
+    ("WARNING", LevelFilter::Warn),
+    ("INFO", LevelFilter::Info),
+    ("DEBUG", LevelFilter::Debug),
+    ("TRACE", LevelFilter::Trace),
+    ("OFF", LevelFilter::Off),
+];
+
+struct LogRecord {
+    level: Level,
+    target: String,
+    message: String,
+}
+
+/// Passes the records to the forwarding thread, so the threads logging while holding the queue
+/// locks never wait for the GIL.
+///
+struct Forwarder(Mutex<Sender<LogRecord>>);
+
+impl Log for Forwarder {
+    fn enabled(&self, _metadata: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let record = LogRecord {
+            level: record.level(),
+            target: record.target().to_string(),
+            message: record.args().to_string(),
+        };
+        if let Ok(tx) = self.0.lock() {
+            _ = tx.send(record);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+/// Returns the Python logger name of the Rust module, e.g. ``rocksq.mpmc`` for
+/// ``queue_rs::mpmc``.
+///
+fn logger_name(target: &str) -> String {
+    let target = target.replace("::", ".");
+    match target.strip_prefix("queue_rs") {
+        Some(module) => format!("rocksq{}", module),
+        None => target,
+    }
+}
+
+fn python_level(level: Level) -> u8 {
+    match level {
+        Level::Error => 40,
+        Level::Warn => 30,
+        Level::Info => 20,
+        Level::Debug => 10,
+        Level::Trace => 5,
+    }
+}
+
+fn forward(rx: Receiver<LogRecord>) {
+    for record in rx {
+        Python::with_gil(|py| {
+            let res = py.import_bound("logging").and_then(|logging| {
+                logging
+                    .call_method1("getLogger", (logger_name(&record.target),))?
+                    .call_method1("log", (python_level(record.level), record.message))
+            });
+            if let Err(e) = res {
+                e.print(py);
+            }
+        });
+    }
+}
+
+/// Forwards the log records of the queues (e.g. opening, trimming expired elements, readers
+/// missing expired elements, failures of the working threads) to the Python ``logging`` module.
+/// The records are logged by the ``rocksq`` logger and its children, e.g. ``rocksq.mpmc``, from a
+/// separate thread. The first call installs the forwarding, the next ones change the level.
+///
+/// Parameters
+/// ----------
+/// level : str
+///   The minimal level of the forwarded records: ``CRITICAL``, ``ERROR``, ``WARNING``,
+///   ``INFO``, ``DEBUG``, ``TRACE`` or ``OFF``. Default is ``INFO``.
+///
+/// Raises
+/// ------
+/// ValueError
+///   If the level is unknown.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyfunction)]
+#[pyfunction]
+#[pyo3(signature = (level = "INFO"))]
+pub fn set_log_level(level: &str) -> PyResult<()> {
+    static INSTALL: Once = Once::new();
+
+    let filter = LEVELS
+        .iter()
+        .find(|(name, _)| *name == level)
+        .map(|(_, filter)| *filter)
+        .ok_or_else(|| {
+            PyValueError::new_err(format!(
+                "Unknown level: {}, expected one of: {}",
+                level,
+                LEVELS.map(|(name, _)| name).join(", ")
+            ))
+        })?;
+    INSTALL.call_once(|| {
+        let (tx, rx) = mpsc::channel();
+        // another logger may be installed by the application
+        if log::set_boxed_logger(Box::new(Forwarder(Mutex::new(tx)))).is_ok() {
+            thread::spawn(move || forward(rx));
+        }
+    });
+    log::set_max_level(filter);
+    Ok(())
+}
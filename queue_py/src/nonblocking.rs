@@ -1,20 +1,149 @@
-use crate::{pylist_to_vec_of_byte_vec, value_as_slice, StartPosition};
-use pyo3::exceptions::PyRuntimeError;
+use crate::{ByteBuffers, QueueOptions, StartPosition};
+use pyo3::exceptions::{PyRuntimeError, PyTimeoutError, PyTypeError, PyValueError};
 use pyo3::prelude::*;
-use pyo3::types::{PyBytes, PyList};
+use pyo3::types::{PyBytes, PyCFunction, PyList, PyTuple};
 use queue_rs::mpmc;
-use rocksdb::Options;
+use queue_rs::nonblocking::TypedResponse;
+use std::mem::ManuallyDrop;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Condvar, Mutex, OnceLock};
 use std::time::Duration;
 
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::{gen_stub_pyclass, gen_stub_pyfunction, gen_stub_pymethods};
+
+type Delivery = Box<dyn FnOnce(Python) + Send>;
+
+/// Runs `f` with the GIL on the delivery thread. The working threads of the queues must not wait
+/// for the GIL: the caller holding it may be blocked submitting to the full operation channel
+/// they drain.
+///
+fn deliver<F>(f: F)
+where
+    F: FnOnce(Python) + Send + 'static,
+{
+    static DELIVERY: OnceLock<Mutex<mpsc::Sender<Delivery>>> = OnceLock::new();
+    let sender = DELIVERY.get_or_init(|| {
+        let (tx, rx) = mpsc::channel::<Delivery>();
+        std::thread::Builder::new()
+            .name("rocksq-delivery".to_string())
+            .spawn(move || {
+                for f in rx {
+                    Python::with_gil(f);
+                }
+            })
+            .expect("Failed to spawn the delivery thread");
+        Mutex::new(tx)
+    });
+    if let Ok(sender) = sender.lock() {
+        // the thread runs as long as the process
+        _ = sender.send(Box::new(f));
+    }
+}
+
+/// Returns an asyncio future which is resolved with the result of the response when it is ready.
+/// The result is scheduled on the loop by the delivery thread, see [`deliver`].
+///
+fn as_future<T, V, F>(
+    py: Python,
+    response: &Arc<TypedResponse<T>>,
+    event_loop: Option<Bound<'_, PyAny>>,
+    wrap: F,
+) -> PyResult<PyObject>
+where
+    T: Send + 'static,
+    V: IntoPy<PyObject>,
+    F: FnOnce(T) -> V + Send + 'static,
+{
+    let event_loop = match event_loop {
+        Some(event_loop) => event_loop,
+        None => py
+            .import_bound("asyncio")?
+            .call_method0("get_running_loop")?,
+    };
+    let future = event_loop.call_method0("create_future")?;
+    let (event_loop_ref, future_ref) = (event_loop.unbind(), future.clone().unbind());
+    let source = response.clone();
+    response.on_ready(move || {
+        deliver(move |py| {
+            let outcome = match source.try_get() {
+                Ok(Some(res)) => Ok(wrap(res).into_py(py)),
+                Ok(None) => Err(PyRuntimeError::new_err(
+                    "Failed to get response: the result has already been received",
+                )),
+                Err(e) => Err(PyRuntimeError::new_err(format!(
+                    "Failed to get response: {}",
+                    e
+                ))),
+            };
+            let outcome = Mutex::new(Some(outcome));
+            let target = future_ref.clone_ref(py);
+            let resolve = PyCFunction::new_closure_bound(
+                py,
+                None,
+                None,
+                move |args: &Bound<'_, PyTuple>, _| -> PyResult<()> {
+                    let future = target.bind(args.py());
+                    // the future may have been cancelled meanwhile
+                    if future.call_method0("done")?.is_truthy()? {
+                        return Ok(());
+                    }
+                    match outcome.lock().ok().and_then(|mut outcome| outcome.take()) {
+                        Some(Ok(res)) => future.call_method1("set_result", (res,))?,
+                        Some(Err(e)) => future.call_method1("set_exception", (e,))?,
+                        None => return Ok(()),
+                    };
+                    Ok(())
+                },
+            );
+            // the loop may already be closed, the result can't be delivered then
+            _ = resolve.and_then(|resolve| {
+                event_loop_ref
+                    .bind(py)
+                    .call_method1("call_soon_threadsafe", (resolve,))
+            });
+        })
+    });
+    Ok(future.unbind())
+}
+
 /// A response variant containing the actual data for push, pop, size and length operations of
 /// ``PersistentQueueWithCapacity``. The object is created only by the library, there is no
 /// public constructor.
 ///
-#[pyclass]
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
 pub struct ResponseVariant(queue_rs::nonblocking::ResponseVariant);
 
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl ResponseVariant {
+    /// Returns the index range of the ``push()`` operation.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// (int, int)
+    ///   The index of the first pushed element and the number of the elements if the operation
+    ///   was successful,
+    /// ``None``
+    ///   if the future doesn't represent the ``push()`` operation.
+    ///
+    #[getter]
+    fn index_range(&self) -> PyResult<Option<(u64, usize)>> {
+        match &self.0 {
+            queue_rs::nonblocking::ResponseVariant::Push(data) => Ok(data
+                .as_ref()
+                .map(|r| Some(*r))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get response: {}", e)))?),
+            _ => Ok(None),
+        }
+    }
+
     /// Returns the data for the ``pop()`` operation.
     ///
     /// Raises
@@ -76,6 +205,23 @@ impl ResponseVariant {
         }
     }
 
+    /// Returns whether the queue is empty.
+    ///
+    /// Returns
+    /// -------
+    /// ``bool``
+    ///   ``True`` if the queue is empty, ``False`` otherwise,
+    /// ``None``
+    ///   if the future doesn't represent the ``is_empty()`` operation.
+    ///
+    #[getter]
+    fn is_empty(&self) -> Option<bool> {
+        match &self.0 {
+            queue_rs::nonblocking::ResponseVariant::IsEmpty(data) => Some(*data),
+            _ => None,
+        }
+    }
+
     /// Returns the size of the queue.
     ///
     /// Raises
@@ -102,9 +248,17 @@ impl ResponseVariant {
     }
 }
 
-#[pyclass]
-pub struct Response(queue_rs::nonblocking::Response);
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
+pub struct Response(Arc<queue_rs::nonblocking::Response>);
 
+impl From<queue_rs::nonblocking::Response> for Response {
+    fn from(response: queue_rs::nonblocking::Response) -> Self {
+        Self(Arc::new(response))
+    }
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl Response {
     /// Checks if the response is ready.
@@ -164,6 +318,62 @@ impl Response {
             })
         })
     }
+
+    /// Returns an asyncio future resolved with the response when it is ready. The result is
+    /// delivered by the working thread, which takes the GIL to schedule it on the event loop. The
+    /// result can be received only once, either from the future or with ``get()``.
+    ///
+    /// Parameters
+    /// ----------
+    /// event_loop : asyncio.AbstractEventLoop, optional
+    ///   The event loop of the future. Default is the running event loop.
+    ///
+    /// Raises
+    /// ------
+    /// RuntimeError
+    ///   If there is no running event loop and ``event_loop`` is not given.
+    ///
+    /// Returns
+    /// -------
+    /// asyncio.Future
+    ///   The future resolved with :py:class:`ResponseVariant`, or failed with ``RuntimeError`` if
+    ///   the response can't be received.
+    ///
+    #[pyo3(signature = (event_loop = None))]
+    fn as_future(&self, event_loop: Option<Bound<'_, PyAny>>) -> PyResult<PyObject> {
+        Python::with_gil(|py| as_future(py, &self.0, event_loop, ResponseVariant))
+    }
+
+    /// Makes the response awaitable in the running event loop, ``await response`` returns
+    /// :py:class:`ResponseVariant`. See ``as_future()``.
+    ///
+    fn __await__(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            as_future(py, &self.0, None, ResponseVariant)?.call_method0(py, "__await__")
+        })
+    }
+
+    /// Adds the callback invoked with the response as the only argument when it is ready, or
+    /// right away if it already is. The callbacks are invoked one by one by the delivery thread
+    /// of the library, not by the working threads, so a blocking callback delays the others but
+    /// not the queue. The exceptions raised by the callback are reported as unraisable.
+    ///
+    /// Parameters
+    /// ----------
+    /// callback : Callable[[Response], None]
+    ///   The callback to invoke.
+    ///
+    fn add_done_callback(slf: Bound<'_, Self>, callback: PyObject) {
+        let response = slf.clone().unbind();
+        let source = slf.borrow().0.clone();
+        source.on_ready(move || {
+            deliver(move |py| {
+                if let Err(e) = callback.call1(py, (response,)) {
+                    e.write_unraisable_bound(py, Some(callback.bind(py)));
+                }
+            })
+        });
+    }
 }
 
 /// A persistent queue with a fixed capacity. This is a non-blocking implementation.
@@ -171,37 +381,56 @@ impl Response {
 ///
 /// Parameters
 /// ----------
-/// path : str
+/// path : str or os.PathLike
 ///   The path to the queue.
 /// max_elements : int
 ///   The maximum number of elements the queue can hold. Default to ``1_000_000_000``.
 /// max_inflight_ops : int
 ///   The maximum number of inflight operations. If the number of inflight operations reached its limit,
 ///   further ops are blocked until the capacity is available. Default to ``1_000``.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The RocksDB options of the queue. Default is the RocksDB defaults.
 ///
 /// Raises
 /// ------
 /// PyRuntimeError
 ///   If the queue could not be created.
 ///
-#[pyclass]
-pub struct PersistentQueueWithCapacity(queue_rs::nonblocking::PersistentQueueWithCapacity);
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
+pub struct PersistentQueueWithCapacity(
+    ManuallyDrop<queue_rs::nonblocking::PersistentQueueWithCapacity>,
+);
+
+impl Drop for PersistentQueueWithCapacity {
+    fn drop(&mut self) {
+        // SAFETY: the queue is not used after being taken
+        let queue = unsafe { ManuallyDrop::take(&mut self.0) };
+        // joining the working threads may take a while, the other Python threads keep running
+        Python::with_gil(|py| py.allow_threads(move || drop(queue)));
+    }
+}
 
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl PersistentQueueWithCapacity {
     #[new]
-    #[pyo3(signature=(path, max_elements = 1_000_000_000, max_inflight_ops = 1_000))]
-    fn new(path: &str, max_elements: usize, max_inflight_ops: usize) -> PyResult<Self> {
-        let q = queue_rs::nonblocking::PersistentQueueWithCapacity::new(
-            path,
-            max_elements,
-            max_inflight_ops,
-            Options::default(),
-        )
-        .map_err(|e| {
-            PyRuntimeError::new_err(format!("Failed to create persistent queue: {}", e))
-        })?;
-        Ok(Self(q))
+    #[pyo3(signature=(path, max_elements = 1_000_000_000, max_inflight_ops = 1_000, options = None))]
+    fn new(
+        path: PathBuf,
+        max_elements: usize,
+        max_inflight_ops: usize,
+        options: Option<QueueOptions>,
+    ) -> PyResult<Self> {
+        let queue = options
+            .unwrap_or_default()
+            .open_queue(&path, max_elements)?;
+        let q =
+            queue_rs::nonblocking::PersistentQueueWithCapacity::from_queue(queue, max_inflight_ops)
+                .map_err(|e| {
+                    PyRuntimeError::new_err(format!("Failed to create persistent queue: {}", e))
+                })?;
+        Ok(Self(ManuallyDrop::new(q)))
     }
 
     /// Adds items to the queue.
@@ -210,8 +439,8 @@ impl PersistentQueueWithCapacity {
     ///
     /// Parameters
     /// ----------
-    /// items : list of bytes
-    ///   The items to add to the queue.
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
@@ -219,6 +448,8 @@ impl PersistentQueueWithCapacity {
     /// ------
     /// PyRuntimeError
     ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
     ///
     /// Returns
     /// -------
@@ -228,8 +459,8 @@ impl PersistentQueueWithCapacity {
     ///
     #[pyo3(signature = (items, no_gil = true))]
     fn push(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<Response> {
-        let items = pylist_to_vec_of_byte_vec(items);
-        let data = value_as_slice(&items);
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
         Python::with_gil(|py| {
             let f = || {
                 self.0
@@ -243,7 +474,7 @@ impl PersistentQueueWithCapacity {
                 f()
             }
         })
-        .map(Response)
+        .map(Response::from)
     }
 
     #[getter]
@@ -253,6 +484,32 @@ impl PersistentQueueWithCapacity {
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get inflight ops: {}", e)))
     }
 
+    /// Checks if the working threads are running. The operations of an unhealthy queue fail.
+    ///
+    /// Returns
+    /// -------
+    /// bool
+    ///   ``True`` if the queue is healthy, ``False`` otherwise.
+    ///
+    #[getter]
+    pub fn is_healthy(&self) -> bool {
+        self.0.is_healthy()
+    }
+
+    /// Returns the error the working threads last failed with.
+    ///
+    /// Returns
+    /// -------
+    /// str
+    ///   The error message,
+    /// ``None``
+    ///   if the working threads have not failed.
+    ///
+    #[getter]
+    pub fn last_error(&self) -> Option<String> {
+        self.0.last_error()
+    }
+
     /// Retrieves items from the queue.
     ///
     /// **GIL**: the method can optionally be called without the GIL.
@@ -283,7 +540,44 @@ impl PersistentQueueWithCapacity {
             } else {
                 self.0.pop(max_elements)
             }
-            .map(Response)
+            .map(Response::from)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
+        })
+    }
+
+    /// Retrieves all the items from the queue. The items are popped in large batches, which is
+    /// much faster than draining the queue with ``pop()``.
+    ///
+    /// **GIL**: the method can optionally be called without the GIL.
+    ///
+    /// Parameters
+    /// ----------
+    /// max_bytes : int, optional
+    ///   If set, stops once the total size of the retrieved items reaches it, so the result can
+    ///   exceed it by the size of the last item. Default is ``None``.
+    /// no_gil : bool
+    ///   If True, the method will be called without the GIL. Default is ``True``.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`Response`
+    ///   The future-like object which must be used to get the actual response, the items are
+    ///   returned by :py:attr:`ResponseVariant.data`.
+    ///
+    #[pyo3(signature = (max_bytes = None, no_gil = true))]
+    fn pop_all(&self, max_bytes: Option<u64>, no_gil: bool) -> PyResult<Response> {
+        Python::with_gil(|py| {
+            if no_gil {
+                py.allow_threads(|| self.0.pop_all(max_bytes))
+            } else {
+                self.0.pop_all(max_bytes)
+            }
+            .map(Response::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
         })
     }
@@ -304,7 +598,7 @@ impl PersistentQueueWithCapacity {
     pub fn disk_size(&self) -> PyResult<Response> {
         self.0
             .disk_size()
-            .map(Response)
+            .map(Response::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get size: {}", e)))
     }
 
@@ -324,7 +618,7 @@ impl PersistentQueueWithCapacity {
     pub fn payload_size(&self) -> PyResult<Response> {
         self.0
             .payload_size()
-            .map(Response)
+            .map(Response::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get size: {}", e)))
     }
 
@@ -345,19 +639,68 @@ impl PersistentQueueWithCapacity {
     pub fn len(&self) -> PyResult<Response> {
         self.0
             .len()
-            .map(Response)
+            .map(Response::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get length: {}", e)))
     }
+
+    /// Checks if the queue is empty.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`Response`
+    ///   The future-like object which must be used to get the actual response. For the emptiness
+    ///   check, the response object is useful to call for ``is_ready()``, ``try_get()`` and ``get()``.
+    ///
+    #[getter]
+    pub fn is_empty(&self) -> PyResult<Response> {
+        self.0
+            .is_empty()
+            .map(Response::from)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to check emptiness: {}", e)))
+    }
 }
 
 /// A response variant containing the actual data for add, next, size and length operations of
 /// ``MpmcQueue``. The object is created only by the library, there is no public constructor.
 ///
-#[pyclass]
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
 pub struct MpmcResponseVariant(queue_rs::nonblocking::MpmcResponseVariant);
 
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl MpmcResponseVariant {
+    /// Returns the index range of the ``add()`` operation.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// (int, int)
+    ///   The index of the first added element and the number of the elements if the operation
+    ///   was successful,
+    /// ``None``
+    ///   if the future doesn't represent the ``add()`` operation.
+    ///
+    #[getter]
+    fn index_range(&self) -> PyResult<Option<(u64, usize)>> {
+        match &self.0 {
+            queue_rs::nonblocking::MpmcResponseVariant::Add(data) => Ok(data
+                .as_ref()
+                .map(|r| Some(*r))
+                .map_err(|e| PyRuntimeError::new_err(format!("Failed to get response: {}", e)))?),
+            _ => Ok(None),
+        }
+    }
+
     /// Returns the data for the ``next()`` operation.
     ///
     /// Raises
@@ -468,6 +811,23 @@ impl MpmcResponseVariant {
         }
     }
 
+    /// Returns whether the queue is empty.
+    ///
+    /// Returns
+    /// -------
+    /// ``bool``
+    ///   ``True`` if the queue is empty, ``False`` otherwise,
+    /// ``None``
+    ///   if the future doesn't represent the ``is_empty()`` operation.
+    ///
+    #[getter]
+    fn is_empty(&self) -> Option<bool> {
+        match &self.0 {
+            queue_rs::nonblocking::MpmcResponseVariant::IsEmpty(data) => Some(*data),
+            _ => None,
+        }
+    }
+
     /// Returns the size of the queue.
     ///
     /// Raises
@@ -494,9 +854,17 @@ impl MpmcResponseVariant {
     }
 }
 
-#[pyclass]
-pub struct MpmcResponse(queue_rs::nonblocking::MpmcResponse);
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
+pub struct MpmcResponse(Arc<queue_rs::nonblocking::MpmcResponse>);
 
+impl From<queue_rs::nonblocking::MpmcResponse> for MpmcResponse {
+    fn from(response: queue_rs::nonblocking::MpmcResponse) -> Self {
+        Self(Arc::new(response))
+    }
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl MpmcResponse {
     /// Checks if the response is ready.
@@ -556,6 +924,62 @@ impl MpmcResponse {
             })
         })
     }
+
+    /// Returns an asyncio future resolved with the response when it is ready. The result is
+    /// delivered by the working thread, which takes the GIL to schedule it on the event loop. The
+    /// result can be received only once, either from the future or with ``get()``.
+    ///
+    /// Parameters
+    /// ----------
+    /// event_loop : asyncio.AbstractEventLoop, optional
+    ///   The event loop of the future. Default is the running event loop.
+    ///
+    /// Raises
+    /// ------
+    /// RuntimeError
+    ///   If there is no running event loop and ``event_loop`` is not given.
+    ///
+    /// Returns
+    /// -------
+    /// asyncio.Future
+    ///   The future resolved with :py:class:`MpmcResponseVariant`, or failed with ``RuntimeError`` if
+    ///   the response can't be received.
+    ///
+    #[pyo3(signature = (event_loop = None))]
+    fn as_future(&self, event_loop: Option<Bound<'_, PyAny>>) -> PyResult<PyObject> {
+        Python::with_gil(|py| as_future(py, &self.0, event_loop, MpmcResponseVariant))
+    }
+
+    /// Makes the response awaitable in the running event loop, ``await response`` returns
+    /// :py:class:`MpmcResponseVariant`. See ``as_future()``.
+    ///
+    fn __await__(&self) -> PyResult<PyObject> {
+        Python::with_gil(|py| {
+            as_future(py, &self.0, None, MpmcResponseVariant)?.call_method0(py, "__await__")
+        })
+    }
+
+    /// Adds the callback invoked with the response as the only argument when it is ready, or
+    /// right away if it already is. The callbacks are invoked one by one by the delivery thread
+    /// of the library, not by the working threads, so a blocking callback delays the others but
+    /// not the queue. The exceptions raised by the callback are reported as unraisable.
+    ///
+    /// Parameters
+    /// ----------
+    /// callback : Callable[[MpmcResponse], None]
+    ///   The callback to invoke.
+    ///
+    fn add_done_callback(slf: Bound<'_, Self>, callback: PyObject) {
+        let response = slf.clone().unbind();
+        let source = slf.borrow().0.clone();
+        source.on_ready(move || {
+            deliver(move |py| {
+                if let Err(e) = callback.call1(py, (response,)) {
+                    e.write_unraisable_bound(py, Some(callback.bind(py)));
+                }
+            })
+        });
+    }
 }
 
 /// A persistent queue with a ttl that supports multiple consumers marked with labels. This is a
@@ -564,7 +988,7 @@ impl MpmcResponse {
 ///
 /// Parameters
 /// ----------
-/// path : str
+/// path : str or os.PathLike
 ///   The path to the queue.
 /// ttl : int
 ///   The amount of seconds after which the element in the queue will be removed. TTL is not strict.
@@ -574,27 +998,44 @@ impl MpmcResponse {
 /// max_inflight_ops : int
 ///   The maximum number of inflight operations. If the number of inflight operations reached its limit,
 ///   further ops are blocked until the capacity is available. Default to ``1_000``.
+/// options : :py:class:`rocksq.QueueOptions`, optional
+///   The RocksDB options of the queue. Default is the RocksDB defaults.
 ///
 /// Raises
 /// ------
 /// PyRuntimeError
 ///   If the queue could not be created.
 ///
-#[pyclass]
-pub struct MpmcQueue(queue_rs::nonblocking::MpmcQueue);
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq.nonblocking")]
+pub struct MpmcQueue(ManuallyDrop<queue_rs::nonblocking::MpmcQueue>);
 
+impl Drop for MpmcQueue {
+    fn drop(&mut self) {
+        // SAFETY: the queue is not used after being taken
+        let queue = unsafe { ManuallyDrop::take(&mut self.0) };
+        // joining the working threads may take a while, the other Python threads keep running
+        Python::with_gil(|py| py.allow_threads(move || drop(queue)));
+    }
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
 #[pymethods]
 impl MpmcQueue {
     #[new]
-    #[pyo3(signature=(path, ttl, max_inflight_ops = 1_000))]
-    fn new(path: &str, ttl: u32, max_inflight_ops: usize) -> PyResult<Self> {
-        let q = queue_rs::nonblocking::MpmcQueue::new(
-            path,
-            Duration::from_secs(ttl as u64),
-            max_inflight_ops,
-        )
-        .map_err(|e| PyRuntimeError::new_err(format!("Failed to create mpmc queue: {}", e)))?;
-        Ok(Self(q))
+    #[pyo3(signature=(path, ttl, max_inflight_ops = 1_000, options = None))]
+    fn new(
+        path: PathBuf,
+        ttl: u32,
+        max_inflight_ops: usize,
+        options: Option<QueueOptions>,
+    ) -> PyResult<Self> {
+        let queue = options
+            .unwrap_or_default()
+            .open_mpmc_queue(&path, Duration::from_secs(ttl as u64))?;
+        let q = queue_rs::nonblocking::MpmcQueue::from_queue(queue, max_inflight_ops)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to create mpmc queue: {}", e)))?;
+        Ok(Self(ManuallyDrop::new(q)))
     }
 
     /// Adds items to the queue.
@@ -603,8 +1044,8 @@ impl MpmcQueue {
     ///
     /// Parameters
     /// ----------
-    /// items : list of bytes
-    ///   The items to add to the queue.
+    /// items : list of bytes-like objects
+    ///   The items to add to the queue, any objects supporting the buffer protocol.
     /// no_gil : bool
     ///   If True, the method will be called without the GIL. Default is ``True``.
     ///
@@ -612,6 +1053,8 @@ impl MpmcQueue {
     /// ------
     /// PyRuntimeError
     ///   If the method fails.
+    /// TypeError
+    ///   If an item doesn't support the buffer protocol.
     ///
     /// Returns
     /// -------
@@ -621,8 +1064,8 @@ impl MpmcQueue {
     ///
     #[pyo3(signature = (items, no_gil = true))]
     fn add(&self, items: &Bound<'_, PyList>, no_gil: bool) -> PyResult<MpmcResponse> {
-        let items = pylist_to_vec_of_byte_vec(items);
-        let data = value_as_slice(&items);
+        let items = ByteBuffers::from_list(items)?;
+        let data = items.as_slices();
         Python::with_gil(|py| {
             let f = || {
                 self.0
@@ -636,7 +1079,7 @@ impl MpmcQueue {
                 f()
             }
         })
-        .map(MpmcResponse)
+        .map(MpmcResponse::from)
     }
 
     #[getter]
@@ -646,6 +1089,32 @@ impl MpmcQueue {
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get inflight ops: {}", e)))
     }
 
+    /// Checks if the working threads are running. The operations of an unhealthy queue fail.
+    ///
+    /// Returns
+    /// -------
+    /// bool
+    ///   ``True`` if the queue is healthy, ``False`` otherwise.
+    ///
+    #[getter]
+    pub fn is_healthy(&self) -> bool {
+        self.0.is_healthy()
+    }
+
+    /// Returns the error the working threads last failed with.
+    ///
+    /// Returns
+    /// -------
+    /// str
+    ///   The error message,
+    /// ``None``
+    ///   if the working threads have not failed.
+    ///
+    #[getter]
+    pub fn last_error(&self) -> Option<String> {
+        self.0.last_error()
+    }
+
     /// Retrieves items from the queue.
     ///
     /// **GIL**: the method can optionally be called without the GIL.
@@ -695,7 +1164,7 @@ impl MpmcQueue {
             } else {
                 self.0.next(max_elements, label, start_position)
             }
-            .map(MpmcResponse)
+            .map(MpmcResponse::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to pop items: {}", e)))
         })
     }
@@ -716,7 +1185,7 @@ impl MpmcQueue {
     pub fn disk_size(&self) -> PyResult<MpmcResponse> {
         self.0
             .disk_size()
-            .map(MpmcResponse)
+            .map(MpmcResponse::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get size: {}", e)))
     }
 
@@ -737,10 +1206,31 @@ impl MpmcQueue {
     pub fn len(&self) -> PyResult<MpmcResponse> {
         self.0
             .len()
-            .map(MpmcResponse)
+            .map(MpmcResponse::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get length: {}", e)))
     }
 
+    /// Checks if the queue is empty.
+    ///
+    /// Raises
+    /// ------
+    /// PyRuntimeError
+    ///   If the method fails.
+    ///
+    /// Returns
+    /// -------
+    /// :py:class:`MpmcResponse`
+    ///   The future-like object which must be used to get the actual response. For the emptiness
+    ///   check, the response object is useful to call for ``is_ready()``, ``try_get()`` and ``get()``.
+    ///
+    #[getter]
+    pub fn is_empty(&self) -> PyResult<MpmcResponse> {
+        self.0
+            .is_empty()
+            .map(MpmcResponse::from)
+            .map_err(|e| PyRuntimeError::new_err(format!("Failed to check emptiness: {}", e)))
+    }
+
     /// Returns the consumer labels.
     ///
     /// Returns
@@ -752,7 +1242,7 @@ impl MpmcQueue {
     fn labels(&self) -> PyResult<MpmcResponse> {
         self.0
             .get_labels()
-            .map(MpmcResponse)
+            .map(MpmcResponse::from)
             .map_err(|e| PyRuntimeError::new_err(format!("Failed to get labels: {}", e)))
     }
 
@@ -792,6 +1282,125 @@ impl MpmcQueue {
                 f()
             }
         })
-        .map(MpmcResponse)
+        .map(MpmcResponse::from)
+    }
+}
+
+/// A response of any queue passed to ``wait_all()``.
+///
+enum Waited {
+    Queue(Arc<queue_rs::nonblocking::Response>),
+    Mpmc(Arc<queue_rs::nonblocking::MpmcResponse>),
+}
+
+/// Waits until all the responses are ready and returns their results. The results are received
+/// only if all the responses are ready in time, so on timeout they can be received later.
+///
+/// **GIL**: the method releases the GIL
+///
+/// Parameters
+/// ----------
+/// responses : list of :py:class:`Response` or :py:class:`MpmcResponse`
+///   The responses to wait for.
+/// timeout : float, optional
+///   The maximum time to wait in seconds. Default is to wait indefinitely.
+///
+/// Raises
+/// ------
+/// PyTimeoutError
+///   If the responses are not ready in time.
+/// PyRuntimeError
+///   If the method fails.
+///
+/// Returns
+/// -------
+/// list of :py:class:`ResponseVariant` or :py:class:`MpmcResponseVariant`
+///   The results in the order of the responses.
+///
+#[cfg_attr(
+    feature = "stub-gen",
+    gen_stub_pyfunction(module = "rocksq.nonblocking")
+)]
+#[pyfunction]
+#[pyo3(signature = (responses, timeout = None))]
+pub fn wait_all(
+    py: Python,
+    responses: Vec<Bound<'_, PyAny>>,
+    timeout: Option<f64>,
+) -> PyResult<Vec<PyObject>> {
+    let timeout = timeout
+        .map(Duration::try_from_secs_f64)
+        .transpose()
+        .map_err(|e| PyValueError::new_err(format!("Invalid timeout: {}", e)))?;
+    let responses = responses
+        .iter()
+        .map(|r| {
+            if let Ok(r) = r.downcast::<Response>() {
+                Ok(Waited::Queue(r.borrow().0.clone()))
+            } else if let Ok(r) = r.downcast::<MpmcResponse>() {
+                Ok(Waited::Mpmc(r.borrow().0.clone()))
+            } else {
+                Err(PyTypeError::new_err(
+                    "Expected a list of Response or MpmcResponse objects",
+                ))
+            }
+        })
+        .collect::<PyResult<Vec<_>>>()?;
+
+    let pending = Arc::new((Mutex::new(responses.len()), Condvar::new()));
+    for response in &responses {
+        let pending = pending.clone();
+        let ready = move || {
+            let (count, cvar) = &*pending;
+            if let Ok(mut count) = count.lock() {
+                *count -= 1;
+            }
+            cvar.notify_all();
+        };
+        match response {
+            Waited::Queue(r) => r.on_ready(ready),
+            Waited::Mpmc(r) => r.on_ready(ready),
+        }
     }
+
+    let ready = py.allow_threads(|| {
+        let (count, cvar) = &*pending;
+        let count = count.lock().unwrap_or_else(|e| e.into_inner());
+        match timeout {
+            Some(timeout) => cvar
+                .wait_timeout_while(count, timeout, |count| *count > 0)
+                .map(|(_, res)| !res.timed_out())
+                .unwrap_or(false),
+            None => cvar.wait_while(count, |count| *count > 0).is_ok(),
+        }
+    });
+    if !ready {
+        return Err(PyTimeoutError::new_err(
+            "Failed to wait for responses: timeout expired",
+        ));
+    }
+
+    responses
+        .iter()
+        .map(|response| {
+            let res = match response {
+                Waited::Queue(r) => r
+                    .try_get()
+                    .map(|v| v.map(|v| ResponseVariant(v).into_py(py))),
+                Waited::Mpmc(r) => r
+                    .try_get()
+                    .map(|v| v.map(|v| MpmcResponseVariant(v).into_py(py))),
+            };
+            match res {
+                Ok(Some(res)) => Ok(res),
+                Ok(None) => Err(PyRuntimeError::new_err(
+                    "Failed to get response: the result has already been received",
+                )),
+                Err(e) => Err(PyRuntimeError::new_err(format!(
+                    "Failed to get response: {}",
+                    e
+                ))),
+            }
+        })
+        .collect()
 }
@@ -0,0 +1,574 @@
+use crate::StartPosition;
+use pyo3::exceptions::{PyRuntimeError, PyValueError};
+use pyo3::prelude::*;
+use queue_rs::alerts::{DiskAlerts, DiskThreshold};
+use queue_rs::limiter::{RateLimit, RateLimitStrategy};
+use queue_rs::mpmc;
+use queue_rs::options;
+use queue_rs::resources;
+use queue_rs::QueueError;
+use rocksdb::{DBCompressionType, Options};
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::Duration;
+
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::{gen_stub_pyclass, gen_stub_pymethods};
+
+fn start_position(position: StartPosition) -> mpmc::StartPosition {
+    match position {
+        StartPosition::Oldest => mpmc::StartPosition::Oldest,
+        StartPosition::Newest => mpmc::StartPosition::Newest,
+    }
+}
+
+fn compression_type(name: &str) -> PyResult<DBCompressionType> {
+    options::db_compression(name).map_err(|e| PyValueError::new_err(e.to_string()))
+}
+
+fn rate_limit(
+    elements_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+    fail_fast: bool,
+) -> Option<RateLimit> {
+    if elements_per_sec.is_none() && bytes_per_sec.is_none() {
+        return None;
+    }
+    Some(RateLimit {
+        elements_per_sec,
+        bytes_per_sec,
+        strategy: if fail_fast {
+            RateLimitStrategy::FailFast
+        } else {
+            RateLimitStrategy::Block
+        },
+    })
+}
+
+/// The RocksDB resources shared by the queues opened with them, e.g. by many queues hosted by
+/// one process, which otherwise allocate a block cache and background threads each.
+///
+/// Parameters
+/// ----------
+/// block_cache_mb : int, optional
+///   The size in megabytes of the block cache shared by the queues, it replaces the
+///   ``block_cache_mb`` of their options.
+/// background_threads : int, optional
+///   The number of the threads running the flushes and the compactions of all the queues.
+/// max_open_files : int, optional
+///   The maximum number of the files each queue keeps open.
+///
+/// Raises
+/// ------
+/// RuntimeError
+///   If the resources can't be created.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq", frozen)]
+#[derive(Clone)]
+pub struct SharedResources(resources::SharedResources);
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl SharedResources {
+    #[new]
+    #[pyo3(signature = (block_cache_mb = None, background_threads = None, max_open_files = None))]
+    fn new(
+        block_cache_mb: Option<usize>,
+        background_threads: Option<i32>,
+        max_open_files: Option<i32>,
+    ) -> PyResult<Self> {
+        resources::SharedResources::new(
+            block_cache_mb.map(|mb| mb * 1024 * 1024),
+            background_threads,
+            max_open_files,
+        )
+        .map(Self)
+        .map_err(|e| PyRuntimeError::new_err(format!("Failed to create shared resources: {}", e)))
+    }
+
+    /// The memory used by the shared block cache in bytes.
+    ///
+    /// Returns
+    /// -------
+    /// int
+    ///
+    #[getter]
+    fn cache_usage(&self) -> usize {
+        self.0.cache_usage()
+    }
+}
+
+/// The RocksDB options of a queue. The options which are not set keep the RocksDB defaults.
+///
+/// Parameters
+/// ----------
+/// write_buffer_size : int, optional
+///   The size in bytes of a memtable.
+/// max_write_buffers : int, optional
+///   The maximum number of memtables, both active and immutable.
+/// compression : str, optional
+///   The compression of the stored data: ``none``, ``snappy``, ``zlib``, ``bz2``, ``lz4``,
+///   ``lz4hc`` or ``zstd``.
+/// block_cache_mb : int, optional
+///   The size in megabytes of the block cache.
+/// sync_writes : bool
+///   If True, every write is synced to disk before it is acknowledged. Default is ``False``.
+/// enable_blob_files : bool
+///   If True, large values are stored in separate blob files. Default is ``False``.
+/// min_blob_size : int, optional
+///   The size in bytes from which values are stored in blob files.
+/// blob_file_size : int, optional
+///   The size in bytes of a blob file.
+/// enable_statistics : bool
+///   If True, RocksDB collects the statistics reported by ``db_stats()``, e.g. the write stalls
+///   and the block cache hits. Default is ``False``.
+/// background_write_bytes_per_sec : int, optional
+///   The maximal rate of the flush and compaction writes of RocksDB, e.g. so the compactions
+///   don't starve the pushes on slow storage.
+/// auto_tune_background_writes : bool
+///   If True, the rate of the flush and compaction writes is tuned by RocksDB up to
+///   ``background_write_bytes_per_sec``. Default is ``False``.
+/// low_priority_compactions : bool
+///   If True, the compaction threads run with the lowered IO and CPU priority. The threads are
+///   shared by the queues of the process, so the priority is lowered for all of them. Default
+///   is ``False``.
+/// push_items_per_sec : float, optional
+///   The maximal rate of the pushed items, bursts of up to one second of the rate are allowed.
+///   The rates must be positive, the queues opened with others raise ``ValueError``.
+/// push_bytes_per_sec : float, optional
+///   The maximal rate of the pushed bytes.
+/// push_rate_fail_fast : bool
+///   If True, the pushes exceeding the rate fail instead of waiting. Default is ``False``.
+/// pop_items_per_sec : float, optional
+///   The maximal rate of the popped items, e.g. so draining a backlog doesn't saturate the
+///   network. A pop may exceed the rate, the next ones wait until it is compensated.
+/// pop_bytes_per_sec : float, optional
+///   The maximal rate of the popped bytes.
+/// pop_rate_fail_fast : bool
+///   If True, the pops exceeding the rate fail instead of waiting. Default is ``False``.
+/// resources : SharedResources, optional
+///   The block cache and the background threads shared with other queues.
+/// verify_on_open : bool
+///   If True, the queue is opened with the RocksDB paranoid checks and its invariants are
+///   verified, a queue failing the verification raises ``RuntimeError``. Default is ``False``.
+/// repair_on_open : bool
+///   If True, a queue failing the verification is repaired and verified again instead.
+///   Default is ``False``.
+/// index_space : int, optional
+///   The number of the indices the elements of a new queue are stored under, e.g. a small ring
+///   for the embedded deployments. The existing queues opened with a different one raise
+///   ``ValueError``.
+/// strict_ttl : bool
+///   If True, the MPMC queues never return the elements which have outlived the TTL but are
+///   not removed yet, they are reported as expired. Default is ``False``.
+/// retention_idle_secs : float, optional
+///   If set, the elements of the MPMC queues not read by the readers which have read within
+///   the time don't expire while the queue takes less disk bytes than
+///   ``retention_max_disk_size``.
+/// retention_max_disk_size : int, optional
+///   The disk ceiling of the retention guard, it is set together with ``retention_idle_secs``.
+/// prefetch : int
+///   The number of the entries the nonblocking queues load into the block cache after every
+///   read, while the read elements are processed. Default is ``0``, no prefetch.
+/// reader_persist_elements : int, optional
+///   If set, the position of an MPMC reader is written once it has read the number of the
+///   elements since it was written, instead of with every read. After a crash, the reader gets
+///   up to the number of the elements again.
+/// reader_persist_interval_secs : float, optional
+///   If set, the position of an MPMC reader is written with the first read after the interval
+///   since it was changed. It can't be set together with ``reader_persist_elements``.
+/// start_position : StartPosition, optional
+///   If set, the new MPMC consumers start at the position instead of the one passed to the
+///   reads, so the consumers of a fleet start consistently.
+/// start_position_prefixes : dict, optional
+///   The start positions of the new MPMC consumers by the prefixes of their labels, the longest
+///   matching prefix wins. The consumers not matching them start at ``start_position``, it
+///   must be set.
+/// disk_quota : int, optional
+///   The disk size in bytes the queue is expected to stay under, the ``disk_alert_percents``
+///   are relative to it. It is not enforced.
+/// disk_alert_percents : list[int], optional
+///   The disk sizes of the queue in percents of ``disk_quota`` reported by ``stats()`` once
+///   they are reached.
+/// disk_alert_bytes : list[int], optional
+///   The disk sizes of the queue in bytes reported by ``stats()`` once they are reached.
+/// disk_alert_available_bytes : list[int], optional
+///   The space available on the file system of the queue reported by ``stats()`` once less of
+///   it is available.
+///
+/// Raises
+/// ------
+/// ValueError
+///   If the compression is unknown, the background write rate is zero, only one of the
+///   retention guard settings is set, both reader persistence settings are set or
+///   ``disk_alert_percents`` are set without ``disk_quota``.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq")]
+#[derive(Clone, Default)]
+pub struct QueueOptions {
+    #[pyo3(get, set)]
+    write_buffer_size: Option<usize>,
+    #[pyo3(get, set)]
+    max_write_buffers: Option<i32>,
+    compression: Option<String>,
+    #[pyo3(get, set)]
+    block_cache_mb: Option<usize>,
+    #[pyo3(get, set)]
+    sync_writes: bool,
+    #[pyo3(get, set)]
+    enable_blob_files: bool,
+    #[pyo3(get, set)]
+    min_blob_size: Option<u64>,
+    #[pyo3(get, set)]
+    blob_file_size: Option<u64>,
+    #[pyo3(get, set)]
+    enable_statistics: bool,
+    #[pyo3(get, set)]
+    background_write_bytes_per_sec: Option<i64>,
+    #[pyo3(get, set)]
+    auto_tune_background_writes: bool,
+    #[pyo3(get, set)]
+    low_priority_compactions: bool,
+    #[pyo3(get, set)]
+    push_items_per_sec: Option<f64>,
+    #[pyo3(get, set)]
+    push_bytes_per_sec: Option<f64>,
+    #[pyo3(get, set)]
+    push_rate_fail_fast: bool,
+    #[pyo3(get, set)]
+    pop_items_per_sec: Option<f64>,
+    #[pyo3(get, set)]
+    pop_bytes_per_sec: Option<f64>,
+    #[pyo3(get, set)]
+    pop_rate_fail_fast: bool,
+    #[pyo3(get, set)]
+    resources: Option<SharedResources>,
+    #[pyo3(get, set)]
+    verify_on_open: bool,
+    #[pyo3(get, set)]
+    repair_on_open: bool,
+    #[pyo3(get, set)]
+    index_space: Option<u64>,
+    #[pyo3(get, set)]
+    strict_ttl: bool,
+    #[pyo3(get, set)]
+    retention_idle_secs: Option<f64>,
+    #[pyo3(get, set)]
+    retention_max_disk_size: Option<u64>,
+    #[pyo3(get, set)]
+    prefetch: usize,
+    #[pyo3(get, set)]
+    reader_persist_elements: Option<u64>,
+    #[pyo3(get, set)]
+    reader_persist_interval_secs: Option<f64>,
+    #[pyo3(get, set)]
+    start_position: Option<StartPosition>,
+    #[pyo3(get, set)]
+    start_position_prefixes: Option<HashMap<String, StartPosition>>,
+    #[pyo3(get, set)]
+    disk_quota: Option<u64>,
+    #[pyo3(get, set)]
+    disk_alert_percents: Option<Vec<u8>>,
+    #[pyo3(get, set)]
+    disk_alert_bytes: Option<Vec<u64>>,
+    #[pyo3(get, set)]
+    disk_alert_available_bytes: Option<Vec<u64>>,
+}
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl QueueOptions {
+    #[new]
+    #[pyo3(signature = (
+        write_buffer_size = None,
+        max_write_buffers = None,
+        compression = None,
+        block_cache_mb = None,
+        sync_writes = false,
+        enable_blob_files = false,
+        min_blob_size = None,
+        blob_file_size = None,
+        enable_statistics = false,
+        background_write_bytes_per_sec = None,
+        auto_tune_background_writes = false,
+        low_priority_compactions = false,
+        push_items_per_sec = None,
+        push_bytes_per_sec = None,
+        push_rate_fail_fast = false,
+        pop_items_per_sec = None,
+        pop_bytes_per_sec = None,
+        pop_rate_fail_fast = false,
+        resources = None,
+        verify_on_open = false,
+        repair_on_open = false,
+        index_space = None,
+        strict_ttl = false,
+        retention_idle_secs = None,
+        retention_max_disk_size = None,
+        prefetch = 0,
+        reader_persist_elements = None,
+        reader_persist_interval_secs = None,
+        start_position = None,
+        start_position_prefixes = None,
+        disk_quota = None,
+        disk_alert_percents = None,
+        disk_alert_bytes = None,
+        disk_alert_available_bytes = None,
+    ))]
+    #[allow(clippy::too_many_arguments)]
+    fn new(
+        write_buffer_size: Option<usize>,
+        max_write_buffers: Option<i32>,
+        compression: Option<String>,
+        block_cache_mb: Option<usize>,
+        sync_writes: bool,
+        enable_blob_files: bool,
+        min_blob_size: Option<u64>,
+        blob_file_size: Option<u64>,
+        enable_statistics: bool,
+        background_write_bytes_per_sec: Option<i64>,
+        auto_tune_background_writes: bool,
+        low_priority_compactions: bool,
+        push_items_per_sec: Option<f64>,
+        push_bytes_per_sec: Option<f64>,
+        push_rate_fail_fast: bool,
+        pop_items_per_sec: Option<f64>,
+        pop_bytes_per_sec: Option<f64>,
+        pop_rate_fail_fast: bool,
+        resources: Option<SharedResources>,
+        verify_on_open: bool,
+        repair_on_open: bool,
+        index_space: Option<u64>,
+        strict_ttl: bool,
+        retention_idle_secs: Option<f64>,
+        retention_max_disk_size: Option<u64>,
+        prefetch: usize,
+        reader_persist_elements: Option<u64>,
+        reader_persist_interval_secs: Option<f64>,
+        start_position: Option<StartPosition>,
+        start_position_prefixes: Option<HashMap<String, StartPosition>>,
+        disk_quota: Option<u64>,
+        disk_alert_percents: Option<Vec<u8>>,
+        disk_alert_bytes: Option<Vec<u64>>,
+        disk_alert_available_bytes: Option<Vec<u64>>,
+    ) -> PyResult<Self> {
+        let mut options = Self {
+            write_buffer_size,
+            max_write_buffers,
+            compression: None,
+            block_cache_mb,
+            sync_writes,
+            enable_blob_files,
+            min_blob_size,
+            blob_file_size,
+            enable_statistics,
+            background_write_bytes_per_sec,
+            auto_tune_background_writes,
+            low_priority_compactions,
+            push_items_per_sec,
+            push_bytes_per_sec,
+            push_rate_fail_fast,
+            pop_items_per_sec,
+            pop_bytes_per_sec,
+            pop_rate_fail_fast,
+            resources,
+            verify_on_open,
+            repair_on_open,
+            index_space,
+            strict_ttl,
+            retention_idle_secs,
+            retention_max_disk_size,
+            prefetch,
+            reader_persist_elements,
+            reader_persist_interval_secs,
+            start_position,
+            start_position_prefixes,
+            disk_quota,
+            disk_alert_percents,
+            disk_alert_bytes,
+            disk_alert_available_bytes,
+        };
+        options.set_compression(compression)?;
+        Ok(options)
+    }
+
+    /// The compression of the stored data, ``None`` keeps the RocksDB default.
+    ///
+    #[getter]
+    fn compression(&self) -> Option<String> {
+        self.compression.clone()
+    }
+
+    #[setter]
+    fn set_compression(&mut self, compression: Option<String>) -> PyResult<()> {
+        if let Some(name) = &compression {
+            compression_type(name)?;
+        }
+        self.compression = compression;
+        Ok(())
+    }
+}
+
+impl QueueOptions {
+    /// Returns the typed options of the queue.
+    fn queue_options(&self) -> PyResult<options::QueueOptions> {
+        let mut opts = options::QueueOptions::default()
+            .enable_statistics(self.enable_statistics)
+            .low_priority_compactions(self.low_priority_compactions)
+            .sync_writes(self.sync_writes)
+            .strict_ttl(self.strict_ttl)
+            .prefetch(self.prefetch);
+        if let Some(size) = self.write_buffer_size {
+            opts = opts.write_buffer_size(size);
+        }
+        if let Some(number) = self.max_write_buffers {
+            opts = opts.max_write_buffers(number);
+        }
+        if let Some(name) = &self.compression {
+            opts = opts.compression(compression_type(name)?);
+        }
+        if let Some(mb) = self.block_cache_mb {
+            opts = opts.block_cache_size(mb * 1024 * 1024);
+        }
+        if self.enable_blob_files {
+            opts = opts.blob_files(self.min_blob_size, self.blob_file_size);
+        }
+        if let Some(rate) = self.background_write_bytes_per_sec {
+            opts = opts.background_write_rate(rate, self.auto_tune_background_writes);
+        }
+        if let Some(limit) = rate_limit(
+            self.push_items_per_sec,
+            self.push_bytes_per_sec,
+            self.push_rate_fail_fast,
+        ) {
+            opts = opts.push_rate_limit(limit);
+        }
+        if let Some(limit) = rate_limit(
+            self.pop_items_per_sec,
+            self.pop_bytes_per_sec,
+            self.pop_rate_fail_fast,
+        ) {
+            opts = opts.pop_rate_limit(limit);
+        }
+        if let Some(resources) = &self.resources {
+            opts = opts.resources(resources.0.clone());
+        }
+        if self.verify_on_open {
+            opts = opts.verify_on_open(self.repair_on_open);
+        }
+        if let Some(index_space) = self.index_space {
+            opts = opts.index_space(index_space);
+        }
+        match (self.retention_idle_secs, self.retention_max_disk_size) {
+            (Some(idle_secs), Some(max_disk_size)) => {
+                let idle_after = Duration::try_from_secs_f64(idle_secs)
+                    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                opts = opts.retention_guard(mpmc::RetentionGuard {
+                    idle_after,
+                    max_disk_size,
+                });
+            }
+            (None, None) => {}
+            _ => {
+                return Err(PyValueError::new_err(
+                    "retention_idle_secs and retention_max_disk_size are set together",
+                ))
+            }
+        }
+        match (
+            self.reader_persist_elements,
+            self.reader_persist_interval_secs,
+        ) {
+            (Some(elements), None) => {
+                opts = opts.reader_persistence(mpmc::ReaderPersistence::EveryElements(elements));
+            }
+            (None, Some(secs)) => {
+                let interval = Duration::try_from_secs_f64(secs)
+                    .map_err(|e| PyValueError::new_err(e.to_string()))?;
+                opts = opts.reader_persistence(mpmc::ReaderPersistence::Interval(interval));
+            }
+            (None, None) => {}
+            _ => {
+                return Err(PyValueError::new_err(
+                    "reader_persist_elements and reader_persist_interval_secs are exclusive",
+                ))
+            }
+        }
+        match (self.start_position, &self.start_position_prefixes) {
+            (Some(default), prefixes) => {
+                let mut policy = mpmc::StartPolicy::new(start_position(default));
+                for (prefix, position) in prefixes.iter().flatten() {
+                    policy = policy.with_prefix(prefix, start_position(*position));
+                }
+                opts = opts.start_policy(policy);
+            }
+            (None, None) => {}
+            (None, Some(_)) => {
+                return Err(PyValueError::new_err(
+                    "start_position_prefixes requires start_position",
+                ))
+            }
+        }
+        let thresholds = self
+            .disk_alert_percents
+            .iter()
+            .flatten()
+            .map(|percent| DiskThreshold::Percent(*percent))
+            .chain(
+                self.disk_alert_bytes
+                    .iter()
+                    .flatten()
+                    .map(|bytes| DiskThreshold::Bytes(*bytes)),
+            )
+            .chain(
+                self.disk_alert_available_bytes
+                    .iter()
+                    .flatten()
+                    .map(|bytes| DiskThreshold::Available(*bytes)),
+            )
+            .collect::<Vec<_>>();
+        if self.disk_quota.is_some() || !thresholds.is_empty() {
+            let alerts = DiskAlerts {
+                quota: self.disk_quota,
+                thresholds,
+            };
+            if alerts.quota.is_none() && self.disk_alert_percents.is_some() {
+                return Err(PyValueError::new_err(
+                    "disk_alert_percents requires disk_quota",
+                ));
+            }
+            opts = opts.disk_alerts(alerts);
+        }
+        Ok(opts)
+    }
+
+    /// Returns the RocksDB options built from the options.
+    pub(crate) fn db_options(&self) -> PyResult<Options> {
+        self.queue_options()?
+            .build_db_options()
+            .map_err(|e| PyValueError::new_err(e.to_string()))
+    }
+
+    /// Opens ``PersistentQueueWithCapacity`` with the options.
+    pub(crate) fn open_queue(
+        &self,
+        path: &Path,
+        max_elements: usize,
+    ) -> PyResult<queue_rs::PersistentQueueWithCapacity> {
+        queue_rs::PersistentQueueWithCapacity::open(path, max_elements, &self.queue_options()?)
+            .map_err(|e| match e {
+                QueueError::InvalidArgument(_) => PyValueError::new_err(e.to_string()),
+                _ => PyRuntimeError::new_err(format!("Failed to create persistent queue: {}", e)),
+            })
+    }
+
+    /// Opens ``MpmcQueue`` with the options.
+    pub(crate) fn open_mpmc_queue(&self, path: &Path, ttl: Duration) -> PyResult<mpmc::MpmcQueue> {
+        mpmc::MpmcQueue::open(path, ttl, &self.queue_options()?).map_err(|e| match e {
+            QueueError::InvalidArgument(_) => PyValueError::new_err(e.to_string()),
+            _ => PyRuntimeError::new_err(format!("Failed to create mpmc queue: {}", e)),
+        })
+    }
+}
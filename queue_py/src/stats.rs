@@ -0,0 +1,92 @@
+use pyo3::prelude::*;
+
+#[cfg(feature = "stub-gen")]
+use pyo3_stub_gen::derive::{gen_stub_pyclass, gen_stub_pymethods};
+
+/// The RocksDB internals of a queue, e.g. to find out why the writes stall. The tickers
+/// (``stall_micros``, ``block_cache_hits``, ``block_cache_misses``) are collected only if the
+/// queue is opened with ``QueueOptions(enable_statistics=True)``, otherwise they are ``None``.
+///
+#[cfg_attr(feature = "stub-gen", gen_stub_pyclass)]
+#[pyclass(module = "rocksq", frozen)]
+pub struct DbStats(pub(crate) queue_rs::stats::DbStats);
+
+#[cfg_attr(feature = "stub-gen", gen_stub_pymethods)]
+#[pymethods]
+impl DbStats {
+    /// ``True`` if a compaction is pending.
+    #[getter]
+    fn compaction_pending(&self) -> bool {
+        self.0.compaction_pending
+    }
+
+    /// The number of the running compactions.
+    #[getter]
+    fn running_compactions(&self) -> u64 {
+        self.0.running_compactions
+    }
+
+    /// The estimated number of bytes the pending compactions have to rewrite.
+    #[getter]
+    fn pending_compaction_bytes(&self) -> u64 {
+        self.0.pending_compaction_bytes
+    }
+
+    /// ``True`` if the writes are stopped.
+    #[getter]
+    fn write_stopped(&self) -> bool {
+        self.0.write_stopped
+    }
+
+    /// The rate in bytes per second the writes are delayed to, ``0`` if they are not delayed.
+    #[getter]
+    fn delayed_write_rate(&self) -> u64 {
+        self.0.delayed_write_rate
+    }
+
+    /// The size in bytes of the memtables.
+    #[getter]
+    fn memtables_size(&self) -> u64 {
+        self.0.memtables_size
+    }
+
+    /// The size in bytes of the blocks in the block cache.
+    #[getter]
+    fn block_cache_usage(&self) -> u64 {
+        self.0.block_cache_usage
+    }
+
+    /// The estimated number of the stored keys.
+    #[getter]
+    fn estimated_keys(&self) -> u64 {
+        self.0.estimated_keys
+    }
+
+    /// The total time in microseconds the writes have been stalled.
+    #[getter]
+    fn stall_micros(&self) -> Option<u64> {
+        self.0.stall_micros
+    }
+
+    /// The number of the block cache hits.
+    #[getter]
+    fn block_cache_hits(&self) -> Option<u64> {
+        self.0.block_cache_hits
+    }
+
+    /// The number of the block cache misses.
+    #[getter]
+    fn block_cache_misses(&self) -> Option<u64> {
+        self.0.block_cache_misses
+    }
+
+    /// The share of the block cache lookups which are hits.
+    #[getter]
+    fn block_cache_hit_rate(&self) -> Option<f64> {
+        self.0.block_cache_hit_rate()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.0)
+    }
+}
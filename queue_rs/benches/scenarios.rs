@@ -0,0 +1,133 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use queue_rs::blocking::{MpmcQueue, PersistentQueueWithCapacity};
+use queue_rs::mpmc::StartPosition;
+use queue_rs::options::QueueOptions;
+use rocksdb::Options;
+use std::thread;
+use std::time::Duration;
+
+const COUNT: usize = 100;
+const CAPACITY: usize = 1_000_000;
+const TTL: Duration = Duration::from_secs(600);
+const WAIT: Duration = Duration::from_secs(10);
+
+/// One producer and one consumer running in parallel threads.
+fn concurrent_producer_consumer(c: &mut Criterion) {
+    let block = vec![0u8; 4 * 1024];
+    let path = "/tmp/test_bench_concurrent".to_string();
+    _ = PersistentQueueWithCapacity::remove_db(&path);
+    {
+        let db = PersistentQueueWithCapacity::new(&path, CAPACITY, Options::default()).unwrap();
+        let mut group = c.benchmark_group("concurrent");
+        group.throughput(Throughput::Elements(COUNT as u64));
+        group.bench_function("producer_consumer", |b| {
+            b.iter(|| {
+                thread::scope(|s| {
+                    s.spawn(|| {
+                        for _ in 0..COUNT {
+                            db.push(&[&block]).unwrap();
+                        }
+                    });
+                    let mut consumed = 0;
+                    while consumed < COUNT {
+                        consumed += db.pop_wait(COUNT - consumed, WAIT).unwrap().len();
+                    }
+                });
+            })
+        });
+        group.finish();
+    }
+    PersistentQueueWithCapacity::remove_db(&path).unwrap();
+}
+
+/// The elements read by every label of an MPMC queue.
+fn mpmc_labels(c: &mut Criterion) {
+    let block = vec![0u8; 4 * 1024];
+    let mut group = c.benchmark_group("mpmc_labels");
+    for labels in [1usize, 16, 64] {
+        let path = format!("/tmp/test_bench_labels_{}", labels);
+        _ = MpmcQueue::remove_db(&path);
+        {
+            let db = MpmcQueue::new(&path, TTL).unwrap();
+            let labels: Vec<String> = (0..labels).map(|i| format!("label-{}", i)).collect();
+            group.throughput(Throughput::Elements((COUNT * labels.len()) as u64));
+            group.bench_with_input(
+                BenchmarkId::from_parameter(labels.len()),
+                &labels,
+                |b, labels| {
+                    b.iter(|| {
+                        for _ in 0..COUNT {
+                            db.add(&[&block]).unwrap();
+                        }
+                        for label in labels {
+                            let (read, _) = db.next(COUNT, label, StartPosition::Oldest).unwrap();
+                            assert_eq!(read.len(), COUNT);
+                        }
+                    })
+                },
+            );
+        }
+        MpmcQueue::remove_db(&path).unwrap();
+    }
+    group.finish();
+}
+
+/// The pushes and the pops of the small and the large elements.
+fn payload_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("payload_size");
+    for size in [64usize, 4 * 1024, 1024 * 1024] {
+        let block = vec![0u8; size];
+        let path = format!("/tmp/test_bench_payload_{}", size);
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = PersistentQueueWithCapacity::new(&path, CAPACITY, Options::default()).unwrap();
+            group.throughput(Throughput::Bytes((COUNT * size) as u64));
+            group.bench_with_input(BenchmarkId::from_parameter(size), &block, |b, block| {
+                b.iter(|| {
+                    for _ in 0..COUNT {
+                        db.push(&[block]).unwrap();
+                    }
+                    for _ in 0..COUNT {
+                        db.pop(1).unwrap();
+                    }
+                })
+            });
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+    group.finish();
+}
+
+/// The pushes acknowledged after the WAL is synced to disk and without the sync.
+fn sync_writes(c: &mut Criterion) {
+    let block = vec![0u8; 4 * 1024];
+    let mut group = c.benchmark_group("sync_writes");
+    group.throughput(Throughput::Elements(COUNT as u64));
+    for sync in [false, true] {
+        let path = format!("/tmp/test_bench_sync_{}", sync);
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let options = QueueOptions::default().sync_writes(sync);
+            let db = PersistentQueueWithCapacity::open(&path, CAPACITY, &options).unwrap();
+            group.bench_with_input(BenchmarkId::from_parameter(sync), &block, |b, block| {
+                b.iter(|| {
+                    for _ in 0..COUNT {
+                        db.push(&[block]).unwrap();
+                    }
+                    db.pop(COUNT).unwrap();
+                })
+            });
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    concurrent_producer_consumer,
+    mpmc_labels,
+    payload_size,
+    sync_writes
+);
+criterion_main!(benches);
@@ -1,47 +1,55 @@
-#![feature(test)]
-
-extern crate test;
-
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use queue_rs::blocking::MpmcQueue;
 use queue_rs::mpmc::StartPosition;
 use std::time::Duration;
-use test::Bencher;
 
 const COUNT: usize = 10;
+const BLOCK_SIZE: usize = 256 * 1024;
 const LABEL: &str = "label";
 
-#[bench]
-fn rw_mixed(b: &mut Bencher) {
-    let block = vec![0u8; 256 * 1024];
+fn rw_mixed(c: &mut Criterion) {
+    let block = vec![0u8; BLOCK_SIZE];
     let path = "/tmp/test_mpmc_b1".to_string();
     _ = MpmcQueue::remove_db(&path);
     {
         let db = MpmcQueue::new(&path, Duration::from_secs(60)).unwrap();
-        b.iter(|| {
-            for _ in 0..COUNT {
-                db.add(&[&block]).unwrap();
-                db.next(1, LABEL, StartPosition::Oldest).unwrap();
-            }
+        let mut group = c.benchmark_group("mpmc");
+        group.throughput(Throughput::Bytes((COUNT * BLOCK_SIZE) as u64));
+        group.bench_function("rw_mixed", |b| {
+            b.iter(|| {
+                for _ in 0..COUNT {
+                    db.add(&[&block]).unwrap();
+                    db.next(1, LABEL, StartPosition::Oldest).unwrap();
+                }
+            })
         });
+        group.finish();
     }
     MpmcQueue::remove_db(&path).unwrap();
 }
 
-#[bench]
-fn write_read(b: &mut Bencher) {
-    let block = vec![0u8; 256 * 1024];
+fn write_read(c: &mut Criterion) {
+    let block = vec![0u8; BLOCK_SIZE];
     let path = "/tmp/test_mpmc_b2".to_string();
     _ = MpmcQueue::remove_db(&path);
     {
         let db = MpmcQueue::new(&path, Duration::from_secs(60)).unwrap();
-        b.iter(|| {
-            for _ in 0..COUNT {
-                db.add(&[&block]).unwrap();
-            }
-            for _ in 0..COUNT {
-                db.next(1, LABEL, StartPosition::Oldest).unwrap();
-            }
+        let mut group = c.benchmark_group("mpmc");
+        group.throughput(Throughput::Bytes((COUNT * BLOCK_SIZE) as u64));
+        group.bench_function("write_read", |b| {
+            b.iter(|| {
+                for _ in 0..COUNT {
+                    db.add(&[&block]).unwrap();
+                }
+                for _ in 0..COUNT {
+                    db.next(1, LABEL, StartPosition::Oldest).unwrap();
+                }
+            })
         });
+        group.finish();
     }
     MpmcQueue::remove_db(&path).unwrap();
 }
+
+criterion_group!(benches, rw_mixed, write_read);
+criterion_main!(benches);
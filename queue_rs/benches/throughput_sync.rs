@@ -1,45 +1,53 @@
-#![feature(test)]
-
-extern crate test;
-
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use queue_rs::blocking::PersistentQueueWithCapacity;
 use rocksdb::Options;
-use test::Bencher;
 
 const COUNT: usize = 10;
+const BLOCK_SIZE: usize = 256 * 1024;
 
-#[bench]
-fn rw_mixed(b: &mut Bencher) {
-    let block = vec![0u8; 256 * 1024];
+fn rw_mixed(c: &mut Criterion) {
+    let block = vec![0u8; BLOCK_SIZE];
     let path = "/tmp/test_b1".to_string();
     _ = PersistentQueueWithCapacity::remove_db(&path);
     {
         let db = PersistentQueueWithCapacity::new(&path, COUNT, Options::default()).unwrap();
-        b.iter(|| {
-            for _ in 0..COUNT {
-                db.push(&[&block]).unwrap();
-                db.pop(1).unwrap();
-            }
+        let mut group = c.benchmark_group("persistent");
+        group.throughput(Throughput::Bytes((COUNT * BLOCK_SIZE) as u64));
+        group.bench_function("rw_mixed", |b| {
+            b.iter(|| {
+                for _ in 0..COUNT {
+                    db.push(&[&block]).unwrap();
+                    db.pop(1).unwrap();
+                }
+            })
         });
+        group.finish();
     }
     PersistentQueueWithCapacity::remove_db(&path).unwrap();
 }
 
-#[bench]
-fn write_read(b: &mut Bencher) {
-    let block = vec![0u8; 256 * 1024];
+fn write_read(c: &mut Criterion) {
+    let block = vec![0u8; BLOCK_SIZE];
     let path = "/tmp/test_b2".to_string();
     _ = PersistentQueueWithCapacity::remove_db(&path);
     {
         let db = PersistentQueueWithCapacity::new(&path, COUNT, Options::default()).unwrap();
-        b.iter(|| {
-            for _ in 0..COUNT {
-                db.push(&[&block]).unwrap();
-            }
-            for _ in 0..COUNT {
-                db.pop(1).unwrap();
-            }
+        let mut group = c.benchmark_group("persistent");
+        group.throughput(Throughput::Bytes((COUNT * BLOCK_SIZE) as u64));
+        group.bench_function("write_read", |b| {
+            b.iter(|| {
+                for _ in 0..COUNT {
+                    db.push(&[&block]).unwrap();
+                }
+                for _ in 0..COUNT {
+                    db.pop(1).unwrap();
+                }
+            })
         });
+        group.finish();
     }
     PersistentQueueWithCapacity::remove_db(&path).unwrap();
 }
+
+criterion_group!(benches, rw_mixed, write_read);
+criterion_main!(benches);
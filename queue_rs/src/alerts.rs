@@ -0,0 +1,256 @@
+use crate::error::{QueueError, Result};
+use crate::events::{Listeners, QueueEvent};
+use crate::fs;
+use parking_lot::Mutex;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+/// The writes check the disk usage at most once per the interval, the check sums the sizes of
+/// the files of the queue.
+///
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A disk usage the listeners are notified of with [`QueueEvent::DiskAlert`] when it is reached
+/// and when it is left, see [`DiskAlerts`].
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DiskThreshold {
+    /// The disk size of the queue in bytes.
+    Bytes(u64),
+    /// The disk size of the queue in percents of the quota, see [`DiskAlerts::quota`].
+    Percent(u8),
+    /// The space available on the file system of the queue in bytes, the alert is active while
+    /// less space is available.
+    Available(u64),
+}
+
+/// The disk usage alerts of a queue. The active thresholds are returned by `stats()` of the
+/// queues, the listeners are notified when a threshold is reached and left, the disk usage is
+/// checked by the writes and by `stats()`.
+///
+/// ```
+/// use queue_rs::alerts::{DiskAlerts, DiskThreshold};
+///
+/// let alerts = DiskAlerts::new(Some(10 * 1024 * 1024 * 1024))
+///     .with_threshold(DiskThreshold::Percent(80))
+///     .with_threshold(DiskThreshold::Available(1024 * 1024 * 1024));
+/// ```
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DiskAlerts {
+    /// The disk size the queue is expected to stay under, e.g. the size of its volume, the
+    /// percent thresholds are relative to it. It is not enforced.
+    ///
+    pub quota: Option<u64>,
+    pub thresholds: Vec<DiskThreshold>,
+}
+
+impl DiskAlerts {
+    pub fn new(quota: Option<u64>) -> Self {
+        Self {
+            quota,
+            thresholds: Vec::new(),
+        }
+    }
+
+    pub fn with_threshold(mut self, threshold: DiskThreshold) -> Self {
+        self.thresholds.push(threshold);
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        let percent = self
+            .thresholds
+            .iter()
+            .any(|t| matches!(t, DiskThreshold::Percent(_)));
+        match self.quota {
+            Some(0) => Err(QueueError::InvalidArgument(
+                "The disk quota must be positive".to_string(),
+            )),
+            None if percent => Err(QueueError::InvalidArgument(
+                "The percent disk thresholds require the quota".to_string(),
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    fn is_reached(&self, threshold: DiskThreshold, disk_size: u64, available: u64) -> bool {
+        match threshold {
+            DiskThreshold::Bytes(bytes) => disk_size >= bytes,
+            DiskThreshold::Percent(percent) => {
+                // the quota is validated
+                let quota = self.quota.unwrap_or(u64::MAX) as u128;
+                disk_size as u128 * 100 >= quota * percent as u128
+            }
+            DiskThreshold::Available(bytes) => available < bytes,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct MonitorState {
+    checked: Option<Instant>,
+    active: Vec<DiskThreshold>,
+}
+
+/// Tracks the active thresholds of the alerts of a queue.
+#[derive(Debug, Default)]
+pub(crate) struct DiskMonitor {
+    alerts: Option<DiskAlerts>,
+    state: Mutex<MonitorState>,
+}
+
+impl DiskMonitor {
+    pub fn new(alerts: Option<DiskAlerts>) -> Result<Self> {
+        if let Some(alerts) = &alerts {
+            alerts.validate()?;
+        }
+        Ok(Self {
+            alerts,
+            state: Mutex::default(),
+        })
+    }
+
+    pub fn alerts(&self) -> Option<&DiskAlerts> {
+        self.alerts.as_ref()
+    }
+
+    /// Updates the active thresholds with the disk size of the queue, notifies the listeners of
+    /// the changes and returns the active thresholds.
+    ///
+    pub fn update(
+        &self,
+        path: &Path,
+        disk_size: u64,
+        listeners: &Listeners,
+    ) -> Result<Vec<DiskThreshold>> {
+        let mut state = self.state.lock();
+        self.evaluate(&mut state, path, disk_size, listeners)?;
+        Ok(state.active.clone())
+    }
+
+    /// Checks the disk usage after a write unless it was checked within [`CHECK_INTERVAL`] or
+    /// is being checked by another write. The failures are logged.
+    ///
+    pub fn check(&self, path: &Path, listeners: &Listeners) {
+        if self.alerts.is_none() {
+            return;
+        }
+        let Some(mut state) = self.state.try_lock() else {
+            return;
+        };
+        if state.checked.is_some_and(|c| c.elapsed() < CHECK_INTERVAL) {
+            return;
+        }
+        let res = fs::dir_size(path)
+            .map_err(QueueError::from)
+            .and_then(|disk_size| self.evaluate(&mut state, path, disk_size as u64, listeners));
+        if let Err(e) = res {
+            log::warn!(
+                "Failed to check the disk usage of the queue at {}: {}",
+                path.display(),
+                e
+            );
+        }
+    }
+
+    fn evaluate(
+        &self,
+        state: &mut MonitorState,
+        path: &Path,
+        disk_size: u64,
+        listeners: &Listeners,
+    ) -> Result<()> {
+        let Some(alerts) = &self.alerts else {
+            return Ok(());
+        };
+        state.checked = Some(Instant::now());
+        let available = if alerts
+            .thresholds
+            .iter()
+            .any(|t| matches!(t, DiskThreshold::Available(_)))
+        {
+            fs::available_space(path)?
+        } else {
+            u64::MAX
+        };
+        for threshold in &alerts.thresholds {
+            let reached = alerts.is_reached(*threshold, disk_size, available);
+            if reached == state.active.contains(threshold) {
+                continue;
+            }
+            if reached {
+                state.active.push(*threshold);
+            } else {
+                state.active.retain(|t| t != threshold);
+            }
+            listeners.emit(|| QueueEvent::DiskAlert {
+                threshold: *threshold,
+                active: reached,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_thresholds() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let listeners = Listeners::default();
+        let sink = events.clone();
+        listeners.add(move |event| sink.lock().push(event.clone()));
+
+        let path = Path::new("/tmp");
+        let monitor = DiskMonitor::new(Some(
+            DiskAlerts::new(Some(1000))
+                .with_threshold(DiskThreshold::Percent(80))
+                .with_threshold(DiskThreshold::Bytes(900)),
+        ))
+        .unwrap();
+        assert!(monitor.update(path, 500, &listeners).unwrap().is_empty());
+        assert_eq!(
+            monitor.update(path, 850, &listeners).unwrap(),
+            vec![DiskThreshold::Percent(80)]
+        );
+        assert_eq!(
+            monitor.update(path, 950, &listeners).unwrap(),
+            vec![DiskThreshold::Percent(80), DiskThreshold::Bytes(900)]
+        );
+        assert!(monitor.update(path, 100, &listeners).unwrap().is_empty());
+        assert_eq!(
+            *events.lock(),
+            vec![
+                QueueEvent::DiskAlert {
+                    threshold: DiskThreshold::Percent(80),
+                    active: true
+                },
+                QueueEvent::DiskAlert {
+                    threshold: DiskThreshold::Bytes(900),
+                    active: true
+                },
+                QueueEvent::DiskAlert {
+                    threshold: DiskThreshold::Percent(80),
+                    active: false
+                },
+                QueueEvent::DiskAlert {
+                    threshold: DiskThreshold::Bytes(900),
+                    active: false
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let alerts = DiskAlerts::new(None).with_threshold(DiskThreshold::Percent(80));
+        assert!(DiskMonitor::new(Some(alerts)).is_err());
+        assert!(DiskMonitor::new(Some(DiskAlerts::new(Some(0)))).is_err());
+        let alerts = DiskAlerts::new(None).with_threshold(DiskThreshold::Bytes(1));
+        assert!(DiskMonitor::new(Some(alerts)).is_ok());
+    }
+}
@@ -0,0 +1,764 @@
+use crate::error::{QueueError, Result};
+use crate::mpmc;
+use crate::mpmc::StartPosition;
+use crate::nonblocking::{
+    execute, execute_mpmc, MpmcOperation, MpmcResponseVariant, Operation, ResponseVariant,
+};
+use futures_core::Stream;
+use futures_sink::Sink;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::path::Path;
+use std::pin::{pin, Pin};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::thread;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot, Notify};
+
+type Request<O, R> = (O, oneshot::Sender<R>);
+
+struct Worker<O, R> {
+    tx: Option<mpsc::Sender<Request<O, R>>>,
+    thread: Option<thread::JoinHandle<()>>,
+    // notified when elements are added
+    added: Arc<Notify>,
+    // notified when elements are removed
+    removed: Arc<Notify>,
+    // the maximum number of elements in the queue
+    capacity: usize,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OpKind {
+    Add,
+    Remove,
+    Other,
+}
+
+impl<O, R> Drop for Worker<O, R> {
+    fn drop(&mut self) {
+        // closing the channel stops the working thread after the queued operations
+        self.tx.take();
+        let Some(thread) = self.thread.take() else {
+            return;
+        };
+        let join = move || {
+            if thread.join().is_err() {
+                log::error!("The working thread of the queue panicked");
+            }
+        };
+        // the threads of the runtime must not wait for the queued operations
+        match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => drop(runtime.spawn_blocking(join)),
+            Err(_) => join(),
+        }
+    }
+}
+
+/// The queue operated by the working thread like the one in [`crate::nonblocking`], but the
+/// operations are `async` and resolve when the working thread completes them. Handles are
+/// cheap to clone and can be shared between tasks; the working thread stops when the last
+/// handle is dropped. Dropped within a runtime, the handle doesn't wait for the thread, which
+/// is joined by a blocking task, so the queue may stay open for a while after the drop.
+///
+/// The operations are not fully cancel-safe: the operation of a future dropped before the
+/// working thread takes it, e.g. by `tokio::select!` or a timeout, is skipped, but the result
+/// of the operation being executed when the future is dropped is discarded, so the elements
+/// it pops or reads are lost.
+///
+pub struct AsyncQueueWrapper<O, R>(Arc<Worker<O, R>>);
+
+pub type PersistentQueueWithCapacity = AsyncQueueWrapper<Operation, ResponseVariant>;
+pub type MpmcQueue = AsyncQueueWrapper<MpmcOperation, MpmcResponseVariant>;
+
+impl<O, R> Clone for AsyncQueueWrapper<O, R> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<O, R> AsyncQueueWrapper<O, R>
+where
+    O: Send + 'static,
+    R: Send + 'static,
+{
+    fn start<Q, F>(
+        mut queue: Q,
+        execute: F,
+        kind: fn(&O) -> OpKind,
+        capacity: usize,
+        max_inflight_ops: usize,
+    ) -> Result<Self>
+    where
+        Q: Send + 'static,
+        F: Fn(&mut Q, O) -> R + Send + 'static,
+    {
+        if max_inflight_ops == 0 {
+            return Err(QueueError::InvalidArgument(
+                "max_inflight_ops must be greater than 0".to_string(),
+            ));
+        }
+        let (tx, mut rx) = mpsc::channel::<Request<O, R>>(max_inflight_ops);
+        let added = Arc::new(Notify::new());
+        let removed = Arc::new(Notify::new());
+        let (thread_added, thread_removed) = (added.clone(), removed.clone());
+        let thread = thread::spawn(move || {
+            while let Some((op, resp_tx)) = rx.blocking_recv() {
+                // the operations of the cancelled calls are skipped, so e.g. a pop cancelled
+                // before it is executed doesn't lose the elements
+                if resp_tx.is_closed() {
+                    continue;
+                }
+                let kind = kind(&op);
+                // the result is dropped if the caller has stopped waiting for it meanwhile
+                let _ = resp_tx.send(execute(&mut queue, op));
+                match kind {
+                    OpKind::Add => thread_added.notify_waiters(),
+                    OpKind::Remove => thread_removed.notify_waiters(),
+                    OpKind::Other => {}
+                }
+            }
+        });
+        Ok(Self(Arc::new(Worker {
+            tx: Some(tx),
+            thread: Some(thread),
+            added,
+            removed,
+            capacity,
+        })))
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        !self
+            .0
+            .thread
+            .as_ref()
+            .map(|t| t.is_finished())
+            .unwrap_or(true)
+    }
+
+    /// Returns the number of operations waiting for the working thread.
+    pub fn inflight_ops(&self) -> Result<usize> {
+        if !self.is_healthy() {
+            return Err(QueueError::Closed);
+        }
+        let tx = self.0.tx.as_ref().unwrap();
+        Ok(tx.max_capacity() - tx.capacity())
+    }
+
+    /// Runs `fetch` until it returns elements, waiting for new elements to be added between
+    /// the attempts.
+    ///
+    async fn wait_for_elements<F, Fut>(&self, mut fetch: F) -> Result<Vec<Vec<u8>>>
+    where
+        F: FnMut() -> Fut,
+        Fut: Future<Output = Result<Vec<Vec<u8>>>>,
+    {
+        loop {
+            // subscribed before fetching so that elements added in between are not missed
+            let mut added = pin!(self.0.added.notified());
+            added.as_mut().enable();
+            let res = fetch().await?;
+            if !res.is_empty() {
+                return Ok(res);
+            }
+            added.await;
+        }
+    }
+
+    async fn call(&self, op: O) -> Result<R> {
+        let (resp_tx, resp_rx) = oneshot::channel();
+        self.0
+            .tx
+            .as_ref()
+            .unwrap()
+            .send((op, resp_tx))
+            .await
+            .map_err(|_| QueueError::Closed)?;
+        resp_rx.await.map_err(|_| QueueError::Closed)
+    }
+}
+
+fn unexpected_response<T>() -> Result<T> {
+    Err(QueueError::Internal("Unexpected response".to_string()))
+}
+
+type Fetch = Pin<Box<dyn Future<Output = Result<Vec<Vec<u8>>>> + Send>>;
+
+/// An endless stream of queue elements. When there are no new elements, the stream waits until
+/// elements are added through any handle of the queue. Errors are yielded as items, the stream
+/// continues after them.
+///
+pub struct QueueStream {
+    fetch: Box<dyn FnMut() -> Fetch + Send>,
+    pending: Option<Fetch>,
+    buffered: VecDeque<Vec<u8>>,
+}
+
+impl QueueStream {
+    fn new<F>(fetch: F) -> Self
+    where
+        F: FnMut() -> Fetch + Send + 'static,
+    {
+        Self {
+            fetch: Box::new(fetch),
+            pending: None,
+            buffered: VecDeque::new(),
+        }
+    }
+}
+
+impl Stream for QueueStream {
+    type Item = Result<Vec<u8>>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(value) = this.buffered.pop_front() {
+                return Poll::Ready(Some(Ok(value)));
+            }
+            let pending = this.pending.get_or_insert_with(|| (this.fetch)());
+            match pending.as_mut().poll(cx) {
+                Poll::Ready(res) => {
+                    this.pending = None;
+                    match res {
+                        Ok(values) => this.buffered.extend(values),
+                        Err(e) => return Poll::Ready(Some(Err(e))),
+                    }
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+type Push = Pin<Box<dyn Future<Output = Result<()>> + Send>>;
+
+/// A sink pushing elements to the queue in batches. Elements are buffered until the batch is
+/// full or the sink is flushed; while the batch is being pushed the next one is buffered. The
+/// sink is not ready when both are full, so producers are slowed down by the queue capacity
+/// and the inflight operations limit. Elements of the batch that failed to be pushed are lost.
+///
+pub struct QueueSink {
+    push: Box<dyn FnMut(Vec<Vec<u8>>) -> Push + Send>,
+    pending: Option<Push>,
+    buffered: Vec<Vec<u8>>,
+    batch: usize,
+}
+
+impl QueueSink {
+    fn new<F>(batch: usize, push: F) -> Self
+    where
+        F: FnMut(Vec<Vec<u8>>) -> Push + Send + 'static,
+    {
+        let batch = batch.max(1);
+        Self {
+            push: Box::new(push),
+            pending: None,
+            buffered: Vec::with_capacity(batch),
+            batch,
+        }
+    }
+
+    /// Completes the pending push and pushes the buffered elements.
+    fn poll_push(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        loop {
+            if let Some(pending) = self.pending.as_mut() {
+                let res = match pending.as_mut().poll(cx) {
+                    Poll::Ready(res) => res,
+                    Poll::Pending => return Poll::Pending,
+                };
+                self.pending = None;
+                res?;
+            }
+            if self.buffered.is_empty() {
+                return Poll::Ready(Ok(()));
+            }
+            let values = std::mem::replace(&mut self.buffered, Vec::with_capacity(self.batch));
+            self.pending = Some((self.push)(values));
+        }
+    }
+}
+
+impl Sink<Vec<u8>> for QueueSink {
+    type Error = QueueError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        if this.buffered.len() < this.batch {
+            Poll::Ready(Ok(()))
+        } else {
+            this.poll_push(cx)
+        }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Vec<u8>) -> Result<()> {
+        self.get_mut().buffered.push(item);
+        Ok(())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_push(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut().poll_push(cx)
+    }
+}
+
+impl PersistentQueueWithCapacity {
+    pub fn new<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        max_inflight_ops: usize,
+        db_options: rocksdb::Options,
+    ) -> Result<Self> {
+        let queue = crate::PersistentQueueWithCapacity::new(path, max_elements, db_options)?;
+        Self::from_queue(queue, max_inflight_ops)
+    }
+
+    /// Wraps an already opened and configured queue.
+    pub fn from_queue(
+        queue: crate::PersistentQueueWithCapacity,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        let capacity = queue.max_elements();
+        Self::start(
+            queue,
+            |queue, op| execute(queue, op),
+            |op| match op {
+                Operation::Push(_) => OpKind::Add,
+                Operation::Pop(_) | Operation::PopAll(_) => OpKind::Remove,
+                _ => OpKind::Other,
+            },
+            capacity,
+            max_inflight_ops,
+        )
+    }
+
+    /// Turns the queue into the stream of popped elements. Elements are popped in batches of
+    /// up to `batch` (at least 1) elements. Not cancel-safe: the batch being popped when the
+    /// stream is dropped is lost, see [`AsyncQueueWrapper`].
+    ///
+    pub fn into_stream(self, batch: usize) -> QueueStream {
+        let batch = batch.max(1);
+        QueueStream::new(move || {
+            let queue = self.clone();
+            Box::pin(async move { queue.wait_for_elements(|| queue.pop(batch)).await })
+        })
+    }
+
+    /// Turns the queue into the sink pushing elements in batches of up to `batch` (at least 1)
+    /// elements. While the queue is full, the sink waits until elements are popped through any
+    /// handle of the queue.
+    ///
+    pub fn into_sink(self, batch: usize) -> QueueSink {
+        QueueSink::new(batch, move |values| {
+            let queue = self.clone();
+            Box::pin(async move { queue.push_waiting(values).await })
+        })
+    }
+
+    /// Pushes the elements, waiting for free space while the queue is full. Pushes through
+    /// other handles may still take the space and make the push fail.
+    ///
+    async fn push_waiting(&self, values: Vec<Vec<u8>>) -> Result<()> {
+        if values.len() > self.0.capacity {
+            return Err(QueueError::InvalidArgument(
+                "The batch is larger than the queue capacity".to_string(),
+            ));
+        }
+        loop {
+            // subscribed before checking so that elements popped in between are not missed
+            let mut removed = pin!(self.0.removed.notified());
+            removed.as_mut().enable();
+            if self.len().await? + values.len() <= self.0.capacity {
+                return match self.call(Operation::Push(values)).await? {
+                    ResponseVariant::Push(res) => res.map(|_| ()),
+                    _ => unexpected_response(),
+                };
+            }
+            removed.await;
+        }
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        match self.call(Operation::Length).await? {
+            ResponseVariant::Length(len) => Ok(len),
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn disk_size(&self) -> Result<usize> {
+        match self.call(Operation::DiskSize).await? {
+            ResponseVariant::Size(size) => size,
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn payload_size(&self) -> Result<u64> {
+        match self.call(Operation::PayloadSize).await? {
+            ResponseVariant::Size(size) => Ok(size? as u64),
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn push(&self, values: &[&[u8]]) -> Result<()> {
+        let values = values.iter().map(|e| e.to_vec()).collect();
+        match self.call(Operation::Push(values)).await? {
+            ResponseVariant::Push(res) => res.map(|_| ()),
+            _ => unexpected_response(),
+        }
+    }
+
+    /// Pops up to `max_elements` elements. Not cancel-safe: the elements popped when the
+    /// future is dropped are lost, see [`AsyncQueueWrapper`].
+    ///
+    pub async fn pop(&self, max_elements: usize) -> Result<Vec<Vec<u8>>> {
+        match self.call(Operation::Pop(max_elements)).await? {
+            ResponseVariant::Pop(res) => res,
+            _ => unexpected_response(),
+        }
+    }
+
+    /// Pops the elements like [`crate::PersistentQueueWithCapacity::pop_all`]. Not cancel-safe,
+    /// see [`Self::pop`].
+    ///
+    pub async fn pop_all(&self, max_bytes: Option<u64>) -> Result<Vec<Vec<u8>>> {
+        match self.call(Operation::PopAll(max_bytes)).await? {
+            ResponseVariant::Pop(res) => res,
+            _ => unexpected_response(),
+        }
+    }
+}
+
+impl MpmcQueue {
+    pub fn new<P: AsRef<Path>>(path: P, ttl: Duration, max_inflight_ops: usize) -> Result<Self> {
+        let queue = mpmc::MpmcQueue::new(path, ttl)?;
+        Self::from_queue(queue, max_inflight_ops)
+    }
+
+    /// Wraps an already opened and configured queue.
+    pub fn from_queue(queue: mpmc::MpmcQueue, max_inflight_ops: usize) -> Result<Self> {
+        Self::start(
+            queue,
+            execute_mpmc,
+            |op| match op {
+                MpmcOperation::Add(_) => OpKind::Add,
+                _ => OpKind::Other,
+            },
+            usize::MAX,
+            max_inflight_ops,
+        )
+    }
+
+    /// Turns the queue into the sink adding elements in batches of up to `batch` (at least 1)
+    /// elements.
+    ///
+    pub fn into_sink(self, batch: usize) -> QueueSink {
+        QueueSink::new(batch, move |values| {
+            let queue = self.clone();
+            Box::pin(async move {
+                match queue.call(MpmcOperation::Add(values)).await? {
+                    MpmcResponseVariant::Add(res) => res.map(|_| ()),
+                    _ => unexpected_response(),
+                }
+            })
+        })
+    }
+
+    /// Turns the queue into the stream of elements read by the consumer with the label.
+    /// Elements are read in batches of up to `batch` (at least 1) elements. Expired elements
+    /// are silently skipped. Not cancel-safe: the batch being read when the stream is dropped
+    /// is skipped by the consumer, see [`AsyncQueueWrapper`].
+    ///
+    pub fn into_stream(
+        self,
+        label: &str,
+        start_position: StartPosition,
+        batch: usize,
+    ) -> QueueStream {
+        let batch = batch.max(1);
+        let label = label.to_string();
+        QueueStream::new(move || {
+            let queue = self.clone();
+            let label = label.clone();
+            Box::pin(async move {
+                queue
+                    .wait_for_elements(|| async {
+                        Ok(queue.next(batch, &label, start_position).await?.0)
+                    })
+                    .await
+            })
+        })
+    }
+
+    pub async fn disk_size(&self) -> Result<usize> {
+        match self.call(MpmcOperation::DiskSize).await? {
+            MpmcResponseVariant::Size(size) => size,
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn len(&self) -> Result<usize> {
+        match self.call(MpmcOperation::Length).await? {
+            MpmcResponseVariant::Length(len) => Ok(len),
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn add(&self, values: &[&[u8]]) -> Result<()> {
+        let values = values.iter().map(|e| e.to_vec()).collect();
+        match self.call(MpmcOperation::Add(values)).await? {
+            MpmcResponseVariant::Add(res) => res.map(|_| ()),
+            _ => unexpected_response(),
+        }
+    }
+
+    /// Reads up to `max_elts` elements of the consumer. Not cancel-safe: the elements read when
+    /// the future is dropped are skipped by the consumer, see [`AsyncQueueWrapper`].
+    ///
+    pub async fn next(
+        &self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        let op = MpmcOperation::Next(max_elts, label.to_string(), start_position);
+        match self.call(op).await? {
+            MpmcResponseVariant::Next(res) => res,
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn get_labels(&self) -> Result<Vec<String>> {
+        match self.call(MpmcOperation::GetLabels).await? {
+            MpmcResponseVariant::GetLabels(labels) => Ok(labels),
+            _ => unexpected_response(),
+        }
+    }
+
+    pub async fn remove_label(&self, label: &str) -> Result<bool> {
+        match self
+            .call(MpmcOperation::RemoveLabel(label.to_string()))
+            .await?
+        {
+            MpmcResponseVariant::RemoveLabel(res) => res,
+            _ => unexpected_response(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>(_: &T) {}
+    fn assert_send<T: Send>(_: &T) {}
+
+    async fn next_item(stream: &mut QueueStream) -> Vec<u8> {
+        std::future::poll_fn(|cx| Pin::new(&mut *stream).poll_next(cx))
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    async fn send_item(sink: &mut QueueSink, item: &[u8]) {
+        std::future::poll_fn(|cx| Pin::new(&mut *sink).poll_ready(cx))
+            .await
+            .unwrap();
+        Pin::new(&mut *sink).start_send(item.to_vec()).unwrap();
+    }
+
+    async fn flush(sink: &mut QueueSink) -> Result<()> {
+        std::future::poll_fn(|cx| Pin::new(&mut *sink).poll_flush(cx)).await
+    }
+
+    async fn poll_once<F: Future>(mut future: Pin<&mut F>) {
+        std::future::poll_fn(|cx| {
+            _ = future.as_mut().poll(cx);
+            Poll::Ready(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_pop() {
+        let path = "/tmp/test_async_cancelled_pop".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue =
+                crate::PersistentQueueWithCapacity::new(&path, 3, rocksdb::Options::default())
+                    .unwrap();
+            // the listener blocks the working thread after the push until it is released
+            let (entered_tx, entered_rx) = crossbeam_channel::unbounded();
+            let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+            queue.add_listener(move |_| {
+                _ = entered_tx.send(());
+                _ = release_rx.recv();
+            });
+            let queue = PersistentQueueWithCapacity::from_queue(queue, 10).unwrap();
+
+            let mut push = Box::pin(queue.push(&[&[1u8]]));
+            poll_once(push.as_mut()).await;
+            entered_rx.recv().unwrap();
+            // the pop is cancelled before the working thread takes it
+            let mut pop = Box::pin(queue.pop(1));
+            poll_once(pop.as_mut()).await;
+            drop(pop);
+            drop(release_tx);
+            push.await.unwrap();
+
+            assert_eq!(queue.pop(1).await.unwrap(), vec![vec![1u8]]);
+            assert!(queue.is_healthy());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue() {
+        let path = "/tmp/test_async_persistent_queue".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 3, 10, rocksdb::Options::default())
+                .unwrap();
+            assert_send_sync(&queue);
+            assert!(queue.is_healthy());
+
+            let push = queue.push(&[&[1u8, 2], &[3u8]]);
+            assert_send(&push);
+            push.await.unwrap();
+
+            let other = queue.clone();
+            assert_eq!(other.len().await.unwrap(), 2);
+            assert_eq!(other.payload_size().await.unwrap(), 3);
+            assert!(queue.push(&[&[4u8], &[5u8]]).await.is_err());
+
+            assert_eq!(queue.pop(1).await.unwrap(), vec![vec![1u8, 2]]);
+            assert_eq!(other.pop(5).await.unwrap(), vec![vec![3u8]]);
+            assert_eq!(queue.inflight_ops().unwrap(), 0);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_mpmc_queue() {
+        let path = "/tmp/test_async_mpmc_queue".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = MpmcQueue::new(&path, Duration::from_secs(10), 10).unwrap();
+            assert_send_sync(&queue);
+
+            queue.add(&[&[1u8], &[2u8]]).await.unwrap();
+            assert_eq!(queue.len().await.unwrap(), 2);
+
+            let next = queue.next(5, "label", StartPosition::Oldest);
+            assert_send(&next);
+            assert_eq!(next.await.unwrap(), (vec![vec![1u8], vec![2u8]], false));
+
+            assert_eq!(queue.get_labels().await.unwrap(), vec!["label".to_string()]);
+            assert!(queue.remove_label("label").await.unwrap());
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_stream() {
+        let path = "/tmp/test_async_persistent_queue_stream".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 3, 10, rocksdb::Options::default())
+                .unwrap();
+            queue.push(&[&[1u8], &[2u8]]).await.unwrap();
+            let mut stream = queue.clone().into_stream(1);
+            assert_send(&stream);
+            assert_eq!(next_item(&mut stream).await, vec![1u8]);
+            assert_eq!(next_item(&mut stream).await, vec![2u8]);
+
+            let (value, res) = tokio::join!(next_item(&mut stream), queue.push(&[&[3u8]]));
+            res.unwrap();
+            assert_eq!(value, vec![3u8]);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_mpmc_queue_stream() {
+        let path = "/tmp/test_async_mpmc_queue_stream".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = MpmcQueue::new(&path, Duration::from_secs(10), 10).unwrap();
+            queue.add(&[&[1u8], &[2u8], &[3u8]]).await.unwrap();
+            let mut stream = queue.clone().into_stream("label", StartPosition::Oldest, 2);
+            assert_eq!(next_item(&mut stream).await, vec![1u8]);
+            assert_eq!(next_item(&mut stream).await, vec![2u8]);
+            assert_eq!(next_item(&mut stream).await, vec![3u8]);
+
+            let (value, res) = tokio::join!(next_item(&mut stream), queue.add(&[&[4u8]]));
+            res.unwrap();
+            assert_eq!(value, vec![4u8]);
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_persistent_queue_sink() {
+        let path = "/tmp/test_async_persistent_queue_sink".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 2, 10, rocksdb::Options::default())
+                .unwrap();
+            let mut sink = queue.clone().into_sink(2);
+            assert_send(&sink);
+            send_item(&mut sink, &[1u8]).await;
+            send_item(&mut sink, &[2u8]).await;
+            assert_eq!(queue.len().await.unwrap(), 0);
+            flush(&mut sink).await.unwrap();
+            assert_eq!(queue.len().await.unwrap(), 2);
+
+            // the queue is full, so the push completes after the elements are popped
+            send_item(&mut sink, &[3u8]).await;
+            let (res, values) = tokio::join!(flush(&mut sink), async {
+                tokio::task::yield_now().await;
+                queue.pop(2).await.unwrap()
+            });
+            res.unwrap();
+            assert_eq!(values, vec![vec![1u8], vec![2u8]]);
+            assert_eq!(queue.pop(2).await.unwrap(), vec![vec![3u8]]);
+
+            let mut sink = queue.clone().into_sink(3);
+            for item in [[4u8], [5u8], [6u8]] {
+                send_item(&mut sink, &item).await;
+            }
+            assert!(flush(&mut sink).await.is_err());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[tokio::test]
+    async fn test_mpmc_queue_sink() {
+        let path = "/tmp/test_async_mpmc_queue_sink".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = MpmcQueue::new(&path, Duration::from_secs(10), 10).unwrap();
+            let mut sink = queue.clone().into_sink(2);
+            for item in [[1u8], [2u8], [3u8]] {
+                send_item(&mut sink, &item).await;
+            }
+            flush(&mut sink).await.unwrap();
+            assert_eq!(
+                queue.next(5, "label", StartPosition::Oldest).await.unwrap(),
+                (vec![vec![1u8], vec![2u8], vec![3u8]], false)
+            );
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
+    #[test]
+    fn test_zero_inflight_ops() {
+        let path = "/tmp/test_async_zero_inflight_ops".to_string();
+        assert!(MpmcQueue::new(&path, Duration::from_secs(10), 0).is_err());
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+}
@@ -1,67 +1,349 @@
+use crate::counters::Counters;
+use crate::error::{QueueError, Result};
 use crate::mpmc;
 use crate::mpmc::StartPosition;
-use anyhow::Result;
-use parking_lot::Mutex;
+use crate::options::{QueueOptions, RemoveOptions};
+use crate::producer::{GroupCommit, Producer};
+use crate::stats::{DbStats, MpmcQueueStats, QueueStats, StatsSnapshot};
+use crate::verify::VerificationReport;
+use parking_lot::{Condvar, Mutex};
 use rocksdb::Options;
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
+/// Wakes up the consumers waiting for new elements, it is notified by the push listener.
+#[derive(Default)]
+struct Wakeup {
+    // the number of pushes, the waiters compare it with the number seen before reading
+    pushes: Mutex<u64>,
+    cvar: Condvar,
+}
+
+impl Wakeup {
+    fn notify(&self) {
+        *self.pushes.lock() += 1;
+        self.cvar.notify_all();
+    }
+
+    fn pushes(&self) -> u64 {
+        *self.pushes.lock()
+    }
+
+    /// Waits until there is a push after `seen` ones, returns `false` after the deadline.
+    fn wait(&self, seen: u64, deadline: Option<Instant>) -> bool {
+        let mut pushes = self.pushes.lock();
+        while *pushes == seen {
+            match deadline {
+                Some(deadline) => {
+                    if self.cvar.wait_until(&mut pushes, deadline).timed_out() {
+                        return *pushes != seen;
+                    }
+                }
+                None => self.cvar.wait(&mut pushes),
+            }
+        }
+        true
+    }
+}
+
+/// Pushes and pops don't block each other, the queue synchronizes them itself.
 #[derive(Clone)]
-pub struct PersistentQueueWithCapacity(Arc<Mutex<crate::PersistentQueueWithCapacity>>);
+pub struct PersistentQueueWithCapacity(
+    Arc<crate::PersistentQueueWithCapacity>,
+    Arc<Counters>,
+    Arc<Wakeup>,
+);
 
 impl PersistentQueueWithCapacity {
-    pub fn new(path: &str, max_elements: usize, db_options: Options) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(path: P, max_elements: usize, db_options: Options) -> Result<Self> {
         let queue = crate::PersistentQueueWithCapacity::new(path, max_elements, db_options)?;
-        let queue = Arc::new(Mutex::new(queue));
-        Ok(Self(queue))
+        Ok(Self::from(queue))
+    }
+
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        options: &QueueOptions,
+    ) -> Result<Self> {
+        let queue = crate::PersistentQueueWithCapacity::open(path, max_elements, options)?;
+        Ok(Self::from(queue))
     }
 
     pub fn is_empty(&self) -> bool {
-        self.0.lock().is_empty()
+        self.1.is_empty()
+    }
+
+    /// Returns the store keeping the positions of the readers of an MPMC queue processed into
+    /// this queue, see [`MpmcQueue::process`].
+    ///
+    pub fn offset_store(&self) -> mpmc::QueueOffsetStore {
+        mpmc::QueueOffsetStore::new(self.0.clone())
     }
 
     pub fn disk_size(&self) -> Result<usize> {
-        self.0.lock().disk_size()
+        self.0.disk_size()
+    }
+
+    pub fn db_stats(&self) -> Result<DbStats> {
+        self.0.db_stats()
+    }
+
+    pub fn stats(&self) -> Result<QueueStats> {
+        self.0.stats()
+    }
+
+    pub fn stats_snapshot(&self) -> Result<StatsSnapshot> {
+        self.0.stats_snapshot()
+    }
+
+    pub fn oldest_age(&self) -> Result<Option<Duration>> {
+        self.0.oldest_age()
+    }
+
+    pub fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> Result<u64> {
+        self.0.estimate_disk_for(elements, avg_size)
+    }
+
+    pub fn estimate_remaining_capacity(&self) -> Result<Option<u64>> {
+        self.0.estimate_remaining_capacity()
     }
 
     pub fn payload_size(&self) -> u64 {
-        self.0.lock().payload_size()
+        self.1.payload_size()
     }
 
     pub fn len(&self) -> usize {
-        self.0.lock().len()
+        self.1.len()
     }
 
     pub fn push(&self, values: &[&[u8]]) -> Result<()> {
-        self.0.lock().push(values)
+        self.0.push(values)
+    }
+
+    pub fn push_pairs(&self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.0.push_pairs(pairs)
+    }
+
+    pub fn push_with_keys(&self, elements: &[(&[u8], &[u8])]) -> Result<()> {
+        self.0.push_with_keys(elements)
+    }
+
+    pub fn find_by_key(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.0.find_by_key(key)
+    }
+
+    pub fn delete_by_key(&self, key: &[u8]) -> Result<usize> {
+        self.0.delete_by_key(key)
+    }
+
+    pub fn push_with_sequence(
+        &self,
+        producer_id: &str,
+        seq: u64,
+        values: &[&[u8]],
+    ) -> Result<bool> {
+        self.0.push_with_sequence(producer_id, seq, values)
+    }
+
+    pub fn producer_sequence(&self, producer_id: &str) -> Result<Option<u64>> {
+        self.0.producer_sequence(producer_id)
+    }
+
+    pub fn remove_producer_session(&self, producer_id: &str) -> Result<()> {
+        self.0.remove_producer_session(producer_id)
+    }
+
+    pub fn register_epoch(&self, producer_id: &str, epoch: u64) -> Result<()> {
+        self.0.register_epoch(producer_id, epoch)
+    }
+
+    pub fn producer_epoch(&self, producer_id: &str) -> Result<Option<u64>> {
+        self.0.producer_epoch(producer_id)
+    }
+
+    pub fn push_with_epoch(&self, producer_id: &str, epoch: u64, values: &[&[u8]]) -> Result<()> {
+        self.0.push_with_epoch(producer_id, epoch, values)
+    }
+
+    pub fn push_partial(&self, values: &[&[u8]]) -> Result<usize> {
+        self.0.push_partial(values)
+    }
+
+    pub fn push_front(&self, values: &[&[u8]]) -> Result<()> {
+        self.0.push_front(values)
     }
 
     pub fn pop(&self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
-        self.0.lock().pop(max_elts)
+        self.0.pop(max_elts)
+    }
+
+    pub fn pop_pairs(&self, max_elts: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        self.0.pop_pairs(max_elts)
+    }
+
+    pub fn pop_all(&self, max_bytes: Option<u64>) -> Result<Vec<Vec<u8>>> {
+        self.0.pop_all(max_bytes)
+    }
+
+    pub fn pop_back(&self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        self.0.pop_back(max_elts)
+    }
+
+    /// Returns a handle pushing to the queue with the other handles in groups, see [`Producer`].
+    pub fn producer(&self, group_commit: GroupCommit) -> Producer {
+        Producer::new(self.0.clone(), group_commit)
+    }
+
+    /// Pops the elements, waiting up to `timeout` for them if the queue is empty. The waiting
+    /// consumers are woken up by pushes, so the elements are handed off without a delay. Returns
+    /// no elements after the timeout.
+    ///
+    pub fn pop_wait(&self, max_elts: usize, timeout: Duration) -> Result<Vec<Vec<u8>>> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.2.pushes();
+            let res = self.0.pop(max_elts)?;
+            if !res.is_empty() || !self.2.wait(seen, deadline) {
+                return Ok(res);
+            }
+        }
     }
 
-    pub fn remove_db(path: &str) -> Result<()> {
+    /// Pops exactly `n` elements, waiting up to `timeout` for them like [`Self::pop_wait`].
+    /// Returns no elements after the timeout, never a partial batch.
+    ///
+    pub fn pop_exact(&self, n: usize, timeout: Duration) -> Result<Vec<Vec<u8>>> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.2.pushes();
+            let res = self.0.pop_exact(n)?;
+            if !res.is_empty() || !self.2.wait(seen, deadline) {
+                return Ok(res);
+            }
+        }
+    }
+
+    pub fn verify(&self) -> Result<VerificationReport> {
+        self.0.verify()
+    }
+
+    pub fn remove_db<P: AsRef<Path>>(path: P) -> Result<()> {
         crate::PersistentQueueWithCapacity::remove_db(path)
     }
+
+    pub fn remove_db_forced<P: AsRef<Path>>(path: P) -> Result<()> {
+        crate::PersistentQueueWithCapacity::remove_db_forced(path)
+    }
+
+    pub fn remove_db_with<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
+        crate::PersistentQueueWithCapacity::remove_db_with(path, options)
+    }
+
+    pub fn repair<P: AsRef<Path>>(path: P, db_options: Options) -> Result<()> {
+        crate::PersistentQueueWithCapacity::repair(path, db_options)
+    }
+
+    /// Closes the queue, see [`crate::PersistentQueueWithCapacity::close`]. Fails with
+    /// [`QueueError::InUse`] if the queue has other clones.
+    ///
+    pub fn close(self) -> Result<()> {
+        let path = self.0.path.clone();
+        Arc::try_unwrap(self.0)
+            .map_err(|_| QueueError::InUse { path })?
+            .close()
+    }
+}
+
+impl From<crate::PersistentQueueWithCapacity> for PersistentQueueWithCapacity {
+    fn from(queue: crate::PersistentQueueWithCapacity) -> Self {
+        let counters = queue.counters();
+        let wakeup = Arc::new(Wakeup::default());
+        let waiters = wakeup.clone();
+        queue.on_push(move |_| waiters.notify());
+        Self(Arc::new(queue), counters, wakeup)
+    }
 }
 
 #[derive(Clone)]
-pub struct MpmcQueue(Arc<Mutex<mpmc::MpmcQueue>>);
+pub struct MpmcQueue(Arc<Mutex<mpmc::MpmcQueue>>, Arc<Wakeup>);
+
+impl From<mpmc::MpmcQueue> for MpmcQueue {
+    fn from(queue: mpmc::MpmcQueue) -> Self {
+        let wakeup = Arc::new(Wakeup::default());
+        let waiters = wakeup.clone();
+        queue.on_push(move |_| waiters.notify());
+        Self(Arc::new(Mutex::new(queue)), wakeup)
+    }
+}
 
 impl MpmcQueue {
-    pub fn new(path: &str, ttl: Duration) -> Result<Self> {
+    pub fn new<P: AsRef<Path>>(path: P, ttl: Duration) -> Result<Self> {
         let inner = mpmc::MpmcQueue::new(path, ttl)?;
-        Ok(Self(Arc::new(Mutex::new(inner))))
+        Ok(Self::from(inner))
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P, ttl: Duration, options: &QueueOptions) -> Result<Self> {
+        let inner = mpmc::MpmcQueue::open(path, ttl, options)?;
+        Ok(Self::from(inner))
     }
 
-    pub fn remove_db(path: &str) -> Result<()> {
+    pub fn remove_db<P: AsRef<Path>>(path: P) -> Result<()> {
         mpmc::MpmcQueue::remove_db(path)
     }
 
+    pub fn remove_db_forced<P: AsRef<Path>>(path: P) -> Result<()> {
+        mpmc::MpmcQueue::remove_db_forced(path)
+    }
+
+    pub fn remove_db_with<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
+        mpmc::MpmcQueue::remove_db_with(path, options)
+    }
+
+    pub fn repair<P: AsRef<Path>>(path: P, ttl: Duration) -> Result<()> {
+        mpmc::MpmcQueue::repair(path, ttl)
+    }
+
+    /// Closes the queue, see [`mpmc::MpmcQueue::close`]. Fails with [`QueueError::InUse`] if the queue
+    /// has other clones.
+    ///
+    pub fn close(self) -> Result<()> {
+        match Arc::try_unwrap(self.0) {
+            Ok(queue) => queue.into_inner().close(),
+            Err(queue) => Err(QueueError::InUse {
+                path: queue.lock().path.clone(),
+            }),
+        }
+    }
+
     pub fn disk_size(&self) -> Result<usize> {
         self.0.lock().disk_size()
     }
 
+    pub fn db_stats(&self) -> Result<DbStats> {
+        self.0.lock().db_stats()
+    }
+
+    pub fn stats(&self) -> Result<MpmcQueueStats> {
+        self.0.lock().stats()
+    }
+
+    pub fn stats_snapshot(&self) -> Result<StatsSnapshot> {
+        self.0.lock().stats_snapshot()
+    }
+
+    pub fn oldest_age(&self) -> Result<Option<Duration>> {
+        self.0.lock().oldest_age()
+    }
+
+    pub fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> Result<u64> {
+        self.0.lock().estimate_disk_for(elements, avg_size)
+    }
+
+    pub fn estimate_remaining_capacity(&self) -> Result<Option<u64>> {
+        self.0.lock().estimate_remaining_capacity()
+    }
+
     pub fn len(&self) -> usize {
         self.0.lock().len()
     }
@@ -74,6 +356,39 @@ impl MpmcQueue {
         self.0.lock().add(values)
     }
 
+    pub fn add_with_timestamps(&self, values: &[&[u8]], timestamps: &[u64]) -> Result<()> {
+        self.0.lock().add_with_timestamps(values, timestamps)
+    }
+
+    pub fn add_with_keys(&self, elements: &[(&[u8], &[u8])]) -> Result<()> {
+        self.0.lock().add_with_keys(elements)
+    }
+
+    pub fn get_by_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.0.lock().get_by_key(key)
+    }
+
+    pub fn add_pairs(&self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        self.0.lock().add_pairs(pairs)
+    }
+
+    pub fn read_range(&self, from: u64, to: u64, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        self.0.lock().read_range(from, to, max_elts)
+    }
+
+    pub fn purge_before(&self, timestamp: u64) -> Result<usize> {
+        self.0.lock().purge_before(timestamp)
+    }
+
+    pub fn next_pairs(
+        &self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, bool)> {
+        self.0.lock().next_pairs(max_elts, label, start_position)
+    }
+
     pub fn next(
         &self,
         max_elts: usize,
@@ -83,11 +398,224 @@ impl MpmcQueue {
         self.0.lock().next(max_elts, label, start_position)
     }
 
+    /// Retrieves the elements like [`next`](Self::next), waiting up to `timeout` for them if
+    /// there are no new elements for the label. The waiting consumers are woken up by adds.
+    /// Returns no elements after the timeout, returns immediately if elements have expired.
+    ///
+    pub fn next_wait(
+        &self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+        timeout: Duration,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.1.pushes();
+            let res = self.next(max_elts, label, start_position)?;
+            if !res.0.is_empty() || res.1 || !self.1.wait(seen, deadline) {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// Retrieves exactly `n` elements like [`MpmcQueue::next_exact`](mpmc::MpmcQueue::next_exact),
+    /// waiting up to `timeout` for them like [`next_wait`](Self::next_wait). Returns no elements
+    /// after the timeout, never a partial batch.
+    ///
+    pub fn next_exact(
+        &self,
+        n: usize,
+        label: &str,
+        start_position: StartPosition,
+        timeout: Duration,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        let deadline = Instant::now().checked_add(timeout);
+        loop {
+            let seen = self.1.pushes();
+            let res = self.0.lock().next_exact(n, label, start_position)?;
+            if !res.0.is_empty() || !self.1.wait(seen, deadline) {
+                return Ok(res);
+            }
+        }
+    }
+
     pub fn get_labels(&self) -> Vec<String> {
         self.0.lock().get_labels()
     }
 
+    pub fn verify(&self) -> Result<VerificationReport> {
+        self.0.lock().verify()
+    }
+
     pub fn remove_label(&self, label: &str) -> Result<bool> {
         self.0.lock().remove_label(label)
     }
+
+    pub fn join_group(&self, label: &str, group: &str) -> Result<()> {
+        self.0.lock().join_group(label, group)
+    }
+
+    pub fn leave_group(&self, label: &str) -> bool {
+        self.0.lock().leave_group(label)
+    }
+
+    pub fn export_labels(&self) -> Result<mpmc::LabelCheckpoint> {
+        self.0.lock().export_labels()
+    }
+
+    pub fn import_labels(&self, checkpoint: &mpmc::LabelCheckpoint) -> Result<()> {
+        self.0.lock().import_labels(checkpoint)
+    }
+
+    pub fn set_offset_store(&self, store: Option<Box<dyn mpmc::OffsetStore>>) -> Result<()> {
+        self.0.lock().set_offset_store(store)
+    }
+
+    pub fn process<F>(
+        &self,
+        label: &str,
+        max_elts: usize,
+        start_position: StartPosition,
+        destination: &PersistentQueueWithCapacity,
+        f: F,
+    ) -> Result<(usize, bool)>
+    where
+        F: FnOnce(&[Vec<u8>]) -> Result<Vec<Vec<u8>>>,
+    {
+        self.0
+            .lock()
+            .process(label, max_elts, start_position, &destination.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MpmcQueue, PersistentQueueWithCapacity};
+    use crate::mpmc::StartPosition;
+    use rocksdb::Options;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn pop_wait() {
+        let path = "/tmp/test_blocking_pop_wait".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 5, Options::default()).unwrap();
+            assert!(queue
+                .pop_wait(1, Duration::from_millis(10))
+                .unwrap()
+                .is_empty());
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    queue.push(&[&[1u8]]).unwrap();
+                })
+            };
+            let started = Instant::now();
+            assert_eq!(
+                queue.pop_wait(1, Duration::from_secs(10)).unwrap(),
+                vec![vec![1u8]]
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
+            producer.join().unwrap();
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn pop_exact() {
+        let path = "/tmp/test_blocking_pop_exact".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 5, Options::default()).unwrap();
+            queue.push(&[&[1u8]]).unwrap();
+            assert!(queue
+                .pop_exact(2, Duration::from_millis(10))
+                .unwrap()
+                .is_empty());
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    queue.push(&[&[2u8]]).unwrap();
+                })
+            };
+            assert_eq!(
+                queue.pop_exact(2, Duration::from_secs(10)).unwrap(),
+                vec![vec![1u8], vec![2u8]]
+            );
+            producer.join().unwrap();
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn next_wait() {
+        let path = "/tmp/test_blocking_next_wait".to_string();
+        _ = MpmcQueue::remove_db(&path);
+        {
+            let queue = MpmcQueue::new(&path, Duration::from_secs(60)).unwrap();
+            assert_eq!(
+                queue
+                    .next_wait(1, "label", StartPosition::Oldest, Duration::from_millis(10))
+                    .unwrap(),
+                (vec![], false)
+            );
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    queue.add(&[&[1u8]]).unwrap();
+                })
+            };
+            let started = Instant::now();
+            assert_eq!(
+                queue
+                    .next_wait(1, "label", StartPosition::Oldest, Duration::from_secs(10))
+                    .unwrap(),
+                (vec![vec![1u8]], false)
+            );
+            assert!(started.elapsed() < Duration::from_secs(5));
+            producer.join().unwrap();
+        }
+        _ = MpmcQueue::remove_db(&path);
+    }
+
+    #[test]
+    fn next_exact() {
+        let path = "/tmp/test_blocking_next_exact".to_string();
+        _ = MpmcQueue::remove_db(&path);
+        {
+            let queue = MpmcQueue::new(&path, Duration::from_secs(60)).unwrap();
+            queue.add(&[&[1u8]]).unwrap();
+            assert_eq!(
+                queue
+                    .next_exact(2, "label", StartPosition::Oldest, Duration::from_millis(10))
+                    .unwrap(),
+                (vec![], false)
+            );
+
+            let producer = {
+                let queue = queue.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(100));
+                    queue.add(&[&[2u8]]).unwrap();
+                })
+            };
+            assert_eq!(
+                queue
+                    .next_exact(2, "label", StartPosition::Oldest, Duration::from_secs(10))
+                    .unwrap(),
+                (vec![vec![1u8], vec![2u8]], false)
+            );
+            producer.join().unwrap();
+        }
+        _ = MpmcQueue::remove_db(&path);
+    }
 }
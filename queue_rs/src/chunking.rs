@@ -0,0 +1,154 @@
+use crate::error::Result;
+use crate::utilities::index_to_key;
+use crate::U64_BYTE_LEN;
+use rocksdb::{ColumnFamily, WriteBatch, DB};
+
+// Large elements are split into chunks to keep individual RocksDB values small. The first chunk
+// is stored under the element key, the remaining ones under sub-keys sharing the element key as
+// a prefix. The sub-key with the chunk number 0 holds the manifest (number of chunks and the
+// total length), its presence marks the element as chunked.
+const CHUNK_NUMBER_LEN: usize = 4;
+const CHUNK_KEY_LEN: usize = U64_BYTE_LEN + CHUNK_NUMBER_LEN;
+const MANIFEST_LEN: usize = CHUNK_NUMBER_LEN + U64_BYTE_LEN;
+const MANIFEST_CHUNK: u32 = 0;
+
+pub fn chunk_key(index: u64, chunk: u32) -> [u8; CHUNK_KEY_LEN] {
+    let mut key = [0u8; CHUNK_KEY_LEN];
+    key[..U64_BYTE_LEN].copy_from_slice(&index_to_key(index));
+    // big endian keeps chunks in order when iterating over the element prefix
+    key[U64_BYTE_LEN..].copy_from_slice(&chunk.to_be_bytes());
+    key
+}
+
+fn put(batch: &mut WriteBatch, cf: Option<&ColumnFamily>, key: &[u8], value: &[u8]) {
+    match cf {
+        Some(cf) => batch.put_cf(cf, key, value),
+        None => batch.put(key, value),
+    }
+}
+
+fn delete(batch: &mut WriteBatch, cf: Option<&ColumnFamily>, key: &[u8]) {
+    match cf {
+        Some(cf) => batch.delete_cf(cf, key),
+        None => batch.delete(key),
+    }
+}
+
+/// Adds the element to the batch splitting it into chunks if it is longer than `chunk_size`.
+/// Returns `true` if the element has been chunked.
+///
+pub fn put_element(
+    batch: &mut WriteBatch,
+    cf: Option<&ColumnFamily>,
+    index: u64,
+    value: &[u8],
+    chunk_size: usize,
+) -> bool {
+    if chunk_size == 0 || value.len() <= chunk_size {
+        put(batch, cf, &index_to_key(index), value);
+        return false;
+    }
+
+    let chunks = value.chunks(chunk_size).collect::<Vec<_>>();
+    let mut manifest = [0u8; MANIFEST_LEN];
+    manifest[..CHUNK_NUMBER_LEN].copy_from_slice(&(chunks.len() as u32).to_le_bytes());
+    manifest[CHUNK_NUMBER_LEN..].copy_from_slice(&(value.len() as u64).to_le_bytes());
+
+    put(batch, cf, &index_to_key(index), chunks[0]);
+    put(batch, cf, &chunk_key(index, MANIFEST_CHUNK), &manifest);
+    for (n, chunk) in chunks.iter().enumerate().skip(1) {
+        put(batch, cf, &chunk_key(index, n as u32), chunk);
+    }
+    true
+}
+
+/// A stored element with the keys it occupies.
+pub struct StoredElement {
+    pub value: Vec<u8>,
+    pub keys: Vec<Box<[u8]>>,
+}
+
+/// Reads the element at the index. If `chunked` is `false` the queue has never stored chunked
+/// elements and a plain lookup is done. Returns `None` if the element does not exist or some of
+/// its chunks are missing (e.g. have been removed by TTL).
+///
+pub fn get_element(
+    db: &DB,
+    cf: Option<&ColumnFamily>,
+    index: u64,
+    chunked: bool,
+) -> Result<Option<StoredElement>> {
+    let key = index_to_key(index);
+    if !chunked {
+        let value = match cf {
+            Some(cf) => db.get_cf(cf, key)?,
+            None => db.get(key)?,
+        };
+        return Ok(value.map(|value| StoredElement {
+            value,
+            keys: vec![Box::from(key.as_slice())],
+        }));
+    }
+
+    let iterator = match cf {
+        Some(cf) => db.prefix_iterator_cf(cf, key),
+        None => db.prefix_iterator(key),
+    };
+
+    let mut keys = Vec::new();
+    let mut parts = Vec::new();
+    for item in iterator {
+        let (k, v) = item?;
+        if k.len() < U64_BYTE_LEN || k[..U64_BYTE_LEN] != key {
+            break;
+        }
+        if k.len() == U64_BYTE_LEN || k.len() == CHUNK_KEY_LEN {
+            keys.push(k);
+            parts.push(v);
+        }
+    }
+
+    if keys.is_empty() || keys[0].len() != U64_BYTE_LEN {
+        return Ok(None);
+    }
+    if keys.len() == 1 {
+        let value = parts.pop().unwrap().into_vec();
+        return Ok(Some(StoredElement { value, keys }));
+    }
+
+    let manifest = &parts[1];
+    if keys[1][U64_BYTE_LEN..] != MANIFEST_CHUNK.to_be_bytes() || manifest.len() != MANIFEST_LEN {
+        return Ok(None);
+    }
+    let mut buf = [0u8; CHUNK_NUMBER_LEN];
+    buf.copy_from_slice(&manifest[..CHUNK_NUMBER_LEN]);
+    let count = u32::from_le_bytes(buf) as usize;
+    let mut buf = [0u8; U64_BYTE_LEN];
+    buf.copy_from_slice(&manifest[CHUNK_NUMBER_LEN..]);
+    let length = u64::from_le_bytes(buf) as usize;
+
+    // the head chunk and the manifest are followed by the rest of the chunks in order
+    if keys.len() != count + 1 {
+        return Ok(None);
+    }
+    let mut value = Vec::with_capacity(length);
+    value.extend_from_slice(&parts[0]);
+    for (n, (k, part)) in keys.iter().zip(parts.iter()).enumerate().skip(2) {
+        if k[U64_BYTE_LEN..] != ((n - 1) as u32).to_be_bytes() {
+            return Ok(None);
+        }
+        value.extend_from_slice(part);
+    }
+    if value.len() != length {
+        return Ok(None);
+    }
+
+    Ok(Some(StoredElement { value, keys }))
+}
+
+/// Adds removal of the keys occupied by the element to the batch.
+pub fn delete_element(batch: &mut WriteBatch, cf: Option<&ColumnFamily>, element: &StoredElement) {
+    for key in element.keys.iter() {
+        delete(batch, cf, key);
+    }
+}
@@ -0,0 +1,506 @@
+use crate::error::{QueueError, Result};
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Nonce};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
+use thiserror::Error;
+
+/// The format of stored elements in queues created before the element header was introduced:
+/// elements are stored as is.
+pub(crate) const LEGACY_FORMAT_VERSION: u64 = 1;
+/// Every stored element starts with a flag byte describing how the rest of it is encoded.
+pub(crate) const FORMAT_VERSION: u64 = 2;
+
+const FLAG_ZSTD: u8 = 0b001;
+const FLAG_LZ4: u8 = 0b010;
+const COMPRESSION_MASK: u8 = 0b011;
+const FLAG_ENCRYPTED: u8 = 0b100;
+const FLAG_CHECKSUM: u8 = 0b1000;
+const KNOWN_FLAGS: u8 = COMPRESSION_MASK | FLAG_ENCRYPTED | FLAG_CHECKSUM;
+
+const CHECKSUM_LEN: usize = 4;
+
+const KEY_ID_LEN: usize = 4;
+const NONCE_LEN: usize = 12;
+// the flag byte and the key id are authenticated together with the payload
+const ENCRYPTION_HEADER_LEN: usize = 1 + KEY_ID_LEN;
+
+/// The length of encryption keys (AES-256-GCM).
+pub const KEY_LEN: usize = 32;
+
+/// Payload compression applied to newly added elements. Elements are decompressed
+/// transparently regardless of the current setting, so it can be changed between restarts.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Zstandard with the compression level.
+    Zstd(i32),
+    Lz4,
+}
+
+/// The error returned, wrapped in [`QueueError::Corrupted`], when a stored element fails the
+/// integrity check.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Error)]
+#[error("Element {index} is corrupted: checksum mismatch")]
+pub struct CorruptedElement {
+    pub index: u64,
+}
+
+/// Keys used to encrypt payloads with AES-256-GCM. New elements are encrypted with the active
+/// key, the id of which is stored with every element, so the keys that encrypted elements
+/// still in the queue must remain in the keyring after the active key is rotated.
+///
+#[derive(Clone)]
+pub struct Keyring {
+    keys: HashMap<u32, Aes256Gcm>,
+    active_key_id: u32,
+}
+
+impl Keyring {
+    /// Creates the keyring with the active key. The key must be [`KEY_LEN`] bytes long.
+    pub fn new(key_id: u32, key: &[u8]) -> Result<Self> {
+        let mut keyring = Self {
+            keys: HashMap::new(),
+            active_key_id: key_id,
+        };
+        keyring.add_key(key_id, key)?;
+        Ok(keyring)
+    }
+
+    /// Adds the key used to decrypt elements. Replaces the key with the same id.
+    pub fn add_key(&mut self, key_id: u32, key: &[u8]) -> Result<()> {
+        if key.len() != KEY_LEN {
+            return Err(QueueError::InvalidArgument(format!(
+                "Invalid encryption key length {}, expected {}",
+                key.len(),
+                KEY_LEN
+            )));
+        }
+        let cipher = Aes256Gcm::new_from_slice(key)
+            .map_err(|e| QueueError::InvalidArgument(e.to_string()))?;
+        self.keys.insert(key_id, cipher);
+        Ok(())
+    }
+
+    /// Makes the key with the id the one new elements are encrypted with.
+    pub fn set_active_key(&mut self, key_id: u32) -> Result<()> {
+        if !self.keys.contains_key(&key_id) {
+            return Err(QueueError::InvalidArgument(format!(
+                "Encryption key {} is not in the keyring",
+                key_id
+            )));
+        }
+        self.active_key_id = key_id;
+        Ok(())
+    }
+
+    pub fn active_key_id(&self) -> u32 {
+        self.active_key_id
+    }
+
+    pub fn key_ids(&self) -> Vec<u32> {
+        let mut ids = self.keys.keys().copied().collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids
+    }
+
+    fn encrypt(&self, header: &[u8], body: &[u8]) -> Result<Vec<u8>> {
+        let cipher = &self.keys[&self.active_key_id];
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: body,
+                    aad: header,
+                },
+            )
+            .map_err(|_| QueueError::Codec("Failed to encrypt the element".to_string()))?;
+        let mut res = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        res.extend_from_slice(nonce.as_slice());
+        res.extend_from_slice(&ciphertext);
+        Ok(res)
+    }
+
+    fn decrypt(&self, key_id: u32, header: &[u8], value: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.keys.get(&key_id).ok_or_else(|| {
+            QueueError::Codec(format!("Encryption key {} is not in the keyring", key_id))
+        })?;
+        if value.len() < NONCE_LEN {
+            return Err(QueueError::Codec(
+                "Encrypted element is truncated".to_string(),
+            ));
+        }
+        let (nonce, ciphertext) = value.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: header,
+                },
+            )
+            .map_err(|_| {
+                QueueError::Codec(
+                    "Failed to decrypt the element: it is corrupted or the key is wrong"
+                        .to_string(),
+                )
+            })
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // keys are never printed
+        f.debug_struct("Keyring")
+            .field("key_ids", &self.key_ids())
+            .field("active_key_id", &self.active_key_id)
+            .finish()
+    }
+}
+
+/// The per-queue settings defining how payloads are stored.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Codec {
+    pub compression: Compression,
+    pub keyring: Option<Keyring>,
+    /// The setting, the queues record whether their stored elements carry the checksums and
+    /// apply the setting when they are empty, see [`Self::encode_versioned`].
+    ///
+    pub checksums: bool,
+}
+
+impl Codec {
+    /// Encodes the payload into the stored representation: the flag byte followed by the
+    /// (optionally compressed) payload. The payload is stored uncompressed if compression
+    /// doesn't reduce its size. If the keyring is set, the flag byte is followed by the key id,
+    /// the nonce and the encrypted payload. If checksums are enabled, the CRC32C of the rest of
+    /// the element is inserted right after the flag byte.
+    ///
+    pub fn encode(&self, value: &[u8]) -> Result<Vec<u8>> {
+        self.encode_element(value, self.checksums)
+    }
+
+    fn encode_element(&self, value: &[u8], checksums: bool) -> Result<Vec<u8>> {
+        let compressed = match self.compression {
+            Compression::None => None,
+            Compression::Zstd(level) => Some((
+                FLAG_ZSTD,
+                zstd::encode_all(value, level).map_err(|e| QueueError::Codec(e.to_string()))?,
+            )),
+            Compression::Lz4 => Some((FLAG_LZ4, lz4_flex::compress_prepend_size(value))),
+        };
+
+        let (flags, body) = match compressed {
+            Some((flags, body)) if body.len() < value.len() => (flags, Cow::Owned(body)),
+            _ => (0, Cow::Borrowed(value)),
+        };
+
+        let mut res = match &self.keyring {
+            None => {
+                let mut res = Vec::with_capacity(CHECKSUM_LEN + body.len() + 1);
+                res.push(flags);
+                res.extend_from_slice(&body);
+                res
+            }
+            Some(keyring) => {
+                let mut res = Vec::with_capacity(
+                    CHECKSUM_LEN + ENCRYPTION_HEADER_LEN + NONCE_LEN + body.len(),
+                );
+                res.push(flags | FLAG_ENCRYPTED);
+                res.extend_from_slice(&keyring.active_key_id().to_le_bytes());
+                let encrypted = keyring.encrypt(&res, &body)?;
+                res.extend_from_slice(&encrypted);
+                res
+            }
+        };
+
+        if checksums {
+            res[0] |= FLAG_CHECKSUM;
+            let checksum = crc32c::crc32c_append(crc32c::crc32c(&res[..1]), &res[1..]);
+            res.splice(1..1, checksum.to_le_bytes());
+        }
+        Ok(res)
+    }
+
+    /// Decodes the stored representation of the element at the index back into the payload.
+    /// Returns [`CorruptedElement`] if the element has a checksum and it doesn't match.
+    ///
+    pub fn decode(&self, mut value: Vec<u8>, index: u64) -> Result<Vec<u8>> {
+        if value.is_empty() {
+            return Err(QueueError::Codec("Element header is missing".to_string()));
+        }
+        let mut flags = value[0];
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(QueueError::Codec(format!(
+                "Unknown element flags {:#04x}",
+                flags
+            )));
+        }
+
+        if flags & FLAG_CHECKSUM != 0 {
+            if value.len() < CHECKSUM_LEN + 1 {
+                return Err(CorruptedElement { index }.into());
+            }
+            let mut buf = [0u8; CHECKSUM_LEN];
+            buf.copy_from_slice(&value[1..CHECKSUM_LEN + 1]);
+            let checksum =
+                crc32c::crc32c_append(crc32c::crc32c(&value[..1]), &value[CHECKSUM_LEN + 1..]);
+            if checksum != u32::from_le_bytes(buf) {
+                return Err(CorruptedElement { index }.into());
+            }
+            // the rest is the element as it would be stored without the checksum
+            value.drain(1..CHECKSUM_LEN + 1);
+            flags &= !FLAG_CHECKSUM;
+            value[0] = flags;
+        }
+
+        let body = if flags & FLAG_ENCRYPTED != 0 {
+            let keyring = self.keyring.as_ref().ok_or_else(|| {
+                QueueError::Codec("Element is encrypted but no keyring is set".to_string())
+            })?;
+            if value.len() < ENCRYPTION_HEADER_LEN {
+                return Err(QueueError::Codec(
+                    "Encrypted element header is truncated".to_string(),
+                ));
+            }
+            let (header, rest) = value.split_at(ENCRYPTION_HEADER_LEN);
+            let mut buf = [0u8; KEY_ID_LEN];
+            buf.copy_from_slice(&header[1..]);
+            keyring.decrypt(u32::from_le_bytes(buf), header, rest)?
+        } else {
+            value.drain(..1);
+            value
+        };
+
+        match flags & COMPRESSION_MASK {
+            0 => Ok(body),
+            FLAG_ZSTD => {
+                zstd::decode_all(body.as_slice()).map_err(|e| QueueError::Codec(e.to_string()))
+            }
+            FLAG_LZ4 => lz4_flex::decompress_size_prepended(&body)
+                .map_err(|e| QueueError::Codec(e.to_string())),
+            _ => Err(QueueError::Codec(format!(
+                "Unknown element compression flags {:#04x}",
+                flags
+            ))),
+        }
+    }
+
+    /// Encodes the payload according to the format version of the queue, with the checksum if
+    /// the stored elements of the queue carry them. The elements of the legacy format are stored
+    /// as is, so they are refused while the keyring is set rather than stored unencrypted.
+    ///
+    pub fn encode_versioned<'a>(
+        &self,
+        value: &'a [u8],
+        format_version: u64,
+        checksums: bool,
+    ) -> Result<Cow<'a, [u8]>> {
+        if format_version == LEGACY_FORMAT_VERSION {
+            if self.keyring.is_some() {
+                return Err(QueueError::InvalidArgument(
+                    "The queue in the legacy format can't encrypt the elements until it is drained"
+                        .to_string(),
+                ));
+            }
+            Ok(Cow::Borrowed(value))
+        } else {
+            Ok(Cow::Owned(self.encode_element(value, checksums)?))
+        }
+    }
+
+    /// Decodes the stored element according to the format version of the queue. The flag byte
+    /// isn't covered by the checksum, so the element is [`CorruptedElement`] if its checksum flag
+    /// disagrees with `checksums`, whether the stored elements of the queue carry them.
+    ///
+    pub fn decode_versioned(
+        &self,
+        value: Vec<u8>,
+        index: u64,
+        format_version: u64,
+        checksums: bool,
+    ) -> Result<Vec<u8>> {
+        if format_version == LEGACY_FORMAT_VERSION {
+            return Ok(value);
+        }
+        if value
+            .first()
+            .is_some_and(|flags| (flags & FLAG_CHECKSUM != 0) != checksums)
+        {
+            return Err(CorruptedElement { index }.into());
+        }
+        self.decode(value, index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn codec(compression: Compression, keyring: Option<Keyring>) -> Codec {
+        Codec {
+            compression,
+            keyring,
+            checksums: false,
+        }
+    }
+
+    #[test]
+    fn encode_decode() {
+        let value = vec![7u8; 1024];
+        for compression in [Compression::None, Compression::Zstd(3), Compression::Lz4] {
+            let codec = codec(compression, None);
+            let encoded = codec.encode(&value).unwrap();
+            if compression != Compression::None {
+                assert!(encoded.len() < value.len());
+            }
+            assert_eq!(codec.decode(encoded, 0).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn incompressible_stored_as_is() {
+        let value = vec![1u8, 2, 3];
+        let codec = codec(Compression::Zstd(3), None);
+        let encoded = codec.encode(&value).unwrap();
+        assert_eq!(encoded, vec![0, 1, 2, 3]);
+        assert_eq!(codec.decode(encoded, 0).unwrap(), value);
+    }
+
+    #[test]
+    fn encrypt_decrypt() {
+        let value = vec![7u8; 1024];
+        let keyring = Keyring::new(1, &[1u8; KEY_LEN]).unwrap();
+        for compression in [Compression::None, Compression::Lz4] {
+            let codec = codec(compression, Some(keyring.clone()));
+            let encoded = codec.encode(&value).unwrap();
+            assert_ne!(encoded[0] & FLAG_ENCRYPTED, 0);
+            assert!(!encoded.windows(16).any(|w| w == &value[..16]));
+            assert_eq!(codec.decode(encoded, 0).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn key_rotation() {
+        let value = vec![1u8, 2, 3];
+        let mut keyring = Keyring::new(1, &[1u8; KEY_LEN]).unwrap();
+        let old = codec(Compression::None, Some(keyring.clone()))
+            .encode(&value)
+            .unwrap();
+
+        keyring.add_key(2, &[2u8; KEY_LEN]).unwrap();
+        keyring.set_active_key(2).unwrap();
+        let codec = codec(Compression::None, Some(keyring));
+        let new = codec.encode(&value).unwrap();
+
+        assert_eq!(codec.decode(old, 0).unwrap(), value);
+        assert_eq!(codec.decode(new, 0).unwrap(), value);
+    }
+
+    #[test]
+    fn encryption_errors() {
+        let value = vec![1u8, 2, 3];
+        assert!(Keyring::new(1, &[1u8; 16]).is_err());
+
+        let mut keyring = Keyring::new(1, &[1u8; KEY_LEN]).unwrap();
+        assert!(keyring.set_active_key(2).is_err());
+        let encoded = codec(Compression::None, Some(keyring.clone()))
+            .encode(&value)
+            .unwrap();
+
+        // no keyring
+        assert!(codec(Compression::None, None)
+            .decode(encoded.clone(), 0)
+            .is_err());
+
+        // unknown key
+        let other = Keyring::new(2, &[1u8; KEY_LEN]).unwrap();
+        assert!(codec(Compression::None, Some(other))
+            .decode(encoded.clone(), 0)
+            .is_err());
+
+        // wrong key
+        let other = Keyring::new(1, &[2u8; KEY_LEN]).unwrap();
+        assert!(codec(Compression::None, Some(other))
+            .decode(encoded.clone(), 0)
+            .is_err());
+
+        // tampered header
+        let mut tampered = encoded.clone();
+        tampered[0] |= FLAG_LZ4;
+        keyring.add_key(3, &[1u8; KEY_LEN]).unwrap();
+        assert!(codec(Compression::None, Some(keyring.clone()))
+            .decode(tampered, 0)
+            .is_err());
+        let mut tampered = encoded;
+        tampered[1] = 3;
+        assert!(codec(Compression::None, Some(keyring))
+            .decode(tampered, 0)
+            .is_err());
+    }
+
+    #[test]
+    fn checksums() {
+        let value = vec![7u8; 1024];
+        let keyring = Keyring::new(1, &[1u8; KEY_LEN]).unwrap();
+        for keyring in [None, Some(keyring)] {
+            let mut codec = codec(Compression::Lz4, keyring);
+            codec.checksums = true;
+            let encoded = codec.encode(&value).unwrap();
+            assert_eq!(codec.decode(encoded.clone(), 0).unwrap(), value);
+
+            for pos in [0, 1, encoded.len() - 1] {
+                let mut corrupted = encoded.clone();
+                corrupted[pos] ^= 0b1000_0000;
+                let err = codec.decode(corrupted, 5).unwrap_err();
+                if pos != 0 {
+                    assert!(matches!(
+                        err,
+                        QueueError::Corrupted(CorruptedElement { index: 5 })
+                    ));
+                } else {
+                    assert!(err.to_string().starts_with("Unknown element flags"));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn checksum_mode() {
+        let value = vec![7u8; 16];
+        let codec = codec(Compression::None, None);
+        let mut encoded = codec
+            .encode_versioned(&value, FORMAT_VERSION, true)
+            .unwrap()
+            .into_owned();
+        assert_eq!(
+            codec
+                .decode_versioned(encoded.clone(), 0, FORMAT_VERSION, true)
+                .unwrap(),
+            value
+        );
+
+        // the checksum flag is cleared
+        encoded[0] &= !FLAG_CHECKSUM;
+        assert!(matches!(
+            codec.decode_versioned(encoded, 3, FORMAT_VERSION, true),
+            Err(QueueError::Corrupted(CorruptedElement { index: 3 }))
+        ));
+
+        // the checksum flag is set
+        let mut encoded = codec
+            .encode_versioned(&value, FORMAT_VERSION, false)
+            .unwrap()
+            .into_owned();
+        encoded[0] |= FLAG_CHECKSUM;
+        assert!(matches!(
+            codec.decode_versioned(encoded, 3, FORMAT_VERSION, false),
+            Err(QueueError::Corrupted(CorruptedElement { index: 3 }))
+        ));
+    }
+}
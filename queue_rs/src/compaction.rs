@@ -0,0 +1,36 @@
+use crate::codec::Codec;
+use crate::utilities::key_to_index;
+use crate::{DEFAULT_INDEX_SPACE, U64_BYTE_LEN};
+use rocksdb::{CompactionDecision, Options};
+use std::sync::Arc;
+
+const EVICTION_FILTER_NAME: &str = "rocksq_eviction";
+
+/// Decides whether the element is evicted, it gets the decoded element, see
+/// [`QueueOptions::eviction_filter`](crate::options::QueueOptions::eviction_filter).
+///
+pub type EvictionFilter = Arc<dyn Fn(&[u8]) -> bool + Send + Sync>;
+
+/// Sets the compaction filter evicting the elements the filter returns `true` for. The chunks,
+/// the system cells and the elements which can't be decoded without the queue settings (e.g.
+/// the encrypted ones) are kept.
+///
+pub(crate) fn set_eviction_filter(opts: &mut Options, filter: EvictionFilter) {
+    let codec = Codec::default();
+    opts.set_compaction_filter(
+        EVICTION_FILTER_NAME,
+        move |_level: u32, key: &[u8], value: &[u8]| {
+            if key.len() != U64_BYTE_LEN {
+                return CompactionDecision::Keep;
+            }
+            let index = key_to_index(key);
+            if index >= DEFAULT_INDEX_SPACE {
+                return CompactionDecision::Keep;
+            }
+            match codec.decode(value.to_vec(), index) {
+                Ok(element) if filter(&element) => CompactionDecision::Remove,
+                _ => CompactionDecision::Keep,
+            }
+        },
+    );
+}
@@ -0,0 +1,407 @@
+use crate::error::{QueueError, Result};
+use crate::limiter::{RateLimit, RateLimitStrategy};
+use crate::mpmc::MpmcQueue;
+use crate::options::{db_compression, override_value, QueueOptions, ENV_PREFIX};
+use crate::PersistentQueueWithCapacity;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{env, fs};
+
+/// The options of a queue as declared in a configuration file, the fields which are not set
+/// keep the defaults of [`QueueOptions`].
+///
+/// ```toml
+/// write_buffer_size = 67108864
+/// compression = "lz4"
+/// sync_writes = true
+/// push_items_per_sec = 1000.0
+/// ```
+///
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OptionsConfig {
+    pub write_buffer_size: Option<usize>,
+    pub max_write_buffers: Option<i32>,
+    pub compression: Option<String>,
+    pub block_cache_mb: Option<usize>,
+    pub sync_writes: bool,
+    pub enable_blob_files: bool,
+    pub min_blob_size: Option<u64>,
+    pub blob_file_size: Option<u64>,
+    pub enable_statistics: bool,
+    pub background_write_bytes_per_sec: Option<i64>,
+    pub auto_tune_background_writes: bool,
+    pub low_priority_compactions: bool,
+    pub checksums: bool,
+    pub push_items_per_sec: Option<f64>,
+    pub push_bytes_per_sec: Option<f64>,
+    pub push_rate_fail_fast: bool,
+    pub pop_items_per_sec: Option<f64>,
+    pub pop_bytes_per_sec: Option<f64>,
+    pub pop_rate_fail_fast: bool,
+    pub verify_on_open: bool,
+    pub repair_on_open: bool,
+}
+
+fn rate_limit(
+    elements_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+    fail_fast: bool,
+) -> Option<RateLimit> {
+    if elements_per_sec.is_none() && bytes_per_sec.is_none() {
+        return None;
+    }
+    Some(RateLimit {
+        elements_per_sec,
+        bytes_per_sec,
+        strategy: if fail_fast {
+            RateLimitStrategy::FailFast
+        } else {
+            RateLimitStrategy::Block
+        },
+    })
+}
+
+impl OptionsConfig {
+    /// Returns the typed options, fails if the compression is unknown.
+    pub fn to_options(&self) -> Result<QueueOptions> {
+        let mut opts = QueueOptions::default()
+            .enable_statistics(self.enable_statistics)
+            .low_priority_compactions(self.low_priority_compactions)
+            .checksums(self.checksums)
+            .sync_writes(self.sync_writes);
+        if let Some(size) = self.write_buffer_size {
+            opts = opts.write_buffer_size(size);
+        }
+        if let Some(number) = self.max_write_buffers {
+            opts = opts.max_write_buffers(number);
+        }
+        if let Some(name) = &self.compression {
+            opts = opts.compression(db_compression(name)?);
+        }
+        if let Some(mb) = self.block_cache_mb {
+            opts = opts.block_cache_size(mb * 1024 * 1024);
+        }
+        if self.enable_blob_files {
+            opts = opts.blob_files(self.min_blob_size, self.blob_file_size);
+        }
+        if let Some(rate) = self.background_write_bytes_per_sec {
+            opts = opts.background_write_rate(rate, self.auto_tune_background_writes);
+        }
+        if let Some(limit) = rate_limit(
+            self.push_items_per_sec,
+            self.push_bytes_per_sec,
+            self.push_rate_fail_fast,
+        ) {
+            opts = opts.push_rate_limit(limit);
+        }
+        if let Some(limit) = rate_limit(
+            self.pop_items_per_sec,
+            self.pop_bytes_per_sec,
+            self.pop_rate_fail_fast,
+        ) {
+            opts = opts.pop_rate_limit(limit);
+        }
+        if self.verify_on_open {
+            opts = opts.verify_on_open(self.repair_on_open);
+        }
+        Ok(opts)
+    }
+}
+
+/// The kind of a declared queue with its capacity or TTL.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum QueueKind {
+    Persistent { max_elements: usize },
+    Mpmc { ttl_secs: u64 },
+}
+
+/// A queue declared in a configuration file.
+#[derive(Clone, Debug, Deserialize)]
+pub struct QueueDeclaration {
+    pub name: String,
+    pub path: PathBuf,
+    #[serde(flatten)]
+    pub kind: QueueKind,
+    #[serde(default)]
+    pub options: OptionsConfig,
+    /// Whether the options are overridden by the environment variables, see
+    /// [`QueueConfig::with_env_overrides`].
+    ///
+    #[serde(skip)]
+    pub env_overrides: bool,
+}
+
+impl QueueDeclaration {
+    /// Opens the declared persistent queue, fails with [`QueueError::Config`] if an MPMC queue
+    /// is declared.
+    ///
+    pub fn open_queue(&self) -> Result<PersistentQueueWithCapacity> {
+        match self.kind {
+            QueueKind::Persistent { max_elements } => {
+                PersistentQueueWithCapacity::open(&self.path, max_elements, &self.to_options()?)
+            }
+            QueueKind::Mpmc { .. } => Err(QueueError::Config(format!(
+                "The queue {} is not a persistent queue",
+                self.name
+            ))),
+        }
+    }
+
+    /// Opens the declared MPMC queue, fails with [`QueueError::Config`] if a persistent queue is
+    /// declared.
+    ///
+    pub fn open_mpmc_queue(&self) -> Result<MpmcQueue> {
+        match self.kind {
+            QueueKind::Mpmc { ttl_secs } => MpmcQueue::open(
+                &self.path,
+                Duration::from_secs(ttl_secs),
+                &self.to_options()?,
+            ),
+            QueueKind::Persistent { .. } => Err(QueueError::Config(format!(
+                "The queue {} is not an MPMC queue",
+                self.name
+            ))),
+        }
+    }
+
+    /// Returns the typed options of the queue, overridden by the environment variables if
+    /// enabled.
+    ///
+    pub fn to_options(&self) -> Result<QueueOptions> {
+        let options = self.options.to_options()?;
+        if !self.env_overrides {
+            return Ok(options);
+        }
+        let prefix = self.env_prefix();
+        options
+            .with_env_overrides()?
+            .with_overrides(|name| env::var(format!("{}{}", prefix, name)).ok())
+    }
+
+    /// Returns the prefix of the environment variables of the queue: `ROCKSQ_` followed by
+    /// the uppercased name, the characters other than the letters and the digits are replaced
+    /// with `_`.
+    ///
+    pub fn env_prefix(&self) -> String {
+        let name: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}{}_", ENV_PREFIX, name)
+    }
+
+    fn apply_overrides<F>(&mut self, lookup: F) -> Result<()>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        match &mut self.kind {
+            QueueKind::Persistent { max_elements } => {
+                if let Some(value) = override_value(&lookup, "MAX_ELEMENTS")? {
+                    *max_elements = value;
+                }
+            }
+            QueueKind::Mpmc { ttl_secs } => {
+                if let Some(value) = override_value(&lookup, "TTL_SECS")? {
+                    *ttl_secs = value;
+                }
+            }
+        }
+        self.env_overrides = true;
+        Ok(())
+    }
+}
+
+/// The queues declared in a configuration file, e.g. of a daemon hosting them.
+///
+/// ```toml
+/// [[queue]]
+/// name = "frames"
+/// path = "/var/lib/rocksq/frames"
+/// kind = "persistent"
+/// max_elements = 100000
+///
+/// [[queue]]
+/// name = "events"
+/// path = "/var/lib/rocksq/events"
+/// kind = "mpmc"
+/// ttl_secs = 3600
+/// options = { sync_writes = true }
+/// ```
+///
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QueueConfig {
+    #[serde(default, rename = "queue")]
+    pub queues: Vec<QueueDeclaration>,
+}
+
+impl QueueConfig {
+    /// Reads the TOML configuration file.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parses the TOML configuration.
+    pub fn parse(config: &str) -> Result<Self> {
+        let config: Self = toml::from_str(config)?;
+        for (i, queue) in config.queues.iter().enumerate() {
+            if config.queues[..i].iter().any(|q| q.name == queue.name) {
+                return Err(QueueError::Config(format!(
+                    "The queue {} is declared twice",
+                    queue.name
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Overrides the declared queues with the environment variables, which take precedence
+    /// over the file: the capacity with `ROCKSQ_<NAME>_MAX_ELEMENTS`, the TTL with
+    /// `ROCKSQ_<NAME>_TTL_SECS` and the options with the variables of
+    /// [`QueueOptions::with_env_overrides`], the ones prefixed with the name of the queue taking
+    /// precedence, e.g. `ROCKSQ_FRAMES_SYNC_WRITES` over `ROCKSQ_SYNC_WRITES`.
+    ///
+    pub fn with_env_overrides(mut self) -> Result<Self> {
+        for queue in &mut self.queues {
+            let prefix = queue.env_prefix();
+            queue.apply_overrides(|name| env::var(format!("{}{}", prefix, name)).ok())?;
+        }
+        Ok(self)
+    }
+
+    /// Returns the declared queue by its name.
+    pub fn queue(&self, name: &str) -> Option<&QueueDeclaration> {
+        self.queues.iter().find(|q| q.name == name)
+    }
+}
+
+impl QueueOptions {
+    /// Reads the options from the TOML file, see [`OptionsConfig`].
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let config: OptionsConfig = toml::from_str(&fs::read_to_string(path)?)?;
+        config.to_options()
+    }
+}
+
+impl From<toml::de::Error> for QueueError {
+    fn from(e: toml::de::Error) -> Self {
+        QueueError::Config(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{QueueConfig, QueueKind};
+    use crate::error::QueueError;
+    use crate::mpmc::StartPosition;
+    use std::fs;
+
+    #[test]
+    fn open_declared_queues() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let config = format!(
+            r#"
+            [[queue]]
+            name = "frames"
+            path = "{0}/frames"
+            kind = "persistent"
+            max_elements = 5
+            options = {{ sync_writes = true, compression = "lz4" }}
+
+            [[queue]]
+            name = "events"
+            path = "{0}/events"
+            kind = "mpmc"
+            ttl_secs = 60
+            "#,
+            dir.path().display()
+        );
+        let file = dir.path().join("queues.toml");
+        fs::write(&file, config).unwrap();
+
+        let config = QueueConfig::from_file(&file).unwrap();
+        assert_eq!(config.queues.len(), 2);
+        let frames = config.queue("frames").unwrap();
+        assert_eq!(frames.kind, QueueKind::Persistent { max_elements: 5 });
+        let queue = frames.open_queue().unwrap();
+        assert!(queue.sync_writes());
+        assert_eq!(queue.max_elements(), 5);
+
+        let events = config.queue("events").unwrap();
+        assert!(matches!(events.open_queue(), Err(QueueError::Config(_))));
+        let mut mpmc = events.open_mpmc_queue().unwrap();
+        mpmc.add(&[&[1u8]]).unwrap();
+        assert_eq!(
+            mpmc.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8]], false)
+        );
+    }
+
+    #[test]
+    fn invalid_config() {
+        let duplicate = r#"
+            [[queue]]
+            name = "a"
+            path = "a"
+            kind = "mpmc"
+            ttl_secs = 60
+
+            [[queue]]
+            name = "a"
+            path = "b"
+            kind = "mpmc"
+            ttl_secs = 60
+            "#;
+        assert!(matches!(
+            QueueConfig::parse(duplicate),
+            Err(QueueError::Config(_))
+        ));
+
+        let unknown = r#"
+            [[queue]]
+            name = "a"
+            path = "a"
+            kind = "persistent"
+            max_elements = 5
+            options = { unknown = 1 }
+            "#;
+        assert!(matches!(
+            QueueConfig::parse(unknown),
+            Err(QueueError::Config(_))
+        ));
+    }
+
+    #[test]
+    fn overridden_capacity() {
+        let mut config = QueueConfig::parse(
+            r#"
+            [[queue]]
+            name = "frames-1"
+            path = "frames"
+            kind = "persistent"
+            max_elements = 5
+            "#,
+        )
+        .unwrap();
+        let queue = &mut config.queues[0];
+        assert_eq!(queue.env_prefix(), "ROCKSQ_FRAMES_1_");
+        queue
+            .apply_overrides(|name| (name == "MAX_ELEMENTS").then(|| "10".to_string()))
+            .unwrap();
+        assert_eq!(queue.kind, QueueKind::Persistent { max_elements: 10 });
+        assert!(queue.env_overrides);
+
+        let invalid = queue.apply_overrides(|_| Some("ten".to_string()));
+        assert!(matches!(invalid, Err(QueueError::Config(_))));
+    }
+}
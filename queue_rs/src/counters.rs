@@ -0,0 +1,174 @@
+use std::cmp::Ordering;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering as MemoryOrdering};
+
+/// Lock-free mirrors of the queue cursors and the payload size. They are updated after each
+/// written batch, so the length and the payload size can be read without the queue lock or
+/// the working thread.
+///
+#[derive(Debug)]
+pub struct Counters {
+    // odd while the mirrors are being updated
+    sequence: AtomicU64,
+    read_index: AtomicU64,
+    write_index: AtomicU64,
+    space_stat: AtomicU64,
+    empty: AtomicBool,
+    index_space: u64,
+}
+
+#[derive(Clone, Copy)]
+struct Snapshot {
+    read_index: u64,
+    write_index: u64,
+    empty: bool,
+}
+
+impl Counters {
+    pub(crate) fn new(
+        read_index: u64,
+        write_index: u64,
+        space_stat: u64,
+        empty: bool,
+        index_space: u64,
+    ) -> Self {
+        Self {
+            sequence: AtomicU64::new(0),
+            read_index: AtomicU64::new(read_index),
+            write_index: AtomicU64::new(write_index),
+            space_stat: AtomicU64::new(space_stat),
+            empty: AtomicBool::new(empty),
+            index_space,
+        }
+    }
+
+    /// Updates the mirrors. Must not be called concurrently, the queue calls it under `&mut self`.
+    pub(crate) fn store(&self, read_index: u64, write_index: u64, space_stat: u64, empty: bool) {
+        let sequence = self.sequence.load(MemoryOrdering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(1), MemoryOrdering::Relaxed);
+        std::sync::atomic::fence(MemoryOrdering::Release);
+        self.read_index.store(read_index, MemoryOrdering::Relaxed);
+        self.write_index.store(write_index, MemoryOrdering::Relaxed);
+        self.space_stat.store(space_stat, MemoryOrdering::Relaxed);
+        self.empty.store(empty, MemoryOrdering::Relaxed);
+        self.sequence
+            .store(sequence.wrapping_add(2), MemoryOrdering::Release);
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        loop {
+            let before = self.sequence.load(MemoryOrdering::Acquire);
+            if before % 2 == 1 {
+                std::hint::spin_loop();
+                continue;
+            }
+            let snapshot = Snapshot {
+                read_index: self.read_index.load(MemoryOrdering::Relaxed),
+                write_index: self.write_index.load(MemoryOrdering::Relaxed),
+                empty: self.empty.load(MemoryOrdering::Relaxed),
+            };
+            std::sync::atomic::fence(MemoryOrdering::Acquire);
+            if self.sequence.load(MemoryOrdering::Relaxed) == before {
+                return snapshot;
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        let s = self.snapshot();
+        length(s.read_index, s.write_index, s.empty, self.index_space)
+    }
+
+    pub fn payload_size(&self) -> u64 {
+        self.space_stat.load(MemoryOrdering::Acquire)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.empty.load(MemoryOrdering::Acquire)
+    }
+}
+
+/// Returns the number of elements between the cursors in the index space of `index_space` indices.
+pub(crate) fn length(read_index: u64, write_index: u64, empty: bool, index_space: u64) -> usize {
+    if empty {
+        0
+    } else {
+        (match write_index.cmp(&read_index) {
+            Ordering::Less => index_space - read_index + write_index,
+            Ordering::Equal => index_space,
+            Ordering::Greater => write_index - read_index,
+        }) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Counters;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_counters() {
+        let counters = Counters::new(0, 0, 0, true, 6);
+        assert_eq!(counters.len(), 0);
+        assert!(counters.is_empty());
+
+        counters.store(4, 1, 10, false);
+        assert_eq!(counters.len(), 3);
+        assert_eq!(counters.payload_size(), 10);
+        assert!(!counters.is_empty());
+
+        counters.store(1, 1, 0, true);
+        assert_eq!(counters.len(), 0);
+        assert!(counters.is_empty());
+    }
+
+    #[test]
+    fn test_queue_counters() {
+        let path = "/tmp/test_queue_counters".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue =
+                crate::PersistentQueueWithCapacity::new(&path, 5, rocksdb::Options::default())
+                    .unwrap();
+            let counters = queue.counters();
+            queue.push(&[&[1u8, 2u8], &[3u8]]).unwrap();
+            assert_eq!(counters.len(), 2);
+            assert_eq!(counters.payload_size(), 3);
+            queue.pop(1).unwrap();
+            assert_eq!(counters.len(), 1);
+            assert_eq!(counters.payload_size(), 1);
+        }
+        {
+            let queue =
+                crate::PersistentQueueWithCapacity::new(&path, 5, rocksdb::Options::default())
+                    .unwrap();
+            let counters = queue.counters();
+            assert_eq!(counters.len(), 1);
+            assert_eq!(counters.payload_size(), 1);
+            assert!(!counters.is_empty());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn test_counters_concurrent_reads() {
+        let counters = Arc::new(Counters::new(0, 0, 0, true, 6));
+        let writer = {
+            let counters = counters.clone();
+            std::thread::spawn(move || {
+                for i in 0..10_000u64 {
+                    // the queue alternates between one element and empty
+                    if i % 2 == 0 {
+                        counters.store(i % 6, (i + 1) % 6, 1, false);
+                    } else {
+                        counters.store(i % 6, i % 6, 0, true);
+                    }
+                }
+            })
+        };
+        while !writer.is_finished() {
+            assert!(counters.len() <= 1);
+        }
+        writer.join().unwrap();
+    }
+}
@@ -0,0 +1,230 @@
+use crate::codec::FORMAT_VERSION;
+use crate::error::{QueueError, Result};
+use crate::stats::StatsSnapshot;
+use crate::utilities::{merge_counter, COUNTER_MERGE_OPERATOR};
+use crate::verify::{Problem, VerificationReport};
+use crate::{fs, mpmc, PersistentQueueWithCapacity};
+use rocksdb::{Options, DB};
+use std::path::{Path, PathBuf};
+
+/// The file every RocksDB database has in its directory.
+const CURRENT_FILE: &str = "CURRENT";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueKind {
+    Persistent,
+    Mpmc,
+}
+
+/// The health of a discovered queue, see [`discover`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueueHealth {
+    Healthy,
+    /// The queue was written by a newer version of the library with the format version.
+    NewerFormat(u64),
+    /// The stored cursors are outside of the index space. `verify()` of the open queue checks
+    /// the elements as well.
+    ///
+    Inconsistent(Vec<Problem>),
+    /// The database can't be read, e.g. its files are damaged.
+    Unreadable(String),
+}
+
+/// A queue found by [`discover`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct QueueInfo {
+    pub path: PathBuf,
+    pub kind: QueueKind,
+    /// `None` if the database can't be read.
+    pub format_version: Option<u64>,
+    pub disk_size: usize,
+    /// The length and the cursors, `None` if the database can't be read.
+    pub stats: Option<StatsSnapshot>,
+    pub health: QueueHealth,
+}
+
+/// The state of a queue read from its database.
+pub(crate) struct Inspection {
+    pub kind: QueueKind,
+    pub format_version: u64,
+    pub index_space: u64,
+    pub stats: StatsSnapshot,
+}
+
+/// Finds the queues in the directory tree, e.g. to inventory the queues left on a node. The
+/// databases are opened read-only, so the queues open in this or another process are found
+/// too, with the state last written by them. The RocksDB databases which are not queues (they
+/// have no format version) are skipped, the queues are returned ordered by their paths. The
+/// legacy queues not migrated to the system column family yet are reported with the legacy
+/// format version 1.
+///
+pub fn discover<P: AsRef<Path>>(root_dir: P) -> Result<Vec<QueueInfo>> {
+    let mut queues = Vec::new();
+    discover_in(root_dir.as_ref(), &mut queues)?;
+    queues.sort_by(|a, b| a.path.cmp(&b.path));
+    Ok(queues)
+}
+
+fn discover_in(dir: &Path, queues: &mut Vec<QueueInfo>) -> Result<()> {
+    if dir.join(CURRENT_FILE).is_file() {
+        queues.extend(inspect(dir)?);
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            discover_in(&entry.path(), queues)?;
+        }
+    }
+    Ok(())
+}
+
+fn inspect(path: &Path) -> Result<Option<QueueInfo>> {
+    let mut db_opts = Options::default();
+    // the payload size of the queues created before the system column family
+    db_opts.set_merge_operator_associative(COUNTER_MERGE_OPERATOR, merge_counter);
+    let cfs = match DB::list_cf(&db_opts, path) {
+        Ok(cfs) => cfs,
+        Err(e) => {
+            log::warn!("Skipping the database at {}: {}", path.display(), e);
+            return Ok(None);
+        }
+    };
+    let has_cf = |name: &str| cfs.iter().any(|cf| cf == name);
+    let kind = if has_cf(mpmc::DATA_CF) && has_cf(mpmc::SYSTEM_CF) {
+        QueueKind::Mpmc
+    } else if has_cf(crate::SYSTEM_CF) || cfs.len() == 1 {
+        // the legacy queues not opened since the system column family was introduced have the
+        // default column family only, they are told from the other databases by their cells
+        QueueKind::Persistent
+    } else {
+        return Ok(None);
+    };
+
+    let disk_size = fs::dir_size(path)?;
+    let unreadable = |e: QueueError| QueueInfo {
+        path: path.to_path_buf(),
+        kind,
+        format_version: None,
+        disk_size,
+        stats: None,
+        health: QueueHealth::Unreadable(e.to_string()),
+    };
+    let inspection = DB::open_cf_for_read_only(&db_opts, path, &cfs, false)
+        .map_err(QueueError::from)
+        .and_then(|db| match kind {
+            QueueKind::Persistent => PersistentQueueWithCapacity::inspect_db(&db),
+            QueueKind::Mpmc => mpmc::MpmcQueue::inspect_db(&db),
+        });
+    Ok(match inspection {
+        Ok(Some(inspection)) => Some(QueueInfo {
+            path: path.to_path_buf(),
+            kind,
+            format_version: Some(inspection.format_version),
+            disk_size,
+            health: health(&inspection),
+            stats: Some(inspection.stats),
+        }),
+        Ok(None) => None,
+        Err(e) => Some(unreadable(e)),
+    })
+}
+
+fn health(inspection: &Inspection) -> QueueHealth {
+    if inspection.format_version > FORMAT_VERSION {
+        return QueueHealth::NewerFormat(inspection.format_version);
+    }
+    let stats = &inspection.stats;
+    let start_cursor = match inspection.kind {
+        QueueKind::Persistent => "read_index",
+        QueueKind::Mpmc => "start_index",
+    };
+    let mut report = VerificationReport::default();
+    report.check_index(start_cursor, stats.start_index, inspection.index_space);
+    report.check_index("write_index", stats.write_index, inspection.index_space);
+    let mut labels = stats.labels.iter().collect::<Vec<_>>();
+    labels.sort_by_key(|(label, _)| *label);
+    for (label, position) in labels {
+        if position.index >= inspection.index_space {
+            report.problems.push(Problem::ReaderOutOfRange {
+                label: label.clone(),
+                index: position.index,
+            });
+        }
+    }
+    if report.is_ok() {
+        QueueHealth::Healthy
+    } else {
+        QueueHealth::Inconsistent(report.problems)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::LEGACY_FORMAT_VERSION;
+    use crate::mpmc::{MpmcQueue, StartPosition};
+    use crate::options::QueueOptions;
+    use crate::utilities::index_to_key;
+    use std::time::Duration;
+
+    #[test]
+    fn test_discover() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let persistent_path = dir.path().join("persistent");
+        let mpmc_path = dir.path().join("nested").join("mpmc");
+        {
+            let queue =
+                PersistentQueueWithCapacity::open(&persistent_path, 10, &QueueOptions::default())
+                    .unwrap();
+            queue.push(&[&[1], &[2]]).unwrap();
+
+            let mut queue = MpmcQueue::open(
+                &mpmc_path,
+                Duration::from_secs(60),
+                &QueueOptions::default(),
+            )
+            .unwrap();
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            queue.next(1, "reader", StartPosition::Oldest).unwrap();
+        }
+        let legacy_path = dir.path().join("legacy");
+        let legacy = DB::open_default(&legacy_path).unwrap();
+        legacy.put(index_to_key(0), [1, 2, 3]).unwrap();
+        legacy
+            .put(index_to_key(crate::WRITE_INDEX_CELL), 1u64.to_le_bytes())
+            .unwrap();
+        drop(legacy);
+        let other = DB::open_default(dir.path().join("other")).unwrap();
+        other.put(b"key", b"value").unwrap();
+        drop(other);
+
+        let queues = discover(dir.path()).unwrap();
+        assert_eq!(queues.len(), 3);
+
+        let legacy = &queues[0];
+        assert_eq!(legacy.path, legacy_path);
+        assert_eq!(legacy.kind, QueueKind::Persistent);
+        assert_eq!(legacy.format_version, Some(LEGACY_FORMAT_VERSION));
+        assert_eq!(legacy.health, QueueHealth::Healthy);
+        assert_eq!(legacy.stats.as_ref().unwrap().len, 1);
+
+        let mpmc = &queues[1];
+        assert_eq!(mpmc.path, mpmc_path);
+        assert_eq!(mpmc.kind, QueueKind::Mpmc);
+        assert_eq!(mpmc.format_version, Some(FORMAT_VERSION));
+        assert_eq!(mpmc.health, QueueHealth::Healthy);
+        assert!(mpmc.disk_size > 0);
+        let stats = mpmc.stats.as_ref().unwrap();
+        assert_eq!(stats.len, 3);
+        assert_eq!(stats.labels["reader"].lag, 2);
+
+        let persistent = &queues[2];
+        assert_eq!(persistent.path, persistent_path);
+        assert_eq!(persistent.kind, QueueKind::Persistent);
+        assert_eq!(persistent.health, QueueHealth::Healthy);
+        let stats = persistent.stats.as_ref().unwrap();
+        assert_eq!(stats.len, 2);
+        assert_eq!(stats.payload_size, Some(2));
+    }
+}
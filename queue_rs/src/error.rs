@@ -0,0 +1,111 @@
+use crate::codec::CorruptedElement;
+use crate::verify::VerificationReport;
+use rocksdb::ErrorKind;
+use std::io;
+use std::path::PathBuf;
+use thiserror::Error;
+
+/// The errors returned by the queues.
+#[derive(Debug, Error)]
+pub enum QueueError {
+    /// The queue has no room for the pushed elements.
+    #[error("Queue is full")]
+    Full,
+    /// The element failed the checksum verification.
+    #[error(transparent)]
+    Corrupted(#[from] CorruptedElement),
+    /// The element can't be encoded or decoded, e.g. it is encrypted with an unknown key.
+    #[error("{0}")]
+    Codec(String),
+    /// The stored queue metadata can't be serialized or deserialized.
+    #[error("Serialization error: {0}")]
+    Serialization(String),
+    /// The database of the queue is still held by another handle, e.g. the queue is reopened
+    /// before the previous handle is closed.
+    ///
+    #[error("The queue at {} is in use by another handle", .path.display())]
+    InUse { path: PathBuf },
+    /// The queue is closed or its working threads have stopped.
+    #[error("Queue is unhealthy: cannot use it anymore.")]
+    Closed,
+    /// The operation was rejected because too many operations are waiting for the working
+    /// threads, see [`crate::nonblocking::BackpressureStrategy`].
+    ///
+    #[error("Too many inflight operations")]
+    Backpressure,
+    /// The push exceeds the rate limit of the queue, see [`crate::limiter::RateLimit`].
+    #[error("Rate limit exceeded")]
+    RateLimited,
+    /// The push carries an older epoch than the registered one, e.g. it comes from a stale
+    /// instance of a restarted producer.
+    ///
+    #[error("Producer {producer_id} is fenced: epoch {epoch} is older than {current}")]
+    Fenced {
+        producer_id: String,
+        epoch: u64,
+        current: u64,
+    },
+    /// The queue opened with `open_verified` failed the verification, see the report for the
+    /// broken invariants.
+    ///
+    #[error(
+        "The queue at {} failed the verification with {} problems",
+        .path.display(),
+        .report.problems.len()
+    )]
+    Unverified {
+        path: PathBuf,
+        report: VerificationReport,
+    },
+    #[error("{0}")]
+    InvalidArgument(String),
+    /// The configuration of the queues can't be read, e.g. a TOML file with unknown options.
+    #[error("Invalid configuration: {0}")]
+    Config(String),
+    #[error(transparent)]
+    Db(#[from] rocksdb::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    /// A broken invariant, e.g. a working thread panicked.
+    #[error("{0}")]
+    Internal(String),
+}
+
+pub type Result<T, E = QueueError> = std::result::Result<T, E>;
+
+impl QueueError {
+    /// Returns `true` if the operation may succeed when retried after the database is reopened,
+    /// e.g. after an IO error which stopped the writes of RocksDB or a busy database. The
+    /// corruptions and the invalid arguments are fatal.
+    ///
+    pub fn is_transient(&self) -> bool {
+        match self {
+            QueueError::Db(e) => matches!(
+                e.kind(),
+                ErrorKind::IOError
+                    | ErrorKind::Busy
+                    | ErrorKind::TimedOut
+                    | ErrorKind::TryAgain
+                    | ErrorKind::Incomplete
+                    | ErrorKind::Aborted
+            ),
+            QueueError::Io(e) => matches!(
+                e.kind(),
+                io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+            ),
+            _ => false,
+        }
+    }
+}
+
+impl From<bincode::error::EncodeError> for QueueError {
+    fn from(e: bincode::error::EncodeError) -> Self {
+        QueueError::Serialization(e.to_string())
+    }
+}
+
+impl From<bincode::error::DecodeError> for QueueError {
+    fn from(e: bincode::error::DecodeError) -> Self {
+        QueueError::Serialization(e.to_string())
+    }
+}
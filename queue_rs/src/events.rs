@@ -0,0 +1,96 @@
+use crate::alerts::DiskThreshold;
+use parking_lot::RwLock;
+use std::fmt;
+
+/// An event of a queue passed to the listeners registered with `add_listener`, `on_push`,
+/// `on_expire` and `on_full`.
+///
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum QueueEvent {
+    /// The elements have been pushed (added to the MPMC queue), carries their number.
+    Push(usize),
+    /// The consumer with the label has missed the expired elements, MPMC queues only.
+    Expire(String),
+    /// The elements have been rejected because the queue is full.
+    Full,
+    /// The database has been reopened, e.g. after a transient IO error.
+    Reopen,
+    /// The disk usage has reached the threshold (`active`) or has left it, see
+    /// [`crate::alerts::DiskAlerts`].
+    ///
+    DiskAlert {
+        threshold: DiskThreshold,
+        active: bool,
+    },
+}
+
+type Listener = Box<dyn Fn(&QueueEvent) + Send + Sync>;
+
+/// The listeners registered on a queue. They are invoked by the thread changing the queue
+/// after the change is written, so they must not block and must not use the queue.
+///
+#[derive(Default)]
+pub(crate) struct Listeners(RwLock<Vec<Listener>>);
+
+impl Listeners {
+    pub(crate) fn add<F>(&self, f: F)
+    where
+        F: Fn(&QueueEvent) + Send + Sync + 'static,
+    {
+        self.0.write().push(Box::new(f));
+    }
+
+    pub(crate) fn on_push<F>(&self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.add(move |event| {
+            if let QueueEvent::Push(count) = event {
+                f(*count)
+            }
+        });
+    }
+
+    pub(crate) fn on_expire<F>(&self, f: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.add(move |event| {
+            if let QueueEvent::Expire(label) = event {
+                f(label)
+            }
+        });
+    }
+
+    pub(crate) fn on_full<F>(&self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.add(move |event| {
+            if let QueueEvent::Full = event {
+                f()
+            }
+        });
+    }
+
+    /// Invokes the listeners, the event is created only if there are any.
+    pub(crate) fn emit<F>(&self, event: F)
+    where
+        F: FnOnce() -> QueueEvent,
+    {
+        let listeners = self.0.read();
+        if listeners.is_empty() {
+            return;
+        }
+        let event = event();
+        for listener in listeners.iter() {
+            listener(&event);
+        }
+    }
+}
+
+impl fmt::Debug for Listeners {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Listeners({})", self.0.read().len())
+    }
+}
@@ -1,6 +1,77 @@
+use crate::error::{QueueError, Result};
+use crate::options::RemoveOptions;
+use crate::utilities::db_error;
+use rocksdb::DB;
+use std::fs::OpenOptions;
+use std::io::Write;
 use std::path::Path;
 use std::{fs, io};
-pub fn dir_size(path: &String) -> io::Result<usize> {
+
+/// The extensions of the files holding the elements: the tables, the blobs and the WAL.
+const DATA_FILE_EXTENSIONS: [&str; 3] = ["sst", "blob", "log"];
+const WIPE_CHUNK: usize = 1024 * 1024;
+
+/// Destroys the database at `path`. The database held open by a queue, in this or another
+/// process, is refused with [`QueueError::InUse`] unless forced, then its directory is removed
+/// regardless and the queue holding it loses its data.
+///
+pub fn destroy_db(path: &Path, options: &RemoveOptions) -> Result<()> {
+    let db_opts = options.db_options.clone().unwrap_or_default();
+    if options.secure_wipe && path.exists() {
+        // the database is opened to take its lock, so an open queue is not wiped
+        let in_use = match DB::list_cf(&db_opts, path)
+            .and_then(|cfs| DB::open_cf(&db_opts, path, cfs))
+            .map_err(|e| db_error(path, e))
+        {
+            Ok(db) => {
+                drop(db);
+                false
+            }
+            Err(QueueError::InUse { .. }) => true,
+            Err(e) => return Err(e),
+        };
+        if in_use && !options.force {
+            return Err(QueueError::InUse {
+                path: path.to_path_buf(),
+            });
+        }
+        wipe_data_files(path)?;
+    }
+    match DB::destroy(&db_opts, path).map_err(|e| db_error(path, e)) {
+        Err(QueueError::InUse { .. }) if options.force => {
+            log::warn!("Removing the queue at {} while it is open", path.display());
+            Ok(fs::remove_dir_all(path)?)
+        }
+        res => res,
+    }
+}
+
+/// Overwrites the data files of the database with zeros and syncs them.
+fn wipe_data_files(path: &Path) -> io::Result<()> {
+    let zeros = vec![0u8; WIPE_CHUNK];
+    for entry in fs::read_dir(path)? {
+        let file_path = entry?.path();
+        let is_data = file_path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| DATA_FILE_EXTENSIONS.contains(&e));
+        if !is_data {
+            continue;
+        }
+        let mut file = OpenOptions::new().write(true).open(&file_path)?;
+        let mut remaining = file.metadata()?.len() as usize;
+        while remaining > 0 {
+            let n = remaining.min(WIPE_CHUNK);
+            file.write_all(&zeros[..n])?;
+            remaining -= n;
+        }
+        file.sync_all()?;
+    }
+    log::info!("Wiped the data files of the queue at {}", path.display());
+    Ok(())
+}
+
+pub fn dir_size(path: &Path) -> io::Result<usize> {
     fn dir_size(mut dir: fs::ReadDir) -> io::Result<usize> {
         dir.try_fold(0, |acc, file| {
             let file = file?;
@@ -11,5 +82,29 @@ pub fn dir_size(path: &String) -> io::Result<usize> {
             Ok(acc + size)
         })
     }
-    dir_size(fs::read_dir(Path::new(path))?)
+    dir_size(fs::read_dir(path)?)
+}
+
+/// Returns the space available to the user on the file system of the path.
+#[cfg(unix)]
+pub fn available_space(path: &Path) -> io::Result<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: the path is NUL-terminated and statvfs only writes the zeroed struct
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // the types of the fields differ between the platforms
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+pub fn available_space(_path: &Path) -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "the available space is known only on Unix",
+    ))
 }
@@ -1,344 +1,3433 @@
+pub mod alerts;
+pub mod asynchronous;
 pub mod blocking;
+mod chunking;
+pub mod codec;
+pub mod compaction;
+#[cfg(feature = "config")]
+pub mod config;
+pub mod counters;
+pub mod discovery;
+pub mod error;
+pub mod events;
 mod fs;
+pub mod limiter;
+pub mod memory;
+pub mod migration;
 pub mod mpmc;
+pub mod multipart;
 pub mod nonblocking;
+pub mod options;
+pub mod producer;
+pub mod queue;
+mod repair;
+pub mod resources;
+pub mod stats;
+mod trace;
 mod utilities;
+pub mod verify;
 
-use crate::utilities::{index_to_key, next_index, u64_from_byte_vec};
-use anyhow::{anyhow, Result};
-use rocksdb::{Options, DB};
-use std::cmp::Ordering;
+use crate::alerts::{DiskAlerts, DiskMonitor};
+use crate::codec::{Codec, Compression, Keyring, FORMAT_VERSION, LEGACY_FORMAT_VERSION};
+use crate::counters::Counters;
+use crate::discovery::{Inspection, QueueKind};
+use crate::error::Result;
+use crate::events::{Listeners, QueueEvent};
+use crate::limiter::{RateLimit, RateLimiter};
+use crate::options::{QueueOptions, RemoveOptions};
+use crate::stats::{DbStats, QueueStats, SizeStats, StatsSnapshot, StorageModel};
+use crate::trace::OpSpan;
+use crate::utilities::{
+    counter_delta, current_timestamp, db_error, index_to_key, key_to_index, merge_counter,
+    next_index, payload_cf_options, previous_index, u64_from_byte_vec, COUNTER_MERGE_OPERATOR,
+};
+use crate::verify::{in_range, ElementsScan, Problem, VerificationReport};
+use parking_lot::Mutex;
+use rocksdb::{
+    ColumnFamilyDescriptor, ErrorKind, IteratorMode, Options, WriteBatch, WriteOptions, DB,
+};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
 
 pub fn version() -> &'static str {
     env!("CARGO_PKG_VERSION")
 }
 
+pub use crate::error::QueueError;
+
+/// The queue can be pushed to and popped from concurrently: pushes and pops are serialized
+/// separately and share only the short section writing the batch, see [`State`].
+///
 #[derive(Debug)]
 pub struct PersistentQueueWithCapacity {
-    db: DB,
-    path: String,
+    db: Option<DB>,
+    db_opts: DbOptions,
+    path: PathBuf,
+    max_elements: u64,
+    index_space: u64,
+    chunk_size: usize,
+    codec: Codec,
+    skip_corrupted: bool,
+    sync_writes: bool,
+    // the elements may be evicted by the compaction filter, see `QueueOptions::eviction_filter`
+    evicting: bool,
+    prefetch: usize,
+    listeners: Listeners,
+    disk_monitor: DiskMonitor,
+    push_limiter: Mutex<Option<RateLimiter>>,
+    pop_limiter: Mutex<Option<RateLimiter>>,
+    // held for the whole push
+    writer: Mutex<()>,
+    // held for the whole pop, keeps the indices of the skipped corrupted elements
+    reader: Mutex<Vec<u64>>,
+    state: Mutex<State>,
+    counters: Arc<Counters>,
+}
+
+/// The options the database is reopened with.
+struct DbOptions(Options);
+
+impl fmt::Debug for DbOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "DbOptions")
+    }
+}
+
+/// The committed state of the queue. It is locked only to take a snapshot and to apply a
+/// written batch of a push or a pop, so the elements are encoded, decoded and written outside
+/// of it. The payload size is stored as deltas merged by RocksDB, so the batches of a push and
+/// a pop don't depend on each other.
+///
+#[derive(Debug)]
+struct State {
     space_stat: u64,
     write_index: u64,
     read_index: u64,
-    max_elements: u64,
     empty: bool,
+    chunked: bool,
+    // the queue has the elements pushed with payloads
+    paired: bool,
+    // the queue has the elements pushed with keys
+    keyed: bool,
+    format_version: u64,
+    // the stored elements carry the checksums
+    checksums: bool,
+    // the numbers of the elements pushed and popped since the queue was opened
+    pushed: u64,
+    popped: u64,
+    sizes: SizeStats,
 }
 
 const U64_BYTE_LEN: usize = 8;
 const WRITE_INDEX_CELL: u64 = u64::MAX;
 const READ_INDEX_CELL: u64 = u64::MAX - 1;
 const SPACE_STAT_CELL: u64 = u64::MAX - 2;
+const CHUNKED_CELL: u64 = u64::MAX - 3;
+const FORMAT_VERSION_CELL: u64 = u64::MAX - 4;
+/// The distribution of the pushed element sizes, it was never stored in the default column family.
+const SIZES_CELL: u64 = u64::MAX - 5;
+/// The number of the indices the queue was created with, see
+/// [`PersistentQueueWithCapacity::with_index_space`]. It was never stored in the default column
+/// family, the queues created without it use [`DEFAULT_INDEX_SPACE`].
+///
+const INDEX_SPACE_CELL: u64 = u64::MAX - 6;
+/// Marks the queue having the paired elements, see [`PersistentQueueWithCapacity::push_pairs`].
+const PAIRED_CELL: u64 = u64::MAX - 7;
+/// Marks the queue having the elements pushed with keys, see
+/// [`PersistentQueueWithCapacity::push_with_keys`].
+///
+const KEYED_CELL: u64 = u64::MAX - 8;
+/// Marks the queue the stored elements of which carry the checksums, see
+/// [`PersistentQueueWithCapacity::set_checksums`].
+///
+const CHECKSUMS_CELL: u64 = u64::MAX - 9;
+const SYSTEM_CELLS: [u64; 5] = [
+    WRITE_INDEX_CELL,
+    READ_INDEX_CELL,
+    SPACE_STAT_CELL,
+    CHUNKED_CELL,
+    FORMAT_VERSION_CELL,
+];
+/// The column family of the system cells, the elements are stored in the default one.
+const SYSTEM_CF: &str = "system";
+/// The column family of the last sequence numbers of the producer sessions, see
+/// [`PersistentQueueWithCapacity::push_with_sequence`], and of the producer epochs.
+///
+const SESSION_CF: &str = "session";
+/// The column family of the times the elements were pushed at, see
+/// [`PersistentQueueWithCapacity::oldest_age`].
+///
+const TIME_CF: &str = "time";
+/// The column family of the payloads of the paired elements, see
+/// [`PersistentQueueWithCapacity::push_pairs`].
+///
+pub(crate) const PAYLOAD_CF: &str = "payload";
+/// The column family of the keys of the elements, see
+/// [`PersistentQueueWithCapacity::push_with_keys`]. The index of an element maps to its key,
+/// the length of the key, the key and the index map to nothing, so the elements of a key are
+/// found by the prefix.
+///
+const KEY_CF: &str = "key";
+/// The state of an element in its index entry of the key column family.
+const KEY_LIVE: u8 = 0;
+const KEY_DELETED: u8 = 1;
+/// Prefixes the keys of the producer epochs, it is never a part of UTF-8 producer ids.
+const EPOCH_KEY_PREFIX: u8 = 0xff;
+/// Prefixes the keys of the reader positions of another queue, see [`mpmc::QueueOffsetStore`].
+const OFFSET_KEY_PREFIX: u8 = 0xfe;
+/// The number of elements popped at once by [`PersistentQueueWithCapacity::pop_all`].
+pub const POP_ALL_BATCH: usize = 1024;
 
-#[cfg(test)]
-const MAX_ALLOWED_INDEX: u64 = 6;
-#[cfg(not(test))]
-const MAX_ALLOWED_INDEX: u64 = u64::MAX - 100;
+/// The number of the indices the elements are stored under, the indices wrap around it. The
+/// keys above it are reserved for the system cells.
+///
+pub const DEFAULT_INDEX_SPACE: u64 = u64::MAX - 100;
 
 // db_opts.set_write_buffer_size(64 * 1024 * 1024);
 // db_opts.set_max_write_buffer_number(5);
 // db_opts.set_min_write_buffer_number_to_merge(2);
 
 impl PersistentQueueWithCapacity {
-    pub fn new(path: &str, max_elements: usize, mut db_opts: Options) -> Result<Self> {
-        if max_elements > MAX_ALLOWED_INDEX as usize {
-            return Err(anyhow!(
-                "max_elements can't be greater than {}",
-                MAX_ALLOWED_INDEX
-            ));
+    /// Opens the database and moves the system cells of queues created by versions without
+    /// the system column family out of the default one.
+    ///
+    fn open_db(path: &Path, mut db_opts: Options, repair: bool) -> Result<DB> {
+        db_opts.create_missing_column_families(true);
+        db_opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(U64_BYTE_LEN));
+        // the payload size of the queues created before the system column family
+        db_opts.set_merge_operator_associative(COUNTER_MERGE_OPERATOR, merge_counter);
+
+        let mut cf_opts = Options::default();
+        cf_opts.set_merge_operator_associative(COUNTER_MERGE_OPERATOR, merge_counter);
+        let open = || {
+            DB::open_cf_descriptors(
+                &db_opts,
+                path,
+                vec![
+                    ColumnFamilyDescriptor::new(SYSTEM_CF, cf_opts.clone()),
+                    ColumnFamilyDescriptor::new(SESSION_CF, Options::default()),
+                    ColumnFamilyDescriptor::new(TIME_CF, Options::default()),
+                    ColumnFamilyDescriptor::new(PAYLOAD_CF, payload_cf_options()),
+                    ColumnFamilyDescriptor::new(KEY_CF, Options::default()),
+                ],
+            )
+        };
+        let db = if repair {
+            repair::open_or_repair(&db_opts, path, open)?
+        } else {
+            open().map_err(|e| db_error(path, e))?
+        };
+
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        for cell in SYSTEM_CELLS {
+            if let Some(value) = db.get(index_to_key(cell))? {
+                batch.put_cf(system_cf, index_to_key(cell), value);
+                batch.delete(index_to_key(cell));
+            }
+        }
+        if !batch.is_empty() {
+            db.write(batch)?;
+        }
+        Ok(db)
+    }
+
+    pub fn new<P: AsRef<Path>>(path: P, max_elements: usize, db_opts: Options) -> Result<Self> {
+        Self::open_with(path.as_ref(), max_elements, None, db_opts)
+    }
+
+    /// Opens the queue storing the elements under `index_space` indices, e.g. a small ring in
+    /// tests or embedded deployments. The index space is stored when the queue is created and
+    /// can't be changed afterwards, opening the queue with a different one fails with
+    /// [`QueueError::InvalidArgument`]. [`Self::new`] opens the queue with the stored index
+    /// space, [`DEFAULT_INDEX_SPACE`] for the new queues.
+    ///
+    pub fn with_index_space<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        index_space: u64,
+        db_opts: Options,
+    ) -> Result<Self> {
+        Self::open_with(path.as_ref(), max_elements, Some(index_space), db_opts)
+    }
+
+    fn open_with(
+        path: &Path,
+        max_elements: usize,
+        index_space: Option<u64>,
+        mut db_opts: Options,
+    ) -> Result<Self> {
+        if let Some(index_space) = index_space {
+            check_index_space(index_space)?;
         }
         db_opts.create_if_missing(true);
-        db_opts.set_prefix_extractor(rocksdb::SliceTransform::create_fixed_prefix(U64_BYTE_LEN));
+        let db = Self::open_db(path, db_opts.clone(), false)?;
+        let state = Self::read_state(&db)?;
+        let index_space = Self::read_index_space(&db, &state, index_space)?;
+        if max_elements as u64 > index_space {
+            return Err(QueueError::InvalidArgument(format!(
+                "max_elements can't be greater than {}",
+                index_space
+            )));
+        }
 
-        let db = DB::open(&db_opts, path)?;
+        let counters = Arc::new(Counters::new(
+            state.read_index,
+            state.write_index,
+            state.space_stat,
+            state.empty,
+            index_space,
+        ));
+        log::info!(
+            "Opened the queue at {} with {} elements",
+            path.display(),
+            counters.len()
+        );
+
+        Ok(Self {
+            db: Some(db),
+            db_opts: DbOptions(db_opts),
+            path: path.to_path_buf(),
+            max_elements: max_elements as u64,
+            index_space,
+            chunk_size: 0,
+            codec: Codec::default(),
+            skip_corrupted: false,
+            evicting: false,
+            prefetch: 0,
+            sync_writes: false,
+            listeners: Listeners::default(),
+            disk_monitor: DiskMonitor::default(),
+            push_limiter: Mutex::new(None),
+            pop_limiter: Mutex::new(None),
+            writer: Mutex::new(()),
+            reader: Mutex::new(Vec::new()),
+            state: Mutex::new(state),
+            counters,
+        })
+    }
+
+    /// Opens the queue with the RocksDB paranoid checks and verifies it (see [`Self::verify`]),
+    /// e.g. after an unclean power-off. A queue failing the verification is not opened: it is
+    /// repaired (see [`Self::repair`]) and verified again if `repair` is set, otherwise
+    /// [`QueueError::Unverified`] is returned. The elements are decoded with the default codec,
+    /// so the encrypted queues fail the verification.
+    ///
+    pub fn open_verified<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        db_opts: Options,
+        repair: bool,
+    ) -> Result<Self> {
+        Self::open_verified_with(path.as_ref(), max_elements, None, db_opts, repair)
+    }
+
+    fn open_verified_with(
+        path: &Path,
+        max_elements: usize,
+        index_space: Option<u64>,
+        mut db_opts: Options,
+        repair: bool,
+    ) -> Result<Self> {
+        db_opts.set_paranoid_checks(true);
+        let queue = match Self::open_with(path, max_elements, index_space, db_opts.clone()) {
+            Err(QueueError::Db(e)) if repair && e.kind() == ErrorKind::Corruption => {
+                log::error!(
+                    "The database of the queue at {} is corrupted: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+            res => Some(res?),
+        };
+        if let Some(queue) = queue {
+            let report = queue.verify()?;
+            if report.is_ok() {
+                return Ok(queue);
+            }
+            log::error!(
+                "The queue at {} failed the verification: {:?}",
+                path.display(),
+                report.problems
+            );
+            queue.close()?;
+            if !repair {
+                return Err(QueueError::Unverified {
+                    path: path.to_path_buf(),
+                    report,
+                });
+            }
+        }
+
+        Self::repair(path, db_opts.clone())?;
+        let queue = Self::open_with(path, max_elements, index_space, db_opts)?;
+        let report = queue.verify()?;
+        if !report.is_ok() {
+            return Err(QueueError::Unverified {
+                path: path.to_path_buf(),
+                report,
+            });
+        }
+        log::warn!("Repaired the queue at {}", path.display());
+        Ok(queue)
+    }
+
+    /// Opens the queue with the typed options, see [`QueueOptions`]. The settings of the queue,
+    /// e.g. the sync writes and the rate limits, are set on the opened queue.
+    ///
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        options: &QueueOptions,
+    ) -> Result<Self> {
+        let path = path.as_ref();
+        let db_opts = options.build_db_options()?;
+        let mut queue = if options.verify_on_open {
+            Self::open_verified_with(
+                path,
+                max_elements,
+                options.index_space,
+                db_opts,
+                options.repair_on_open,
+            )?
+        } else {
+            Self::open_with(path, max_elements, options.index_space, db_opts)?
+        };
+        queue.set_compression(options.element_compression);
+        queue.set_checksums(options.checksums);
+        queue.set_sync_writes(options.sync_writes);
+        queue.set_push_rate_limit(options.push_rate_limit)?;
+        queue.set_pop_rate_limit(options.pop_rate_limit)?;
+        queue.evicting = options.eviction_filter.is_some();
+        queue.set_prefetch(options.prefetch);
+        queue.set_disk_alerts(options.disk_alerts.clone())?;
+        Ok(queue)
+    }
+
+    /// Reads the state of the queue from the system cells, the counters of the pushed and
+    /// popped elements start from zero.
+    ///
+    fn read_state(db: &DB) -> Result<State> {
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
 
-        let write_index_opt = db.get(index_to_key(WRITE_INDEX_CELL))?;
+        let write_index_opt = db.get_cf(system_cf, index_to_key(WRITE_INDEX_CELL))?;
         let write_index = match write_index_opt {
             Some(v) => u64_from_byte_vec(&v),
             None => 0u64,
         };
 
-        let read_index_opt = db.get(index_to_key(READ_INDEX_CELL))?;
+        let read_index_opt = db.get_cf(system_cf, index_to_key(READ_INDEX_CELL))?;
         let read_index = match read_index_opt {
             Some(v) => u64_from_byte_vec(&v),
             None => 0u64,
         };
 
-        let space_stat_opt = db.get(index_to_key(SPACE_STAT_CELL))?;
+        let space_stat_opt = db.get_cf(system_cf, index_to_key(SPACE_STAT_CELL))?;
         let space_stat = match space_stat_opt {
             Some(v) => u64_from_byte_vec(&v),
             None => 0u64,
         };
 
         let empty = db.get(index_to_key(read_index))?.is_none();
+        let chunked = db.get_cf(system_cf, index_to_key(CHUNKED_CELL))?.is_some();
+        let paired = db.get_cf(system_cf, index_to_key(PAIRED_CELL))?.is_some();
+        let keyed = db.get_cf(system_cf, index_to_key(KEYED_CELL))?.is_some();
+        let checksums = db
+            .get_cf(system_cf, index_to_key(CHECKSUMS_CELL))?
+            .is_some();
 
-        Ok(Self {
-            db,
-            path: path.to_string(),
+        let format_version = match db.get_cf(system_cf, index_to_key(FORMAT_VERSION_CELL))? {
+            Some(v) => u64_from_byte_vec(&v),
+            None if empty => {
+                db.put_cf(
+                    system_cf,
+                    index_to_key(FORMAT_VERSION_CELL),
+                    FORMAT_VERSION.to_le_bytes(),
+                )?;
+                FORMAT_VERSION
+            }
+            None => LEGACY_FORMAT_VERSION,
+        };
+
+        let sizes = match db.get_cf(system_cf, index_to_key(SIZES_CELL))? {
+            Some(v) => SizeStats::from_bytes(&v)?,
+            None => SizeStats::default(),
+        };
+
+        Ok(State {
+            space_stat,
             write_index,
             read_index,
-            space_stat,
-            max_elements: max_elements as u64,
             empty,
+            chunked,
+            paired,
+            keyed,
+            format_version,
+            checksums,
+            pushed: 0,
+            popped: 0,
+            sizes,
         })
     }
 
-    pub fn remove_db(path: &str) -> Result<()> {
-        Ok(DB::destroy(&Options::default(), path)?)
+    /// Reads the stored index space, it is stored on the first open: the requested one for the
+    /// new queues and the default one for the queues created without it.
+    ///
+    fn read_index_space(db: &DB, state: &State, requested: Option<u64>) -> Result<u64> {
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let index_space = match db.get_cf(system_cf, index_to_key(INDEX_SPACE_CELL))? {
+            Some(v) => u64_from_byte_vec(&v),
+            None => {
+                let created = state.empty && state.read_index == 0 && state.write_index == 0;
+                let index_space = match requested {
+                    Some(index_space) if created => index_space,
+                    _ => DEFAULT_INDEX_SPACE,
+                };
+                db.put_cf(
+                    system_cf,
+                    index_to_key(INDEX_SPACE_CELL),
+                    index_space.to_le_bytes(),
+                )?;
+                index_space
+            }
+        };
+        match requested {
+            Some(requested) if requested != index_space => {
+                Err(QueueError::InvalidArgument(format!(
+                    "The queue was created with the index space of {}, not {}",
+                    index_space, requested
+                )))
+            }
+            _ => Ok(index_space),
+        }
     }
 
-    pub fn disk_size(&self) -> Result<usize> {
-        Ok(fs::dir_size(&self.path)?)
+    /// Closes the database and opens it again with the same options, e.g. after RocksDB
+    /// stopped accepting the writes because of an IO error, see [`QueueError::is_transient`].
+    /// The state is read again from the database, the settings of the queue are kept. If the
+    /// database can't be opened, the operations fail with [`QueueError::Closed`] until it is
+    /// reopened.
+    ///
+    pub fn reopen(&mut self) -> Result<()> {
+        // RocksDB holds the lock of the database until it is closed
+        self.db = None;
+        let db = Self::open_db(&self.path, self.db_opts.0.clone(), false)?;
+        let state = Self::read_state(&db)?;
+        self.counters.store(
+            state.read_index,
+            state.write_index,
+            state.space_stat,
+            state.empty,
+        );
+        let previous = self.state.get_mut();
+        *previous = State {
+            pushed: previous.pushed,
+            popped: previous.popped,
+            ..state
+        };
+        self.db = Some(db);
+        log::warn!("Reopened the queue at {}", self.path.display());
+        self.listeners.emit(|| QueueEvent::Reopen);
+        Ok(())
     }
 
-    pub fn len(&self) -> usize {
-        if self.empty {
-            0
-        } else {
-            (match self.write_index.cmp(&self.read_index) {
-                Ordering::Less => MAX_ALLOWED_INDEX - self.read_index + self.write_index,
-                Ordering::Equal => MAX_ALLOWED_INDEX,
-                Ordering::Greater => self.write_index - self.read_index,
-            }) as usize
-        }
+    /// Returns the database, it is closed only if reopening it failed.
+    fn db(&self) -> Result<&DB> {
+        self.db.as_ref().ok_or(QueueError::Closed)
     }
 
-    pub fn payload_size(&self) -> u64 {
-        self.space_stat
+    /// Sets the compression applied to pushed elements. Queues created by versions without
+    /// the element header keep storing elements as is until they are drained completely.
+    ///
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.codec.compression = compression;
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.empty
+    pub fn compression(&self) -> Compression {
+        self.codec.compression
     }
 
-    pub fn push(&mut self, values: &[&[u8]]) -> Result<()> {
-        if values.is_empty() {
-            return Ok(());
-        }
-        if self.len() + values.len() > self.max_elements as usize {
-            return Err(anyhow::anyhow!("Queue is full"));
-        }
+    /// Sets the keyring used to encrypt pushed elements with its active key and to decrypt
+    /// stored ones. `None` disables encryption of new elements; encrypted elements can't be
+    /// read without the keyring. The queues created by versions without the element header
+    /// store the elements as is until they are drained completely, so the pushes to them fail
+    /// with [`QueueError::InvalidArgument`] while the keyring is set.
+    ///
+    pub fn set_keyring(&mut self, keyring: Option<Keyring>) {
+        self.codec.keyring = keyring;
+    }
 
-        let mut batch = rocksdb::WriteBatch::default();
-        let mut write_index = self.write_index;
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.codec.keyring.as_ref()
+    }
 
-        for value in values {
-            batch.put(index_to_key(write_index), value);
-            write_index = next_index(write_index);
-        }
+    /// Enables storing the CRC32C checksum with pushed elements. The queue records whether its
+    /// elements carry the checksums, so an element whose flag disagrees is corrupted, and the
+    /// setting applies from the first push to the empty queue, until then the elements are
+    /// stored the way the stored ones are.
+    ///
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.codec.checksums = checksums;
+    }
 
-        batch.put(index_to_key(WRITE_INDEX_CELL), write_index.to_le_bytes());
+    pub fn checksums(&self) -> bool {
+        self.codec.checksums
+    }
+
+    /// If enabled, elements failing the checksum verification are skipped instead of returning
+    /// [`QueueError::Corrupted`]. The indices of the skipped elements are collected and can be
+    /// retrieved with [`take_corrupted`](Self::take_corrupted).
+    ///
+    pub fn set_skip_corrupted(&mut self, skip_corrupted: bool) {
+        self.skip_corrupted = skip_corrupted;
+    }
+
+    pub fn skip_corrupted(&self) -> bool {
+        self.skip_corrupted
+    }
+
+    /// Sets the number of the entries [`Self::prefetch_next`] loads into the block cache, e.g.
+    /// so the sequential pops of the cold data don't wait for the disk. The nonblocking queue
+    /// prefetches them after every pop, while the popped elements are processed. Zero, the
+    /// default, disables the prefetch.
+    ///
+    pub fn set_prefetch(&mut self, entries: usize) {
+        self.prefetch = entries;
+    }
 
-        let space_stat = self.space_stat + values.iter().map(|v| v.len() as u64).sum::<u64>();
+    pub fn prefetch(&self) -> usize {
+        self.prefetch
+    }
 
-        batch.put(index_to_key(SPACE_STAT_CELL), space_stat.to_le_bytes());
+    /// Loads the elements the next pop takes into the block cache, see [`Self::set_prefetch`].
+    /// Returns the indices of the loaded elements.
+    ///
+    pub fn prefetch_next(&self) -> Result<Vec<u64>> {
+        let (read_index, len) = {
+            let state = self.state.lock();
+            let len = counters::length(
+                state.read_index,
+                state.write_index,
+                state.empty,
+                self.index_space,
+            );
+            (state.read_index, len)
+        };
+        let count = self.prefetch.min(len);
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        utilities::prefetch(self.db()?, None, read_index, count, self.index_space)
+    }
 
-        self.db.write(batch)?;
+    /// If enabled, every write is synced to disk before it is acknowledged, so acknowledged
+    /// elements survive a machine crash, not only a process crash. Disabled by default.
+    ///
+    pub fn set_sync_writes(&mut self, sync_writes: bool) {
+        self.sync_writes = sync_writes;
+    }
 
-        self.empty = false;
-        self.write_index = write_index;
-        self.space_stat = space_stat;
+    pub fn sync_writes(&self) -> bool {
+        self.sync_writes
+    }
 
+    /// Limits the rate of the pushes, see [`RateLimit`]. The elements pushed in front of the
+    /// queue are not limited, they have been pushed already. `None` removes the limit.
+    ///
+    pub fn set_push_rate_limit(&mut self, limit: Option<RateLimit>) -> Result<()> {
+        *self.push_limiter.lock() = limit.map(RateLimiter::new).transpose()?;
         Ok(())
     }
 
-    pub fn pop(&mut self, mut max_elts: usize) -> Result<Vec<Vec<u8>>> {
-        let mut res = Vec::with_capacity(max_elts);
-        let mut batch = rocksdb::WriteBatch::default();
-        let mut read_index = self.read_index;
-        loop {
-            let key = index_to_key(read_index);
-            let value = self.db.get(key)?;
-            if let Some(v) = value {
-                batch.delete(key);
-                res.push(v);
-                read_index = next_index(read_index);
-                max_elts -= 1;
-            } else {
-                break;
-            }
+    pub fn push_rate_limit(&self) -> Option<RateLimit> {
+        self.push_limiter.lock().as_ref().map(|l| l.limit())
+    }
 
-            if read_index != self.write_index && max_elts > 0 {
-                continue;
-            } else {
-                break;
-            }
-        }
-        if !res.is_empty() {
-            let empty = read_index == self.write_index;
-            let space_stat = self.space_stat - res.iter().map(|v| v.len() as u64).sum::<u64>();
-            batch.put(index_to_key(SPACE_STAT_CELL), space_stat.to_le_bytes());
-            batch.put(index_to_key(READ_INDEX_CELL), read_index.to_le_bytes());
-            self.db.write(batch)?;
+    /// Limits the rate of the pops, e.g. so draining a backlog doesn't saturate the network
+    /// downstream, see [`RateLimit`]. The size of a pop is known only after it, so a pop may
+    /// exceed the rate and the next ones wait until it is compensated. `None` removes the limit.
+    ///
+    pub fn set_pop_rate_limit(&mut self, limit: Option<RateLimit>) -> Result<()> {
+        *self.pop_limiter.lock() = limit.map(RateLimiter::new).transpose()?;
+        Ok(())
+    }
 
-            self.read_index = read_index;
-            self.space_stat = space_stat;
-            self.empty = empty;
-        }
+    pub fn pop_rate_limit(&self) -> Option<RateLimit> {
+        self.pop_limiter.lock().as_ref().map(|l| l.limit())
+    }
 
-        Ok(res)
+    /// Sets the disk usage alerts, see [`DiskAlerts`]. The listeners are notified with
+    /// [`QueueEvent::DiskAlert`] when a threshold is reached and left, the pushes check the
+    /// usage at most once a second. `None`, the default, removes the alerts.
+    ///
+    pub fn set_disk_alerts(&mut self, alerts: Option<DiskAlerts>) -> Result<()> {
+        self.disk_monitor = DiskMonitor::new(alerts)?;
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    #[test]
-    fn test_normal_ops() {
-        let path = "/tmp/test1".to_string();
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-        {
-            let mut db = PersistentQueueWithCapacity::new(
-                &path,
-                MAX_ALLOWED_INDEX as usize,
-                Options::default(),
-            )
-            .unwrap();
-            db.push(&[&[1, 2, 3]]).unwrap();
-            db.push(&[&[4, 5, 6]]).unwrap();
-            db.push(&[&[7, 8, 9]]).unwrap();
-            db.push(&[&[10, 11, 12]]).unwrap();
-            assert!(!db.is_empty());
-            assert_eq!(db.len(), 4);
-            assert_eq!(db.payload_size(), 12);
-            assert!(matches!(db.pop(1), Ok(v ) if v == vec![vec![1, 2, 3]]));
-            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![4, 5, 6]]));
-            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![7, 8, 9]]));
-            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![10, 11, 12]]));
-            assert!(db.is_empty());
-            assert_eq!(db.len(), 0);
-            db.push(&[&[1, 2, 3]]).unwrap();
-            db.push(&[&[4, 5, 6]]).unwrap();
-            db.push(&[&[7, 8, 9]]).unwrap();
-            assert_eq!(db.len(), 3);
-            assert_eq!(db.read_index, 4);
-            assert_eq!(db.write_index, 1);
-            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![1, 2, 3]]));
-            assert_eq!(db.len(), 2);
-            assert_eq!(db.read_index, 5);
-            assert_eq!(db.write_index, 1);
-            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![4, 5, 6]]));
-            assert_eq!(db.len(), 1);
-            assert_eq!(db.read_index, 0);
-            assert_eq!(db.write_index, 1);
-            let data = db.pop(1).unwrap();
-            assert!(db.is_empty());
-            assert_eq!(db.len(), 0);
-            assert_eq!(db.read_index, 1);
-            assert_eq!(db.write_index, 1);
-            assert_eq!(data, vec![vec![7, 8, 9]]);
-        }
-        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    pub fn disk_alerts(&self) -> Option<&DiskAlerts> {
+        self.disk_monitor.alerts()
     }
 
-    #[test]
-    fn test_limit_len_ops() {
-        let path = "/tmp/test2".to_string();
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-        {
-            let mut db = PersistentQueueWithCapacity::new(&path, 2, Options::default()).unwrap();
-            db.push(&[&[1, 2, 3]]).unwrap();
-            db.push(&[&[4, 5, 6]]).unwrap();
-            assert!(matches!(db.push(&[&[1, 2, 3]]), Err(_)));
-            assert_eq!(db.payload_size(), 6);
-        }
-        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    /// Notifies the listeners of the pushed elements and checks the disk usage.
+    fn notify_push(&self, count: usize) {
+        self.listeners.emit(|| QueueEvent::Push(count));
+        self.disk_monitor.check(&self.path, &self.listeners);
     }
 
-    #[test]
-    fn test_read_with_close() {
-        let path = "/tmp/test3".to_string();
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-        let size = MAX_ALLOWED_INDEX as usize;
-        {
-            let mut db = PersistentQueueWithCapacity::new(&path, size, Options::default()).unwrap();
-            db.push(&[&[1, 2, 3]]).unwrap();
-            db.push(&[&[4, 5, 6]]).unwrap();
-            db.push(&[&[7, 8, 9]]).unwrap();
-        }
+    /// Waits for the rate limit of the pushes, it is not held by the writer lock.
+    fn limit_push(&self, values: &[&[u8]]) -> Result<()> {
+        let bytes = values.iter().map(|v| v.len() as u64).sum();
+        limiter::acquire(&self.push_limiter, values.len(), bytes)
+    }
 
-        {
-            let mut db = PersistentQueueWithCapacity::new(&path, size, Options::default()).unwrap();
-            assert_eq!(db.payload_size(), 9);
-            let res = db.pop(1).unwrap();
-            assert_eq!(res, vec![vec![1, 2, 3]]);
-            assert_eq!(db.payload_size(), 6);
-        }
+    fn write_options(&self) -> WriteOptions {
+        let mut write_opts = WriteOptions::default();
+        write_opts.set_sync(self.sync_writes);
+        write_opts
+    }
 
-        {
-            let mut db = PersistentQueueWithCapacity::new(&path, size, Options::default()).unwrap();
-            let res = db.pop(1).unwrap();
-            assert_eq!(res, vec![vec![4, 5, 6]]);
-            let res = db.pop(1).unwrap();
-            assert_eq!(res, vec![vec![7, 8, 9]]);
-        }
+    /// Registers the listener of the queue events, see [`QueueEvent`]. The listeners are invoked
+    /// by the thread changing the queue after the change is written, so they must not block and
+    /// must not use the queue.
+    ///
+    pub fn add_listener<F>(&self, f: F)
+    where
+        F: Fn(&QueueEvent) + Send + Sync + 'static,
+    {
+        self.listeners.add(f);
+    }
 
-        {
-            let mut db = PersistentQueueWithCapacity::new(&path, size, Options::default()).unwrap();
-            let res = db.pop(1).unwrap();
-            assert!(res.is_empty());
-        }
-        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    /// Registers the listener invoked with the number of pushed elements, see
+    /// [`add_listener`](Self::add_listener).
+    ///
+    pub fn on_push<F>(&self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.listeners.on_push(f);
+    }
+    /// Registers the listener invoked when elements are rejected because the queue is full, see
+    /// [`add_listener`](Self::add_listener).
+    ///
+    pub fn on_full<F>(&self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.listeners.on_full(f);
     }
 
-    #[test]
-    fn push_pop_many() {
-        let path = "/tmp/test_push_pop_many".to_string();
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-        let mut queue = PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
-        queue
-            .push(&[&[1u8, 2u8, 3u8], &[4u8, 5u8, 6u8], &[7u8, 8u8, 9u8]])
-            .unwrap();
+    /// Returns the indices of the corrupted elements skipped since the previous call.
+    pub fn take_corrupted(&mut self) -> Vec<u64> {
+        std::mem::take(self.reader.get_mut())
+    }
 
-        let res = queue.pop(2).unwrap();
+    /// Sets the size in bytes above which pushed elements are split into chunks stored under
+    /// separate keys. `0` disables chunking (the default). Elements already stored are read
+    /// regardless of the setting.
+    ///
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
 
-        assert_eq!(res, vec![vec![1u8, 2u8, 3u8], vec![4u8, 5u8, 6u8]]);
-        let res = queue.pop(1).unwrap();
-        assert_eq!(res, vec![vec![7u8, 8u8, 9u8]]);
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
 
-        let res = queue.pop(1).unwrap();
-        assert!(res.is_empty());
+    /// Removes the queue at `path`, fails with [`QueueError::InUse`] if the queue is open, e.g.
+    /// by another process.
+    ///
+    pub fn remove_db<P: AsRef<Path>>(path: P) -> Result<()> {
+        fs::destroy_db(path.as_ref(), &RemoveOptions::default())
+    }
 
-        _ = PersistentQueueWithCapacity::remove_db(&path);
+    /// Removes the queue at `path` even if it is open, the queue holding it loses its data.
+    pub fn remove_db_forced<P: AsRef<Path>>(path: P) -> Result<()> {
+        fs::destroy_db(path.as_ref(), &RemoveOptions::default().force(true))
     }
 
-    #[test]
-    fn push_pop_max_elements() {
-        let path = "/tmp/test_push_pop_max_elements".to_string();
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-        let size = MAX_ALLOWED_INDEX as usize;
-        let mut queue = PersistentQueueWithCapacity::new(&path, size, Options::default()).unwrap();
+    /// Removes the queue at `path` with the options, e.g. the RocksDB options it was created
+    /// with or the secure wipe of its data files, see [`RemoveOptions`].
+    ///
+    pub fn remove_db_with<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
+        fs::destroy_db(path.as_ref(), options)
+    }
 
-        let values = vec!["a".as_bytes(); size];
-        queue.push(&values).unwrap();
+    /// Flushes the written data and closes the database, so the queue can be reopened at the
+    /// same path right away. Dropping the queue closes the database as well, but the errors
+    /// can't be reported then.
+    ///
+    pub fn close(self) -> Result<()> {
+        let db = self.db()?;
+        db.flush()?;
+        db.flush_cf(db.cf_handle(SYSTEM_CF).unwrap())?;
+        db.flush_cf(db.cf_handle(SESSION_CF).unwrap())?;
+        db.flush_cf(db.cf_handle(TIME_CF).unwrap())?;
+        db.flush_cf(db.cf_handle(PAYLOAD_CF).unwrap())?;
+        db.flush_cf(db.cf_handle(KEY_CF).unwrap())?;
+        Ok(())
+    }
 
-        assert_eq!(queue.read_index, 0);
-        assert_eq!(queue.write_index, 0);
-        assert_eq!(queue.empty, false);
-        assert_eq!(queue.len(), size);
+    /// Rebuilds the read and write indices and the payload size from the stored elements, e.g.
+    /// after a crash left them pointing at removed data. If the database can't be opened, the
+    /// RocksDB repair is run first. The payload size of elements that can't be decoded without
+    /// the queue settings (e.g. encrypted ones) is estimated by their stored size.
+    ///
+    pub fn repair<P: AsRef<Path>>(path: P, db_opts: Options) -> Result<()> {
+        let db = Self::open_db(path.as_ref(), db_opts, true)?;
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
 
-        let res = queue.pop(size).unwrap();
+        let index_space = repair::read_cell(&db, Some(system_cf), INDEX_SPACE_CELL)?
+            .filter(|index_space| check_index_space(*index_space).is_ok())
+            .unwrap_or(DEFAULT_INDEX_SPACE);
+        let read_index = repair::read_cell(&db, Some(system_cf), READ_INDEX_CELL)?.unwrap_or(0);
+        let write_index = repair::read_cell(&db, Some(system_cf), WRITE_INDEX_CELL)?
+            .filter(|index| *index < index_space)
+            .unwrap_or(0);
+        let format_version = repair::read_cell(&db, Some(system_cf), FORMAT_VERSION_CELL)?
+            .unwrap_or(LEGACY_FORMAT_VERSION);
+        let checksums = db
+            .get_cf(system_cf, index_to_key(CHECKSUMS_CELL))?
+            .is_some();
 
-        assert_eq!(res, values);
-        assert_eq!(queue.read_index, 0);
-        assert_eq!(queue.write_index, 0);
-        assert_eq!(queue.empty, true);
-        assert_eq!(queue.len(), 0);
+        let elements = repair::scan(&db, None, index_space)?;
+        let (read_index, write_index) =
+            repair::index_range(&elements.indices, read_index, index_space)
+                .unwrap_or((write_index, write_index));
 
-        _ = PersistentQueueWithCapacity::remove_db(&path);
-    }
+        let codec = Codec::default();
+        let mut space_stat = 0;
+        let payload_cf = db.cf_handle(PAYLOAD_CF).unwrap();
+        for index in elements.indices {
+            if let Some(e) = chunking::get_element(&db, None, index, elements.chunked)? {
+                let stored_size = e.value.len() as u64;
+                space_stat += codec
+                    .decode_versioned(e.value, index, format_version, checksums)
+                    .map_or(stored_size, |v| v.len() as u64);
+            }
+            if let Some(payload) = db.get_cf(payload_cf, index_to_key(index))? {
+                let stored_size = payload.len() as u64;
+                space_stat += codec
+                    .decode_versioned(payload, index, format_version, checksums)
+                    .map_or(stored_size, |v| v.len() as u64);
+            }
+        }
 
-    #[test]
-    fn new_invalid_max_elements() {
-        let result = PersistentQueueWithCapacity::new(
-            "/tmp/test_invalid_max_elements",
-            (MAX_ALLOWED_INDEX + 1) as usize,
-            Options::default(),
+        let mut batch = WriteBatch::default();
+        batch.put_cf(
+            system_cf,
+            index_to_key(READ_INDEX_CELL),
+            read_index.to_le_bytes(),
         );
-
-        assert_eq!(
-            result.is_err_and(|e| e.to_string()
-                == format!("max_elements can't be greater than {}", MAX_ALLOWED_INDEX)),
-            true
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_INDEX_CELL),
+            write_index.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(SPACE_STAT_CELL),
+            space_stat.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(INDEX_SPACE_CELL),
+            index_space.to_le_bytes(),
         );
+        if elements.chunked {
+            batch.put_cf(system_cf, index_to_key(CHUNKED_CELL), [1u8]);
+        }
+        db.write(batch)?;
+
+        Ok(())
+    }
+
+    pub fn disk_size(&self) -> Result<usize> {
+        Ok(fs::dir_size(&self.path)?)
+    }
+
+    /// Returns the RocksDB internals of the queue, see [`DbStats`].
+    pub fn db_stats(&self) -> Result<DbStats> {
+        DbStats::read(self.db()?, None)
+    }
+
+    /// Estimates the disk bytes the elements of the average size take in the queue from the
+    /// compression ratio and the space amplification observed by RocksDB.
+    ///
+    pub fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> Result<u64> {
+        Ok(StorageModel::read(self.db()?, None)?.disk_for(elements, avg_size))
+    }
+
+    /// Estimates the number of the elements which can be pushed yet, limited by the capacity
+    /// and by the space available on the disk. The elements are expected to be of the average
+    /// size of the pushed ones, `None` if none were pushed.
+    ///
+    pub fn estimate_remaining_capacity(&self) -> Result<Option<u64>> {
+        let (len, avg_size) = {
+            let state = self.state.lock();
+            (
+                counters::length(
+                    state.read_index,
+                    state.write_index,
+                    state.empty,
+                    self.index_space,
+                ),
+                state.sizes.avg(),
+            )
+        };
+        let Some(avg_size) = avg_size else {
+            return Ok(None);
+        };
+        let available = fs::available_space(&self.path)?;
+        let fitting =
+            StorageModel::read(self.db()?, None)?.elements_for(available, avg_size.round() as u64);
+        // the queue reopened with a lower limit may hold more elements
+        Ok(Some(
+            fitting.min(self.max_elements.saturating_sub(len as u64)),
+        ))
+    }
+
+    /// Returns the length, the payload size and the numbers of the pushed and popped elements
+    /// read at once, and the disk size.
+    ///
+    pub fn stats(&self) -> Result<QueueStats> {
+        let mut stats = {
+            let state = self.state.lock();
+            QueueStats {
+                len: counters::length(
+                    state.read_index,
+                    state.write_index,
+                    state.empty,
+                    self.index_space,
+                ),
+                payload_size: state.space_stat,
+                disk_size: 0,
+                pushed: state.pushed,
+                popped: state.popped,
+                sizes: state.sizes.clone(),
+                disk_alerts: Vec::new(),
+            }
+        };
+        stats.disk_size = self.disk_size()?;
+        stats.disk_alerts =
+            self.disk_monitor
+                .update(&self.path, stats.disk_size as u64, &self.listeners)?;
+        Ok(stats)
+    }
+
+    /// Returns the length, the payload size and the cursors read against a single RocksDB
+    /// snapshot, see [`StatsSnapshot`].
+    ///
+    pub fn stats_snapshot(&self) -> Result<StatsSnapshot> {
+        Self::read_stats_snapshot(self.db()?, self.index_space)
+    }
+
+    fn read_stats_snapshot(db: &DB, index_space: u64) -> Result<StatsSnapshot> {
+        let snapshot = db.snapshot();
+        // the unmigrated legacy queues keep the cells in the default column family
+        let system_cf = db.cf_handle(SYSTEM_CF);
+        let cell = |cell: u64| -> Result<u64> {
+            let value = match system_cf {
+                Some(cf) => snapshot.get_cf(cf, index_to_key(cell))?,
+                None => snapshot.get(index_to_key(cell))?,
+            };
+            Ok(value.map_or(0, |v| u64_from_byte_vec(&v)))
+        };
+        let write_index = cell(WRITE_INDEX_CELL)?;
+        let read_index = cell(READ_INDEX_CELL)?;
+        let payload_size = cell(SPACE_STAT_CELL)?;
+        // the equal cursors are of the empty or the full queue
+        let empty = read_index == write_index && snapshot.get(index_to_key(read_index))?.is_none();
+        Ok(StatsSnapshot {
+            len: counters::length(read_index, write_index, empty, index_space),
+            payload_size: Some(payload_size),
+            start_index: read_index,
+            write_index,
+            labels: Default::default(),
+        })
+    }
+
+    /// Reads the state of the queue from the database opened read-only, see
+    /// [`discovery::discover`]. Returns `None` if the database has neither the format version
+    /// nor the write index, i.e. it is not a queue. The legacy queues not opened since the system
+    /// column family was introduced are read from the default column family.
+    ///
+    pub(crate) fn inspect_db(db: &DB) -> Result<Option<Inspection>> {
+        let system_cf = db.cf_handle(SYSTEM_CF);
+        let format_version = match repair::read_cell(db, system_cf, FORMAT_VERSION_CELL)? {
+            Some(format_version) => format_version,
+            None if repair::read_cell(db, system_cf, WRITE_INDEX_CELL)?.is_some() => {
+                LEGACY_FORMAT_VERSION
+            }
+            None => return Ok(None),
+        };
+        let index_space =
+            repair::read_cell(db, system_cf, INDEX_SPACE_CELL)?.unwrap_or(DEFAULT_INDEX_SPACE);
+        Ok(Some(Inspection {
+            kind: QueueKind::Persistent,
+            format_version,
+            index_space,
+            stats: Self::read_stats_snapshot(db, index_space)?,
+        }))
+    }
+
+    /// Returns the time since the oldest element was pushed, `None` if the queue is empty or
+    /// the element was pushed by a version not storing the push times.
+    ///
+    pub fn oldest_age(&self) -> Result<Option<Duration>> {
+        // the read index moves only with the reader lock held
+        let _reader = self.reader.lock();
+        let (read_index, empty) = {
+            let state = self.state.lock();
+            (state.read_index, state.empty)
+        };
+        if empty {
+            return Ok(None);
+        }
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let pushed_at = self.db()?.get_cf(time_cf, index_to_key(read_index))?;
+        Ok(pushed_at.map(|t| {
+            Duration::from_nanos(current_timestamp().saturating_sub(u64_from_byte_vec(&t)))
+        }))
+    }
+
+    pub fn len(&self) -> usize {
+        let state = self.state.lock();
+        counters::length(
+            state.read_index,
+            state.write_index,
+            state.empty,
+            self.index_space,
+        )
+    }
+
+    pub fn payload_size(&self) -> u64 {
+        self.state.lock().space_stat
+    }
+
+    pub fn max_elements(&self) -> usize {
+        self.max_elements as usize
+    }
+
+    /// Returns the number of the indices the elements are stored under, see
+    /// [`Self::with_index_space`].
+    ///
+    pub fn index_space(&self) -> u64 {
+        self.index_space
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().empty
+    }
+
+    /// Returns the lock-free mirrors of the length and the payload size, which stay valid
+    /// while the queue is moved to a wrapper or another thread.
+    ///
+    pub fn counters(&self) -> Arc<Counters> {
+        self.counters.clone()
+    }
+
+    /// Checks the queue invariants: the cursors are within the index space, all elements
+    /// between the read and the write index are present and readable, there are no elements
+    /// outside of this range and the stored payload size matches the elements.
+    ///
+    pub fn verify(&self) -> Result<VerificationReport> {
+        let _writer = self.writer.lock();
+        let _reader = self.reader.lock();
+        let state = self.state.lock();
+
+        let mut report = VerificationReport::default();
+        report.check_index("read_index", state.read_index, self.index_space);
+        report.check_index("write_index", state.write_index, self.index_space);
+        if !report.is_ok() {
+            return Ok(report);
+        }
+
+        let scan = ElementsScan {
+            db: self.db()?,
+            cf: None,
+            codec: &self.codec,
+            format_version: state.format_version,
+            checksums: state.checksums,
+            chunked: state.chunked,
+            index_space: self.index_space,
+        };
+        let (mut payload_size, mut complete) = scan.run(
+            state.read_index,
+            state.write_index,
+            state.empty,
+            &mut report,
+        )?;
+        if state.paired {
+            let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+            for item in self.db()?.iterator_cf(payload_cf, IteratorMode::Start) {
+                let (key, payload) = item?;
+                let index = key_to_index(&key);
+                if !in_range(index, state.read_index, state.write_index, state.empty) {
+                    report.problems.push(Problem::UnexpectedElement { index });
+                    continue;
+                }
+                match self.codec.decode_versioned(
+                    payload.into_vec(),
+                    index,
+                    state.format_version,
+                    state.checksums,
+                ) {
+                    Ok(payload) => payload_size += payload.len() as u64,
+                    Err(_) => {
+                        report.problems.push(Problem::CorruptedElement { index });
+                        complete = false;
+                    }
+                }
+            }
+        }
+        if self.evicting {
+            // the evicted elements are missing until they are popped
+            report
+                .problems
+                .retain(|p| !matches!(p, Problem::MissingElement { .. }));
+        }
+        if complete && payload_size != state.space_stat {
+            report.problems.push(Problem::PayloadSizeMismatch {
+                stored: state.space_stat,
+                actual: payload_size,
+            });
+        }
+
+        Ok(report)
+    }
+
+    pub fn push(&self, values: &[&[u8]]) -> Result<()> {
+        self.push_indexed(values).map(|_| ())
+    }
+
+    /// Pushes the elements and returns the index of the first one and their number, e.g. to
+    /// record where the batch landed. The elements take the next indices, wrapping around the
+    /// index space, see [`Self::index_space`].
+    ///
+    pub fn push_indexed(&self, values: &[&[u8]]) -> Result<(u64, usize)> {
+        if values.is_empty() {
+            return Ok((self.state.lock().write_index, 0));
+        }
+        self.limit_push(values)?;
+        let writer = self.writer.lock();
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        let first = self.write_values(values, None, None, &[])?;
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok((first, values.len()))
+    }
+
+    /// Pushes the elements of two parts: the small metadata stored as the element and the large
+    /// payload stored separately, in the blob files, so the metadata is read without the
+    /// payload. [`Self::pop_pairs`] returns both parts, [`Self::pop`] returns the metadata and
+    /// drops the payloads.
+    ///
+    pub fn push_pairs(&self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let bytes = pairs.iter().map(|(m, p)| (m.len() + p.len()) as u64).sum();
+        limiter::acquire(&self.push_limiter, pairs.len(), bytes)?;
+        let (metas, payloads): (Vec<&[u8]>, Vec<&[u8]>) = pairs.iter().copied().unzip();
+        let writer = self.writer.lock();
+        if self.len() + pairs.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        self.write_values(&metas, Some(&payloads), None, &[])?;
+        drop(writer);
+
+        self.notify_push(pairs.len());
+        Ok(())
+    }
+
+    pub fn push_pair(&self, meta: &[u8], payload: &[u8]) -> Result<()> {
+        self.push_pairs(&[(meta, payload)])
+    }
+
+    /// Pushes the elements with their keys, so they can be found with [`Self::find_by_key`] and
+    /// deleted with [`Self::delete_by_key`] before they are popped, e.g. the elements of a
+    /// camera gone offline. The keys don't have to be unique.
+    ///
+    pub fn push_with_keys(&self, elements: &[(&[u8], &[u8])]) -> Result<()> {
+        if elements.is_empty() {
+            return Ok(());
+        }
+        let (keys, values): (Vec<&[u8]>, Vec<&[u8]>) = elements.iter().copied().unzip();
+        self.limit_push(&values)?;
+        let writer = self.writer.lock();
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        self.write_values(&values, None, Some(&keys), &[])?;
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok(())
+    }
+
+    pub fn push_with_key(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.push_with_keys(&[(key, value)])
+    }
+
+    /// Returns the elements pushed with the key which are not popped or deleted yet, oldest
+    /// first, without popping them.
+    ///
+    pub fn find_by_key(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let (read_index, chunked, format_version, checksums) = {
+            let state = self.state.lock();
+            (
+                state.read_index,
+                state.chunked,
+                state.format_version,
+                state.checksums,
+            )
+        };
+        let mut res = Vec::new();
+        for index in self.key_indices(key, read_index)? {
+            // the element may have been popped meanwhile
+            if let Some(e) = chunking::get_element(self.db()?, None, index, chunked)? {
+                res.push(
+                    self.codec
+                        .decode_versioned(e.value, index, format_version, checksums)?,
+                );
+            }
+        }
+        Ok(res)
+    }
+
+    /// Deletes the elements pushed with the key which are not popped yet and returns their
+    /// number. The deleted elements are emptied in place and skipped by the pops, so they count
+    /// towards the length of the queue until then.
+    ///
+    pub fn delete_by_key(&self, key: &[u8]) -> Result<usize> {
+        // the pops are serialized with the deletion, the pushes only add elements
+        let _reader = self.reader.lock();
+        let (read_index, chunked, paired, format_version, checksums) = {
+            let state = self.state.lock();
+            (
+                state.read_index,
+                state.chunked,
+                state.paired,
+                state.format_version,
+                state.checksums,
+            )
+        };
+
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let empty_value = self
+            .codec
+            .encode_versioned(&[], format_version, checksums)?;
+        let mut batch = WriteBatch::default();
+        let mut deleted = 0;
+        let mut removed = 0;
+        for index in self.key_indices(key, read_index)? {
+            let Some(e) = chunking::get_element(self.db()?, None, index, chunked)? else {
+                continue;
+            };
+            chunking::delete_element(&mut batch, None, &e);
+            let value = self
+                .codec
+                .decode_versioned(e.value, index, format_version, checksums)?;
+            let payload =
+                self.take_payload(&mut batch, index, paired, format_version, checksums)?;
+            removed += (value.len() + payload.map_or(0, |p| p.len())) as u64;
+            chunking::put_element(&mut batch, None, index, &empty_value, 0);
+            batch.delete_cf(key_cf, key_entry(key, Some(index))?);
+            batch.put_cf(key_cf, index_to_key(index), [KEY_DELETED]);
+            deleted += 1;
+        }
+        if deleted > 0 {
+            let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+            batch.merge_cf(
+                system_cf,
+                index_to_key(SPACE_STAT_CELL),
+                counter_delta(-(removed as i64)),
+            );
+            self.db()?.write_opt(batch, &self.write_options())?;
+
+            let mut state = self.state.lock();
+            state.space_stat -= removed;
+            self.counters.store(
+                state.read_index,
+                state.write_index,
+                state.space_stat,
+                state.empty,
+            );
+        }
+        Ok(deleted)
+    }
+
+    /// Returns the indices of the elements pushed with the key in the order of the queue.
+    fn key_indices(&self, key: &[u8], read_index: u64) -> Result<Vec<u64>> {
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let prefix = key_entry(key, None)?;
+        let mut indices = Vec::new();
+        for item in self.db()?.iterator_cf(
+            key_cf,
+            IteratorMode::From(&prefix, rocksdb::Direction::Forward),
+        ) {
+            let (entry, _) = item?;
+            if !entry.starts_with(&prefix) {
+                break;
+            }
+            // the index entries of the short keys share the prefix
+            if entry.len() == prefix.len() + U64_BYTE_LEN {
+                indices.push(key_to_index(&entry[prefix.len()..]));
+            }
+        }
+        // the distance from the read index around the index space
+        indices.sort_by_key(|index| {
+            if *index >= read_index {
+                index - read_index
+            } else {
+                index + (self.index_space - read_index)
+            }
+        });
+        Ok(indices)
+    }
+
+    /// Pushes the elements of a producer session unless its sequence number has already been
+    /// pushed, so the retries after a crash or a reconnect are deduplicated. The sequence numbers
+    /// of a producer must grow; the last one is written together with the elements, so the
+    /// deduplication survives restarts. Returns `false` for a duplicate.
+    ///
+    pub fn push_with_sequence(
+        &self,
+        producer_id: &str,
+        seq: u64,
+        values: &[&[u8]],
+    ) -> Result<bool> {
+        self.limit_push(values)?;
+        let writer = self.writer.lock();
+        if self
+            .producer_sequence(producer_id)?
+            .is_some_and(|last| seq <= last)
+        {
+            return Ok(false);
+        }
+        if values.is_empty() {
+            let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+            self.db()?.put_cf_opt(
+                session_cf,
+                producer_id,
+                seq.to_le_bytes(),
+                &self.write_options(),
+            )?;
+            return Ok(true);
+        }
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        self.write_values(
+            values,
+            None,
+            None,
+            &[(producer_id.as_bytes(), seq.to_le_bytes().as_slice())],
+        )?;
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok(true)
+    }
+
+    /// Returns the last pushed sequence number of the producer session, so a reconnected
+    /// producer can resume after it.
+    ///
+    pub fn producer_sequence(&self, producer_id: &str) -> Result<Option<u64>> {
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        Ok(self
+            .db()?
+            .get_cf(session_cf, producer_id)?
+            .map(|v| u64_from_byte_vec(&v)))
+    }
+
+    /// Registers the epoch of the producer, e.g. a restarted producer registers an incremented
+    /// one. The pushes with older epochs are rejected afterwards, so a stale instance of the
+    /// producer can't push anymore. Fails with [`QueueError::Fenced`] if a newer epoch is
+    /// registered.
+    ///
+    pub fn register_epoch(&self, producer_id: &str, epoch: u64) -> Result<()> {
+        let _writer = self.writer.lock();
+        self.check_epoch(producer_id, epoch)?;
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        self.db()?.put_cf_opt(
+            session_cf,
+            epoch_key(producer_id),
+            epoch.to_le_bytes(),
+            &self.write_options(),
+        )?;
+        Ok(())
+    }
+
+    /// Returns the registered epoch of the producer.
+    pub fn producer_epoch(&self, producer_id: &str) -> Result<Option<u64>> {
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        Ok(self
+            .db()?
+            .get_cf(session_cf, epoch_key(producer_id))?
+            .map(|v| u64_from_byte_vec(&v)))
+    }
+
+    /// Pushes the elements unless a newer epoch of the producer is registered, see
+    /// [`Self::register_epoch`]. The epoch is checked under the same lock as the push, so no
+    /// element of a fenced producer is pushed after the newer epoch is registered.
+    ///
+    pub fn push_with_epoch(&self, producer_id: &str, epoch: u64, values: &[&[u8]]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        self.limit_push(values)?;
+        let writer = self.writer.lock();
+        self.check_epoch(producer_id, epoch)?;
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        self.write_values(values, None, None, &[])?;
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok(())
+    }
+
+    fn check_epoch(&self, producer_id: &str, epoch: u64) -> Result<()> {
+        match self.producer_epoch(producer_id)? {
+            Some(current) if epoch < current => Err(QueueError::Fenced {
+                producer_id: producer_id.to_string(),
+                epoch,
+                current,
+            }),
+            _ => Ok(()),
+        }
+    }
+
+    /// Pushes the elements together with the positions of the readers of another queue in one
+    /// write, see [`mpmc::QueueOffsetStore`]. The positions are written even if there are no
+    /// elements.
+    ///
+    pub(crate) fn push_with_offsets(
+        &self,
+        values: &[&[u8]],
+        offsets: &[(String, Vec<u8>)],
+    ) -> Result<()> {
+        let keys = offsets
+            .iter()
+            .map(|(label, _)| offset_key(label))
+            .collect::<Vec<_>>();
+        let session = keys
+            .iter()
+            .zip(offsets)
+            .map(|(key, (_, position))| (key.as_slice(), position.as_slice()))
+            .collect::<Vec<_>>();
+        if values.is_empty() {
+            let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+            let mut batch = WriteBatch::default();
+            for (key, value) in session {
+                batch.put_cf(session_cf, key, value);
+            }
+            self.db()?.write_opt(batch, &self.write_options())?;
+            return Ok(());
+        }
+        self.limit_push(values)?;
+        let writer = self.writer.lock();
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+        self.write_values(values, None, None, &session)?;
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok(())
+    }
+
+    /// Returns the positions of the readers of another queue written with the elements, see
+    /// [`Self::push_with_offsets`].
+    ///
+    pub(crate) fn offsets(&self) -> Result<Vec<(String, Vec<u8>)>> {
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        let mut offsets = Vec::new();
+        for item in self
+            .db()?
+            .prefix_iterator_cf(session_cf, [OFFSET_KEY_PREFIX])
+        {
+            let (key, value) = item?;
+            if key.first() != Some(&OFFSET_KEY_PREFIX) {
+                break;
+            }
+            let label = String::from_utf8(key[1..].to_vec())
+                .map_err(|e| QueueError::Serialization(e.to_string()))?;
+            offsets.push((label, value.to_vec()));
+        }
+        Ok(offsets)
+    }
+
+    pub(crate) fn remove_offset(&self, label: &str) -> Result<()> {
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        self.db()?
+            .delete_cf_opt(session_cf, offset_key(label), &self.write_options())?;
+        Ok(())
+    }
+
+    /// Removes the producer session, the next sequence number of the producer is not checked.
+    pub fn remove_producer_session(&self, producer_id: &str) -> Result<()> {
+        let _writer = self.writer.lock();
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        self.db()?
+            .delete_cf_opt(session_cf, producer_id, &self.write_options())?;
+        Ok(())
+    }
+
+    /// Pushes as many of the elements, in order, as the capacity allows instead of rejecting
+    /// all of them. Returns the number of the pushed elements, so the rest of them starts at it.
+    ///
+    pub fn push_partial(&self, values: &[&[u8]]) -> Result<usize> {
+        if values.is_empty() {
+            return Ok(0);
+        }
+        self.limit_push(values)?;
+        let writer = self.writer.lock();
+        let free = (self.max_elements as usize).saturating_sub(self.len());
+        let pushed = free.min(values.len());
+        if pushed > 0 {
+            self.write_values(&values[..pushed], None, None, &[])?;
+        }
+        drop(writer);
+
+        if pushed > 0 {
+            self.notify_push(pushed);
+        }
+        if pushed < values.len() {
+            self.listeners.emit(|| QueueEvent::Full);
+        }
+        Ok(pushed)
+    }
+
+    /// Writes the elements after the write index together with their payloads, their keys and
+    /// the entries of the session column family, e.g. the sequence number of the producer
+    /// session, the caller holds the writer lock. Returns the index of the first element.
+    ///
+    fn write_values(
+        &self,
+        values: &[&[u8]],
+        payloads: Option<&[&[u8]]>,
+        keys: Option<&[&[u8]]>,
+        session: &[(&[u8], &[u8])],
+    ) -> Result<u64> {
+        let span = OpSpan::enter("push");
+        // only pushes move the write index, pops can only make the queue empty meanwhile
+        let (
+            mut write_index,
+            empty,
+            format_version,
+            checksums,
+            mut sizes,
+            chunked_before,
+            paired_before,
+            keyed_before,
+        ) = {
+            let state = self.state.lock();
+            (
+                state.write_index,
+                state.empty,
+                state.format_version,
+                state.checksums,
+                state.sizes.clone(),
+                state.chunked,
+                state.paired,
+                state.keyed,
+            )
+        };
+        let first_index = write_index;
+
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        let mut chunked = false;
+        let (format_version, checksums) =
+            self.write_format(&mut batch, empty, format_version, checksums)?;
+
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let now = current_timestamp().to_le_bytes();
+        let mut added = 0;
+        for (i, value) in values.iter().enumerate() {
+            let mut size = value.len() as u64;
+            if let Some(payload) = payloads.map(|p| p[i]) {
+                size += payload.len() as u64;
+                let payload = self
+                    .codec
+                    .encode_versioned(payload, format_version, checksums)?;
+                batch.put_cf(payload_cf, index_to_key(write_index), payload);
+            }
+            if let Some(key) = keys.map(|k| k[i]) {
+                batch.put_cf(key_cf, key_entry(key, Some(write_index))?, []);
+                batch.put_cf(
+                    key_cf,
+                    index_to_key(write_index),
+                    [&[KEY_LIVE], key].concat(),
+                );
+            }
+            sizes.add(size);
+            added += size;
+            let value = self
+                .codec
+                .encode_versioned(value, format_version, checksums)?;
+            chunked |=
+                chunking::put_element(&mut batch, None, write_index, &value, self.chunk_size);
+            batch.put_cf(time_cf, index_to_key(write_index), now);
+            write_index = next_index(write_index, self.index_space);
+        }
+        batch.put_cf(system_cf, index_to_key(SIZES_CELL), sizes.to_bytes()?);
+
+        if chunked && !chunked_before {
+            batch.put_cf(system_cf, index_to_key(CHUNKED_CELL), [1u8]);
+        }
+        let paired = payloads.is_some();
+        if paired && !paired_before {
+            batch.put_cf(system_cf, index_to_key(PAIRED_CELL), [1u8]);
+        }
+        let keyed = keys.is_some();
+        if keyed && !keyed_before {
+            batch.put_cf(system_cf, index_to_key(KEYED_CELL), [1u8]);
+        }
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_INDEX_CELL),
+            write_index.to_le_bytes(),
+        );
+
+        batch.merge_cf(
+            system_cf,
+            index_to_key(SPACE_STAT_CELL),
+            counter_delta(added as i64),
+        );
+
+        let session_cf = self.db()?.cf_handle(SESSION_CF).unwrap();
+        for (key, value) in session {
+            batch.put_cf(session_cf, key, value);
+        }
+
+        self.db()?.write_opt(batch, &self.write_options())?;
+
+        let mut state = self.state.lock();
+        let space_stat = state.space_stat + added;
+        state.empty = false;
+        state.write_index = write_index;
+        state.pushed += values.len() as u64;
+        state.sizes = sizes;
+        state.space_stat = space_stat;
+        state.chunked |= chunked;
+        state.paired |= paired;
+        state.keyed |= keyed;
+        state.format_version = format_version;
+        state.checksums = checksums;
+        self.counters
+            .store(state.read_index, write_index, space_stat, false);
+        span.record(values.len(), added);
+        Ok(first_index)
+    }
+
+    /// Pushes the elements in front of the queue, so they are popped first and in order, e.g. to
+    /// return the failed elements for an immediate retry. It moves the read index back, so it is
+    /// serialized with both pushes and pops.
+    ///
+    pub fn push_front(&self, values: &[&[u8]]) -> Result<()> {
+        if values.is_empty() {
+            return Ok(());
+        }
+        let span = OpSpan::enter("push_front");
+        let writer = self.writer.lock();
+        let reader = self.reader.lock();
+        if self.len() + values.len() > self.max_elements as usize {
+            drop(reader);
+            drop(writer);
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+
+        let (read_index, empty, chunked_before, format_version, checksums, mut sizes) = {
+            let state = self.state.lock();
+            (
+                state.read_index,
+                state.empty,
+                state.chunked,
+                state.format_version,
+                state.checksums,
+                state.sizes.clone(),
+            )
+        };
+
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        let mut chunked = false;
+        let (format_version, checksums) =
+            self.write_format(&mut batch, empty, format_version, checksums)?;
+
+        // the elements take the free indices before the read index, the last one is next to it
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let now = current_timestamp().to_le_bytes();
+        let mut index = read_index;
+        for value in values.iter().rev() {
+            index = previous_index(index, self.index_space);
+            sizes.add(value.len() as u64);
+            let value = self
+                .codec
+                .encode_versioned(value, format_version, checksums)?;
+            chunked |= chunking::put_element(&mut batch, None, index, &value, self.chunk_size);
+            batch.put_cf(time_cf, index_to_key(index), now);
+        }
+        batch.put_cf(system_cf, index_to_key(SIZES_CELL), sizes.to_bytes()?);
+        let read_index = index;
+
+        if chunked && !chunked_before {
+            batch.put_cf(system_cf, index_to_key(CHUNKED_CELL), [1u8]);
+        }
+        batch.put_cf(
+            system_cf,
+            index_to_key(READ_INDEX_CELL),
+            read_index.to_le_bytes(),
+        );
+
+        let added = values.iter().map(|v| v.len() as u64).sum::<u64>();
+        batch.merge_cf(
+            system_cf,
+            index_to_key(SPACE_STAT_CELL),
+            counter_delta(added as i64),
+        );
+
+        self.db()?.write_opt(batch, &self.write_options())?;
+
+        let mut state = self.state.lock();
+        let space_stat = state.space_stat + added;
+        state.empty = false;
+        state.read_index = read_index;
+        state.pushed += values.len() as u64;
+        state.sizes = sizes;
+        state.space_stat = space_stat;
+        state.chunked |= chunked;
+        state.format_version = format_version;
+        state.checksums = checksums;
+        self.counters
+            .store(read_index, state.write_index, space_stat, false);
+        span.record(values.len(), added);
+        drop(state);
+        drop(reader);
+        drop(writer);
+
+        self.notify_push(values.len());
+        Ok(())
+    }
+
+    pub fn pop(&self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        self.pop_limited(max_elts, None, false)
+    }
+
+    /// Pops up to `max_elts` elements with their payloads, see [`Self::push_pairs`]. The
+    /// payload of an element pushed without one is empty.
+    ///
+    pub fn pop_pairs(&self, max_elts: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Ok(self
+            .pop_elements(max_elts, None, false, true)?
+            .into_iter()
+            .map(|(value, payload)| (value, payload.unwrap_or_default()))
+            .collect())
+    }
+
+    /// Pops exactly `n` elements or none if there are fewer of them, so the consumers get only
+    /// full batches. The elements deleted by key, the evicted and the skipped corrupted elements
+    /// don't count towards `n`, they are removed with the next full batch.
+    ///
+    pub fn pop_exact(&self, n: usize) -> Result<Vec<Vec<u8>>> {
+        if n > self.max_elements as usize {
+            return Err(QueueError::InvalidArgument(format!(
+                "n can't be greater than max_elements ({})",
+                self.max_elements
+            )));
+        }
+        self.pop_limited(n, None, true)
+    }
+
+    /// Pops the elements in batches of [`POP_ALL_BATCH`] until the queue is empty. If
+    /// `max_bytes` is set, stops once the payload size of the popped elements reaches it, so the
+    /// result can exceed it by the size of the last element.
+    ///
+    pub fn pop_all(&self, max_bytes: Option<u64>) -> Result<Vec<Vec<u8>>> {
+        let mut res = Vec::new();
+        // the bound is checked after each popped element
+        if max_bytes == Some(0) {
+            return Ok(res);
+        }
+        let mut size = 0;
+        loop {
+            let remaining = max_bytes.map(|max_bytes| max_bytes - size);
+            let batch = self.pop_limited(POP_ALL_BATCH, remaining, false)?;
+            let exhausted = batch.len() < POP_ALL_BATCH;
+            size += batch.iter().map(|v| v.len() as u64).sum::<u64>();
+            res.extend(batch);
+            if exhausted || max_bytes.is_some_and(|max_bytes| size >= max_bytes) {
+                return Ok(res);
+            }
+        }
+    }
+
+    /// Pops up to `max_elts` elements, stopping after the element which makes their payload
+    /// size reach `max_bytes`. If `exact` is set, pops nothing unless `max_elts` elements are
+    /// popped.
+    ///
+    fn pop_limited(
+        &self,
+        max_elts: usize,
+        max_bytes: Option<u64>,
+        exact: bool,
+    ) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .pop_elements(max_elts, max_bytes, exact, false)?
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect())
+    }
+
+    /// Pops the elements as [`Self::pop_limited`] together with their payloads if `payloads` is
+    /// set. The payloads are removed regardless, the elements pushed without one have none.
+    ///
+    fn pop_elements(
+        &self,
+        mut max_elts: usize,
+        max_bytes: Option<u64>,
+        exact: bool,
+        payloads: bool,
+    ) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>> {
+        limiter::wait_ready(&self.pop_limiter)?;
+        let span = OpSpan::enter("pop");
+        let mut reader = self.reader.lock();
+
+        // only pops move the read index, pushes can only add elements after the write index
+        let (
+            mut read_index,
+            write_index,
+            empty,
+            chunked,
+            paired,
+            keyed,
+            format_version,
+            checksums,
+            space_stat,
+        ) = {
+            let state = self.state.lock();
+            (
+                state.read_index,
+                state.write_index,
+                state.empty,
+                state.chunked,
+                state.paired,
+                state.keyed,
+                state.format_version,
+                state.checksums,
+                state.space_stat,
+            )
+        };
+
+        let mut res = Vec::with_capacity(max_elts);
+        if empty || max_elts == 0 {
+            return Ok(res);
+        }
+        // pushes only add elements, so there are at least as many as in the snapshot
+        if exact && counters::length(read_index, write_index, empty, self.index_space) < max_elts {
+            return Ok(res);
+        }
+
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        let mut corrupted = Vec::new();
+        let mut corrupted_size = 0;
+        let mut deleted = 0;
+        let mut evicted = 0;
+        let mut size = 0;
+        loop {
+            let value = chunking::get_element(self.db()?, None, read_index, chunked)?;
+            if let Some(e) = value {
+                chunking::delete_element(&mut batch, None, &e);
+                batch.delete_cf(time_cf, index_to_key(read_index));
+                let stored_size = e.value.len() as u64;
+                let decoded = self
+                    .codec
+                    .decode_versioned(e.value, read_index, format_version, checksums)
+                    .and_then(|value| {
+                        let payload = self.take_payload(
+                            &mut batch,
+                            read_index,
+                            paired,
+                            format_version,
+                            checksums,
+                        )?;
+                        Ok((value, payload))
+                    });
+                match decoded {
+                    Ok(_) if self.take_key(&mut batch, read_index, keyed)? => deleted += 1,
+                    Ok((value, payload)) => {
+                        size += value.len() as u64;
+                        size += payload.as_ref().map_or(0, |p| p.len() as u64);
+                        res.push((value, payload.filter(|_| payloads)));
+                        max_elts -= 1;
+                    }
+                    Err(e) if self.skip_corrupted && matches!(e, QueueError::Corrupted(_)) => {
+                        // the original payload size is unknown, the stored one is the estimate
+                        self.delete_payload(&mut batch, read_index, paired)?;
+                        self.take_key(&mut batch, read_index, keyed)?;
+                        corrupted.push(read_index);
+                        corrupted_size += stored_size;
+                    }
+                    Err(e) => return Err(e),
+                }
+                read_index = next_index(read_index, self.index_space);
+            } else if self.evicting {
+                // the element has been evicted by the compaction filter
+                batch.delete_cf(time_cf, index_to_key(read_index));
+                self.delete_payload(&mut batch, read_index, paired)?;
+                self.take_key(&mut batch, read_index, keyed)?;
+                evicted += 1;
+                read_index = next_index(read_index, self.index_space);
+            } else {
+                break;
+            }
+
+            let size_reached = max_bytes.is_some_and(|max_bytes| size >= max_bytes);
+            if read_index != write_index && max_elts > 0 && !size_reached {
+                continue;
+            } else {
+                break;
+            }
+        }
+        if exact && max_elts > 0 {
+            // the skipped elements made the batch partial, nothing is written
+            return Ok(Vec::new());
+        }
+        if !res.is_empty() || !corrupted.is_empty() || deleted + evicted > 0 {
+            // the popped elements are counted in the snapshot, pushes only make it larger
+            let removed = (size + corrupted_size).min(space_stat);
+            let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+            batch.merge_cf(
+                system_cf,
+                index_to_key(SPACE_STAT_CELL),
+                counter_delta(-(removed as i64)),
+            );
+            batch.put_cf(
+                system_cf,
+                index_to_key(READ_INDEX_CELL),
+                read_index.to_le_bytes(),
+            );
+            self.db()?.write_opt(batch, &self.write_options())?;
+
+            let mut state = self.state.lock();
+            let empty = read_index == state.write_index;
+            let space_stat = state.space_stat - removed;
+            state.read_index = read_index;
+            state.space_stat = space_stat;
+            state.empty = empty;
+            state.popped += (res.len() + corrupted.len() + deleted + evicted) as u64;
+            reader.extend(corrupted);
+            self.counters
+                .store(read_index, state.write_index, space_stat, empty);
+        }
+        span.record(res.len(), size);
+        limiter::charge(&self.pop_limiter, res.len(), size);
+
+        Ok(res)
+    }
+
+    /// Pops up to `max_elts` of the newest elements, newest first, so the queue can be consumed
+    /// as a stack while the older elements stay stored. It moves the write index back, so it is
+    /// serialized with both pushes and pops.
+    ///
+    pub fn pop_back(&self, mut max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        limiter::wait_ready(&self.pop_limiter)?;
+        let _writer = self.writer.lock();
+        let span = OpSpan::enter("pop_back");
+        let mut reader = self.reader.lock();
+
+        let (
+            read_index,
+            mut write_index,
+            empty,
+            chunked,
+            paired,
+            keyed,
+            format_version,
+            checksums,
+            space_stat,
+        ) = {
+            let state = self.state.lock();
+            (
+                state.read_index,
+                state.write_index,
+                state.empty,
+                state.chunked,
+                state.paired,
+                state.keyed,
+                state.format_version,
+                state.checksums,
+                state.space_stat,
+            )
+        };
+
+        let mut res = Vec::with_capacity(max_elts);
+        if empty || max_elts == 0 {
+            return Ok(res);
+        }
+
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let mut batch = WriteBatch::default();
+        let mut corrupted = Vec::new();
+        let mut corrupted_size = 0;
+        let mut deleted = 0;
+        let mut evicted = 0;
+        let mut size = 0;
+        loop {
+            let index = previous_index(write_index, self.index_space);
+            let Some(e) = chunking::get_element(self.db()?, None, index, chunked)? else {
+                if !self.evicting {
+                    break;
+                }
+                // the element has been evicted by the compaction filter
+                batch.delete_cf(time_cf, index_to_key(index));
+                self.delete_payload(&mut batch, index, paired)?;
+                self.take_key(&mut batch, index, keyed)?;
+                evicted += 1;
+                write_index = index;
+                if write_index == read_index {
+                    break;
+                }
+                continue;
+            };
+            chunking::delete_element(&mut batch, None, &e);
+            batch.delete_cf(time_cf, index_to_key(index));
+            let stored_size = e.value.len() as u64;
+            let decoded = self
+                .codec
+                .decode_versioned(e.value, index, format_version, checksums)
+                .and_then(|value| {
+                    let payload =
+                        self.take_payload(&mut batch, index, paired, format_version, checksums)?;
+                    Ok((value, payload))
+                });
+            match decoded {
+                Ok(_) if self.take_key(&mut batch, index, keyed)? => deleted += 1,
+                Ok((value, payload)) => {
+                    size += value.len() as u64;
+                    size += payload.map_or(0, |p| p.len() as u64);
+                    res.push(value);
+                    max_elts -= 1;
+                }
+                Err(e) if self.skip_corrupted && matches!(e, QueueError::Corrupted(_)) => {
+                    self.delete_payload(&mut batch, index, paired)?;
+                    self.take_key(&mut batch, index, keyed)?;
+                    corrupted.push(index);
+                    corrupted_size += stored_size;
+                }
+                Err(e) => return Err(e),
+            }
+            write_index = index;
+
+            if write_index == read_index || max_elts == 0 {
+                break;
+            }
+        }
+        if !res.is_empty() || !corrupted.is_empty() || deleted + evicted > 0 {
+            let removed = (size + corrupted_size).min(space_stat);
+            let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+            batch.merge_cf(
+                system_cf,
+                index_to_key(SPACE_STAT_CELL),
+                counter_delta(-(removed as i64)),
+            );
+            batch.put_cf(
+                system_cf,
+                index_to_key(WRITE_INDEX_CELL),
+                write_index.to_le_bytes(),
+            );
+            self.db()?.write_opt(batch, &self.write_options())?;
+
+            let mut state = self.state.lock();
+            let empty = write_index == read_index;
+            let space_stat = state.space_stat - removed;
+            state.write_index = write_index;
+            state.space_stat = space_stat;
+            state.empty = empty;
+            state.popped += (res.len() + corrupted.len() + deleted + evicted) as u64;
+            reader.extend(corrupted);
+            self.counters
+                .store(read_index, write_index, space_stat, empty);
+        }
+        span.record(res.len(), size);
+        limiter::charge(&self.pop_limiter, res.len(), size);
+
+        Ok(res)
+    }
+
+    fn delete_payload(&self, batch: &mut WriteBatch, index: u64, paired: bool) -> Result<()> {
+        if paired {
+            let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+            batch.delete_cf(payload_cf, index_to_key(index));
+        }
+        Ok(())
+    }
+
+    /// Removes the key entries of the element in the batch, returns `true` if the element was
+    /// deleted by [`Self::delete_by_key`]. The keys are looked up only in the queues having the
+    /// elements pushed with keys.
+    ///
+    fn take_key(&self, batch: &mut WriteBatch, index: u64, keyed: bool) -> Result<bool> {
+        if !keyed {
+            return Ok(false);
+        }
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let Some(entry) = self.db()?.get_cf(key_cf, index_to_key(index))? else {
+            return Ok(false);
+        };
+        batch.delete_cf(key_cf, index_to_key(index));
+        match entry.split_first() {
+            Some((&KEY_LIVE, key)) => {
+                batch.delete_cf(key_cf, key_entry(key, Some(index))?);
+                Ok(false)
+            }
+            _ => Ok(true),
+        }
+    }
+
+    /// Returns the format version and the checksum mode of the elements written with the batch,
+    /// see [`Self::set_checksums`]. An empty queue is upgraded from the legacy format and takes
+    /// the checksum setting, the changes are recorded with the batch.
+    ///
+    fn write_format(
+        &self,
+        batch: &mut WriteBatch,
+        empty: bool,
+        format_version: u64,
+        checksums: bool,
+    ) -> Result<(u64, bool)> {
+        if !empty {
+            return Ok((format_version, checksums));
+        }
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+        if format_version != FORMAT_VERSION {
+            batch.put_cf(
+                system_cf,
+                index_to_key(FORMAT_VERSION_CELL),
+                FORMAT_VERSION.to_le_bytes(),
+            );
+        }
+        match (checksums, self.codec.checksums) {
+            (false, true) => batch.put_cf(system_cf, index_to_key(CHECKSUMS_CELL), [1u8]),
+            (true, false) => batch.delete_cf(system_cf, index_to_key(CHECKSUMS_CELL)),
+            _ => {}
+        }
+        Ok((FORMAT_VERSION, self.codec.checksums))
+    }
+
+    /// Removes the payload of the element in the batch and returns it decoded, `None` if the
+    /// element was pushed without one. The payloads are looked up only in the queues having the
+    /// paired elements.
+    ///
+    fn take_payload(
+        &self,
+        batch: &mut WriteBatch,
+        index: u64,
+        paired: bool,
+        format_version: u64,
+        checksums: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        if !paired {
+            return Ok(None);
+        }
+        let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+        let Some(payload) = self.db()?.get_cf(payload_cf, index_to_key(index))? else {
+            return Ok(None);
+        };
+        batch.delete_cf(payload_cf, index_to_key(index));
+        self.codec
+            .decode_versioned(payload, index, format_version, checksums)
+            .map(Some)
+    }
+}
+
+/// Returns the entry of the key column family of the element pushed with the key, or the prefix
+/// of the entries of the key without the index.
+///
+fn key_entry(key: &[u8], index: Option<u64>) -> Result<Vec<u8>> {
+    let len = u32::try_from(key.len()).map_err(|_| {
+        QueueError::InvalidArgument(format!("A key can't be longer than {} bytes", u32::MAX))
+    })?;
+    let mut entry = Vec::with_capacity(4 + key.len() + U64_BYTE_LEN);
+    entry.extend_from_slice(&len.to_le_bytes());
+    entry.extend_from_slice(key);
+    if let Some(index) = index {
+        entry.extend_from_slice(&index_to_key(index));
+    }
+    Ok(entry)
+}
+
+fn epoch_key(producer_id: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(producer_id.len() + 1);
+    key.push(EPOCH_KEY_PREFIX);
+    key.extend_from_slice(producer_id.as_bytes());
+    key
+}
+
+fn offset_key(label: &str) -> Vec<u8> {
+    let mut key = Vec::with_capacity(label.len() + 1);
+    key.push(OFFSET_KEY_PREFIX);
+    key.extend_from_slice(label.as_bytes());
+    key
+}
+
+pub(crate) fn check_index_space(index_space: u64) -> Result<()> {
+    if index_space == 0 || index_space > DEFAULT_INDEX_SPACE {
+        return Err(QueueError::InvalidArgument(format!(
+            "index_space must be in the range [1, {}]",
+            DEFAULT_INDEX_SPACE
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::CorruptedElement;
+
+    /// The index space small enough for the tests to wrap the indices around.
+    const INDEX_SPACE: u64 = 6;
+
+    fn open_queue<P: AsRef<Path>>(
+        path: P,
+        max_elements: usize,
+        db_opts: Options,
+    ) -> Result<PersistentQueueWithCapacity> {
+        PersistentQueueWithCapacity::with_index_space(path, max_elements, INDEX_SPACE, db_opts)
+    }
+
+    fn put_cell(queue: &PersistentQueueWithCapacity, cell: u64, value: &[u8]) {
+        let system_cf = queue.db().unwrap().cf_handle(SYSTEM_CF).unwrap();
+        queue
+            .db()
+            .unwrap()
+            .put_cf(system_cf, index_to_key(cell), value)
+            .unwrap();
+    }
+
+    fn cursors(queue: &PersistentQueueWithCapacity) -> (u64, u64) {
+        let state = queue.state.lock();
+        (state.read_index, state.write_index)
+    }
+
+    #[test]
+    fn test_normal_ops() {
+        let path = "/tmp/test1".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, INDEX_SPACE as usize, Options::default()).unwrap();
+            db.push(&[&[1, 2, 3]]).unwrap();
+            db.push(&[&[4, 5, 6]]).unwrap();
+            db.push(&[&[7, 8, 9]]).unwrap();
+            db.push(&[&[10, 11, 12]]).unwrap();
+            assert!(!db.is_empty());
+            assert_eq!(db.len(), 4);
+            assert_eq!(db.payload_size(), 12);
+            assert!(matches!(db.pop(1), Ok(v ) if v == vec![vec![1, 2, 3]]));
+            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![4, 5, 6]]));
+            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![7, 8, 9]]));
+            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![10, 11, 12]]));
+            assert!(db.is_empty());
+            assert_eq!(db.len(), 0);
+            db.push(&[&[1, 2, 3]]).unwrap();
+            db.push(&[&[4, 5, 6]]).unwrap();
+            db.push(&[&[7, 8, 9]]).unwrap();
+            assert_eq!(db.len(), 3);
+            assert_eq!(cursors(&db), (4, 1));
+            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![1, 2, 3]]));
+            assert_eq!(db.len(), 2);
+            assert_eq!(cursors(&db), (5, 1));
+            assert!(matches!(db.pop(1), Ok(v) if v == vec![vec![4, 5, 6]]));
+            assert_eq!(db.len(), 1);
+            assert_eq!(cursors(&db), (0, 1));
+            let data = db.pop(1).unwrap();
+            assert!(db.is_empty());
+            assert_eq!(db.len(), 0);
+            assert_eq!(cursors(&db), (1, 1));
+            assert_eq!(data, vec![vec![7, 8, 9]]);
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn test_limit_len_ops() {
+        let path = "/tmp/test2".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, 2, Options::default()).unwrap();
+            db.push(&[&[1, 2, 3]]).unwrap();
+            db.push(&[&[4, 5, 6]]).unwrap();
+            assert!(matches!(db.push(&[&[1, 2, 3]]), Err(_)));
+            assert_eq!(db.payload_size(), 6);
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn test_read_with_close() {
+        let path = "/tmp/test3".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let size = INDEX_SPACE as usize;
+        {
+            let db = open_queue(&path, size, Options::default()).unwrap();
+            db.push(&[&[1, 2, 3]]).unwrap();
+            db.push(&[&[4, 5, 6]]).unwrap();
+            db.push(&[&[7, 8, 9]]).unwrap();
+        }
+
+        {
+            let db = open_queue(&path, size, Options::default()).unwrap();
+            assert_eq!(db.payload_size(), 9);
+            let res = db.pop(1).unwrap();
+            assert_eq!(res, vec![vec![1, 2, 3]]);
+            assert_eq!(db.payload_size(), 6);
+        }
+
+        {
+            let db = open_queue(&path, size, Options::default()).unwrap();
+            let res = db.pop(1).unwrap();
+            assert_eq!(res, vec![vec![4, 5, 6]]);
+            let res = db.pop(1).unwrap();
+            assert_eq!(res, vec![vec![7, 8, 9]]);
+        }
+
+        {
+            let db = open_queue(&path, size, Options::default()).unwrap();
+            let res = db.pop(1).unwrap();
+            assert!(res.is_empty());
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn close_and_reopen() {
+        let path = "/tmp/test_close_and_reopen".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        db.push(&[&[1, 2, 3]]).unwrap();
+
+        let err = open_queue(&path, 3, Options::default()).unwrap_err();
+        assert!(matches!(err, QueueError::InUse { path: p } if p == Path::new(&path)));
+        let err = PersistentQueueWithCapacity::remove_db(&path).unwrap_err();
+        assert!(matches!(err, QueueError::InUse { .. }));
+        assert_eq!(db.pop(1).unwrap(), vec![vec![1, 2, 3]]);
+        db.push(&[&[1, 2, 3]]).unwrap();
+
+        db.close().unwrap();
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        assert_eq!(db.pop(1).unwrap(), vec![vec![1, 2, 3]]);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_forced() {
+        let path = "/tmp/test_remove_forced".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        db.push(&[&[1, 2, 3]]).unwrap();
+
+        PersistentQueueWithCapacity::remove_db_forced(&path).unwrap();
+        assert!(!Path::new(&path).exists());
+        drop(db);
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        assert!(db.is_empty());
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn remove_wiped() {
+        let path = "/tmp/test_remove_wiped".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        db.push(&[&[1, 2, 3]]).unwrap();
+
+        let options = RemoveOptions::default().secure_wipe(true);
+        let err = PersistentQueueWithCapacity::remove_db_with(&path, &options).unwrap_err();
+        assert!(matches!(err, QueueError::InUse { .. }));
+        assert_eq!(db.pop(1).unwrap(), vec![vec![1, 2, 3]]);
+
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db_with(&path, &options).unwrap();
+        assert!(!Path::new(&path).exists());
+    }
+
+    #[test]
+    fn reopen() {
+        let path = "/tmp/test_reopen".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let mut db = open_queue(&path, 3, Options::default()).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            db.add_listener(move |event| events.lock().push(event.clone()));
+        }
+        db.set_sync_writes(true);
+        db.push(&[&[1], &[2, 3]]).unwrap();
+        assert_eq!(db.pop(1).unwrap(), vec![vec![1]]);
+
+        db.reopen().unwrap();
+        assert!(db.sync_writes());
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.payload_size(), 2);
+        assert_eq!(db.stats().unwrap().pushed, 2);
+        assert_eq!(db.pop(1).unwrap(), vec![vec![2, 3]]);
+        assert!(matches!(events.lock().last(), Some(QueueEvent::Reopen)));
+
+        assert!(QueueError::Io(std::io::ErrorKind::Interrupted.into()).is_transient());
+        assert!(!QueueError::Full.is_transient());
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn stats() {
+        let path = "/tmp/test_stats".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 5, Options::default()).unwrap();
+        db.push(&[&[1, 2], &[3]]).unwrap();
+        db.push_front(&[&[4]]).unwrap();
+        db.pop(1).unwrap();
+        db.pop_back(1).unwrap();
+
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.len, 1);
+        assert_eq!(stats.payload_size, 2);
+        assert_eq!((stats.pushed, stats.popped), (3, 2));
+        assert!(stats.disk_size > 0);
+        assert_eq!(
+            (stats.sizes.count, stats.sizes.min, stats.sizes.max),
+            (3, 1, 2)
+        );
+        db.close().unwrap();
+
+        // the size distribution is persistent, unlike the counters
+        let db = open_queue(&path, 5, Options::default()).unwrap();
+        let stats = db.stats().unwrap();
+        assert_eq!(stats.pushed, 0);
+        assert_eq!(stats.sizes.total, 4);
+        assert_eq!(&stats.sizes.histogram[..3], &[0, 2, 1]);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn oldest_age() {
+        let path = "/tmp/test_oldest_age".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 5, Options::default()).unwrap();
+        assert_eq!(db.oldest_age().unwrap(), None);
+        db.push(&[&[1]]).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        db.push(&[&[2]]).unwrap();
+
+        let age = db.oldest_age().unwrap().unwrap();
+        assert!(age >= Duration::from_millis(20));
+        db.pop(1).unwrap();
+        assert!(db.oldest_age().unwrap().unwrap() < age);
+        db.pop(1).unwrap();
+        assert_eq!(db.oldest_age().unwrap(), None);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_rate_limit() {
+        let path = "/tmp/test_push_rate_limit".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let mut db = open_queue(&path, 6, Options::default()).unwrap();
+        let limit = RateLimit {
+            elements_per_sec: Some(2.0),
+            strategy: limiter::RateLimitStrategy::FailFast,
+            ..Default::default()
+        };
+        db.set_push_rate_limit(Some(limit)).unwrap();
+        assert_eq!(db.push_rate_limit(), Some(limit));
+
+        db.push(&[&[1], &[2]]).unwrap();
+        assert!(matches!(db.push(&[&[3]]), Err(QueueError::RateLimited)));
+        assert!(matches!(
+            db.push_partial(&[&[3]]),
+            Err(QueueError::RateLimited)
+        ));
+        db.push_front(&[&[0]]).unwrap();
+        assert_eq!(db.len(), 3);
+
+        db.set_push_rate_limit(None).unwrap();
+        db.push(&[&[3]]).unwrap();
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn pop_rate_limit() {
+        let path = "/tmp/test_pop_rate_limit".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let mut db = open_queue(&path, 6, Options::default()).unwrap();
+        db.set_pop_rate_limit(Some(RateLimit {
+            elements_per_sec: Some(2.0),
+            strategy: limiter::RateLimitStrategy::FailFast,
+            ..Default::default()
+        }))
+        .unwrap();
+        db.push(&[&[1], &[2], &[3], &[4]]).unwrap();
+
+        // the pop exceeding the rate is charged after it
+        assert_eq!(db.pop(3).unwrap().len(), 3);
+        assert!(matches!(db.pop(1), Err(QueueError::RateLimited)));
+        assert!(matches!(db.pop_back(1), Err(QueueError::RateLimited)));
+        db.set_pop_rate_limit(None).unwrap();
+        assert_eq!(db.pop(1).unwrap(), vec![vec![4]]);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn estimate_capacity() {
+        let path = "/tmp/test_estimate_capacity".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 5, Options::default()).unwrap();
+        assert_eq!(db.estimate_remaining_capacity().unwrap(), None);
+        db.push(&[&[1; 100], &[2; 100]]).unwrap();
+        assert_eq!(db.estimate_remaining_capacity().unwrap(), Some(3));
+
+        let one = db.estimate_disk_for(1, 100).unwrap();
+        assert!(one > 0);
+        assert!(db.estimate_disk_for(1000, 100).unwrap() >= one * 999);
+        db.close().unwrap();
+
+        let db = open_queue(&path, 1, Options::default()).unwrap();
+        assert_eq!(db.estimate_remaining_capacity().unwrap(), Some(0));
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn db_stats() {
+        let path = "/tmp/test_db_stats".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let mut opts = Options::default();
+        opts.enable_statistics();
+        let db = open_queue(&path, 3, opts).unwrap();
+        db.push(&[&[1, 2, 3]]).unwrap();
+        let stats = db.db_stats().unwrap();
+        assert!(!stats.write_stopped);
+        assert_eq!(stats.delayed_write_rate, 0);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn pop_all() {
+        let path = "/tmp/test_pop_all".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 5, Options::default()).unwrap();
+        db.push(&[&[1], &[2, 2], &[3, 3, 3], &[4], &[5]]).unwrap();
+
+        assert!(db.pop_all(Some(0)).unwrap().is_empty());
+        assert_eq!(db.len(), 5);
+        assert_eq!(db.pop_all(Some(2)).unwrap(), vec![vec![1], vec![2, 2]]);
+        assert_eq!(db.payload_size(), 5);
+        assert_eq!(
+            db.pop_all(None).unwrap(),
+            vec![vec![3, 3, 3], vec![4], vec![5]]
+        );
+        assert!(db.is_empty());
+        assert_eq!(db.payload_size(), 0);
+        assert!(db.pop_all(None).unwrap().is_empty());
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_with_sequence() {
+        let path = "/tmp/test_push_with_sequence".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, 3, Options::default()).unwrap();
+            assert_eq!(db.producer_sequence("p1").unwrap(), None);
+            assert!(db.push_with_sequence("p1", 1, &[&[1]]).unwrap());
+            assert!(db.push_with_sequence("p2", 1, &[&[2]]).unwrap());
+            assert!(matches!(
+                db.push_with_sequence("p1", 2, &[&[3], &[4]]),
+                Err(QueueError::Full)
+            ));
+            assert_eq!(db.producer_sequence("p1").unwrap(), Some(1));
+            db.close().unwrap();
+        }
+        {
+            let db = open_queue(&path, 3, Options::default()).unwrap();
+            // the retry is deduplicated after the restart
+            assert!(!db.push_with_sequence("p1", 1, &[&[1]]).unwrap());
+            assert!(db.push_with_sequence("p1", 2, &[&[3]]).unwrap());
+            assert!(db.push_with_sequence("p2", 5, &[]).unwrap());
+            assert!(!db.push_with_sequence("p2", 3, &[&[4]]).unwrap());
+            assert_eq!(db.pop(3).unwrap(), vec![vec![1], vec![2], vec![3]]);
+
+            db.remove_producer_session("p1").unwrap();
+            assert_eq!(db.producer_sequence("p1").unwrap(), None);
+            assert!(db.push_with_sequence("p1", 1, &[&[5]]).unwrap());
+            assert_eq!(db.pop(1).unwrap(), vec![vec![5]]);
+            assert!(db.verify().unwrap().is_ok());
+            db.close().unwrap();
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_with_epoch() {
+        let path = "/tmp/test_push_with_epoch".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(db.producer_epoch("p").unwrap(), None);
+            db.push_with_epoch("p", 1, &[&[1]]).unwrap();
+            db.register_epoch("p", 2).unwrap();
+            db.close().unwrap();
+        }
+        {
+            let db = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(db.producer_epoch("p").unwrap(), Some(2));
+            let err = db.push_with_epoch("p", 1, &[&[2]]).unwrap_err();
+            assert!(matches!(
+                err,
+                QueueError::Fenced {
+                    epoch: 1,
+                    current: 2,
+                    ..
+                }
+            ));
+            assert!(matches!(
+                db.register_epoch("p", 1),
+                Err(QueueError::Fenced { .. })
+            ));
+            db.push_with_epoch("p", 2, &[&[3]]).unwrap();
+            // the sessions of the producer are independent of the epochs
+            assert_eq!(db.producer_sequence("p").unwrap(), None);
+            assert_eq!(db.pop(5).unwrap(), vec![vec![1], vec![3]]);
+            db.close().unwrap();
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_partial() {
+        let path = "/tmp/test_push_partial".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 3, Options::default()).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            db.add_listener(move |event| events.lock().push(event.clone()));
+        }
+
+        assert_eq!(db.push_partial(&[&[1], &[2]]).unwrap(), 2);
+        assert_eq!(db.push_partial(&[&[3], &[4], &[5]]).unwrap(), 1);
+        assert_eq!(db.push_partial(&[&[4]]).unwrap(), 0);
+        assert_eq!(db.push_partial(&[]).unwrap(), 0);
+        assert_eq!(db.payload_size(), 3);
+        assert_eq!(db.pop(3).unwrap(), vec![vec![1], vec![2], vec![3]]);
+        assert_eq!(
+            *events.lock(),
+            vec![
+                QueueEvent::Push(2),
+                QueueEvent::Push(1),
+                QueueEvent::Full,
+                QueueEvent::Full
+            ]
+        );
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_front() {
+        let path = "/tmp/test_push_front".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, 4, Options::default()).unwrap();
+            // the read index wraps around
+            db.push_front(&[&[1], &[2, 2]]).unwrap();
+            assert_eq!(cursors(&db), (4, 0));
+            db.push(&[&[3]]).unwrap();
+            assert_eq!(db.len(), 3);
+            assert_eq!(db.payload_size(), 4);
+            assert!(matches!(
+                db.push_front(&[&[4], &[5]]),
+                Err(QueueError::Full)
+            ));
+
+            assert_eq!(db.pop(1).unwrap(), vec![vec![1]]);
+            db.push_front(&[&[0]]).unwrap();
+        }
+        {
+            let db = open_queue(&path, 4, Options::default()).unwrap();
+            assert_eq!(cursors(&db), (4, 1));
+            assert_eq!(db.pop(5).unwrap(), vec![vec![0], vec![2, 2], vec![3]]);
+            assert!(db.is_empty());
+            assert_eq!(db.payload_size(), 0);
+            assert!(db.verify().unwrap().is_ok());
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn pop_exact() {
+        let path = "/tmp/test_pop_exact".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 4, Options::default()).unwrap();
+        db.push(&[&[1], &[2], &[3]]).unwrap();
+
+        assert!(db.pop_exact(4).unwrap().is_empty());
+        assert_eq!(db.len(), 3);
+        assert_eq!(db.pop_exact(2).unwrap(), vec![vec![1], vec![2]]);
+        assert!(db.pop_exact(2).unwrap().is_empty());
+        assert!(matches!(
+            db.pop_exact(5),
+            Err(QueueError::InvalidArgument(_))
+        ));
+        assert_eq!(db.pop_exact(1).unwrap(), vec![vec![3]]);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn pop_exact_skipped() {
+        let path = "/tmp/test_pop_exact_skipped".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue
+                .push_with_keys(&[(b"cam1", &[1u8]), (b"cam2", &[2u8])])
+                .unwrap();
+            queue.push(&[&[3u8]]).unwrap();
+            assert_eq!(queue.delete_by_key(b"cam2").unwrap(), 1);
+
+            // the deleted element doesn't count towards the batch
+            assert!(queue.pop_exact(3).unwrap().is_empty());
+            assert_eq!(queue.len(), 3);
+            assert_eq!(queue.pop_exact(2).unwrap(), vec![vec![1u8], vec![3u8]]);
+            assert!(queue.is_empty());
+
+            queue.set_checksums(true);
+            queue.set_skip_corrupted(true);
+            queue.push(&[&[4u8], &[5u8]]).unwrap();
+            let mut value = queue.db().unwrap().get(index_to_key(3)).unwrap().unwrap();
+            *value.last_mut().unwrap() ^= 1;
+            queue.db().unwrap().put(index_to_key(3), value).unwrap();
+
+            // neither does the corrupted one
+            assert!(queue.pop_exact(2).unwrap().is_empty());
+            assert!(queue.take_corrupted().is_empty());
+            assert_eq!(queue.len(), 2);
+            queue.push(&[&[6u8]]).unwrap();
+            assert_eq!(queue.pop_exact(2).unwrap(), vec![vec![5u8], vec![6u8]]);
+            assert_eq!(queue.take_corrupted(), vec![3]);
+            assert!(queue.is_empty());
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn pop_back() {
+        let path = "/tmp/test_pop_back".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = open_queue(&path, 4, Options::default()).unwrap();
+            db.push(&[&[1], &[2, 2], &[3, 3, 3], &[4]]).unwrap();
+            assert_eq!(db.pop(2).unwrap(), vec![vec![1], vec![2, 2]]);
+            // the write index wraps around
+            db.push(&[&[5], &[6, 6]]).unwrap();
+            assert_eq!(cursors(&db), (2, 0));
+
+            assert_eq!(db.pop_back(2).unwrap(), vec![vec![6, 6], vec![5]]);
+            assert_eq!(cursors(&db), (2, 4));
+            assert_eq!(db.len(), 2);
+            assert_eq!(db.payload_size(), 4);
+            db.push(&[&[7]]).unwrap();
+            assert_eq!(db.pop_back(1).unwrap(), vec![vec![7]]);
+            assert_eq!(db.pop(1).unwrap(), vec![vec![3, 3, 3]]);
+        }
+        {
+            let db = open_queue(&path, 4, Options::default()).unwrap();
+            assert_eq!(cursors(&db), (3, 4));
+            assert_eq!(db.pop_back(5).unwrap(), vec![vec![4]]);
+            assert!(db.is_empty());
+            assert_eq!(db.payload_size(), 0);
+            assert!(db.pop_back(1).unwrap().is_empty());
+            assert!(db.verify().unwrap().is_ok());
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn listeners() {
+        let path = "/tmp/test_listeners".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let db = open_queue(&path, 2, Options::default()).unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let pushed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        {
+            let events = events.clone();
+            db.add_listener(move |event| events.lock().push(event.clone()));
+            let pushed = pushed.clone();
+            db.on_push(move |count| {
+                pushed.fetch_add(count, std::sync::atomic::Ordering::Relaxed);
+            });
+        }
+
+        db.push(&[&[1], &[2]]).unwrap();
+        assert!(matches!(db.push(&[&[3]]), Err(QueueError::Full)));
+        assert_eq!(db.pop(2).unwrap(), vec![vec![1], vec![2]]);
+
+        assert_eq!(*events.lock(), vec![QueueEvent::Push(2), QueueEvent::Full]);
+        assert_eq!(pushed.load(std::sync::atomic::Ordering::Relaxed), 2);
+        db.close().unwrap();
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn disk_alerts() {
+        use crate::alerts::DiskThreshold;
+
+        let dir = tempfile::TempDir::new().unwrap();
+        let alerts = DiskAlerts::new(None)
+            .with_threshold(DiskThreshold::Bytes(1))
+            .with_threshold(DiskThreshold::Bytes(u64::MAX));
+        let options = QueueOptions::default().disk_alerts(alerts.clone());
+        let db = PersistentQueueWithCapacity::open(dir.path(), 10, &options).unwrap();
+        assert_eq!(db.disk_alerts(), Some(&alerts));
+        let events = Arc::new(Mutex::new(Vec::new()));
+        {
+            let events = events.clone();
+            db.add_listener(move |event| events.lock().push(event.clone()));
+        }
+
+        db.push(&[&[1]]).unwrap();
+        assert_eq!(
+            *events.lock(),
+            vec![
+                QueueEvent::Push(1),
+                QueueEvent::DiskAlert {
+                    threshold: DiskThreshold::Bytes(1),
+                    active: true
+                }
+            ]
+        );
+        assert_eq!(
+            db.stats().unwrap().disk_alerts,
+            vec![DiskThreshold::Bytes(1)]
+        );
+
+        let options = QueueOptions::default()
+            .disk_alerts(DiskAlerts::new(None).with_threshold(DiskThreshold::Percent(50)));
+        drop(db);
+        assert!(matches!(
+            PersistentQueueWithCapacity::open(dir.path(), 10, &options),
+            Err(QueueError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn concurrent_push_pop() {
+        let path = "/tmp/test_concurrent_push_pop".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = Arc::new(open_queue(&path, 5, Options::default()).unwrap());
+            let producer = {
+                let queue = queue.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200u8 {
+                        while queue.push(&[&[i]]).is_err() {
+                            std::thread::yield_now();
+                        }
+                    }
+                })
+            };
+            let mut res = Vec::new();
+            while res.len() < 200 {
+                res.extend(queue.pop(3).unwrap());
+            }
+            producer.join().unwrap();
+
+            assert_eq!(res, (0..200u8).map(|i| vec![i]).collect::<Vec<_>>());
+            assert!(queue.is_empty());
+            assert_eq!(queue.payload_size(), 0);
+            assert!(queue.verify().unwrap().is_ok());
+            queue.push(&[&[1u8, 2u8]]).unwrap();
+        }
+        {
+            // the merged payload size deltas are persisted
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(queue.payload_size(), 2);
+            assert!(queue.verify().unwrap().is_ok());
+        }
+        PersistentQueueWithCapacity::remove_db(&path).unwrap();
+    }
+
+    #[test]
+    fn push_pop_many() {
+        let path = "/tmp/test_push_pop_many".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let queue = open_queue(&path, 3, Options::default()).unwrap();
+        queue
+            .push(&[&[1u8, 2u8, 3u8], &[4u8, 5u8, 6u8], &[7u8, 8u8, 9u8]])
+            .unwrap();
+
+        let res = queue.pop(2).unwrap();
+
+        assert_eq!(res, vec![vec![1u8, 2u8, 3u8], vec![4u8, 5u8, 6u8]]);
+        let res = queue.pop(1).unwrap();
+        assert_eq!(res, vec![vec![7u8, 8u8, 9u8]]);
+
+        let res = queue.pop(1).unwrap();
+        assert!(res.is_empty());
+
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_max_elements() {
+        let path = "/tmp/test_push_pop_max_elements".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let size = INDEX_SPACE as usize;
+        let queue = open_queue(&path, size, Options::default()).unwrap();
+
+        let values = vec!["a".as_bytes(); size];
+        queue.push(&values).unwrap();
+
+        assert_eq!(cursors(&queue), (0, 0));
+        assert_eq!(queue.state.lock().empty, false);
+        assert_eq!(queue.len(), size);
+
+        let res = queue.pop(size).unwrap();
+
+        assert_eq!(res, values);
+        assert_eq!(cursors(&queue), (0, 0));
+        assert_eq!(queue.state.lock().empty, true);
+        assert_eq!(queue.len(), 0);
+
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_pairs() {
+        let path = "/tmp/test_push_pop_pairs".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let frame = vec![7u8; 10000];
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue.push(&[&[1u8]]).unwrap();
+            queue
+                .push_pairs(&[(&[2u8], &frame), (&[3u8], &[4u8, 5])])
+                .unwrap();
+            queue.push_pair(&[6u8], &frame).unwrap();
+            assert_eq!(queue.payload_size(), 20006);
+            assert!(queue.verify().unwrap().is_ok());
+
+            let res = queue.pop_pairs(2).unwrap();
+            assert_eq!(res, vec![(vec![1u8], vec![]), (vec![2u8], frame.clone())]);
+        }
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(queue.payload_size(), 10004);
+            // the payloads are dropped
+            assert_eq!(queue.pop(1).unwrap(), vec![vec![3u8]]);
+            assert_eq!(queue.pop_back(1).unwrap(), vec![vec![6u8]]);
+            assert!(queue.is_empty());
+            assert_eq!(queue.payload_size(), 0);
+            let payload_cf = queue.db().unwrap().cf_handle(PAYLOAD_CF).unwrap();
+            assert!(queue
+                .db()
+                .unwrap()
+                .iterator_cf(payload_cf, IteratorMode::Start)
+                .next()
+                .is_none());
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn eviction_filter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let options =
+            QueueOptions::default().eviction_filter(|element| element.starts_with(b"debug"));
+        let queue = PersistentQueueWithCapacity::open(dir.path(), 10, &options).unwrap();
+        queue
+            .push(&[b"debug 1", b"info 1", b"debug 2", b"info 2"])
+            .unwrap();
+        queue
+            .db()
+            .unwrap()
+            .compact_range(None::<&[u8]>, None::<&[u8]>);
+
+        assert_eq!(queue.len(), 4);
+        assert!(queue.verify().unwrap().is_ok());
+        assert_eq!(queue.pop(1).unwrap(), vec![b"info 1".to_vec()]);
+        assert_eq!(queue.pop_back(1).unwrap(), vec![b"info 2".to_vec()]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn stats_snapshot() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let queue =
+            PersistentQueueWithCapacity::open(dir.path(), 3, &QueueOptions::default()).unwrap();
+        assert_eq!(queue.stats_snapshot().unwrap().len, 0);
+        queue.push(&[&[1u8], &[2u8, 3u8], &[4u8]]).unwrap();
+        let snapshot = queue.stats_snapshot().unwrap();
+        assert_eq!(snapshot.len, 3);
+        assert_eq!(snapshot.payload_size, Some(4));
+        queue.pop(1).unwrap();
+
+        let snapshot = queue.stats_snapshot().unwrap();
+        assert_eq!(snapshot.len, 2);
+        assert_eq!(snapshot.payload_size, Some(3));
+        assert_eq!((snapshot.start_index, snapshot.write_index), (1, 3));
+        assert!(snapshot.labels.is_empty());
+    }
+
+    #[test]
+    fn prefetch_next() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let options = QueueOptions::default().prefetch(2).index_space(4);
+        let queue = PersistentQueueWithCapacity::open(dir.path(), 4, &options).unwrap();
+        assert_eq!(queue.prefetch(), 2);
+        assert!(queue.prefetch_next().unwrap().is_empty());
+        queue.push(&[&[1u8], &[2u8], &[3u8]]).unwrap();
+        queue.db().unwrap().flush().unwrap();
+
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![1u8]]);
+        assert_eq!(queue.prefetch_next().unwrap(), vec![1, 2]);
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![2u8]]);
+        // the last element only
+        assert_eq!(queue.prefetch_next().unwrap(), vec![2]);
+
+        // the next indices wrap around the index space
+        queue.push(&[&[4u8], &[5u8]]).unwrap();
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![3u8]]);
+        assert_eq!(queue.prefetch_next().unwrap(), vec![3, 0]);
+        assert_eq!(queue.pop(5).unwrap(), vec![vec![4u8], vec![5u8]]);
+        assert!(queue.prefetch_next().unwrap().is_empty());
+    }
+
+    #[test]
+    fn push_with_keys() {
+        let path = "/tmp/test_push_with_keys".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue
+                .push_with_keys(&[(b"cam1", &[1u8]), (b"cam2", &[2u8, 2])])
+                .unwrap();
+            queue.push(&[&[3u8]]).unwrap();
+            queue.push_with_key(b"cam1", &[4u8]).unwrap();
+            assert_eq!(
+                queue.find_by_key(b"cam1").unwrap(),
+                vec![vec![1u8], vec![4u8]]
+            );
+            assert!(queue.find_by_key(b"cam").unwrap().is_empty());
+
+            assert_eq!(queue.delete_by_key(b"cam2").unwrap(), 1);
+            assert_eq!(queue.delete_by_key(b"cam2").unwrap(), 0);
+            assert!(queue.find_by_key(b"cam2").unwrap().is_empty());
+            assert_eq!(queue.len(), 4);
+            assert_eq!(queue.payload_size(), 3);
+            assert!(queue.verify().unwrap().is_ok());
+        }
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(queue.pop(2).unwrap(), vec![vec![1u8], vec![3u8]]);
+            assert_eq!(queue.find_by_key(b"cam1").unwrap(), vec![vec![4u8]]);
+            assert_eq!(queue.pop_back(1).unwrap(), vec![vec![4u8]]);
+            assert!(queue.is_empty());
+            let key_cf = queue.db().unwrap().cf_handle(KEY_CF).unwrap();
+            assert!(queue
+                .db()
+                .unwrap()
+                .iterator_cf(key_cf, IteratorMode::Start)
+                .next()
+                .is_none());
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_chunked() {
+        let path = "/tmp/test_push_pop_chunked".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let big = (0..10u8).collect::<Vec<_>>();
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            queue.set_chunk_size(4);
+            queue.push(&[&big, &[1, 2, 3], &big[..8]]).unwrap();
+            assert_eq!(queue.payload_size(), 21);
+            assert_eq!(queue.len(), 3);
+            let res = queue.pop(1).unwrap();
+            assert_eq!(res, vec![big.clone()]);
+        }
+        {
+            let queue = open_queue(&path, 3, Options::default()).unwrap();
+            let res = queue.pop(2).unwrap();
+            assert_eq!(res, vec![vec![1, 2, 3], big[..8].to_vec()]);
+            assert!(queue.is_empty());
+            assert_eq!(queue.payload_size(), 0);
+            assert!(queue
+                .db()
+                .unwrap()
+                .iterator(rocksdb::IteratorMode::Start)
+                .all(|e| e.unwrap().0.len() == U64_BYTE_LEN));
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_compressed() {
+        let path = "/tmp/test_push_pop_compressed".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let value = vec![1u8; 100];
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            queue.set_compression(Compression::Zstd(3));
+            queue.push(&[&value]).unwrap();
+            queue.set_compression(Compression::Lz4);
+            queue.push(&[&value]).unwrap();
+            assert_eq!(queue.payload_size(), 200);
+        }
+        {
+            let queue = open_queue(&path, 3, Options::default()).unwrap();
+            let res = queue.pop(2).unwrap();
+            assert_eq!(res, vec![value.clone(), value.clone()]);
+            assert_eq!(queue.payload_size(), 0);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_encrypted() {
+        let path = "/tmp/test_push_pop_encrypted".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let mut keyring = Keyring::new(1, &[1u8; codec::KEY_LEN]).unwrap();
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            queue.set_keyring(Some(keyring.clone()));
+            queue.push(&[&[1u8, 2, 3]]).unwrap();
+            keyring.add_key(2, &[2u8; codec::KEY_LEN]).unwrap();
+            keyring.set_active_key(2).unwrap();
+            queue.set_keyring(Some(keyring.clone()));
+            queue.push(&[&[4u8, 5, 6]]).unwrap();
+        }
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            assert!(queue.pop(1).is_err());
+            queue.set_keyring(Some(keyring));
+            let res = queue.pop(2).unwrap();
+            assert_eq!(res, vec![vec![1u8, 2, 3], vec![4u8, 5, 6]]);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn push_pop_corrupted() {
+        let path = "/tmp/test_push_pop_corrupted".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            queue.set_checksums(true);
+            queue.push(&[&[1u8, 2, 3], &[4u8, 5, 6]]).unwrap();
+
+            let mut value = queue.db().unwrap().get(index_to_key(0)).unwrap().unwrap();
+            *value.last_mut().unwrap() ^= 1;
+            queue.db().unwrap().put(index_to_key(0), value).unwrap();
+
+            let err = queue.pop(2).unwrap_err();
+            assert!(matches!(
+                err,
+                QueueError::Corrupted(CorruptedElement { index: 0 })
+            ));
+            assert_eq!(queue.len(), 2);
+
+            queue.set_skip_corrupted(true);
+            let res = queue.pop(2).unwrap();
+            assert_eq!(res, vec![vec![4u8, 5, 6]]);
+            assert_eq!(queue.take_corrupted(), vec![0]);
+            assert!(queue.take_corrupted().is_empty());
+            assert_eq!(queue.len(), 0);
+            assert_eq!(queue.payload_size(), 0);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn checksum_mode() {
+        let path = "/tmp/test_checksum_mode".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            queue.set_checksums(true);
+            queue.push(&[&[1u8], &[2u8]]).unwrap();
+            // the elements of the non-empty queue keep carrying the checksums
+            queue.set_checksums(false);
+            queue.push(&[&[3u8]]).unwrap();
+
+            // the flag byte isn't covered by the checksum
+            let mut value = queue.db().unwrap().get(index_to_key(0)).unwrap().unwrap();
+            value[0] = 0;
+            queue.db().unwrap().put(index_to_key(0), value).unwrap();
+        }
+        {
+            let mut queue = open_queue(&path, 3, Options::default()).unwrap();
+            assert!(matches!(
+                queue.pop(1),
+                Err(QueueError::Corrupted(CorruptedElement { index: 0 }))
+            ));
+            queue.set_skip_corrupted(true);
+            assert_eq!(queue.pop(3).unwrap(), vec![vec![2u8], vec![3u8]]);
+            assert_eq!(queue.take_corrupted(), vec![0]);
+
+            // the empty queue takes the setting
+            queue.push(&[&[4u8]]).unwrap();
+            let value = queue.db().unwrap().get(index_to_key(3)).unwrap().unwrap();
+            assert_eq!(value, vec![0u8, 4]);
+            assert_eq!(queue.pop(1).unwrap(), vec![vec![4u8]]);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn verify() {
+        let path = "/tmp/test_verify".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue = open_queue(&path, 5, Options::default()).unwrap();
+            assert!(queue.verify().unwrap().is_ok());
+
+            queue.set_checksums(true);
+            queue.push(&[&[1u8], &[2u8, 3], &[4u8]]).unwrap();
+            queue.pop(1).unwrap();
+            let report = queue.verify().unwrap();
+            assert!(report.is_ok());
+            assert_eq!(report.elements, 2);
+
+            queue.db().unwrap().delete(index_to_key(2)).unwrap();
+            queue.db().unwrap().put(index_to_key(4), [0u8]).unwrap();
+            let mut value = queue.db().unwrap().get(index_to_key(1)).unwrap().unwrap();
+            *value.last_mut().unwrap() ^= 1;
+            queue.db().unwrap().put(index_to_key(1), value).unwrap();
+
+            let report = queue.verify().unwrap();
+            assert_eq!(
+                report.problems,
+                vec![
+                    Problem::CorruptedElement { index: 1 },
+                    Problem::MissingElement { index: 2 },
+                    Problem::UnexpectedElement { index: 4 },
+                ]
+            );
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn verify_payload_size() {
+        let path = "/tmp/test_verify_payload_size".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue.push(&[&[1u8, 2, 3]]).unwrap();
+            put_cell(&queue, SPACE_STAT_CELL, &5u64.to_le_bytes());
+        }
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            let report = queue.verify().unwrap();
+            assert_eq!(
+                report.problems,
+                vec![Problem::PayloadSizeMismatch {
+                    stored: 5,
+                    actual: 3
+                }]
+            );
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn repair() {
+        let path = "/tmp/test_repair".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue.push(&[&[1u8], &[2u8], &[3u8], &[4u8]]).unwrap();
+            queue.pop(2).unwrap();
+            queue.push(&[&[5u8, 6], &[7u8]]).unwrap();
+            assert_eq!(cursors(&queue), (2, 0));
+
+            put_cell(&queue, READ_INDEX_CELL, &1u64.to_le_bytes());
+            put_cell(&queue, WRITE_INDEX_CELL, &[1u8]);
+            put_cell(&queue, SPACE_STAT_CELL, &100u64.to_le_bytes());
+        }
+        PersistentQueueWithCapacity::repair(&path, Options::default()).unwrap();
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            assert_eq!(cursors(&queue), (2, 0));
+            assert_eq!(queue.payload_size(), 5);
+            assert!(queue.verify().unwrap().is_ok());
+            let res = queue.pop(4).unwrap();
+            assert_eq!(res, vec![vec![3u8], vec![4u8], vec![5u8, 6], vec![7u8]]);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn open_verified() {
+        let path = "/tmp/test_open_verified".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = open_queue(&path, 5, Options::default()).unwrap();
+            queue.push(&[&[1u8], &[2u8, 3]]).unwrap();
+            put_cell(&queue, SPACE_STAT_CELL, &100u64.to_le_bytes());
+        }
+        let err = PersistentQueueWithCapacity::open_verified(&path, 5, Options::default(), false)
+            .unwrap_err();
+        assert!(matches!(
+            err,
+            QueueError::Unverified { report, .. } if report.problems
+                == vec![Problem::PayloadSizeMismatch { stored: 100, actual: 3 }]
+        ));
+
+        let queue =
+            PersistentQueueWithCapacity::open_verified(&path, 5, Options::default(), true).unwrap();
+        assert_eq!(queue.payload_size(), 3);
+        assert_eq!(queue.pop(2).unwrap(), vec![vec![1u8], vec![2u8, 3]]);
+        queue.close().unwrap();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn legacy_format_upgrade() {
+        let path = "/tmp/test_legacy_format_upgrade".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let db = DB::open_default(&path).unwrap();
+            db.put(index_to_key(0), [1, 2, 3]).unwrap();
+            db.put(index_to_key(WRITE_INDEX_CELL), 1u64.to_le_bytes())
+                .unwrap();
+            db.put(index_to_key(SPACE_STAT_CELL), 3u64.to_le_bytes())
+                .unwrap();
+        }
+        {
+            let mut queue = PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+            // the system cells are moved to the system column family
+            let system_cf = queue.db().unwrap().cf_handle(SYSTEM_CF).unwrap();
+            assert!(queue
+                .db()
+                .unwrap()
+                .get(index_to_key(WRITE_INDEX_CELL))
+                .unwrap()
+                .is_none());
+            assert!(queue
+                .db()
+                .unwrap()
+                .get_cf(system_cf, index_to_key(WRITE_INDEX_CELL))
+                .unwrap()
+                .is_some());
+            assert_eq!(queue.len(), 1);
+            assert_eq!(queue.payload_size(), 3);
+            assert_eq!(queue.state.lock().format_version, LEGACY_FORMAT_VERSION);
+            queue.push(&[&[4, 5, 6]]).unwrap();
+            assert_eq!(queue.state.lock().format_version, LEGACY_FORMAT_VERSION);
+            // the elements of the legacy format can't be encrypted
+            queue.set_keyring(Some(Keyring::new(1, &[7; crate::codec::KEY_LEN]).unwrap()));
+            assert!(matches!(
+                queue.push(&[&[10]]),
+                Err(QueueError::InvalidArgument(_))
+            ));
+            queue.set_keyring(None);
+            assert_eq!(queue.len(), 2);
+            let res = queue.pop(2).unwrap();
+            assert_eq!(res, vec![vec![1, 2, 3], vec![4, 5, 6]]);
+            queue.push(&[&[7, 8, 9]]).unwrap();
+            assert_eq!(queue.state.lock().format_version, FORMAT_VERSION);
+        }
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+            assert_eq!(queue.state.lock().format_version, FORMAT_VERSION);
+            let res = queue.pop(1).unwrap();
+            assert_eq!(res, vec![vec![7, 8, 9]]);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn new_invalid_max_elements() {
+        let path = "/tmp/test_invalid_max_elements".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        let result = open_queue(&path, (INDEX_SPACE + 1) as usize, Options::default());
+
+        assert_eq!(
+            result
+                .is_err_and(|e| e.to_string()
+                    == format!("max_elements can't be greater than {}", INDEX_SPACE)),
+            true
+        );
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn index_space() {
+        let path = "/tmp/test_index_space".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = open_queue(&path, 3, Options::default()).unwrap();
+            assert_eq!(queue.index_space(), INDEX_SPACE);
+        }
+        {
+            // the stored index space is kept
+            let queue = PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+            assert_eq!(queue.index_space(), INDEX_SPACE);
+        }
+        assert!(matches!(
+            PersistentQueueWithCapacity::with_index_space(&path, 3, 10, Options::default()),
+            Err(QueueError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            PersistentQueueWithCapacity::with_index_space(&path, 3, 0, Options::default()),
+            Err(QueueError::InvalidArgument(_))
+        ));
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+
+        let queue = PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+        assert_eq!(queue.index_space(), DEFAULT_INDEX_SPACE);
+        drop(queue);
+        _ = PersistentQueueWithCapacity::remove_db(&path);
     }
 }
@@ -0,0 +1,251 @@
+use crate::error::{QueueError, Result};
+use parking_lot::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// What a rate limited operation does when the rate is exceeded.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RateLimitStrategy {
+    /// Waits until the rate allows the operation.
+    #[default]
+    Block,
+    /// Fails with [`QueueError::RateLimited`] immediately.
+    FailFast,
+}
+
+/// The maximal rates of an operation, `None` doesn't limit the rate. Bursts of up to one second
+/// of the rate are allowed after the operation is idle.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RateLimit {
+    pub elements_per_sec: Option<f64>,
+    pub bytes_per_sec: Option<f64>,
+    pub strategy: RateLimitStrategy,
+}
+
+impl RateLimit {
+    fn validate(&self) -> Result<()> {
+        for rate in [self.elements_per_sec, self.bytes_per_sec]
+            .into_iter()
+            .flatten()
+        {
+            if !(rate.is_finite() && rate > 0.0) {
+                return Err(QueueError::InvalidArgument(format!(
+                    "The rate must be positive, got {}",
+                    rate
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64) -> Self {
+        Self {
+            rate,
+            tokens: rate,
+            refilled: Instant::now(),
+        }
+    }
+
+    /// Returns the time until the tokens are available. An operation larger than the burst
+    /// waits for the full bucket and takes it into debt.
+    ///
+    fn wait(&mut self, tokens: f64) -> Duration {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * self.rate)
+            .min(self.rate);
+        self.refilled = now;
+        let missing = tokens.min(self.rate) - self.tokens;
+        if missing > 0.0 {
+            Duration::from_secs_f64(missing / self.rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+
+    fn take(&mut self, tokens: f64) {
+        self.tokens -= tokens;
+    }
+}
+
+/// The token buckets of a [`RateLimit`].
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    limit: RateLimit,
+    elements: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(limit: RateLimit) -> Result<Self> {
+        limit.validate()?;
+        Ok(Self {
+            limit,
+            elements: limit.elements_per_sec.map(TokenBucket::new),
+            bytes: limit.bytes_per_sec.map(TokenBucket::new),
+        })
+    }
+
+    pub(crate) fn limit(&self) -> RateLimit {
+        self.limit
+    }
+
+    /// Takes the tokens of both buckets if they are available, otherwise returns the time to
+    /// wait for them.
+    ///
+    fn try_acquire(&mut self, elements: usize, bytes: u64) -> Duration {
+        let wait = |bucket: &mut Option<TokenBucket>, tokens: f64| {
+            bucket.as_mut().map_or(Duration::ZERO, |b| b.wait(tokens))
+        };
+        let wait =
+            wait(&mut self.elements, elements as f64).max(wait(&mut self.bytes, bytes as f64));
+        if wait.is_zero() {
+            self.charge(elements, bytes);
+        }
+        wait
+    }
+
+    /// Returns the time until the buckets are out of the debt of the charged operations.
+    fn ready(&mut self) -> Duration {
+        let wait = |bucket: &mut Option<TokenBucket>| {
+            bucket.as_mut().map_or(Duration::ZERO, |b| b.wait(1.0))
+        };
+        wait(&mut self.elements).max(wait(&mut self.bytes))
+    }
+
+    fn charge(&mut self, elements: usize, bytes: u64) {
+        if let Some(bucket) = self.elements.as_mut() {
+            bucket.take(elements as f64);
+        }
+        if let Some(bucket) = self.bytes.as_mut() {
+            bucket.take(bytes as f64);
+        }
+    }
+}
+
+/// Waits until the rate allows the operation or fails with [`QueueError::RateLimited`],
+/// depending on the strategy. The limiter is not locked while waiting.
+///
+fn wait<F>(limiter: &Mutex<Option<RateLimiter>>, mut f: F) -> Result<()>
+where
+    F: FnMut(&mut RateLimiter) -> Duration,
+{
+    loop {
+        let wait = match limiter.lock().as_mut() {
+            None => return Ok(()),
+            Some(limiter) => {
+                let wait = f(limiter);
+                if !wait.is_zero() && limiter.limit.strategy == RateLimitStrategy::FailFast {
+                    return Err(QueueError::RateLimited);
+                }
+                wait
+            }
+        };
+        if wait.is_zero() {
+            return Ok(());
+        }
+        thread::sleep(wait);
+    }
+}
+
+/// Takes the tokens of the operation of the known size, e.g. of a push.
+pub(crate) fn acquire(
+    limiter: &Mutex<Option<RateLimiter>>,
+    elements: usize,
+    bytes: u64,
+) -> Result<()> {
+    wait(limiter, |limiter| limiter.try_acquire(elements, bytes))
+}
+
+/// Waits for the operation of the size known only after it, e.g. of a pop, which is charged
+/// with [`charge`] then. The operation may exceed the rate, the next ones wait for its debt.
+///
+pub(crate) fn wait_ready(limiter: &Mutex<Option<RateLimiter>>) -> Result<()> {
+    wait(limiter, RateLimiter::ready)
+}
+
+pub(crate) fn charge(limiter: &Mutex<Option<RateLimiter>>, elements: usize, bytes: u64) {
+    if let Some(limiter) = limiter.lock().as_mut() {
+        limiter.charge(elements, bytes);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{acquire, charge, wait_ready, RateLimit, RateLimitStrategy, RateLimiter};
+    use crate::error::QueueError;
+    use parking_lot::Mutex;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn fail_fast() {
+        let limiter = Mutex::new(Some(
+            RateLimiter::new(RateLimit {
+                elements_per_sec: Some(10.0),
+                bytes_per_sec: Some(100.0),
+                strategy: RateLimitStrategy::FailFast,
+            })
+            .unwrap(),
+        ));
+        // the burst of one second
+        acquire(&limiter, 5, 50).unwrap();
+        acquire(&limiter, 5, 10).unwrap();
+        let err = acquire(&limiter, 1, 1).unwrap_err();
+        assert!(matches!(err, QueueError::RateLimited));
+        // the bytes are limited as well
+        std::thread::sleep(Duration::from_millis(500));
+        let err = acquire(&limiter, 1, 99).unwrap_err();
+        assert!(matches!(err, QueueError::RateLimited));
+        acquire(&limiter, 1, 10).unwrap();
+    }
+
+    #[test]
+    fn block() {
+        let limiter = Mutex::new(Some(
+            RateLimiter::new(RateLimit {
+                elements_per_sec: Some(100.0),
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let started = Instant::now();
+        // larger than the burst, it takes the full bucket into debt
+        acquire(&limiter, 150, 0).unwrap();
+        acquire(&limiter, 10, 0).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(500));
+
+        assert!(acquire(&Mutex::new(None), 1000, 0).is_ok());
+        assert!(RateLimiter::new(RateLimit {
+            bytes_per_sec: Some(0.0),
+            ..Default::default()
+        })
+        .is_err());
+    }
+
+    #[test]
+    fn debt() {
+        let limiter = Mutex::new(Some(
+            RateLimiter::new(RateLimit {
+                bytes_per_sec: Some(100.0),
+                strategy: RateLimitStrategy::FailFast,
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        wait_ready(&limiter).unwrap();
+        charge(&limiter, 3, 150);
+        let err = wait_ready(&limiter).unwrap_err();
+        assert!(matches!(err, QueueError::RateLimited));
+        std::thread::sleep(Duration::from_millis(600));
+        wait_ready(&limiter).unwrap();
+    }
+}
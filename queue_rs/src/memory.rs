@@ -0,0 +1,270 @@
+use crate::error::{QueueError, Result};
+use crate::mpmc::StartPosition;
+use crate::queue::{LabeledQueue, QueueConsumer, QueueProducer};
+use crate::utilities::current_timestamp;
+use std::collections::{HashMap, VecDeque};
+use std::time::Duration;
+
+/// The in-memory counterpart of [`PersistentQueueWithCapacity`](crate::PersistentQueueWithCapacity)
+/// with the same API. Elements are lost when the queue is dropped.
+///
+#[derive(Debug, Default)]
+pub struct MemoryQueue {
+    elements: VecDeque<Vec<u8>>,
+    max_elements: usize,
+    payload_size: u64,
+}
+
+impl MemoryQueue {
+    pub fn new(max_elements: usize) -> Self {
+        Self {
+            elements: VecDeque::new(),
+            max_elements,
+            payload_size: 0,
+        }
+    }
+
+    pub fn disk_size(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn payload_size(&self) -> u64 {
+        self.payload_size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+
+    pub fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        if self.len() + values.len() > self.max_elements {
+            return Err(QueueError::Full);
+        }
+        for value in values {
+            self.payload_size += value.len() as u64;
+            self.elements.push_back(value.to_vec());
+        }
+        Ok(())
+    }
+
+    pub fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        let n = max_elts.min(self.elements.len());
+        let res = self.elements.drain(..n).collect::<Vec<_>>();
+        self.payload_size -= res.iter().map(|v| v.len() as u64).sum::<u64>();
+        Ok(res)
+    }
+}
+
+impl QueueProducer for MemoryQueue {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        MemoryQueue::push(self, values)
+    }
+}
+
+impl QueueConsumer for MemoryQueue {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        MemoryQueue::pop(self, max_elts)
+    }
+}
+
+/// The in-memory counterpart of [`MpmcQueue`](crate::mpmc::MpmcQueue) with the same API.
+/// Elements expire strictly after the TTL. Elements and readers are lost when the queue is
+/// dropped.
+///
+#[derive(Debug)]
+pub struct MemoryMpmcQueue {
+    ttl: u64,
+    // elements with their write timestamps
+    elements: VecDeque<(u64, Vec<u8>)>,
+    // the sequence number of the first element, sequence numbers are never reused
+    start: u64,
+    readers: HashMap<String, u64>,
+}
+
+impl MemoryMpmcQueue {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl: ttl.as_nanos() as u64,
+            elements: VecDeque::new(),
+            start: 0,
+            readers: HashMap::new(),
+        }
+    }
+
+    pub fn disk_size(&self) -> Result<usize> {
+        Ok(0)
+    }
+
+    fn first_alive(&self) -> usize {
+        let now = current_timestamp();
+        self.elements
+            .partition_point(|(timestamp, _)| timestamp.saturating_add(self.ttl) <= now)
+    }
+
+    fn expire(&mut self) {
+        let expired = self.first_alive();
+        self.elements.drain(..expired);
+        self.start += expired as u64;
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len() - self.first_alive()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn add(&mut self, values: &[&[u8]]) -> Result<()> {
+        let timestamp = current_timestamp();
+        self.elements
+            .extend(values.iter().map(|v| (timestamp, v.to_vec())));
+        Ok(())
+    }
+
+    pub fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        self.expire();
+        let end = self.start + self.elements.len() as u64;
+        let position = match self.readers.get(label) {
+            Some(position) => *position,
+            None => match start_position {
+                StartPosition::Oldest => self.start,
+                StartPosition::Newest => end.saturating_sub(1).max(self.start),
+                StartPosition::FromTimestamp(timestamp) => {
+                    self.start
+                        + self
+                            .elements
+                            .partition_point(|(added_at, _)| *added_at < timestamp)
+                            as u64
+                }
+            },
+        };
+        let expired = position < self.start;
+        let position = position.max(self.start);
+
+        let offset = (position - self.start) as usize;
+        let res = self
+            .elements
+            .range(offset..)
+            .take(max_elts)
+            .map(|(_, v)| v.clone())
+            .collect::<Vec<_>>();
+        self.readers
+            .insert(label.to_string(), position + res.len() as u64);
+
+        Ok((res, expired))
+    }
+
+    pub fn get_labels(&self) -> Vec<String> {
+        self.readers.keys().cloned().collect()
+    }
+
+    pub fn remove_label(&mut self, label: &str) -> Result<bool> {
+        Ok(self.readers.remove(label).is_some())
+    }
+}
+
+impl QueueProducer for MemoryMpmcQueue {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        self.add(values)
+    }
+}
+
+impl LabeledQueue for MemoryMpmcQueue {
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        MemoryMpmcQueue::next(self, max_elts, label, start_position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::queue::LabeledConsumer;
+    use std::thread::sleep;
+
+    #[test]
+    fn test_memory_queue() {
+        let mut queue = MemoryQueue::new(2);
+        queue.push(&[&[1u8, 2], &[3u8]]).unwrap();
+        assert!(queue.push(&[&[4u8]]).is_err());
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.payload_size(), 3);
+
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![1u8, 2]]);
+        assert_eq!(queue.pop(5).unwrap(), vec![vec![3u8]]);
+        assert!(queue.pop(1).unwrap().is_empty());
+        assert!(queue.is_empty());
+        assert_eq!(queue.payload_size(), 0);
+    }
+
+    #[test]
+    fn test_memory_mpmc_queue() {
+        let mut queue = MemoryMpmcQueue::new(Duration::from_secs(10));
+        assert_eq!(
+            queue.next(1, "newest", StartPosition::Newest).unwrap(),
+            (vec![], false)
+        );
+        queue.add(&[&[1u8], &[2u8], &[3u8]]).unwrap();
+        assert_eq!(queue.len(), 3);
+
+        assert_eq!(
+            queue.next(2, "oldest", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8], vec![2u8]], false)
+        );
+        assert_eq!(
+            queue.next(2, "oldest", StartPosition::Oldest).unwrap(),
+            (vec![vec![3u8]], false)
+        );
+        assert_eq!(
+            queue.next(5, "newest", StartPosition::Newest).unwrap(),
+            (vec![vec![1u8], vec![2u8], vec![3u8]], false)
+        );
+        assert_eq!(
+            queue.next(5, "latest", StartPosition::Newest).unwrap(),
+            (vec![vec![3u8]], false)
+        );
+
+        let mut labels = queue.get_labels();
+        labels.sort();
+        assert_eq!(labels, vec!["latest", "newest", "oldest"]);
+        assert!(queue.remove_label("latest").unwrap());
+        assert!(!queue.remove_label("latest").unwrap());
+    }
+
+    #[test]
+    fn test_memory_mpmc_queue_expiration() {
+        let mut queue = MemoryMpmcQueue::new(Duration::from_millis(10));
+        queue.add(&[&[1u8], &[2u8]]).unwrap();
+        assert_eq!(
+            queue.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8]], false)
+        );
+
+        sleep(Duration::from_millis(20));
+        assert!(queue.is_empty());
+        queue.add(&[&[3u8]]).unwrap();
+
+        assert_eq!(
+            queue.next(5, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![3u8]], true)
+        );
+
+        queue.add(&[&[4u8]]).unwrap();
+        let mut consumer = LabeledConsumer::new(&mut queue, "label", StartPosition::Oldest);
+        assert_eq!(consumer.pop(5).unwrap(), vec![vec![4u8]]);
+    }
+}
@@ -0,0 +1,176 @@
+use crate::error::{QueueError, Result};
+use crate::mpmc::{
+    timestamp_entry, MpmcQueue, CHUNKED_KEY, DATA_CF, FORMAT_VERSION_KEY, INDEX_SPACE_KEY,
+    PAIRED_KEY, PAYLOAD_CF, START_INDEX_KEY, SYSTEM_CF, TIMESTAMP_CF, WRITE_INDEX_KEY,
+    WRITE_TIMESTAMP_KEY,
+};
+use crate::utilities::{current_timestamp, index_to_key, key_to_index};
+use crate::verify::in_range;
+use crate::{PersistentQueueWithCapacity, U64_BYTE_LEN};
+use rocksdb::{IteratorMode, Options, WriteBatch};
+use std::path::Path;
+use std::time::Duration;
+
+const MIGRATION_BATCH_SIZE: usize = 1024;
+
+/// Converts the [`PersistentQueueWithCapacity`] database into a new [`MpmcQueue`] database
+/// preserving the order of the unconsumed elements. Elements are copied as stored (compressed,
+/// encrypted, chunked), so the queue must be opened with the same settings afterwards. The
+/// source database is left intact. The TTL of the migrated elements counts from the migration.
+/// The MPMC queue takes the index space of the source one.
+///
+pub fn migrate_to_mpmc<P, Q>(src_path: P, dst_path: Q, ttl: Duration) -> Result<()>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+{
+    let dst_path = dst_path.as_ref();
+    if dst_path.exists() {
+        return Err(QueueError::InvalidArgument(format!(
+            "Destination {} already exists",
+            dst_path.display()
+        )));
+    }
+
+    // the source queue is only read, so its capacity doesn't matter
+    let src = PersistentQueueWithCapacity::new(src_path, 0, Options::default())?;
+    let index_space = src.index_space();
+    let dst = MpmcQueue::open_db(dst_path, ttl, Options::default(), false)?;
+    let data_cf = dst.cf_handle(DATA_CF).unwrap();
+    let system_cf = dst.cf_handle(SYSTEM_CF).unwrap();
+    let state = src.state.lock();
+
+    // both queues share the index space, so elements and their chunks keep their keys
+    let mut batch = WriteBatch::default();
+    for item in src.db()?.iterator(IteratorMode::Start) {
+        let (key, value) = item?;
+        if key.len() < U64_BYTE_LEN {
+            continue;
+        }
+        let index = key_to_index(&key);
+        if index >= index_space
+            || !in_range(index, state.read_index, state.write_index, state.empty)
+        {
+            continue;
+        }
+        batch.put_cf(data_cf, key, value);
+        if batch.len() >= MIGRATION_BATCH_SIZE {
+            dst.write(std::mem::take(&mut batch))?;
+        }
+    }
+
+    if state.paired {
+        let src_payload_cf = src.db()?.cf_handle(crate::PAYLOAD_CF).unwrap();
+        let payload_cf = dst.cf_handle(PAYLOAD_CF).unwrap();
+        for item in src.db()?.iterator_cf(src_payload_cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            if !in_range(
+                key_to_index(&key),
+                state.read_index,
+                state.write_index,
+                state.empty,
+            ) {
+                continue;
+            }
+            batch.put_cf(payload_cf, key, value);
+            if batch.len() >= MIGRATION_BATCH_SIZE {
+                dst.write(std::mem::take(&mut batch))?;
+            }
+        }
+        batch.put_cf(system_cf, index_to_key(PAIRED_KEY), [1u8]);
+    }
+
+    let start_index = if state.empty {
+        state.write_index
+    } else {
+        state.read_index
+    };
+    batch.put_cf(
+        system_cf,
+        index_to_key(START_INDEX_KEY),
+        start_index.to_le_bytes(),
+    );
+    batch.put_cf(
+        system_cf,
+        index_to_key(WRITE_INDEX_KEY),
+        state.write_index.to_le_bytes(),
+    );
+    let write_timestamp = current_timestamp();
+    batch.put_cf(
+        system_cf,
+        index_to_key(WRITE_TIMESTAMP_KEY),
+        write_timestamp.to_le_bytes(),
+    );
+    if !state.empty {
+        // the migrated elements count as added at once
+        let timestamp_cf = dst.cf_handle(TIMESTAMP_CF).unwrap();
+        batch.put_cf(
+            timestamp_cf,
+            timestamp_entry(write_timestamp, start_index),
+            [],
+        );
+    }
+    batch.put_cf(
+        system_cf,
+        index_to_key(FORMAT_VERSION_KEY),
+        state.format_version.to_le_bytes(),
+    );
+    batch.put_cf(
+        system_cf,
+        index_to_key(INDEX_SPACE_KEY),
+        index_space.to_le_bytes(),
+    );
+    if state.chunked {
+        batch.put_cf(system_cf, index_to_key(CHUNKED_KEY), [1u8]);
+    }
+    dst.write(batch)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::Compression;
+    use crate::mpmc::StartPosition;
+
+    #[test]
+    fn test_migrate_to_mpmc() {
+        let src_path = "/tmp/test_migrate_to_mpmc_src".to_string();
+        let dst_path = "/tmp/test_migrate_to_mpmc_dst".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&src_path);
+        _ = std::fs::remove_dir_all(&dst_path);
+        let ttl = Duration::from_secs(10);
+        let big = (0..100u8).collect::<Vec<_>>();
+        {
+            let mut queue =
+                PersistentQueueWithCapacity::with_index_space(&src_path, 5, 6, Options::default())
+                    .unwrap();
+            queue.set_chunk_size(16);
+            queue.set_compression(Compression::Lz4);
+            queue.push(&[&[1u8], &[2u8], &[3u8], &[4u8]]).unwrap();
+            queue.pop(3).unwrap();
+            queue.push(&[&big, &[5u8]]).unwrap();
+        }
+        migrate_to_mpmc(&src_path, &dst_path, ttl).unwrap();
+        assert!(migrate_to_mpmc(&src_path, &dst_path, ttl).is_err());
+        {
+            let mut queue = MpmcQueue::new(&dst_path, ttl).unwrap();
+            queue.set_chunk_size(16);
+            queue.set_compression(Compression::Lz4);
+            assert_eq!(queue.len(), 3);
+            assert_eq!(queue.index_space(), 6);
+            assert!(queue.verify().unwrap().is_ok());
+
+            let result = queue.next(5, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(result.0, vec![vec![4u8], big.clone(), vec![5u8]]);
+            assert_eq!(result.1, false);
+        }
+        {
+            let queue = PersistentQueueWithCapacity::new(&src_path, 5, Options::default()).unwrap();
+            assert_eq!(queue.len(), 3);
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&src_path);
+        _ = std::fs::remove_dir_all(&dst_path);
+    }
+}
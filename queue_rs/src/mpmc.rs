@@ -1,28 +1,80 @@
-use std::cmp::Ordering;
-use std::collections::HashMap;
-use std::time::Duration;
-
-use anyhow::{Error, Result};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::error::{QueueError, Result};
+use crate::events::{Listeners, QueueEvent};
+use crate::limiter::{self, RateLimit, RateLimiter};
+use crate::options::{QueueOptions, RemoveOptions};
+use crate::stats::{
+    DbStats, MpmcQueueStats, ReaderPosition, SizeStats, StatsSnapshot, StorageModel,
+};
+use crate::trace::OpSpan;
 use bincode::config::Configuration;
 use bincode::{Decode, Encode};
-use rocksdb::{ColumnFamilyDescriptor, Direction, IteratorMode, Options, SliceTransform, DB};
+use parking_lot::Mutex;
+use rocksdb::{
+    ColumnFamilyDescriptor, Direction, ErrorKind, IteratorMode, Options, SliceTransform,
+    WriteOptions, DB,
+};
 
+use crate::alerts::{DiskAlerts, DiskMonitor};
+use crate::codec::{Codec, Compression, Keyring, FORMAT_VERSION, LEGACY_FORMAT_VERSION};
+use crate::counters;
+use crate::discovery::{Inspection, QueueKind};
 use crate::utilities::{
-    current_timestamp, index_to_key, key_to_index, next_index, previous_index, u64_from_byte_vec,
+    self, current_timestamp, db_error, first_stored, index_to_key, key_to_index, next_index,
+    payload_cf_options, previous_index, u64_from_byte_vec,
+};
+use crate::verify::{in_range, ElementsScan, Problem, VerificationReport};
+use crate::{
+    check_index_space, chunking, fs, repair, PersistentQueueWithCapacity, DEFAULT_INDEX_SPACE,
 };
-use crate::{fs, MAX_ALLOWED_INDEX};
 
-const DATA_CF: &str = "data";
-const SYSTEM_CF: &str = "system";
+pub(crate) const DATA_CF: &str = "data";
+pub(crate) const SYSTEM_CF: &str = "system";
 const READER_CF: &str = "reader";
-const START_INDEX_KEY: u64 = u64::MAX;
-const WRITE_INDEX_KEY: u64 = u64::MAX - 1;
-const WRITE_TIMESTAMP_KEY: u64 = u64::MAX - 2;
-
-#[derive(Clone, Copy)]
+/// The times the elements were added at, they expire together with the elements.
+const TIME_CF: &str = "time";
+/// The payloads of the paired elements, see [`MpmcQueue::add_pairs`].
+pub(crate) const PAYLOAD_CF: &str = "payload";
+/// The first indices of the added batches by their timestamps: the big endian timestamp and the
+/// index map to nothing, so the batch added at or after a time is found with a seek.
+///
+pub(crate) const TIMESTAMP_CF: &str = "timestamp";
+/// The indices of the latest elements by their keys, see [`MpmcQueue::add_with_keys`].
+const KEY_CF: &str = "key";
+/// The indices of the elements superseded by the later ones with the same keys map to nothing,
+/// so the readers tell them from the expired elements. The markers are written after the
+/// elements, so they expire after them.
+///
+const SUPERSEDED_CF: &str = "superseded";
+pub(crate) const START_INDEX_KEY: u64 = u64::MAX;
+pub(crate) const WRITE_INDEX_KEY: u64 = u64::MAX - 1;
+pub(crate) const WRITE_TIMESTAMP_KEY: u64 = u64::MAX - 2;
+pub(crate) const CHUNKED_KEY: u64 = u64::MAX - 3;
+pub(crate) const FORMAT_VERSION_KEY: u64 = u64::MAX - 4;
+const SIZES_KEY: u64 = u64::MAX - 5;
+/// The number of the indices the queue was created with, see [`MpmcQueue::with_index_space`].
+pub(crate) const INDEX_SPACE_KEY: u64 = u64::MAX - 6;
+pub(crate) const PAIRED_KEY: u64 = u64::MAX - 7;
+const KEYED_KEY: u64 = u64::MAX - 8;
+/// Marks the queue the stored elements of which carry the checksums, see
+/// [`MpmcQueue::set_checksums`].
+///
+const CHECKSUMS_KEY: u64 = u64::MAX - 9;
+/// The length of the timestamp RocksDB appends to the values of the databases opened with a
+/// TTL, the values read without it end with the timestamp.
+///
+const TTL_SUFFIX_LEN: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum StartPosition {
     Oldest,
     Newest,
+    /// The first element added at or after the timestamp, nanoseconds since the epoch.
+    FromTimestamp(u64),
 }
 
 #[derive(Encode, Decode, PartialEq, Debug, Clone)]
@@ -33,6 +85,11 @@ struct Reader {
 }
 
 impl Reader {
+    /// Returns `true` if the reader has read all elements written so far.
+    fn at_end(&self, write_index: u64, write_timestamp: u64) -> bool {
+        self.index == write_index && self.end_timestamp == Some(write_timestamp)
+    }
+
     fn new(index: u64, end_timestamp: Option<u64>, expired: bool) -> Self {
         Self {
             index,
@@ -42,22 +99,198 @@ impl Reader {
     }
 }
 
+/// Protects the elements not read by the active readers from the expiration, see
+/// [`MpmcQueue::set_retention_guard`].
+///
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RetentionGuard {
+    /// The readers which haven't read for this time are idle, they don't protect the elements.
+    pub idle_after: Duration,
+    /// The elements are not protected while the queue takes more disk bytes.
+    pub max_disk_size: u64,
+}
+
+/// When the positions of the readers are written, see [`MpmcQueue::set_reader_persistence`].
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ReaderPersistence {
+    /// With every read which moves the reader.
+    #[default]
+    EveryRead,
+    /// Once the reader has read the number of the elements since its position was written.
+    EveryElements(u64),
+    /// With the first read after the interval since the position of the reader was changed.
+    Interval(Duration),
+}
+
+/// The start positions of the new readers, see [`MpmcQueue::set_start_policy`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct StartPolicy {
+    /// The position of the readers whose labels don't match the prefixes.
+    pub default: StartPosition,
+    /// The positions of the readers by the prefixes of their labels, the longest matching
+    /// prefix wins.
+    pub prefixes: Vec<(String, StartPosition)>,
+}
+
+impl StartPolicy {
+    pub fn new(default: StartPosition) -> Self {
+        Self {
+            default,
+            prefixes: Vec::new(),
+        }
+    }
+
+    pub fn with_prefix(mut self, prefix: &str, position: StartPosition) -> Self {
+        self.prefixes.push((prefix.to_string(), position));
+        self
+    }
+
+    /// Returns the start position of the new reader with the label.
+    pub fn position(&self, label: &str) -> StartPosition {
+        self.prefixes
+            .iter()
+            .filter(|(prefix, _)| label.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.default, |(_, position)| *position)
+    }
+}
+
+/// The positions of the readers exported by [`MpmcQueue::export_labels`], e.g. to restore them
+/// with the backup of the data by [`MpmcQueue::import_labels`]. It is stored with
+/// [`Self::to_bytes`].
+///
+#[derive(Encode, Decode, PartialEq, Debug, Clone)]
+pub struct LabelCheckpoint {
+    write_index: u64,
+    write_timestamp: u64,
+    readers: Vec<(String, Reader)>,
+}
+
+impl LabelCheckpoint {
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(bytes, bincode::config::standard())?.0)
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    /// Returns the labels with the indices of the next elements of the readers.
+    pub fn positions(&self) -> Vec<(&str, u64)> {
+        self.readers
+            .iter()
+            .map(|(label, reader)| (label.as_str(), reader.index))
+            .collect()
+    }
+}
+
+/// Stores the positions of the readers instead of the queue, see [`MpmcQueue::set_offset_store`].
+/// The positions are opaque bytes, e.g. the store of a consumer keeping the results of the
+/// processing in its own transactional database writes them in the transaction committing the
+/// results of the read elements, so the elements are processed exactly once.
+///
+pub trait OffsetStore: Send + Sync {
+    /// Returns the stored positions with their labels.
+    fn load(&mut self) -> Result<Vec<(String, Vec<u8>)>>;
+
+    /// Stores the positions of the readers, replacing the stored ones with the same labels.
+    fn store(&mut self, positions: &[(String, Vec<u8>)]) -> Result<()>;
+
+    fn remove(&mut self, label: &str) -> Result<()>;
+
+    /// Returns the queue the positions are stored in by [`QueueOffsetStore`], `None` for the
+    /// other stores.
+    ///
+    fn destination(&self) -> Option<&Arc<PersistentQueueWithCapacity>> {
+        None
+    }
+}
+
+/// Keeps the positions of the readers in the destination queue of [`MpmcQueue::process`], so
+/// they are written together with the outputs of the processed elements.
+///
+pub struct QueueOffsetStore(Arc<PersistentQueueWithCapacity>);
+
+impl QueueOffsetStore {
+    pub fn new(destination: Arc<PersistentQueueWithCapacity>) -> Self {
+        Self(destination)
+    }
+}
+
+impl OffsetStore for QueueOffsetStore {
+    fn load(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+        self.0.offsets()
+    }
+
+    fn store(&mut self, positions: &[(String, Vec<u8>)]) -> Result<()> {
+        self.0.push_with_offsets(&[], positions)
+    }
+
+    fn remove(&mut self, label: &str) -> Result<()> {
+        self.0.remove_offset(label)
+    }
+
+    fn destination(&self) -> Option<&Arc<PersistentQueueWithCapacity>> {
+        Some(&self.0)
+    }
+}
+
 pub struct MpmcQueue {
-    db: DB,
-    path: String,
+    db: Option<DB>,
+    db_opts: Options,
+    pub(crate) path: PathBuf,
+    ttl: Duration,
     empty: bool,
     start_index: u64,
     write_index: u64,
     write_timestamp: u64,
+    index_space: u64,
     read_indices: HashMap<String, Reader>,
     configuration: Configuration,
+    chunk_size: usize,
+    chunked: bool,
+    paired: bool,
+    // the elements are superseded by the later ones with the same keys, see `add_with_keys`
+    keyed: bool,
+    codec: Codec,
+    format_version: u64,
+    // the stored elements carry the checksums, see `set_checksums`
+    checksums: bool,
+    skip_corrupted: bool,
+    sync_writes: bool,
+    // the elements may be evicted by the compaction filter, see `QueueOptions::eviction_filter`
+    evicting: bool,
+    strict_ttl: bool,
+    retention_guard: Option<RetentionGuard>,
+    // the last read times of the labels and the last time the unread elements were protected
+    read_times: HashMap<String, u64>,
+    guarded_at: u64,
+    prefetch: usize,
+    // the index the last reader stopped at, see `prefetch_next`
+    prefetch_from: Option<u64>,
+    reader_persistence: ReaderPersistence,
+    start_policy: Option<StartPolicy>,
+    // the groups of the labels sharing their positions by the labels
+    groups: HashMap<String, String>,
+    // the readers whose positions are not written with the number of the elements read since
+    // the last write and the time of the first change
+    unwritten: HashMap<String, (u64, Instant)>,
+    offset_store: Option<Box<dyn OffsetStore>>,
+    listeners: Listeners,
+    disk_monitor: DiskMonitor,
+    push_limiter: Mutex<Option<RateLimiter>>,
+    pop_limiter: Mutex<Option<RateLimiter>>,
+    corrupted: Vec<u64>,
+    // the numbers of the elements added and expired since the queue was opened
+    added: u64,
+    expired: u64,
+    sizes: SizeStats,
 }
 
 impl MpmcQueue {
-    pub fn new(path: &str, ttl: Duration) -> Result<Self> {
-        let configuration = bincode::config::standard();
-
-        let mut cf_opts = Options::default();
+    /// The data column family takes the tuning of the database options, e.g. the write buffers.
+    fn column_families(db_opts: &Options) -> Vec<ColumnFamilyDescriptor> {
+        let mut cf_opts = db_opts.clone();
         cf_opts.create_if_missing(true);
         cf_opts.set_prefix_extractor(SliceTransform::create_fixed_prefix(crate::U64_BYTE_LEN));
         let data_cf = ColumnFamilyDescriptor::new(DATA_CF, cf_opts);
@@ -67,17 +300,80 @@ impl MpmcQueue {
         let system_cf = ColumnFamilyDescriptor::new(SYSTEM_CF, cf_opts);
 
         let reader_cf = ColumnFamilyDescriptor::new(READER_CF, Options::default());
+        let time_cf = ColumnFamilyDescriptor::new(TIME_CF, Options::default());
+        let payload_cf = ColumnFamilyDescriptor::new(PAYLOAD_CF, payload_cf_options());
+        let timestamp_cf = ColumnFamilyDescriptor::new(TIMESTAMP_CF, Options::default());
+        let key_cf = ColumnFamilyDescriptor::new(KEY_CF, Options::default());
+        let superseded_cf = ColumnFamilyDescriptor::new(SUPERSEDED_CF, Options::default());
 
-        let mut db_opts = Options::default();
+        vec![
+            system_cf,
+            data_cf,
+            reader_cf,
+            time_cf,
+            payload_cf,
+            timestamp_cf,
+            key_cf,
+            superseded_cf,
+        ]
+    }
+
+    pub(crate) fn open_db(
+        path: &Path,
+        ttl: Duration,
+        mut db_opts: Options,
+        repair: bool,
+    ) -> Result<DB> {
         db_opts.create_missing_column_families(true);
         db_opts.create_if_missing(true);
 
-        let db = DB::open_cf_descriptors_with_ttl(
-            &db_opts,
-            path,
-            vec![system_cf, data_cf, reader_cf],
-            ttl,
-        )?;
+        let open = || {
+            DB::open_cf_descriptors_with_ttl(&db_opts, path, Self::column_families(&db_opts), ttl)
+        };
+        if repair {
+            repair::open_or_repair(&db_opts, path, open)
+        } else {
+            open().map_err(|e| db_error(path, e))
+        }
+    }
+
+    pub fn new<P: AsRef<Path>>(path: P, ttl: Duration) -> Result<Self> {
+        Self::with_options(path, ttl, Options::default())
+    }
+
+    /// Opens the queue with the RocksDB options, e.g. to tune the write buffers or the block
+    /// cache. The options required by the queue are set on top of them.
+    ///
+    pub fn with_options<P: AsRef<Path>>(path: P, ttl: Duration, db_opts: Options) -> Result<Self> {
+        Self::open_with(path.as_ref(), ttl, None, db_opts)
+    }
+
+    /// Opens the queue storing the elements under `index_space` indices, e.g. a small ring in
+    /// tests or embedded deployments. The index space is stored with every added batch, so it
+    /// can't be changed until all elements are expired: opening the queue with a different one
+    /// fails with [`QueueError::InvalidArgument`]. [`Self::with_options`] opens the queue with
+    /// the stored index space, [`DEFAULT_INDEX_SPACE`] for the new queues.
+    ///
+    pub fn with_index_space<P: AsRef<Path>>(
+        path: P,
+        ttl: Duration,
+        index_space: u64,
+        db_opts: Options,
+    ) -> Result<Self> {
+        Self::open_with(path.as_ref(), ttl, Some(index_space), db_opts)
+    }
+
+    fn open_with(
+        path: &Path,
+        ttl: Duration,
+        requested: Option<u64>,
+        db_opts: Options,
+    ) -> Result<Self> {
+        if let Some(index_space) = requested {
+            check_index_space(index_space)?;
+        }
+        let configuration = bincode::config::standard();
+        let db = Self::open_db(path, ttl, db_opts.clone(), false)?;
 
         let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
         let start_index_opt = db.get_cf(&system_cf, index_to_key(START_INDEX_KEY))?;
@@ -95,6 +391,17 @@ impl MpmcQueue {
             Some(v) => u64_from_byte_vec(&v),
             None => current_timestamp(),
         };
+        let chunked = db.get_cf(&system_cf, index_to_key(CHUNKED_KEY))?.is_some();
+        let paired = db.get_cf(&system_cf, index_to_key(PAIRED_KEY))?.is_some();
+        let keyed = db.get_cf(&system_cf, index_to_key(KEYED_KEY))?.is_some();
+        let checksums = db
+            .get_cf(&system_cf, index_to_key(CHECKSUMS_KEY))?
+            .is_some();
+        let format_version_opt = db.get_cf(&system_cf, index_to_key(FORMAT_VERSION_KEY))?;
+        let sizes = match db.get_cf(&system_cf, index_to_key(SIZES_KEY))? {
+            Some(v) => SizeStats::from_bytes(&v)?,
+            None => SizeStats::default(),
+        };
 
         let data_cf = db.cf_handle(DATA_CF).unwrap();
         let mut empty = true;
@@ -107,258 +414,2064 @@ impl MpmcQueue {
             break;
         }
 
+        let format_version = match format_version_opt {
+            Some(v) => u64_from_byte_vec(&v),
+            None if empty => FORMAT_VERSION,
+            None => LEGACY_FORMAT_VERSION,
+        };
+
+        // the index space expires together with the elements, the empty queue takes the
+        // requested one
+        let index_space = match db.get_cf(&system_cf, index_to_key(INDEX_SPACE_KEY))? {
+            Some(v) => u64_from_byte_vec(&v),
+            None => {
+                let index_space = match requested {
+                    Some(index_space) if empty && write_index < index_space => index_space,
+                    _ => DEFAULT_INDEX_SPACE,
+                };
+                db.put_cf(
+                    &system_cf,
+                    index_to_key(INDEX_SPACE_KEY),
+                    index_space.to_le_bytes(),
+                )?;
+                index_space
+            }
+        };
+        if let Some(requested) = requested.filter(|requested| *requested != index_space) {
+            return Err(QueueError::InvalidArgument(format!(
+                "The queue was created with the index space of {}, not {}",
+                index_space, requested
+            )));
+        }
+
         let mut read_indices = HashMap::new();
         let reader_cf = db.cf_handle(READER_CF).unwrap();
         let iterator = db.iterator_cf(reader_cf, IteratorMode::Start);
         for item in iterator {
             let (key, value) = item?;
 
-            let key = String::from_utf8(Vec::from(key)).map_err(Error::from)?;
+            let key = String::from_utf8(Vec::from(key))
+                .map_err(|e| QueueError::Serialization(e.to_string()))?;
             let value = bincode::decode_from_slice(&value, configuration)?.0;
 
             read_indices.insert(key, value);
         }
+        // the readers are active until they are idle for the time of the retention guard
+        let now = current_timestamp();
+        let read_times = read_indices
+            .keys()
+            .map(|label| (label.clone(), now))
+            .collect();
+        log::info!(
+            "Opened the MPMC queue at {} with {} readers",
+            path.display(),
+            read_indices.len()
+        );
 
         Ok(Self {
-            db,
-            path: path.to_string(),
+            db: Some(db),
+            db_opts,
+            path: path.to_path_buf(),
+            ttl,
             empty,
             start_index,
             write_index,
             write_timestamp,
+            index_space,
             read_indices,
             configuration,
+            chunk_size: 0,
+            chunked,
+            paired,
+            keyed,
+            codec: Codec::default(),
+            format_version,
+            checksums,
+            skip_corrupted: false,
+            evicting: false,
+            strict_ttl: false,
+            retention_guard: None,
+            read_times,
+            guarded_at: 0,
+            prefetch: 0,
+            prefetch_from: None,
+            reader_persistence: ReaderPersistence::default(),
+            start_policy: None,
+            groups: HashMap::new(),
+            unwritten: HashMap::new(),
+            offset_store: None,
+            sync_writes: false,
+            listeners: Listeners::default(),
+            disk_monitor: DiskMonitor::default(),
+            push_limiter: Mutex::new(None),
+            pop_limiter: Mutex::new(None),
+            corrupted: Vec::new(),
+            added: 0,
+            expired: 0,
+            sizes,
         })
     }
 
-    pub fn remove_db(path: &str) -> Result<()> {
-        Ok(DB::destroy(&Options::default(), path)?)
+    /// Sets the compression applied to added elements. Queues created by versions without the
+    /// element header keep storing elements as is until all elements are expired.
+    ///
+    pub fn set_compression(&mut self, compression: Compression) {
+        self.codec.compression = compression;
     }
 
-    pub fn disk_size(&self) -> Result<usize> {
-        Ok(fs::dir_size(&self.path)?)
+    pub fn compression(&self) -> Compression {
+        self.codec.compression
     }
 
-    pub fn len(&self) -> usize {
-        if self.empty {
-            0
-        } else {
-            (match self.write_index.cmp(&self.start_index) {
-                Ordering::Less => MAX_ALLOWED_INDEX - self.start_index + self.write_index,
-                Ordering::Equal => MAX_ALLOWED_INDEX,
-                Ordering::Greater => self.write_index - self.start_index,
-            }) as usize
-        }
+    /// Sets the keyring used to encrypt added elements with its active key and to decrypt
+    /// stored ones. `None` disables encryption of new elements; encrypted elements can't be
+    /// read without the keyring. The queues created by versions without the element header
+    /// store the elements as is until they expire completely, so the adds to them fail with
+    /// [`QueueError::InvalidArgument`] while the keyring is set.
+    ///
+    pub fn set_keyring(&mut self, keyring: Option<Keyring>) {
+        self.codec.keyring = keyring;
     }
 
-    pub fn is_empty(&self) -> bool {
-        self.empty
+    pub fn keyring(&self) -> Option<&Keyring> {
+        self.codec.keyring.as_ref()
     }
 
-    pub fn add(&mut self, values: &[&[u8]]) -> Result<()> {
-        if values.is_empty() {
-            return Ok(());
-        }
-        self.actualize_indices()?;
-        if self.len() + values.len() > MAX_ALLOWED_INDEX as usize {
-            return Err(anyhow::anyhow!("Queue is full"));
-        }
+    /// Enables storing the CRC32C checksum with added elements. The queue records whether its
+    /// elements carry the checksums, so an element whose flag disagrees is corrupted, and the
+    /// setting applies from the first add to the empty queue, until then the elements are
+    /// stored the way the stored ones are.
+    ///
+    pub fn set_checksums(&mut self, checksums: bool) {
+        self.codec.checksums = checksums;
+    }
 
-        let data_cf = self.db.cf_handle(DATA_CF).unwrap();
-        let system_cf = self.db.cf_handle(SYSTEM_CF).unwrap();
-        let mut batch = rocksdb::WriteBatch::default();
-        let mut write_index = self.write_index;
+    pub fn checksums(&self) -> bool {
+        self.codec.checksums
+    }
 
-        for value in values {
-            batch.put_cf(data_cf, index_to_key(write_index), value);
-            write_index = next_index(write_index);
-        }
+    /// If enabled, elements failing the checksum verification are skipped instead of returning
+    /// [`QueueError::Corrupted`]. The indices of the skipped elements are collected and can be
+    /// retrieved with [`take_corrupted`](Self::take_corrupted).
+    ///
+    pub fn set_skip_corrupted(&mut self, skip_corrupted: bool) {
+        self.skip_corrupted = skip_corrupted;
+    }
 
-        batch.put_cf(
-            system_cf,
-            index_to_key(WRITE_INDEX_KEY),
-            write_index.to_le_bytes(),
-        );
-        let write_timestamp = current_timestamp();
-        batch.put_cf(
-            system_cf,
-            index_to_key(WRITE_TIMESTAMP_KEY),
-            write_timestamp.to_le_bytes(),
-        );
+    pub fn skip_corrupted(&self) -> bool {
+        self.skip_corrupted
+    }
 
-        self.db.write(batch)?;
-        self.write_index = write_index;
-        self.write_timestamp = write_timestamp;
-        self.empty = false;
+    /// Returns the indices of the corrupted elements skipped since the previous call.
+    pub fn take_corrupted(&mut self) -> Vec<u64> {
+        std::mem::take(&mut self.corrupted)
+    }
 
-        Ok(())
+    /// If enabled, the elements which have outlived the TTL are never returned even if RocksDB
+    /// hasn't removed them yet: they are skipped and reported as expired. The add times of the
+    /// elements are read with every element then. Disabled by default, the TTL of RocksDB is
+    /// not strict.
+    ///
+    pub fn set_strict_ttl(&mut self, strict_ttl: bool) {
+        self.strict_ttl = strict_ttl;
     }
 
-    pub fn next(
-        &mut self,
-        max_elts: usize,
-        label: &str,
-        start_position: StartPosition,
-    ) -> Result<(Vec<Vec<u8>>, bool)> {
-        let mut res = Vec::with_capacity(max_elts);
+    pub fn strict_ttl(&self) -> bool {
+        self.strict_ttl
+    }
 
-        self.actualize_indices()?;
-        let label = label.to_string();
-        let data_cf = self.db.cf_handle(DATA_CF).unwrap();
-        let reader_cf = self.db.cf_handle(READER_CF).unwrap();
-        let mut reader = match self.read_indices.get(&label) {
-            Some(e) => e.clone(),
-            None => {
-                let index = match start_position {
-                    StartPosition::Oldest => self.start_index,
-                    StartPosition::Newest => {
-                        if self.empty {
-                            self.write_index
-                        } else {
-                            previous_index(self.write_index)
-                        }
-                    }
-                };
-                Reader::new(index, None, false)
-            }
-        };
+    /// If set, the elements not read by the active readers don't expire while the queue takes
+    /// less disk bytes than the ceiling: they are rewritten with the adds and the reads, which
+    /// restarts their TTL, at most every quarter of the TTL. A reader is active until it
+    /// doesn't read for the idle time of the guard, the readers of an opened queue are active.
+    /// Disabled by default, the strict TTL mode still skips the protected elements.
+    ///
+    pub fn set_retention_guard(&mut self, guard: Option<RetentionGuard>) {
+        self.retention_guard = guard;
+    }
 
-        let mut end = match reader.end_timestamp {
-            None => reader.index == self.write_index && self.empty,
-            Some(timestamp) => timestamp == self.write_timestamp,
-        };
+    pub fn retention_guard(&self) -> Option<RetentionGuard> {
+        self.retention_guard
+    }
 
-        while !end && res.len() < max_elts {
-            let value = self.db.get_cf(data_cf, index_to_key(reader.index))?;
-            if let Some(v) = value {
-                res.push(v);
-            } else {
-                res.clear();
-                reader.expired = true;
-            }
-            reader.index = next_index(reader.index);
-            end = reader.index == self.write_index;
-        }
+    /// Sets when the positions of the readers are written, e.g. to spare the write of every
+    /// read of the frequent small reads. The positions which are not written are written by
+    /// [`Self::flush_readers`], when the queue is closed or dropped and when the expired
+    /// elements are trimmed. After a crash, a reader gets again the elements read since its
+    /// position was written: up to the number of the elements, or the elements read within the
+    /// interval and until the next read after it. The new readers are written at once.
+    ///
+    pub fn set_reader_persistence(&mut self, persistence: ReaderPersistence) {
+        self.reader_persistence = persistence;
+    }
 
-        reader.end_timestamp = if end {
-            Some(self.write_timestamp)
-        } else {
-            None
-        };
-        let expired = reader.expired;
-        reader.expired = false;
+    pub fn reader_persistence(&self) -> ReaderPersistence {
+        self.reader_persistence
+    }
 
-        if !self.read_indices.get(&label).is_some_and(|e| *e == reader) {
-            self.db.put_cf(
-                reader_cf,
-                label.as_bytes(),
-                bincode::encode_to_vec(reader.clone(), self.configuration)?,
-            )?;
+    /// Sets the start positions of the new readers by their labels, they are used instead of
+    /// the start positions passed to the reads, so the readers of a fleet start consistently
+    /// whatever their callers pass. `None`, the default, uses the passed positions.
+    ///
+    pub fn set_start_policy(&mut self, policy: Option<StartPolicy>) {
+        self.start_policy = policy;
+    }
 
-            self.read_indices.insert(label, reader);
-        }
+    pub fn start_policy(&self) -> Option<&StartPolicy> {
+        self.start_policy.as_ref()
+    }
 
-        Ok((res, expired))
+    /// Sets the disk usage alerts, see [`DiskAlerts`]. The listeners are notified with
+    /// [`QueueEvent::DiskAlert`] when a threshold is reached and left, the adds check the usage
+    /// at most once a second. `None`, the default, removes the alerts.
+    ///
+    pub fn set_disk_alerts(&mut self, alerts: Option<DiskAlerts>) -> Result<()> {
+        self.disk_monitor = DiskMonitor::new(alerts)?;
+        Ok(())
     }
 
-    pub fn get_labels(&self) -> Vec<String> {
-        self.read_indices
-            .iter()
-            .map(|e| e.0.clone())
-            .collect::<Vec<String>>()
+    pub fn disk_alerts(&self) -> Option<&DiskAlerts> {
+        self.disk_monitor.alerts()
     }
 
-    pub fn remove_label(&mut self, label: &str) -> Result<bool> {
-        let label = label.to_string();
-        if self.read_indices.contains_key(&label) {
-            let reader_cf = self.db.cf_handle(READER_CF).unwrap();
-            self.db.delete_cf(reader_cf, label.as_bytes())?;
+    /// Writes the positions of the readers which are not written yet, see
+    /// [`Self::set_reader_persistence`].
+    ///
+    pub fn flush_readers(&mut self) -> Result<()> {
+        if self.unwritten.is_empty() {
+            return Ok(());
+        }
+        let mut positions = Vec::with_capacity(self.unwritten.len());
+        for label in self.unwritten.keys() {
+            if let Some(reader) = self.read_indices.get(label) {
+                positions.push((
+                    label.clone(),
+                    bincode::encode_to_vec(reader.clone(), self.configuration)?,
+                ));
+            }
+        }
+        self.write_readers(rocksdb::WriteBatch::default(), positions)?;
+        self.unwritten.clear();
+        Ok(())
+    }
 
-            self.read_indices.remove(&label);
+    /// Stores the positions of the readers in the store instead of the queue, e.g. in the
+    /// database of the consumer, see [`OffsetStore`]. The positions of the readers are loaded
+    /// from the store, the readers which are not in it start from their start positions again.
+    /// `None` returns to the positions stored in the queue.
+    ///
+    pub fn set_offset_store(&mut self, store: Option<Box<dyn OffsetStore>>) -> Result<()> {
+        self.flush_readers()?;
+        self.offset_store = store;
+        self.read_indices = self.load_readers()?;
+        let now = current_timestamp();
+        self.read_times = self
+            .read_indices
+            .keys()
+            .map(|label| (label.clone(), now))
+            .collect();
+        Ok(())
+    }
 
-            return Ok(true);
+    pub fn has_offset_store(&self) -> bool {
+        self.offset_store.is_some()
+    }
+
+    /// Returns the positions of the readers from the offset store if it is set, otherwise from
+    /// the queue.
+    ///
+    fn load_readers(&mut self) -> Result<HashMap<String, Reader>> {
+        let mut read_indices = HashMap::new();
+        if let Some(store) = self.offset_store.as_mut() {
+            for (label, value) in store.load()? {
+                let reader = bincode::decode_from_slice(&value, self.configuration)?.0;
+                read_indices.insert(label, reader);
+            }
+            return Ok(read_indices);
         }
-        Ok(false)
+        let reader_cf = self.db()?.cf_handle(READER_CF).unwrap();
+        for item in self.db()?.iterator_cf(reader_cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let label = String::from_utf8(Vec::from(key))
+                .map_err(|e| QueueError::Serialization(e.to_string()))?;
+            let reader = bincode::decode_from_slice(&value, self.configuration)?.0;
+            read_indices.insert(label, reader);
+        }
+        Ok(read_indices)
     }
 
-    fn actualize_indices(&mut self) -> Result<()> {
-        if self.empty {
-            return Ok(());
+    /// Writes the batch and the encoded positions of the readers, they are stored by the offset
+    /// store if it is set after the batch is written.
+    ///
+    fn write_readers(
+        &mut self,
+        mut batch: rocksdb::WriteBatch,
+        positions: Vec<(String, Vec<u8>)>,
+    ) -> Result<()> {
+        if self.offset_store.is_none() {
+            let reader_cf = self.db()?.cf_handle(READER_CF).unwrap();
+            for (label, position) in &positions {
+                batch.put_cf(reader_cf, label.as_bytes(), position);
+            }
+            return Ok(self.db()?.write_opt(batch, &self.write_options())?);
+        }
+        if !batch.is_empty() {
+            self.db()?.write_opt(batch, &self.write_options())?;
         }
+        match self.offset_store.as_mut() {
+            Some(store) if !positions.is_empty() => store.store(&positions),
+            _ => Ok(()),
+        }
+    }
 
-        let data_cf = self.db.cf_handle(DATA_CF).unwrap();
-        let system_cf = self.db.cf_handle(SYSTEM_CF).unwrap();
-        let reader_cf = self.db.cf_handle(READER_CF).unwrap();
+    /// Sets the number of the entries [`Self::prefetch_next`] loads into the block cache, e.g.
+    /// so the sequential reads of the cold data don't wait for the disk. The nonblocking queue
+    /// prefetches them after every read, while the read elements are processed. Zero, the
+    /// default, disables the prefetch.
+    ///
+    pub fn set_prefetch(&mut self, entries: usize) {
+        self.prefetch = entries;
+    }
 
-        let mut iter = self.db.iterator_cf(
-            data_cf,
-            IteratorMode::From(&index_to_key(self.start_index), Direction::Forward),
-        );
-        let first_entry = if let Some(e) = iter.next() {
-            Some(e)
-        } else {
-            // MAX_ALLOWED_INDEX handling
-            let mut iter = self.db.iterator_cf(data_cf, IteratorMode::Start);
-            iter.next()
-        };
+    pub fn prefetch(&self) -> usize {
+        self.prefetch
+    }
 
-        let (start_index, empty, f) = match first_entry {
-            Some(Err(e)) => return Err(anyhow::Error::from(e)),
-            Some(Ok(e)) => {
-                let start_index = key_to_index(e.0);
-                if self.start_index == start_index {
-                    // no elements have been expired
-                    return Ok(());
-                } else {
-                    // some elements have been expired
-                    let f: fn(u64, u64, u64, &mut Reader) =
-                        |start, write_index, _write_timestamp, reader| {
-                            let index = if reader.index == write_index {
-                                reader.index
-                            } else if reader.index > write_index {
-                                if start > write_index {
-                                    u64::max(start, reader.index)
-                                } else {
-                                    start
-                                }
-                            } else if start > write_index {
-                                reader.index
-                            } else {
-                                u64::max(start, reader.index)
-                            };
-                            reader.expired = index != reader.index;
-                            reader.index = index;
-                        };
-                    (start_index, false, f)
-                }
-            }
-            None => {
-                // all elements have been expired
-                let f: fn(u64, u64, u64, &mut Reader) =
-                    |_start, write_index, write_timestamp, reader| {
-                        reader.expired = reader.end_timestamp != Some(write_timestamp);
-                        reader.index = write_index;
-                        reader.end_timestamp = Some(write_timestamp);
-                    };
-                (self.write_index, true, f)
-            }
+    /// Loads the elements after the last read ones into the block cache, so the next read of
+    /// the reader finds them there, see [`Self::set_prefetch`]. Returns the indices of the
+    /// loaded elements.
+    ///
+    pub fn prefetch_next(&self) -> Result<Vec<u64>> {
+        let Some(index) = self.prefetch_from else {
+            return Ok(Vec::new());
         };
+        let len = counters::length(index, self.write_index, self.empty, self.index_space);
+        let count = self.prefetch.min(len);
+        if count == 0 {
+            return Ok(Vec::new());
+        }
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        utilities::prefetch(self.db()?, Some(data_cf), index, count, self.index_space)
+    }
 
-        let mut batch = rocksdb::WriteBatch::default();
+    /// If enabled, every write is synced to disk before it is acknowledged, so acknowledged
+    /// elements survive a machine crash, not only a process crash. Disabled by default.
+    ///
+    pub fn set_sync_writes(&mut self, sync_writes: bool) {
+        self.sync_writes = sync_writes;
+    }
 
-        for (label, reader) in self.read_indices.iter() {
-            let mut reader = reader.clone();
+    pub fn sync_writes(&self) -> bool {
+        self.sync_writes
+    }
+
+    /// Limits the rate of the adds, see [`RateLimit`]. The blocking adds wait holding the queue,
+    /// so the readers of a shared queue wait as well. `None` removes the limit.
+    ///
+    pub fn set_push_rate_limit(&mut self, limit: Option<RateLimit>) -> Result<()> {
+        *self.push_limiter.lock() = limit.map(RateLimiter::new).transpose()?;
+        Ok(())
+    }
+
+    pub fn push_rate_limit(&self) -> Option<RateLimit> {
+        self.push_limiter.lock().as_ref().map(|l| l.limit())
+    }
+
+    /// Limits the rate of the reads of all the readers together, see [`RateLimit`] and
+    /// [`crate::PersistentQueueWithCapacity::set_pop_rate_limit`]. `None` removes the limit.
+    ///
+    pub fn set_pop_rate_limit(&mut self, limit: Option<RateLimit>) -> Result<()> {
+        *self.pop_limiter.lock() = limit.map(RateLimiter::new).transpose()?;
+        Ok(())
+    }
+
+    pub fn pop_rate_limit(&self) -> Option<RateLimit> {
+        self.pop_limiter.lock().as_ref().map(|l| l.limit())
+    }
+
+    fn write_options(&self) -> WriteOptions {
+        let mut write_opts = WriteOptions::default();
+        write_opts.set_sync(self.sync_writes);
+        write_opts
+    }
+
+    /// Registers the listener of the queue events, see [`QueueEvent`]. The listeners are invoked
+    /// by the thread changing the queue after the change is written, so they must not block and
+    /// must not use the queue.
+    ///
+    pub fn add_listener<F>(&self, f: F)
+    where
+        F: Fn(&QueueEvent) + Send + Sync + 'static,
+    {
+        self.listeners.add(f);
+    }
+
+    /// Registers the listener invoked with the number of added elements, see
+    /// [`add_listener`](Self::add_listener).
+    ///
+    pub fn on_push<F>(&self, f: F)
+    where
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.listeners.on_push(f);
+    }
+
+    /// Registers the listener invoked with the label of the consumer which has missed expired
+    /// elements, see [`add_listener`](Self::add_listener).
+    ///
+    pub fn on_expire<F>(&self, f: F)
+    where
+        F: Fn(&str) + Send + Sync + 'static,
+    {
+        self.listeners.on_expire(f);
+    }
+    /// Registers the listener invoked when elements are rejected because the queue is full, see
+    /// [`add_listener`](Self::add_listener).
+    ///
+    pub fn on_full<F>(&self, f: F)
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.listeners.on_full(f);
+    }
+
+    /// Sets the size in bytes above which added elements are split into chunks stored under
+    /// separate keys. `0` disables chunking (the default). Elements already stored are read
+    /// regardless of the setting.
+    ///
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size;
+    }
+
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Rebuilds the start and write indices from the stored elements, e.g. after a crash left
+    /// them pointing at removed data, and moves the readers outside of the rebuilt range to
+    /// its start. If the database can't be opened, the RocksDB repair is run first.
+    ///
+    pub fn repair<P: AsRef<Path>>(path: P, ttl: Duration) -> Result<()> {
+        let path = path.as_ref();
+        let configuration = bincode::config::standard();
+        let db = Self::open_db(path, ttl, Options::default(), true)?;
+        let data_cf = db.cf_handle(DATA_CF).unwrap();
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let reader_cf = db.cf_handle(READER_CF).unwrap();
+
+        let index_space = repair::read_cell(&db, Some(system_cf), INDEX_SPACE_KEY)?
+            .filter(|index_space| check_index_space(*index_space).is_ok())
+            .unwrap_or(DEFAULT_INDEX_SPACE);
+        let start_index = repair::read_cell(&db, Some(system_cf), START_INDEX_KEY)?.unwrap_or(0);
+        let write_index = repair::read_cell(&db, Some(system_cf), WRITE_INDEX_KEY)?
+            .filter(|index| *index < index_space)
+            .unwrap_or(0);
+
+        let elements = repair::scan(&db, Some(data_cf), index_space)?;
+        let (start_index, write_index, empty) =
+            match repair::index_range(&elements.indices, start_index, index_space) {
+                Some((start, end)) => (start, end, false),
+                None => (write_index, write_index, true),
+            };
+
+        let mut batch = rocksdb::WriteBatch::default();
+        for item in db.iterator_cf(reader_cf, IteratorMode::Start) {
+            let (key, value) = item?;
+            let reader = bincode::decode_from_slice::<Reader, _>(&value, configuration)
+                .ok()
+                .map(|r| r.0)
+                .filter(|r| r.index < index_space);
+            let valid = reader.as_ref().is_some_and(|r| {
+                r.index == write_index || in_range(r.index, start_index, write_index, empty)
+            });
+            if !valid {
+                let reader = Reader::new(start_index, None, false);
+                batch.put_cf(
+                    reader_cf,
+                    key,
+                    bincode::encode_to_vec(reader, configuration)?,
+                );
+            }
+        }
+
+        batch.put_cf(
+            system_cf,
+            index_to_key(START_INDEX_KEY),
+            start_index.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_INDEX_KEY),
+            write_index.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(INDEX_SPACE_KEY),
+            index_space.to_le_bytes(),
+        );
+        // readers that have reached the end re-check the rebuilt range
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_TIMESTAMP_KEY),
+            current_timestamp().to_le_bytes(),
+        );
+        if elements.chunked {
+            batch.put_cf(system_cf, index_to_key(CHUNKED_KEY), [1u8]);
+        }
+        db.write(batch)?;
+
+        Ok(())
+    }
+
+    /// Opens the queue with the RocksDB paranoid checks and verifies it (see [`Self::verify`]),
+    /// e.g. after an unclean power-off. A queue failing the verification is not opened: it is
+    /// repaired (see [`Self::repair`]) and verified again if `repair` is set, otherwise
+    /// [`QueueError::Unverified`] is returned. The elements are decoded with the default codec,
+    /// so the encrypted queues fail the verification.
+    ///
+    pub fn open_verified<P: AsRef<Path>>(
+        path: P,
+        ttl: Duration,
+        db_opts: Options,
+        repair: bool,
+    ) -> Result<Self> {
+        Self::open_verified_with(path.as_ref(), ttl, None, db_opts, repair)
+    }
+
+    fn open_verified_with(
+        path: &Path,
+        ttl: Duration,
+        index_space: Option<u64>,
+        mut db_opts: Options,
+        repair: bool,
+    ) -> Result<Self> {
+        db_opts.set_paranoid_checks(true);
+        let queue = match Self::open_with(path, ttl, index_space, db_opts.clone()) {
+            Err(QueueError::Db(e)) if repair && e.kind() == ErrorKind::Corruption => {
+                log::error!(
+                    "The database of the queue at {} is corrupted: {}",
+                    path.display(),
+                    e
+                );
+                None
+            }
+            res => Some(res?),
+        };
+        if let Some(mut queue) = queue {
+            let report = queue.verify()?;
+            if report.is_ok() {
+                return Ok(queue);
+            }
+            log::error!(
+                "The queue at {} failed the verification: {:?}",
+                path.display(),
+                report.problems
+            );
+            queue.close()?;
+            if !repair {
+                return Err(QueueError::Unverified {
+                    path: path.to_path_buf(),
+                    report,
+                });
+            }
+        }
+
+        Self::repair(path, ttl)?;
+        let mut queue = Self::open_with(path, ttl, index_space, db_opts)?;
+        let report = queue.verify()?;
+        if !report.is_ok() {
+            return Err(QueueError::Unverified {
+                path: path.to_path_buf(),
+                report,
+            });
+        }
+        log::warn!("Repaired the queue at {}", path.display());
+        Ok(queue)
+    }
+
+    /// Opens the queue with the typed options, see [`QueueOptions`]. The settings of the queue,
+    /// e.g. the sync writes and the rate limits, are set on the opened queue.
+    ///
+    pub fn open<P: AsRef<Path>>(path: P, ttl: Duration, options: &QueueOptions) -> Result<Self> {
+        let path = path.as_ref();
+        let db_opts = options.build_db_options()?;
+        let mut queue = if options.verify_on_open {
+            Self::open_verified_with(
+                path,
+                ttl,
+                options.index_space,
+                db_opts,
+                options.repair_on_open,
+            )?
+        } else {
+            Self::open_with(path, ttl, options.index_space, db_opts)?
+        };
+        queue.set_compression(options.element_compression);
+        queue.set_checksums(options.checksums);
+        queue.set_sync_writes(options.sync_writes);
+        queue.set_push_rate_limit(options.push_rate_limit)?;
+        queue.set_pop_rate_limit(options.pop_rate_limit)?;
+        queue.evicting = options.eviction_filter.is_some();
+        queue.set_strict_ttl(options.strict_ttl);
+        queue.set_retention_guard(options.retention_guard);
+        queue.set_prefetch(options.prefetch);
+        queue.set_reader_persistence(options.reader_persistence);
+        queue.set_start_policy(options.start_policy.clone());
+        queue.set_disk_alerts(options.disk_alerts.clone())?;
+        Ok(queue)
+    }
+
+    /// Closes the database and opens it again with the same options, e.g. after RocksDB
+    /// stopped accepting the writes because of an IO error, see [`QueueError::is_transient`].
+    /// The indices of the readers are read again from the database, the settings of the queue
+    /// are kept. If the database can't be opened, the operations fail with
+    /// [`QueueError::Closed`] until it is reopened.
+    ///
+    pub fn reopen(&mut self) -> Result<()> {
+        if let Err(e) = self.flush_readers() {
+            log::warn!(
+                "Failed to write the readers of the queue at {} before reopening it: {}",
+                self.path.display(),
+                e
+            );
+        }
+        self.unwritten.clear();
+        // RocksDB holds the lock of the database until it is closed
+        self.db = None;
+        let mut reopened = Self::with_options(&self.path, self.ttl, self.db_opts.clone())?;
+        self.empty = reopened.empty;
+        self.start_index = reopened.start_index;
+        self.write_index = reopened.write_index;
+        self.write_timestamp = reopened.write_timestamp;
+        self.index_space = reopened.index_space;
+        self.read_indices = std::mem::take(&mut reopened.read_indices);
+        self.chunked = reopened.chunked;
+        self.paired = reopened.paired;
+        self.keyed = reopened.keyed;
+        self.format_version = reopened.format_version;
+        self.checksums = reopened.checksums;
+        self.sizes = std::mem::take(&mut reopened.sizes);
+        self.db = reopened.db.take();
+        if self.offset_store.is_some() {
+            self.read_indices = self.load_readers()?;
+        }
+        log::warn!("Reopened the MPMC queue at {}", self.path.display());
+        self.listeners.emit(|| QueueEvent::Reopen);
+        Ok(())
+    }
+
+    /// Returns the database, it is closed only if reopening it failed.
+    fn db(&self) -> Result<&DB> {
+        self.db.as_ref().ok_or(QueueError::Closed)
+    }
+
+    /// Removes the queue at `path`, fails with [`QueueError::InUse`] if the queue is open, e.g.
+    /// by another process.
+    ///
+    pub fn remove_db<P: AsRef<Path>>(path: P) -> Result<()> {
+        fs::destroy_db(path.as_ref(), &RemoveOptions::default())
+    }
+
+    /// Removes the queue at `path` even if it is open, the queue holding it loses its data.
+    pub fn remove_db_forced<P: AsRef<Path>>(path: P) -> Result<()> {
+        fs::destroy_db(path.as_ref(), &RemoveOptions::default().force(true))
+    }
+
+    /// Removes the queue at `path` with the options, e.g. the RocksDB options it was created
+    /// with or the secure wipe of its data files, see [`RemoveOptions`].
+    ///
+    pub fn remove_db_with<P: AsRef<Path>>(path: P, options: &RemoveOptions) -> Result<()> {
+        fs::destroy_db(path.as_ref(), options)
+    }
+
+    /// Flushes the written data and closes the database, so the queue can be reopened at the
+    /// same path right away. Dropping the queue closes the database as well, but the errors
+    /// can't be reported then.
+    ///
+    pub fn close(mut self) -> Result<()> {
+        self.flush_readers()?;
+        for cf in [
+            SYSTEM_CF,
+            DATA_CF,
+            READER_CF,
+            TIME_CF,
+            PAYLOAD_CF,
+            TIMESTAMP_CF,
+            KEY_CF,
+            SUPERSEDED_CF,
+        ] {
+            self.db()?.flush_cf(self.db()?.cf_handle(cf).unwrap())?;
+        }
+        Ok(())
+    }
+
+    pub fn disk_size(&self) -> Result<usize> {
+        Ok(fs::dir_size(&self.path)?)
+    }
+
+    /// Returns the RocksDB internals of the queue, see [`DbStats`].
+    pub fn db_stats(&self) -> Result<DbStats> {
+        DbStats::read(self.db()?, self.db()?.cf_handle(DATA_CF))
+    }
+
+    /// Estimates the disk bytes the elements of the average size take in the queue from the
+    /// compression ratio and the space amplification observed by RocksDB.
+    ///
+    pub fn estimate_disk_for(&self, elements: u64, avg_size: u64) -> Result<u64> {
+        Ok(
+            StorageModel::read(self.db()?, self.db()?.cf_handle(DATA_CF))?
+                .disk_for(elements, avg_size),
+        )
+    }
+
+    /// Estimates the number of the elements which can be added before the disk is full, the
+    /// expired elements are accounted first. The elements are expected to be of the average
+    /// size of the added ones, `None` if none were added.
+    ///
+    pub fn estimate_remaining_capacity(&mut self) -> Result<Option<u64>> {
+        self.actualize_indices()?;
+        let Some(avg_size) = self.sizes.avg() else {
+            return Ok(None);
+        };
+        let available = fs::available_space(&self.path)?;
+        let fitting = StorageModel::read(self.db()?, self.db()?.cf_handle(DATA_CF))?
+            .elements_for(available, avg_size.round() as u64);
+        Ok(Some(
+            fitting.min(self.index_space - self.actualized_len() as u64),
+        ))
+    }
+
+    /// Returns the length, the counters of the elements and the reader positions, the expired
+    /// elements are accounted first.
+    ///
+    pub fn stats(&mut self) -> Result<MpmcQueueStats> {
+        self.actualize_indices()?;
+        let disk_size = self.disk_size()?;
+        Ok(MpmcQueueStats {
+            len: self.actualized_len(),
+            disk_size,
+            newest_timestamp: (!self.empty).then_some(self.write_timestamp),
+            added: self.added,
+            expired: self.expired,
+            labels: self
+                .read_indices
+                .iter()
+                .map(|(label, reader)| (label.clone(), reader.index))
+                .collect(),
+            sizes: self.sizes.clone(),
+            disk_alerts: self
+                .disk_monitor
+                .update(&self.path, disk_size as u64, &self.listeners)?,
+        })
+    }
+
+    /// Returns the length, the indices and the positions of the readers read against a single
+    /// RocksDB snapshot, see [`StatsSnapshot`]. The expired elements which are not removed yet
+    /// are counted, the positions which are not written yet (see
+    /// [`Self::set_reader_persistence`]) are the written ones. The positions of the readers in
+    /// the offset store (see [`Self::set_offset_store`]) are the current ones.
+    ///
+    pub fn stats_snapshot(&self) -> Result<StatsSnapshot> {
+        // the store is not a part of the snapshot
+        let readers = self.offset_store.as_ref().map(|_| &self.read_indices);
+        Self::read_stats_snapshot(self.db()?, self.index_space, readers, 0)
+    }
+
+    /// Reads the stats against a snapshot of the database, the readers are read from it unless
+    /// they are given. The last `suffix_len` bytes of the stored values are skipped, see
+    /// [`TTL_SUFFIX_LEN`].
+    ///
+    fn read_stats_snapshot(
+        db: &DB,
+        index_space: u64,
+        readers: Option<&HashMap<String, Reader>>,
+        suffix_len: usize,
+    ) -> Result<StatsSnapshot> {
+        let snapshot = db.snapshot();
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let data_cf = db.cf_handle(DATA_CF).unwrap();
+        let reader_cf = db.cf_handle(READER_CF).unwrap();
+        let cell = |key: u64| -> Result<Option<u64>> {
+            Ok(snapshot
+                .get_cf(system_cf, index_to_key(key))?
+                .filter(|v| v.len() == crate::U64_BYTE_LEN + suffix_len)
+                .map(|v| u64_from_byte_vec(&v[..crate::U64_BYTE_LEN])))
+        };
+        let write_index = cell(WRITE_INDEX_KEY)?.unwrap_or(0);
+        let write_timestamp = cell(WRITE_TIMESTAMP_KEY)?.unwrap_or(0);
+        let stored_start = cell(START_INDEX_KEY)?.unwrap_or(0);
+        // the equal indices are of the empty or the full queue
+        let stored_empty = stored_start == write_index
+            && snapshot
+                .iterator_cf(data_cf, IteratorMode::Start)
+                .next()
+                .transpose()?
+                .is_none();
+        let first = if stored_empty {
+            None
+        } else {
+            first_stored(
+                db,
+                data_cf,
+                Some(&snapshot),
+                stored_start,
+                counters::length(stored_start, write_index, false, index_space) as u64,
+                index_space,
+            )?
+        };
+        let (start_index, empty) = match first {
+            Some(index) => (index, false),
+            None => (write_index, true),
+        };
+        let len = counters::length(start_index, write_index, empty, index_space);
+
+        let mut stored = Vec::new();
+        match readers {
+            Some(readers) => stored.extend(readers.clone()),
+            None => {
+                for item in snapshot.iterator_cf(reader_cf, IteratorMode::Start) {
+                    let (key, value) = item?;
+                    let label = String::from_utf8(Vec::from(key))
+                        .map_err(|e| QueueError::Serialization(e.to_string()))?;
+                    let value = &value[..value.len().saturating_sub(suffix_len)];
+                    let reader: Reader =
+                        bincode::decode_from_slice(value, bincode::config::standard())?.0;
+                    stored.push((label, reader));
+                }
+            }
+        }
+        let mut labels = HashMap::new();
+        for (label, reader) in stored {
+            let read_all = reader.at_end(write_index, write_timestamp)
+                || (reader.index == write_index && (len as u64) < index_space);
+            let lag = if empty || read_all {
+                0
+            } else {
+                // the readers behind the expired elements lag by the whole queue
+                counters::length(reader.index, write_index, false, index_space).min(len)
+            };
+            labels.insert(
+                label,
+                ReaderPosition {
+                    index: reader.index,
+                    lag,
+                },
+            );
+        }
+        Ok(StatsSnapshot {
+            len,
+            payload_size: None,
+            start_index,
+            write_index,
+            labels,
+        })
+    }
+
+    /// Reads the state of the queue from the database opened read-only, see
+    /// [`discovery::discover`]. Returns `None` if the database has no format version, i.e. it
+    /// is not a queue.
+    ///
+    pub(crate) fn inspect_db(db: &DB) -> Result<Option<Inspection>> {
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let cell = |key: u64| -> Result<Option<u64>> {
+            Ok(db
+                .get_cf(system_cf, index_to_key(key))?
+                .filter(|v| v.len() == crate::U64_BYTE_LEN + TTL_SUFFIX_LEN)
+                .map(|v| u64_from_byte_vec(&v[..crate::U64_BYTE_LEN])))
+        };
+        let Some(format_version) = cell(FORMAT_VERSION_KEY)? else {
+            return Ok(None);
+        };
+        let index_space = cell(INDEX_SPACE_KEY)?.unwrap_or(DEFAULT_INDEX_SPACE);
+        Ok(Some(Inspection {
+            kind: QueueKind::Mpmc,
+            format_version,
+            index_space,
+            stats: Self::read_stats_snapshot(db, index_space, None, TTL_SUFFIX_LEN)?,
+        }))
+    }
+
+    /// Returns the time since the oldest not expired element was added, `None` if the queue is
+    /// empty or the element was added by a version not storing the add times.
+    ///
+    pub fn oldest_age(&mut self) -> Result<Option<Duration>> {
+        self.actualize_indices()?;
+        if self.empty {
+            return Ok(None);
+        }
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let added_at = self.db()?.get_cf(time_cf, index_to_key(self.start_index))?;
+        Ok(added_at.map(|t| {
+            Duration::from_nanos(current_timestamp().saturating_sub(u64_from_byte_vec(&t)))
+        }))
+    }
+
+    /// Returns the number of the elements, the elements trimmed by the compactions since the
+    /// indices were actualized are not counted, so the length doesn't jump when the indices
+    /// catch up with the expiry. The length of the actualized indices is returned, and the
+    /// failure logged, if the database can't be read.
+    ///
+    pub fn len(&self) -> usize {
+        if self.empty {
+            return 0;
+        }
+        match self.stored_start_index() {
+            Ok(Some(start_index)) => {
+                counters::length(start_index, self.write_index, false, self.index_space)
+            }
+            Ok(None) => 0,
+            Err(e) => {
+                log::warn!(
+                    "Failed to read the start index of the queue at {}: {}",
+                    self.path.display(),
+                    e
+                );
+                self.actualized_len()
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the number of the elements between the actualized indices, the elements trimmed
+    /// since the indices were actualized are counted.
+    ///
+    fn actualized_len(&self) -> usize {
+        counters::length(
+            self.start_index,
+            self.write_index,
+            self.empty,
+            self.index_space,
+        )
+    }
+
+    /// Returns the index of the oldest stored element, `None` if all elements are expired. Only
+    /// the indices trimmed since the indices were actualized are probed.
+    ///
+    fn stored_start_index(&self) -> Result<Option<u64>> {
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        first_stored(
+            self.db()?,
+            data_cf,
+            None,
+            self.start_index,
+            self.actualized_len() as u64,
+            self.index_space,
+        )
+    }
+
+    /// Returns `true` if the element added at the stored time has outlived the TTL by `now`.
+    fn outlived_ttl(&self, index: u64, now: u64) -> Result<bool> {
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let added_at = self.db()?.get_cf(time_cf, index_to_key(index))?;
+        Ok(added_at.is_some_and(|t| {
+            u64_from_byte_vec(&t).saturating_add(self.ttl.as_nanos() as u64) <= now
+        }))
+    }
+
+    /// Checks if the element at the index was removed by [`Self::add_with_keys`] rather than
+    /// expired.
+    ///
+    fn is_superseded(&self, index: u64) -> Result<bool> {
+        if !self.keyed {
+            return Ok(false);
+        }
+        let superseded_cf = self.db()?.cf_handle(SUPERSEDED_CF).unwrap();
+        Ok(self
+            .db()?
+            .get_cf(superseded_cf, index_to_key(index))?
+            .is_some())
+    }
+
+    /// Returns the index of the first element added at or after the timestamp, `None` if all
+    /// elements are older. The indices are actualized by the caller.
+    ///
+    fn index_at(&self, timestamp: u64) -> Result<Option<u64>> {
+        if self.empty {
+            return Ok(None);
+        }
+        let timestamp_cf = self.db()?.cf_handle(TIMESTAMP_CF).unwrap();
+        let mut iter = self.db()?.iterator_cf(
+            timestamp_cf,
+            IteratorMode::From(&timestamp.to_be_bytes(), Direction::Forward),
+        );
+        match iter.next() {
+            Some(Err(e)) => Err(e.into()),
+            Some(Ok((entry, _))) => {
+                let index = key_to_index(&entry[crate::U64_BYTE_LEN..]);
+                // the entries of the expired elements may not have been compacted yet
+                if in_range(index, self.start_index, self.write_index, self.empty) {
+                    Ok(Some(index))
+                } else {
+                    Ok(Some(self.start_index))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Returns up to `max_elts` elements added in the time range `[from, to)`, nanoseconds since
+    /// the epoch, without moving the readers.
+    ///
+    pub fn read_range(&mut self, from: u64, to: u64, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        self.actualize_indices()?;
+        let mut res = Vec::new();
+        if from >= to {
+            return Ok(res);
+        }
+        let Some(mut index) = self.index_at(from)? else {
+            return Ok(res);
+        };
+        let end = self.index_at(to)?.unwrap_or(self.write_index);
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        let now = current_timestamp();
+        while index != end && res.len() < max_elts {
+            // the expired elements are skipped
+            let value = chunking::get_element(self.db()?, Some(data_cf), index, self.chunked)?;
+            if self.strict_ttl && value.is_some() && self.outlived_ttl(index, now)? {
+                index = next_index(index, self.index_space);
+                continue;
+            }
+            if let Some(e) = value {
+                res.push(self.codec.decode_versioned(
+                    e.value,
+                    index,
+                    self.format_version,
+                    self.checksums,
+                )?);
+            }
+            index = next_index(index, self.index_space);
+        }
+        Ok(res)
+    }
+
+    /// Removes the elements added before the timestamp, nanoseconds since the epoch, without
+    /// waiting for them to expire and returns their number. The readers behind them are moved
+    /// to the first remaining element and get the expiration flag, as for the expired elements.
+    ///
+    pub fn purge_before(&mut self, timestamp: u64) -> Result<usize> {
+        self.actualize_indices()?;
+        if self.empty {
+            return Ok(0);
+        }
+        let end = self.index_at(timestamp)?.unwrap_or(self.write_index);
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+        let timestamp_cf = self.db()?.cf_handle(TIMESTAMP_CF).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut purged = 0;
+        let mut index = self.start_index;
+        while index != end {
+            if let Some(e) = chunking::get_element(self.db()?, Some(data_cf), index, self.chunked)?
+            {
+                chunking::delete_element(&mut batch, Some(data_cf), &e);
+                batch.delete_cf(time_cf, index_to_key(index));
+                batch.delete_cf(payload_cf, index_to_key(index));
+                purged += 1;
+            }
+            index = next_index(index, self.index_space);
+        }
+        batch.delete_range_cf(timestamp_cf, 0u64.to_be_bytes(), timestamp.to_be_bytes());
+        self.db()?.write_opt(batch, &self.write_options())?;
+        self.actualize_indices()?;
+        Ok(purged)
+    }
+
+    /// Returns the number of the indices the elements are stored under, see
+    /// [`Self::with_index_space`].
+    ///
+    pub fn index_space(&self) -> u64 {
+        self.index_space
+    }
+
+    /// Checks the queue invariants: the cursors are within the index space, all elements
+    /// between the start and the write index are present and readable, there are no elements
+    /// outside of this range and the reader positions are within it. Expired elements are
+    /// accounted before the check.
+    ///
+    pub fn verify(&mut self) -> Result<VerificationReport> {
+        let mut report = VerificationReport::default();
+        report.check_index("start_index", self.start_index, self.index_space);
+        report.check_index("write_index", self.write_index, self.index_space);
+        if !report.is_ok() {
+            return Ok(report);
+        }
+        self.actualize_indices()?;
+
+        let scan = ElementsScan {
+            db: self.db()?,
+            cf: Some(self.db()?.cf_handle(DATA_CF).unwrap()),
+            codec: &self.codec,
+            format_version: self.format_version,
+            checksums: self.checksums,
+            chunked: self.chunked,
+            index_space: self.index_space,
+        };
+        scan.run(self.start_index, self.write_index, self.empty, &mut report)?;
+
+        let mut labels = self.read_indices.keys().collect::<Vec<_>>();
+        labels.sort();
+        for label in labels {
+            let index = self.read_indices[label].index;
+            if index != self.write_index
+                && !in_range(index, self.start_index, self.write_index, self.empty)
+            {
+                report.problems.push(Problem::ReaderOutOfRange {
+                    label: label.clone(),
+                    index,
+                });
+            }
+        }
+
+        Ok(report)
+    }
+
+    pub fn add(&mut self, values: &[&[u8]]) -> Result<()> {
+        self.add_indexed(values).map(|_| ())
+    }
+
+    /// Adds the elements and returns the index of the first one and their number, e.g. to
+    /// record where the batch landed. The elements take the next indices, wrapping around the
+    /// index space, see [`Self::index_space`].
+    ///
+    pub fn add_indexed(&mut self, values: &[&[u8]]) -> Result<(u64, usize)> {
+        if values.is_empty() {
+            return Ok((self.write_index, 0));
+        }
+        let bytes = values.iter().map(|v| v.len() as u64).sum();
+        limiter::acquire(&self.push_limiter, values.len(), bytes)?;
+        let first = self.add_values(values, None, None, None)?;
+        Ok((first, values.len()))
+    }
+
+    /// Adds the elements with their add times, e.g. so the replayed historical data keeps its
+    /// times for [`StartPosition::FromTimestamp`], [`Self::read_range`], [`Self::purge_before`]
+    /// and the strict TTL (see [`Self::set_strict_ttl`]); RocksDB expires them the TTL after the
+    /// add. The timestamps, nanoseconds since the epoch, must not decrease, must not be earlier
+    /// than the last add of a non-empty queue or in the future, the last one must differ from
+    /// the last add time.
+    ///
+    pub fn add_with_timestamps(&mut self, values: &[&[u8]], timestamps: &[u64]) -> Result<()> {
+        if values.len() != timestamps.len() {
+            return Err(QueueError::InvalidArgument(format!(
+                "{} timestamps are given for {} elements",
+                timestamps.len(),
+                values.len()
+            )));
+        }
+        let Some(&last) = timestamps.last() else {
+            return Ok(());
+        };
+        self.actualize_indices()?;
+        // the readers of an empty queue are at its end, the last add time tells them apart
+        if (!self.empty && timestamps[0] < self.write_timestamp)
+            || timestamps.windows(2).any(|w| w[1] < w[0])
+            || last == self.write_timestamp
+            || last > current_timestamp()
+        {
+            return Err(QueueError::InvalidArgument(format!(
+                "The timestamps must grow from the last add at {} and can't be in the future",
+                self.write_timestamp
+            )));
+        }
+        let bytes = values.iter().map(|v| v.len() as u64).sum();
+        limiter::acquire(&self.push_limiter, values.len(), bytes)?;
+        self.add_values(values, None, Some(timestamps), None)
+            .map(|_| ())
+    }
+
+    /// Adds the elements of two parts: the small metadata stored as the element and the large
+    /// payload stored separately, in the blob files, so the readers of [`Self::next`] and
+    /// [`Self::next_meta_only`] don't read the payloads. [`Self::next_pairs`] returns both parts.
+    ///
+    pub fn add_pairs(&mut self, pairs: &[(&[u8], &[u8])]) -> Result<()> {
+        if pairs.is_empty() {
+            return Ok(());
+        }
+        let bytes = pairs.iter().map(|(m, p)| (m.len() + p.len()) as u64).sum();
+        limiter::acquire(&self.push_limiter, pairs.len(), bytes)?;
+        let (metas, payloads): (Vec<&[u8]>, Vec<&[u8]>) = pairs.iter().copied().unzip();
+        self.add_values(&metas, Some(&payloads), None, None)
+            .map(|_| ())
+    }
+
+    pub fn add_pair(&mut self, meta: &[u8], payload: &[u8]) -> Result<()> {
+        self.add_pairs(&[(meta, payload)])
+    }
+
+    /// Adds the elements with their keys, an element supersedes the one added with the same key
+    /// before, which is removed, so the queue keeps the latest element per key like a compacted
+    /// topic, e.g. the latest state of every camera. The readers skip the removed elements and
+    /// read the rest in the order of the adds; of the elements with the same key in the batch,
+    /// only the last one is added. [`Self::get_by_key`] reads the latest element of a key.
+    ///
+    pub fn add_with_keys(&mut self, elements: &[(&[u8], &[u8])]) -> Result<()> {
+        // the last element of every key is kept, the batch is deduplicated from its end
+        let mut seen = HashSet::with_capacity(elements.len());
+        let mut latest = elements
+            .iter()
+            .rev()
+            .filter(|(key, _)| seen.insert(*key))
+            .copied()
+            .collect::<Vec<_>>();
+        latest.reverse();
+        let (keys, values): (Vec<&[u8]>, Vec<&[u8]>) = latest.into_iter().unzip();
+        if values.is_empty() {
+            return Ok(());
+        }
+        let bytes = values.iter().map(|v| v.len() as u64).sum();
+        limiter::acquire(&self.push_limiter, values.len(), bytes)?;
+        self.add_values(&values, None, None, Some(&keys))
+            .map(|_| ())
+    }
+
+    /// Returns the latest element added with the key by [`Self::add_with_keys`], `None` if it
+    /// has expired or there is no such key.
+    ///
+    pub fn get_by_key(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let Some(index) = self.db()?.get_cf(key_cf, key)? else {
+            return Ok(None);
+        };
+        let index = u64_from_byte_vec(&index);
+        if !in_range(index, self.start_index, self.write_index, self.empty) {
+            return Ok(None);
+        }
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        chunking::get_element(self.db()?, Some(data_cf), index, self.chunked)?
+            .map(|e| {
+                self.codec
+                    .decode_versioned(e.value, index, self.format_version, self.checksums)
+            })
+            .transpose()
+    }
+
+    /// Adds the elements with their payloads, at their timestamps if they are given, otherwise
+    /// now, and with their distinct keys superseding the elements added with the same keys
+    /// before. Returns the index of the first added element.
+    ///
+    fn add_values(
+        &mut self,
+        values: &[&[u8]],
+        payloads: Option<&[&[u8]]>,
+        timestamps: Option<&[u64]>,
+        keys: Option<&[&[u8]]>,
+    ) -> Result<u64> {
+        let span = OpSpan::enter("add");
+        self.actualize_indices()?;
+        self.guard_retention()?;
+        if self.actualized_len() + values.len() > self.index_space as usize {
+            self.listeners.emit(|| QueueEvent::Full);
+            return Err(QueueError::Full);
+        }
+
+        let data_cf = self.db()?.cf_handle(DATA_CF).unwrap();
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+        let time_cf = self.db()?.cf_handle(TIME_CF).unwrap();
+        let payload_cf = self.db()?.cf_handle(PAYLOAD_CF).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut write_index = self.write_index;
+        let mut chunked = false;
+        let now = current_timestamp();
+        let timestamp_cf = self.db()?.cf_handle(TIMESTAMP_CF).unwrap();
+        let key_cf = self.db()?.cf_handle(KEY_CF).unwrap();
+        let superseded_cf = self.db()?.cf_handle(SUPERSEDED_CF).unwrap();
+        let keyed = self.keyed || keys.is_some();
+        let mut previous_timestamp = (!self.empty).then_some(self.write_timestamp);
+        let mut superseded = Vec::new();
+
+        // an empty queue in the legacy format is upgraded and takes the checksum setting
+        let (format_version, checksums) = if self.empty {
+            (FORMAT_VERSION, self.codec.checksums)
+        } else {
+            (self.format_version, self.checksums)
+        };
+
+        let mut sizes = self.sizes.clone();
+        let mut bytes = 0;
+        for (i, value) in values.iter().enumerate() {
+            let mut size = value.len() as u64;
+            match payloads.map(|p| p[i]) {
+                Some(payload) => {
+                    size += payload.len() as u64;
+                    let payload =
+                        self.codec
+                            .encode_versioned(payload, format_version, checksums)?;
+                    batch.put_cf(payload_cf, index_to_key(write_index), payload);
+                }
+                // the payload of an expired element may not have been compacted yet
+                None if self.paired => batch.delete_cf(payload_cf, index_to_key(write_index)),
+                None => {}
+            }
+            sizes.add(size);
+            bytes += size;
+            let timestamp = timestamps.map_or(now, |t| t[i]);
+            // the elements added at the same time are found by the entry of the first one
+            if previous_timestamp != Some(timestamp) {
+                batch.put_cf(timestamp_cf, timestamp_entry(timestamp, write_index), []);
+                previous_timestamp = Some(timestamp);
+            }
+            let value = self
+                .codec
+                .encode_versioned(value, format_version, checksums)?;
+            chunked |= chunking::put_element(
+                &mut batch,
+                Some(data_cf),
+                write_index,
+                &value,
+                self.chunk_size,
+            );
+            batch.put_cf(time_cf, index_to_key(write_index), timestamp.to_le_bytes());
+            if keyed {
+                // the marker of the element superseded at the index in the previous lap
+                batch.delete_cf(superseded_cf, index_to_key(write_index));
+            }
+            if let Some(key) = keys.map(|k| k[i]) {
+                if let Some(index) = self.db()?.get_cf(key_cf, key)? {
+                    let index = u64_from_byte_vec(&index);
+                    let element = if in_range(index, self.start_index, self.write_index, self.empty)
+                    {
+                        chunking::get_element(self.db()?, Some(data_cf), index, self.chunked)?
+                    } else {
+                        None
+                    };
+                    if let Some(e) = element {
+                        chunking::delete_element(&mut batch, Some(data_cf), &e);
+                        batch.delete_cf(time_cf, index_to_key(index));
+                        batch.delete_cf(payload_cf, index_to_key(index));
+                        batch.put_cf(superseded_cf, index_to_key(index), []);
+                        superseded.push(index);
+                    }
+                }
+                batch.put_cf(key_cf, key, write_index.to_le_bytes());
+            }
+            write_index = next_index(write_index, self.index_space);
+        }
+
+        let write_timestamp = timestamps.and_then(|t| t.last().copied()).unwrap_or(now);
+        if chunked {
+            // the marker is rewritten with every chunked batch so that it outlives the chunked
+            // elements under TTL
+            batch.put_cf(system_cf, index_to_key(CHUNKED_KEY), [1u8]);
+        }
+        if payloads.is_some() {
+            batch.put_cf(system_cf, index_to_key(PAIRED_KEY), [1u8]);
+        }
+        if keys.is_some() {
+            batch.put_cf(system_cf, index_to_key(KEYED_KEY), [1u8]);
+        }
+        if checksums {
+            batch.put_cf(system_cf, index_to_key(CHECKSUMS_KEY), [1u8]);
+        } else if self.checksums {
+            batch.delete_cf(system_cf, index_to_key(CHECKSUMS_KEY));
+        }
+        // rewritten with every batch for the same reason
+        batch.put_cf(
+            system_cf,
+            index_to_key(FORMAT_VERSION_KEY),
+            format_version.to_le_bytes(),
+        );
+        batch.put_cf(system_cf, index_to_key(SIZES_KEY), sizes.to_bytes()?);
+        batch.put_cf(
+            system_cf,
+            index_to_key(INDEX_SPACE_KEY),
+            self.index_space.to_le_bytes(),
+        );
+
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_INDEX_KEY),
+            write_index.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_TIMESTAMP_KEY),
+            write_timestamp.to_le_bytes(),
+        );
+
+        self.db()?.write_opt(batch, &self.write_options())?;
+        let first_index = self.write_index;
+        self.write_index = write_index;
+        self.write_timestamp = write_timestamp;
+        self.empty = false;
+        self.chunked |= chunked;
+        self.paired |= payloads.is_some();
+        self.keyed |= keys.is_some();
+        self.format_version = format_version;
+        self.checksums = checksums;
+        self.added += values.len() as u64;
+        self.sizes = sizes;
+        span.record(values.len(), bytes);
+
+        self.skip_superseded(&superseded)?;
+
+        self.listeners.emit(|| QueueEvent::Push(values.len()));
+        self.disk_monitor.check(&self.path, &self.listeners);
+        Ok(first_index)
+    }
+
+    /// Moves the start of the queue and the readers at it past the removed superseded elements
+    /// (see [`Self::add_with_keys`]), so the readers are not told they have missed expired
+    /// elements.
+    ///
+    fn skip_superseded(&mut self, superseded: &[u64]) -> Result<()> {
+        let mut start_index = self.start_index;
+        while start_index != self.write_index && superseded.contains(&start_index) {
+            start_index = next_index(start_index, self.index_space);
+        }
+        if start_index == self.start_index {
+            return Ok(());
+        }
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+        let mut batch = rocksdb::WriteBatch::default();
+        batch.put_cf(
+            system_cf,
+            index_to_key(START_INDEX_KEY),
+            start_index.to_le_bytes(),
+        );
+        let mut positions = Vec::new();
+        for (label, reader) in self.read_indices.iter_mut() {
+            if in_range(reader.index, self.start_index, start_index, false) {
+                reader.index = start_index;
+                positions.push((
+                    label.clone(),
+                    bincode::encode_to_vec(reader.clone(), self.configuration)?,
+                ));
+            }
+        }
+        self.write_readers(batch, positions)?;
+        self.start_index = start_index;
+        Ok(())
+    }
+
+    pub fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        let (res, expired) =
+            self.next_elements(max_elts, label, start_position, false, true, false)?;
+        Ok((res.into_iter().map(|(value, _)| value).collect(), expired))
+    }
+
+    /// Reads exactly `n` elements like [`Self::next`] or none if there are fewer of them, so the
+    /// readers get only full batches. The reader stays at its position until `n` elements can
+    /// be read, the expired, the evicted, the superseded and the skipped corrupted elements
+    /// don't count towards `n`.
+    ///
+    pub fn next_exact(
+        &mut self,
+        n: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        if n as u64 > self.index_space {
+            return Err(QueueError::InvalidArgument(format!(
+                "n can't be greater than the index space ({})",
+                self.index_space
+            )));
+        }
+        let (res, expired) = self.next_elements(n, label, start_position, false, true, true)?;
+        Ok((res.into_iter().map(|(value, _)| value).collect(), expired))
+    }
+
+    /// Reads the metadata of the paired elements (see [`Self::add_pairs`]) without their
+    /// payloads. It is the same as [`Self::next`], the plain elements are returned as they are.
+    ///
+    pub fn next_meta_only(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        self.next(max_elts, label, start_position)
+    }
+
+    /// Reads the metadata and the payloads of the paired elements, see [`Self::add_pairs`]. The
+    /// payloads of the plain elements are empty.
+    ///
+    pub fn next_pairs(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<(Vec<u8>, Vec<u8>)>, bool)> {
+        let (res, expired) =
+            self.next_elements(max_elts, label, start_position, true, true, false)?;
+        let pairs = res
+            .into_iter()
+            .map(|(meta, payload)| (meta, payload.unwrap_or_default()))
+            .collect();
+        Ok((pairs, expired))
+    }
+
+    /// Reads up to `max_elts` elements like [`Self::next`], processes them with `f` and pushes
+    /// its outputs into the destination together with the new position of the reader in one
+    /// RocksDB write, so each element is processed exactly once. If `f` or the push fails, the
+    /// reader stays at its position. The positions are read from the destination, the queue
+    /// needs the [`QueueOffsetStore`] of the destination as its offset store (see
+    /// [`Self::set_offset_store`]), otherwise it fails with [`QueueError::InvalidArgument`].
+    /// Returns the number of the processed elements and whether some elements expired.
+    ///
+    pub fn process<F>(
+        &mut self,
+        label: &str,
+        max_elts: usize,
+        start_position: StartPosition,
+        destination: &PersistentQueueWithCapacity,
+        f: F,
+    ) -> Result<(usize, bool)>
+    where
+        F: FnOnce(&[Vec<u8>]) -> Result<Vec<Vec<u8>>>,
+    {
+        let stored_in_destination = self
+            .offset_store
+            .as_ref()
+            .and_then(|store| store.destination())
+            .is_some_and(|store| std::ptr::eq(Arc::as_ptr(store), destination));
+        if !stored_in_destination {
+            return Err(QueueError::InvalidArgument(
+                "The positions of the processed queue must be stored in the destination"
+                    .to_string(),
+            ));
+        }
+        let label = self.reader_label(label);
+        let label = label.as_str();
+        let previous = self.read_indices.get(label).cloned();
+        let (res, expired) =
+            self.next_elements(max_elts, label, start_position, false, false, false)?;
+        let values = res.into_iter().map(|(value, _)| value).collect::<Vec<_>>();
+        let reader = self.read_indices[label].clone();
+        let committed = (|| {
+            let outputs = if values.is_empty() {
+                Vec::new()
+            } else {
+                f(&values)?
+            };
+            let outputs = outputs.iter().map(|o| o.as_slice()).collect::<Vec<_>>();
+            let position = bincode::encode_to_vec(reader, self.configuration)?;
+            destination.push_with_offsets(&outputs, &[(label.to_string(), position)])
+        })();
+        if let Err(e) = committed {
+            // the position of the reader is not moved
+            match previous {
+                Some(reader) => {
+                    self.read_indices.insert(label.to_string(), reader);
+                }
+                None => {
+                    self.read_indices.remove(label);
+                    self.read_times.remove(label);
+                }
+            }
+            return Err(e);
+        }
+        self.unwritten.remove(label);
+        Ok((values.len(), expired))
+    }
+
+    /// Reads up to `max_elts` elements of the reader, the new position of the reader is written
+    /// with `write_reader` only, otherwise the caller writes it. If `exact` is set, reads nothing
+    /// and leaves the reader at its position unless `max_elts` elements are read.
+    ///
+    fn next_elements(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+        payloads: bool,
+        write_reader: bool,
+        exact: bool,
+    ) -> Result<(Vec<(Vec<u8>, Option<Vec<u8>>)>, bool)> {
+        limiter::wait_ready(&self.pop_limiter)?;
+        let span = OpSpan::enter("next");
+        let mut res = Vec::with_capacity(max_elts);
+
+        self.actualize_indices()?;
+        let label = self.reader_label(label);
+        self.read_times.insert(label.clone(), current_timestamp());
+        self.guard_retention()?;
+        let db = self.db()?;
+        let data_cf = db.cf_handle(DATA_CF).unwrap();
+        let payload_cf = db.cf_handle(PAYLOAD_CF).unwrap();
+        let mut reader = match self.read_indices.get(&label) {
+            Some(e) => e.clone(),
+            None => {
+                let start_position = self
+                    .start_policy
+                    .as_ref()
+                    .map_or(start_position, |policy| policy.position(&label));
+                let index = match start_position {
+                    StartPosition::Oldest => Some(self.start_index),
+                    StartPosition::Newest => {
+                        if self.empty {
+                            Some(self.write_index)
+                        } else {
+                            Some(previous_index(self.write_index, self.index_space))
+                        }
+                    }
+                    StartPosition::FromTimestamp(timestamp) => self.index_at(timestamp)?,
+                };
+                match index {
+                    Some(index) => Reader::new(index, None, false),
+                    // all elements are older, the reader waits for the next ones
+                    None => Reader::new(self.write_index, Some(self.write_timestamp), false),
+                }
+            }
+        };
+
+        let mut end = match reader.end_timestamp {
+            None => reader.index == self.write_index && self.empty,
+            Some(timestamp) => timestamp == self.write_timestamp,
+        };
+
+        let start = (reader.clone(), end);
+        let now = current_timestamp();
+        let mut corrupted = Vec::new();
+        while !end && res.len() < max_elts {
+            let value = chunking::get_element(db, Some(data_cf), reader.index, self.chunked)?;
+            let outlived = match value {
+                Some(_) if self.strict_ttl => self.outlived_ttl(reader.index, now)?,
+                _ => false,
+            };
+            if outlived {
+                // the compactions haven't removed the expired element yet
+                reader.expired = true;
+            } else if let Some(e) = value {
+                let element = self
+                    .codec
+                    .decode_versioned(e.value, reader.index, self.format_version, self.checksums)
+                    .and_then(|value| {
+                        if !payloads || !self.paired {
+                            return Ok((value, None));
+                        }
+                        let payload = db
+                            .get_cf(payload_cf, index_to_key(reader.index))?
+                            .map(|p| {
+                                self.codec.decode_versioned(
+                                    p,
+                                    reader.index,
+                                    self.format_version,
+                                    self.checksums,
+                                )
+                            })
+                            .transpose()?;
+                        Ok((value, payload))
+                    });
+                match element {
+                    Ok(element) => res.push(element),
+                    Err(e) if self.skip_corrupted && matches!(e, QueueError::Corrupted(_)) => {
+                        corrupted.push(reader.index)
+                    }
+                    Err(e) => return Err(e),
+                }
+            } else if !self.evicting && !self.is_superseded(reader.index)? {
+                res.clear();
+                reader.expired = true;
+            } // the evicted and the superseded elements are skipped
+            reader.index = next_index(reader.index, self.index_space);
+            end = reader.index == self.write_index;
+        }
+        if exact && res.len() < max_elts {
+            // the reader reads the same elements again with the next call
+            if self.read_indices.contains_key(&label) {
+                return Ok((Vec::new(), false));
+            }
+            // the new reader is registered at its start position
+            (reader, end) = start;
+            res.clear();
+            corrupted.clear();
+        }
+        self.corrupted.extend(corrupted);
+
+        reader.end_timestamp = if end {
+            Some(self.write_timestamp)
+        } else {
+            None
+        };
+        let expired = reader.expired;
+        reader.expired = false;
+        let expired_label = expired.then(|| label.clone());
+        self.prefetch_from = (!end).then_some(reader.index);
+
+        let moved = !self.read_indices.get(&label).is_some_and(|e| *e == reader);
+        if moved && write_reader {
+            let unwritten = self
+                .unwritten
+                .entry(label.clone())
+                .or_insert_with(|| (0, Instant::now()));
+            unwritten.0 += res.len() as u64;
+            let write = match self.reader_persistence {
+                // the new readers are registered at once
+                _ if !self.read_indices.contains_key(&label) => true,
+                ReaderPersistence::EveryRead => true,
+                ReaderPersistence::EveryElements(elements) => unwritten.0 >= elements,
+                ReaderPersistence::Interval(interval) => unwritten.1.elapsed() >= interval,
+            };
+            if write {
+                let position = bincode::encode_to_vec(reader.clone(), self.configuration)?;
+                self.write_readers(
+                    rocksdb::WriteBatch::default(),
+                    vec![(label.clone(), position)],
+                )?;
+                self.unwritten.remove(&label);
+            }
+        }
+        if moved {
+            self.read_indices.insert(label, reader);
+        }
+
+        let size = res
+            .iter()
+            .map(|(v, p)| (v.len() + p.as_ref().map_or(0, |p| p.len())) as u64)
+            .sum::<u64>();
+        span.record(res.len(), size);
+        limiter::charge(&self.pop_limiter, res.len(), size);
+
+        if let Some(label) = expired_label {
+            log::warn!(
+                "The reader {} of the queue at {} has missed expired elements",
+                label,
+                self.path.display()
+            );
+            self.listeners.emit(|| QueueEvent::Expire(label));
+        }
+        Ok((res, expired))
+    }
+
+    /// Makes the label a member of the group: the reads of the members share the position of the
+    /// group, so any member's read moves it, e.g. for the cooperating threads of a process. The
+    /// position is stored under the label of the group, the position of the label is kept for
+    /// when it leaves the group. The membership is not stored.
+    ///
+    pub fn join_group(&mut self, label: &str, group: &str) -> Result<()> {
+        if label == group
+            || self.groups.contains_key(group)
+            || self.groups.values().any(|g| g == label)
+        {
+            return Err(QueueError::InvalidArgument(format!(
+                "The label {} can't join the group {}, the groups can't be nested",
+                label, group
+            )));
+        }
+        self.groups.insert(label.to_string(), group.to_string());
+        Ok(())
+    }
+
+    /// Returns `true` if the label was a member of a group, see [`Self::join_group`].
+    pub fn leave_group(&mut self, label: &str) -> bool {
+        self.groups.remove(label).is_some()
+    }
+
+    pub fn group_of(&self, label: &str) -> Option<&str> {
+        self.groups.get(label).map(String::as_str)
+    }
+
+    /// Returns the label the position of the reader with the label is stored under, the label of
+    /// its group if it is a member of one.
+    ///
+    fn reader_label(&self, label: &str) -> String {
+        self.groups
+            .get(label)
+            .cloned()
+            .unwrap_or_else(|| label.to_string())
+    }
+
+    pub fn get_labels(&self) -> Vec<String> {
+        self.read_indices
+            .iter()
+            .map(|e| e.0.clone())
+            .collect::<Vec<String>>()
+    }
+
+    pub fn remove_label(&mut self, label: &str) -> Result<bool> {
+        let label = label.to_string();
+        if self.read_indices.contains_key(&label) {
+            if let Some(store) = self.offset_store.as_mut() {
+                store.remove(&label)?;
+            } else {
+                let reader_cf = self.db()?.cf_handle(READER_CF).unwrap();
+                self.db()?
+                    .delete_cf_opt(reader_cf, label.as_bytes(), &self.write_options())?;
+            }
+
+            self.read_indices.remove(&label);
+            self.read_times.remove(&label);
+            self.unwritten.remove(&label);
+
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    /// Returns the positions of all readers, see [`LabelCheckpoint`].
+    pub fn export_labels(&mut self) -> Result<LabelCheckpoint> {
+        self.actualize_indices()?;
+        let mut readers = self
+            .read_indices
+            .iter()
+            .map(|(label, reader)| (label.clone(), reader.clone()))
+            .collect::<Vec<_>>();
+        readers.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(LabelCheckpoint {
+            write_index: self.write_index,
+            write_timestamp: self.write_timestamp,
+            readers,
+        })
+    }
+
+    /// Restores the positions of the readers exported by [`Self::export_labels`], the readers
+    /// with the same labels are replaced, the others are kept. The readers which had read all
+    /// elements when exported have read all elements of the queue if they are at its write
+    /// index, the readers behind the expired elements miss them with the next read.
+    ///
+    pub fn import_labels(&mut self, checkpoint: &LabelCheckpoint) -> Result<()> {
+        if let Some((label, _)) = checkpoint
+            .readers
+            .iter()
+            .find(|(_, reader)| reader.index >= self.index_space)
+        {
+            return Err(QueueError::InvalidArgument(format!(
+                "The position of the reader {} is out of the index space of the queue",
+                label
+            )));
+        }
+        self.actualize_indices()?;
+        let mut positions = Vec::with_capacity(checkpoint.readers.len());
+        let mut readers = Vec::with_capacity(checkpoint.readers.len());
+        for (label, reader) in &checkpoint.readers {
+            let at_end = reader.at_end(checkpoint.write_index, checkpoint.write_timestamp);
+            let end_timestamp =
+                (at_end && reader.index == self.write_index).then_some(self.write_timestamp);
+            let reader = Reader::new(reader.index, end_timestamp, reader.expired);
+            positions.push((
+                label.clone(),
+                bincode::encode_to_vec(reader.clone(), self.configuration)?,
+            ));
+            readers.push((label.clone(), reader));
+        }
+        self.write_readers(rocksdb::WriteBatch::default(), positions)?;
+
+        let now = current_timestamp();
+        for (label, reader) in readers {
+            self.unwritten.remove(&label);
+            self.read_times.insert(label.clone(), now);
+            self.read_indices.insert(label, reader);
+        }
+        Ok(())
+    }
+
+    /// Rewrites the elements not read by the active readers which were added before the last
+    /// quarter of the TTL, see [`Self::set_retention_guard`]. The add times of the elements
+    /// are kept. The indices are actualized by the caller.
+    ///
+    fn guard_retention(&mut self) -> Result<()> {
+        let Some(guard) = self.retention_guard else {
+            return Ok(());
+        };
+        let now = current_timestamp();
+        let period = (self.ttl / 4).as_nanos() as u64;
+        if self.empty || now.saturating_sub(self.guarded_at) < period {
+            return Ok(());
+        }
+        self.guarded_at = now;
+        let idle_after = guard.idle_after.as_nanos() as u64;
+        let distance = |index: u64| {
+            if index >= self.start_index {
+                index - self.start_index
+            } else {
+                self.index_space - self.start_index + index
+            }
+        };
+        let slowest = self
+            .read_indices
+            .iter()
+            .filter(|(label, reader)| {
+                !reader.at_end(self.write_index, self.write_timestamp)
+                    && self
+                        .read_times
+                        .get(*label)
+                        .is_some_and(|t| now.saturating_sub(*t) < idle_after)
+            })
+            .map(|(_, reader)| reader.index)
+            .min_by_key(|index| distance(*index));
+        let Some(mut index) = slowest else {
+            return Ok(());
+        };
+        if self.disk_size()? as u64 >= guard.max_disk_size {
+            log::warn!(
+                "The unread elements of the queue at {} are not protected, the disk ceiling of {} bytes is reached",
+                self.path.display(),
+                guard.max_disk_size
+            );
+            return Ok(());
+        }
+
+        let db = self.db()?;
+        let data_cf = db.cf_handle(DATA_CF).unwrap();
+        let system_cf = db.cf_handle(SYSTEM_CF).unwrap();
+        let time_cf = db.cf_handle(TIME_CF).unwrap();
+        let payload_cf = db.cf_handle(PAYLOAD_CF).unwrap();
+        let timestamp_cf = db.cf_handle(TIMESTAMP_CF).unwrap();
+        let before = now.saturating_sub(period);
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut chunked = false;
+        let mut protected = 0;
+        let mut first_added = None;
+        // the elements are in the order of their add times, the rest are added recently
+        while index != self.write_index {
+            let Some(added_at) = db.get_cf(time_cf, index_to_key(index))? else {
+                break;
+            };
+            if u64_from_byte_vec(&added_at) >= before {
+                break;
+            }
+            if let Some(e) = chunking::get_element(db, Some(data_cf), index, self.chunked)? {
+                chunking::delete_element(&mut batch, Some(data_cf), &e);
+                chunked |= chunking::put_element(
+                    &mut batch,
+                    Some(data_cf),
+                    index,
+                    &e.value,
+                    self.chunk_size,
+                );
+                if let Some(payload) = db.get_cf(payload_cf, index_to_key(index))? {
+                    batch.put_cf(payload_cf, index_to_key(index), payload);
+                }
+                first_added.get_or_insert(u64_from_byte_vec(&added_at));
+                batch.put_cf(time_cf, index_to_key(index), added_at);
+                protected += 1;
+            }
+            index = next_index(index, self.index_space);
+        }
+        let Some(first_added) = first_added else {
+            return Ok(());
+        };
+        for item in db.iterator_cf(
+            timestamp_cf,
+            IteratorMode::From(&first_added.to_be_bytes(), Direction::Forward),
+        ) {
+            let (key, _) = item?;
+            if key[..crate::U64_BYTE_LEN] >= before.to_be_bytes()[..] {
+                break;
+            }
+            batch.put_cf(timestamp_cf, key, []);
+        }
+
+        // the system cells must outlive the protected elements
+        if chunked || self.chunked {
+            batch.put_cf(system_cf, index_to_key(CHUNKED_KEY), [1u8]);
+        }
+        if self.paired {
+            batch.put_cf(system_cf, index_to_key(PAIRED_KEY), [1u8]);
+        }
+        if self.keyed {
+            batch.put_cf(system_cf, index_to_key(KEYED_KEY), [1u8]);
+        }
+        if self.checksums {
+            batch.put_cf(system_cf, index_to_key(CHECKSUMS_KEY), [1u8]);
+        }
+        batch.put_cf(
+            system_cf,
+            index_to_key(FORMAT_VERSION_KEY),
+            self.format_version.to_le_bytes(),
+        );
+        batch.put_cf(system_cf, index_to_key(SIZES_KEY), self.sizes.to_bytes()?);
+        batch.put_cf(
+            system_cf,
+            index_to_key(INDEX_SPACE_KEY),
+            self.index_space.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_INDEX_KEY),
+            self.write_index.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(WRITE_TIMESTAMP_KEY),
+            self.write_timestamp.to_le_bytes(),
+        );
+        batch.put_cf(
+            system_cf,
+            index_to_key(START_INDEX_KEY),
+            self.start_index.to_le_bytes(),
+        );
+        db.write_opt(batch, &self.write_options())?;
+        self.chunked |= chunked;
+        log::debug!(
+            "{} unread elements of the queue at {} are protected from the expiration",
+            protected,
+            self.path.display()
+        );
+        Ok(())
+    }
+
+    fn actualize_indices(&mut self) -> Result<()> {
+        if self.empty {
+            return Ok(());
+        }
+
+        let system_cf = self.db()?.cf_handle(SYSTEM_CF).unwrap();
+
+        let (start_index, empty, f) = match self.stored_start_index()? {
+            Some(start_index) => {
+                if self.start_index == start_index {
+                    // no elements have been expired
+                    return Ok(());
+                } else {
+                    // some elements have been expired
+                    let f: fn(u64, u64, u64, &mut Reader) =
+                        |start, write_index, _write_timestamp, reader| {
+                            let index = if reader.index == write_index {
+                                reader.index
+                            } else if reader.index > write_index {
+                                if start > write_index {
+                                    u64::max(start, reader.index)
+                                } else {
+                                    start
+                                }
+                            } else if start > write_index {
+                                reader.index
+                            } else {
+                                u64::max(start, reader.index)
+                            };
+                            reader.expired = index != reader.index;
+                            reader.index = index;
+                        };
+                    (start_index, false, f)
+                }
+            }
+            None => {
+                // all elements have been expired
+                let f: fn(u64, u64, u64, &mut Reader) =
+                    |_start, write_index, write_timestamp, reader| {
+                        reader.expired = reader.end_timestamp != Some(write_timestamp);
+                        reader.index = write_index;
+                        reader.end_timestamp = Some(write_timestamp);
+                    };
+                (self.write_index, true, f)
+            }
+        };
+
+        let mut batch = rocksdb::WriteBatch::default();
+        let mut positions = Vec::with_capacity(self.read_indices.len());
+
+        for (label, reader) in self.read_indices.iter() {
+            let mut reader = reader.clone();
             f(
                 start_index,
                 self.write_index,
                 self.write_timestamp,
                 &mut reader,
             );
-            batch.put_cf(
-                reader_cf,
-                label.as_bytes(),
+            positions.push((
+                label.clone(),
                 bincode::encode_to_vec(reader, self.configuration)?,
-            );
+            ));
         }
         batch.put_cf(
             system_cf,
@@ -366,10 +2479,20 @@ impl MpmcQueue {
             start_index.to_le_bytes(),
         );
 
-        self.db.write(batch)?;
+        self.write_readers(batch, positions)?;
+        // all readers are written
+        self.unwritten.clear();
+        log::debug!(
+            "Expired elements of the queue at {} are trimmed, the start index is moved from {} to {}",
+            self.path.display(),
+            self.start_index,
+            start_index
+        );
 
+        let len = self.actualized_len();
         self.start_index = start_index;
         self.empty = empty;
+        self.expired += (len - self.actualized_len()) as u64;
         self.read_indices
             .iter_mut()
             .for_each(|e| f(start_index, self.write_index, self.write_timestamp, e.1));
@@ -378,83 +2501,809 @@ impl MpmcQueue {
     }
 }
 
+impl Drop for MpmcQueue {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush_readers() {
+            log::error!(
+                "Failed to write the readers of the queue at {}: {}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
+
+pub(crate) fn timestamp_entry(timestamp: u64, index: u64) -> Vec<u8> {
+    [timestamp.to_be_bytes(), index_to_key(index)].concat()
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::mpmc::{MpmcQueue, Reader, StartPosition, DATA_CF};
+    use crate::codec::{Compression, CorruptedElement, Keyring, FORMAT_VERSION, KEY_LEN};
+    use crate::error::{QueueError, Result};
+    use crate::events::QueueEvent;
+    use crate::limiter::{RateLimit, RateLimitStrategy};
+    use crate::mpmc::{
+        LabelCheckpoint, MpmcQueue, OffsetStore, QueueOffsetStore, Reader, ReaderPersistence,
+        RetentionGuard, StartPolicy, StartPosition, DATA_CF, READER_CF, START_INDEX_KEY, SYSTEM_CF,
+        WRITE_INDEX_KEY,
+    };
+    use crate::options::QueueOptions;
+    use crate::stats::ReaderPosition;
     use crate::utilities::{current_timestamp, index_to_key};
-    use crate::MAX_ALLOWED_INDEX;
+    use crate::verify::Problem;
+    use crate::PersistentQueueWithCapacity;
+    use parking_lot::Mutex;
+    use rocksdb::Options;
     use std::collections::HashMap;
     use std::fs;
     use std::ops::{Add, Div, Mul};
+    use std::sync::Arc;
     use std::thread::sleep;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
+
+    /// The index space small enough for the tests to wrap the indices around.
+    const INDEX_SPACE: u64 = 6;
+
+    fn open_queue(path: &str, ttl: Duration) -> crate::error::Result<MpmcQueue> {
+        MpmcQueue::with_index_space(path, ttl, INDEX_SPACE, Options::default())
+    }
+
+    #[test]
+    pub fn test_new_empty() {
+        let path = std::env::temp_dir().join("empty");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(60);
+        let now = current_timestamp();
+
+        let queue = open_queue(path, ttl).unwrap();
+
+        assert_eq!(queue.path, std::path::Path::new(path));
+        assert_eq!(queue.start_index, 0);
+        assert_eq!(queue.write_index, 0);
+        assert!(queue.write_timestamp > now);
+        assert_eq!(queue.read_indices.is_empty(), true);
+        assert_eq!(queue.empty, true);
+        assert_eq!(queue.len(), 0);
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_new_non_empty() {
+        let path = std::env::temp_dir().join("non-empty");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(1);
+        let values = vec!["a".as_bytes(), "b".as_bytes()];
+        let label = "label";
+        let write_timestamp = {
+            let mut queue = open_queue(path, ttl).unwrap();
+
+            queue.add(&values).unwrap();
+            queue.next(1, label, StartPosition::Oldest).unwrap();
+
+            wait_and_expire(&mut queue, ttl.mul(2));
+
+            queue.add(&values).unwrap();
+            queue.next(1, label, StartPosition::Oldest).unwrap();
+
+            assert_eq!(queue.start_index, 2);
+            assert_eq!(queue.write_index, 4);
+            assert_eq!(
+                queue.read_indices,
+                HashMap::from([(label.to_string(), Reader::new(3, None, false))])
+            );
+            assert_eq!(queue.empty, false);
+            queue.write_timestamp
+        };
+
+        {
+            let queue = open_queue(path, ttl).unwrap();
+
+            assert_eq!(queue.start_index, 2);
+            assert_eq!(queue.write_index, 4);
+            assert_eq!(queue.write_timestamp, write_timestamp);
+            assert_eq!(
+                queue.read_indices,
+                HashMap::from([(label.to_string(), Reader::new(3, None, false))])
+            );
+            assert_eq!(queue.empty, false);
+        }
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_close_and_reopen() {
+        let path = std::env::temp_dir().join("close-and-reopen");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(60);
+        let mut queue = open_queue(path, ttl).unwrap();
+        queue.add(&[&[1u8]]).unwrap();
+
+        let err = open_queue(path, ttl).err().unwrap();
+        assert!(matches!(err, QueueError::InUse { .. }));
+
+        queue.close().unwrap();
+        let mut queue = open_queue(path, ttl).unwrap();
+        assert_eq!(
+            queue.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8]], false)
+        );
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_reopen() {
+        let path = std::env::temp_dir().join("reopen");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(60);
+        let mut queue = open_queue(path, ttl).unwrap();
+        queue.add(&[&[1u8], &[2u8]]).unwrap();
+        assert_eq!(
+            queue.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8]], false)
+        );
+
+        queue.reopen().unwrap();
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.get_labels(), vec!["label".to_string()]);
+        assert_eq!(
+            queue.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![2u8]], false)
+        );
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_with_options() {
+        let path = std::env::temp_dir().join("with-options");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(60);
+        let mut db_opts = Options::default();
+        db_opts.set_write_buffer_size(1024 * 1024);
+        let mut queue = MpmcQueue::with_options(path, ttl, db_opts).unwrap();
+        assert!(!queue.sync_writes());
+        queue.set_sync_writes(true);
+        queue.add(&[&[1u8]]).unwrap();
+        assert_eq!(
+            queue.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8]], false)
+        );
+        assert!(queue.remove_label("label").unwrap());
+
+        queue.close().unwrap();
+        let queue = MpmcQueue::new(path, ttl).unwrap();
+        assert_eq!(queue.len(), 1);
+        assert!(queue.get_labels().is_empty());
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_index_space() {
+        let path = std::env::temp_dir().join("index-space");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+
+        let ttl = Duration::from_secs(60);
+        {
+            let mut queue = open_queue(path, ttl).unwrap();
+            assert_eq!(queue.index_space(), INDEX_SPACE);
+            queue.add(&["a".as_bytes(); INDEX_SPACE as usize]).unwrap();
+            assert!(matches!(queue.add(&[&[1u8]]), Err(QueueError::Full)));
+        }
+        {
+            // the stored index space is kept
+            let queue = MpmcQueue::new(path, ttl).unwrap();
+            assert_eq!(queue.index_space(), INDEX_SPACE);
+        }
+        assert!(matches!(
+            MpmcQueue::with_index_space(path, ttl, 10, Options::default()),
+            Err(QueueError::InvalidArgument(_))
+        ));
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_stats() {
+        test(Duration::from_secs(60), |mut queue| {
+            assert_eq!(queue.stats().unwrap().newest_timestamp, None);
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+
+            // emulate the expiration of the first element
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(0))
+                .unwrap();
+            queue.next(1, "label", StartPosition::Oldest).unwrap();
+
+            let stats = queue.stats().unwrap();
+            assert_eq!(stats.len, 2);
+            assert_eq!((stats.added, stats.expired), (3, 1));
+            assert_eq!(stats.newest_timestamp, Some(queue.write_timestamp));
+            assert_eq!(stats.labels, HashMap::from([("label".to_string(), 2)]));
+            assert_eq!(stats.sizes.count, 3);
+            assert_eq!(stats.sizes.avg(), Some(1.0));
+        });
+    }
+
+    #[test]
+    pub fn test_push_rate_limit() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue
+                .set_push_rate_limit(Some(RateLimit {
+                    bytes_per_sec: Some(100.0),
+                    strategy: RateLimitStrategy::FailFast,
+                    ..Default::default()
+                }))
+                .unwrap();
+            queue.add(&[&[1; 60], &[2; 40]]).unwrap();
+            assert!(matches!(
+                queue.add(&[&[3; 10]]),
+                Err(QueueError::RateLimited)
+            ));
+            assert_eq!(queue.len(), 2);
+        });
+    }
+
+    #[test]
+    pub fn test_pop_rate_limit() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue
+                .set_pop_rate_limit(Some(RateLimit {
+                    elements_per_sec: Some(4.0),
+                    ..Default::default()
+                }))
+                .unwrap();
+            queue.add(&[&[1], &[2], &[3], &[4], &[5], &[6]]).unwrap();
+            let started = Instant::now();
+            let (items, _) = queue.next(5, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(items.len(), 5);
+            // the debt of the previous read is compensated first
+            let (items, _) = queue.next(1, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(items, vec![vec![6]]);
+            assert!(started.elapsed() >= Duration::from_millis(400));
+        });
+    }
+
+    #[test]
+    pub fn test_estimate_capacity() {
+        test(Duration::from_secs(60), |mut queue| {
+            assert_eq!(queue.estimate_remaining_capacity().unwrap(), None);
+            queue.add(&[&[1; 100]]).unwrap();
+            let remaining = queue.estimate_remaining_capacity().unwrap().unwrap();
+            assert!(remaining < INDEX_SPACE);
+            assert!(queue.estimate_disk_for(10, 100).unwrap() > 0);
+        });
+    }
+
+    #[test]
+    pub fn test_oldest_age() {
+        test(Duration::from_secs(60), |mut queue| {
+            assert_eq!(queue.oldest_age().unwrap(), None);
+            queue.add(&[&[1]]).unwrap();
+            sleep(Duration::from_millis(20));
+            queue.add(&[&[2]]).unwrap();
+            assert!(queue.oldest_age().unwrap().unwrap() >= Duration::from_millis(20));
+
+            // emulate the expiration of the first element
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(0))
+                .unwrap();
+            assert!(queue.oldest_age().unwrap().unwrap() < Duration::from_millis(20));
+        });
+    }
+
+    #[test]
+    pub fn test_eviction_filter() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let options =
+            QueueOptions::default().eviction_filter(|element| element.starts_with(b"debug"));
+        let mut queue = MpmcQueue::open(dir.path(), Duration::from_secs(60), &options).unwrap();
+        queue.add(&[b"info 1", b"debug 1", b"info 2"]).unwrap();
+        let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+        queue
+            .db()
+            .unwrap()
+            .compact_range_cf(data_cf, None::<&[u8]>, None::<&[u8]>);
+
+        let result = queue.next(10, "label", StartPosition::Oldest).unwrap();
+        assert_eq!(
+            result,
+            (vec![b"info 1".to_vec(), b"info 2".to_vec()], false)
+        );
+    }
+
+    #[test]
+    pub fn test_strict_ttl() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ttl = Duration::from_secs(1);
+        let options = QueueOptions::default().strict_ttl(true);
+        let mut queue = MpmcQueue::open(dir.path(), ttl, &options).unwrap();
+        assert!(queue.strict_ttl());
+        queue.add(&[b"a", b"b"]).unwrap();
+        sleep(ttl.mul(2));
+        queue.add(&[b"c"]).unwrap();
+
+        let result = queue.next(10, "strict", StartPosition::Oldest).unwrap();
+        assert_eq!(result, (vec![b"c".to_vec()], true));
+
+        queue.set_strict_ttl(false);
+        let result = queue.next(10, "lenient", StartPosition::Oldest).unwrap();
+        assert_eq!(
+            result,
+            (vec![b"a".to_vec(), b"b".to_vec(), b"c".to_vec()], false)
+        );
+    }
+
+    #[test]
+    pub fn test_retention_guard() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let ttl = Duration::from_secs(2);
+        let options = QueueOptions::default().retention_guard(RetentionGuard {
+            idle_after: Duration::from_secs(60),
+            max_disk_size: u64::MAX,
+        });
+        let mut queue = MpmcQueue::open(dir.path(), ttl, &options).unwrap();
+        queue.add(&[b"a", b"b"]).unwrap();
+        queue.next(1, "active", StartPosition::Oldest).unwrap();
+        sleep(ttl.div(2).add(Duration::from_millis(200)));
+        // the unread element is rewritten with the add
+        queue.add(&[b"c"]).unwrap();
+        wait_and_expire(&mut queue, ttl - Duration::from_millis(200));
+
+        let result = queue.next(10, "active", StartPosition::Oldest).unwrap();
+        assert_eq!(result, (vec![b"b".to_vec(), b"c".to_vec()], false));
+        assert_eq!(queue.len(), 2);
+    }
+
+    #[test]
+    pub fn test_reader_persistence() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let options =
+            QueueOptions::default().reader_persistence(ReaderPersistence::EveryElements(2));
+        let mut queue = MpmcQueue::open(dir.path(), Duration::from_secs(60), &options).unwrap();
+        queue.add(&[&[1], &[2], &[3], &[4]]).unwrap();
+        let stored = |queue: &MpmcQueue| {
+            let reader_cf = queue.db().unwrap().cf_handle(READER_CF).unwrap();
+            let value = queue
+                .db()
+                .unwrap()
+                .get_cf(reader_cf, "label")
+                .unwrap()
+                .unwrap();
+            bincode::decode_from_slice::<Reader, _>(&value, queue.configuration)
+                .unwrap()
+                .0
+                .index
+        };
+
+        // the new reader is written at once
+        queue.next(1, "label", StartPosition::Oldest).unwrap();
+        assert_eq!(stored(&queue), 1);
+        queue.next(1, "label", StartPosition::Oldest).unwrap();
+        assert_eq!(stored(&queue), 1);
+        queue.next(1, "label", StartPosition::Oldest).unwrap();
+        assert_eq!(stored(&queue), 3);
+        queue.next(1, "label", StartPosition::Oldest).unwrap();
+        assert_eq!(stored(&queue), 3);
+        queue.flush_readers().unwrap();
+        assert_eq!(stored(&queue), 4);
+    }
+
+    #[test]
+    pub fn test_export_import_labels() {
+        let mut checkpoint = None;
+        test(Duration::from_secs(60), |mut queue| {
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            queue.next(1, "slow", StartPosition::Oldest).unwrap();
+            queue.next(10, "fast", StartPosition::Oldest).unwrap();
+
+            let exported = queue.export_labels().unwrap();
+            assert_eq!(exported.positions(), vec![("fast", 3), ("slow", 1)]);
+            let bytes = exported.to_bytes().unwrap();
+            assert_eq!(LabelCheckpoint::from_bytes(&bytes).unwrap(), exported);
+            checkpoint = Some(bytes);
+        });
+        let checkpoint = LabelCheckpoint::from_bytes(&checkpoint.unwrap()).unwrap();
+
+        test(Duration::from_secs(60), |mut queue| {
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            queue.next(1, "other", StartPosition::Oldest).unwrap();
+            queue.import_labels(&checkpoint).unwrap();
+            assert_eq!(
+                queue.next(10, "slow", StartPosition::Oldest).unwrap(),
+                (vec![vec![2], vec![3]], false)
+            );
+            assert_eq!(
+                queue.next(10, "fast", StartPosition::Oldest).unwrap(),
+                (vec![], false)
+            );
+            assert_eq!(
+                queue.next(10, "other", StartPosition::Oldest).unwrap(),
+                (vec![vec![2], vec![3]], false)
+            );
+
+            queue.reopen().unwrap();
+            assert_eq!(queue.export_labels().unwrap().positions().len(), 3);
+        });
+    }
+
+    #[derive(Clone, Default)]
+    struct MemoryOffsetStore(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+
+    impl OffsetStore for MemoryOffsetStore {
+        fn load(&mut self) -> Result<Vec<(String, Vec<u8>)>> {
+            Ok(self.0.lock().clone().into_iter().collect())
+        }
+
+        fn store(&mut self, positions: &[(String, Vec<u8>)]) -> Result<()> {
+            self.0.lock().extend(positions.iter().cloned());
+            Ok(())
+        }
+
+        fn remove(&mut self, label: &str) -> Result<()> {
+            self.0.lock().remove(label);
+            Ok(())
+        }
+    }
+
+    #[test]
+    pub fn test_offset_store() {
+        test(Duration::from_secs(60), |mut queue| {
+            let store = MemoryOffsetStore::default();
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            queue.next(1, "queue", StartPosition::Oldest).unwrap();
+            queue
+                .set_offset_store(Some(Box::new(store.clone())))
+                .unwrap();
+            assert!(queue.has_offset_store());
+            assert!(queue.get_labels().is_empty());
+
+            queue.next(1, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(store.0.lock().len(), 1);
+            let reader_cf = queue.db().unwrap().cf_handle(READER_CF).unwrap();
+            assert!(queue
+                .db()
+                .unwrap()
+                .get_cf(reader_cf, "label")
+                .unwrap()
+                .is_none());
+
+            queue.reopen().unwrap();
+            assert_eq!(
+                queue.next(10, "label", StartPosition::Oldest).unwrap(),
+                (vec![vec![2], vec![3]], false)
+            );
+            assert!(queue.remove_label("label").unwrap());
+            assert!(store.0.lock().is_empty());
+
+            queue.set_offset_store(None).unwrap();
+            assert_eq!(queue.get_labels(), vec!["queue".to_string()]);
+        });
+    }
+
+    #[test]
+    pub fn test_process() {
+        test(Duration::from_secs(60), |mut queue| {
+            let directory = tempfile::TempDir::new().unwrap();
+            let destination = Arc::new(
+                PersistentQueueWithCapacity::open(directory.path(), 10, &QueueOptions::default())
+                    .unwrap(),
+            );
+            let double = |items: &[Vec<u8>]| Ok(items.iter().map(|i| vec![i[0] * 2]).collect());
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            assert!(matches!(
+                queue.process("p", 2, StartPosition::Oldest, &destination, double),
+                Err(QueueError::InvalidArgument(_))
+            ));
+
+            // the positions are stored in another queue
+            let other_directory = tempfile::TempDir::new().unwrap();
+            let other = Arc::new(
+                PersistentQueueWithCapacity::open(
+                    other_directory.path(),
+                    10,
+                    &QueueOptions::default(),
+                )
+                .unwrap(),
+            );
+            queue
+                .set_offset_store(Some(Box::new(QueueOffsetStore::new(other))))
+                .unwrap();
+            assert!(matches!(
+                queue.process("p", 2, StartPosition::Oldest, &destination, double),
+                Err(QueueError::InvalidArgument(_))
+            ));
+
+            let store = QueueOffsetStore::new(destination.clone());
+            queue.set_offset_store(Some(Box::new(store))).unwrap();
+            assert_eq!(
+                queue
+                    .process("p", 2, StartPosition::Oldest, &destination, double)
+                    .unwrap(),
+                (2, false)
+            );
+            assert_eq!(destination.pop(10).unwrap(), vec![vec![2], vec![4]]);
+
+            // the failed processing keeps the position of the reader
+            assert!(queue
+                .process("p", 2, StartPosition::Oldest, &destination, |_| Err(
+                    QueueError::Internal("failed".to_string())
+                ))
+                .is_err());
+            assert!(destination.is_empty());
+
+            let store = QueueOffsetStore::new(destination.clone());
+            queue.set_offset_store(Some(Box::new(store))).unwrap();
+            assert_eq!(
+                queue
+                    .process("p", 2, StartPosition::Oldest, &destination, double)
+                    .unwrap(),
+                (1, false)
+            );
+            assert_eq!(destination.pop(10).unwrap(), vec![vec![6]]);
+            assert_eq!(
+                queue
+                    .process("p", 2, StartPosition::Oldest, &destination, double)
+                    .unwrap(),
+                (0, false)
+            );
+        });
+    }
+
+    #[test]
+    pub fn test_stats_snapshot() {
+        test(Duration::from_secs(60), |mut queue| {
+            assert_eq!(queue.stats_snapshot().unwrap().len, 0);
+            queue.add(&[&[1], &[2], &[3]]).unwrap();
+            queue.next(1, "slow", StartPosition::Oldest).unwrap();
+            queue.next(10, "fast", StartPosition::Oldest).unwrap();
+
+            let snapshot = queue.stats_snapshot().unwrap();
+            assert_eq!(snapshot.len, 3);
+            assert_eq!(snapshot.payload_size, None);
+            assert_eq!((snapshot.start_index, snapshot.write_index), (0, 3));
+            assert_eq!(
+                snapshot.labels,
+                HashMap::from([
+                    ("slow".to_string(), ReaderPosition { index: 1, lag: 2 }),
+                    ("fast".to_string(), ReaderPosition { index: 3, lag: 0 }),
+                ])
+            );
+        });
+    }
 
     #[test]
-    pub fn test_new_empty() {
-        let path = std::env::temp_dir().join("empty");
-        let path = path.to_str().unwrap();
-        let _ = fs::remove_dir_all(path);
+    pub fn test_timestamps() {
+        test(Duration::from_secs(60), |mut queue| {
+            let before = current_timestamp();
+            queue.add(&[&[1], &[2]]).unwrap();
+            let middle = current_timestamp();
+            queue.add(&[&[3]]).unwrap();
+            let after = current_timestamp();
 
-        let ttl = Duration::from_secs(60);
-        let now = current_timestamp();
+            assert_eq!(
+                queue.read_range(before, middle, 10).unwrap(),
+                vec![vec![1], vec![2]]
+            );
+            assert_eq!(queue.read_range(middle, after, 10).unwrap(), vec![vec![3]]);
+            assert_eq!(queue.read_range(before, after, 2).unwrap().len(), 2);
+            assert!(queue.read_range(after, u64::MAX, 10).unwrap().is_empty());
 
-        let queue = MpmcQueue::new(path, ttl).unwrap();
+            let result = queue
+                .next(10, "middle", StartPosition::FromTimestamp(middle))
+                .unwrap();
+            assert_eq!(result.0, vec![vec![3]]);
+            let result = queue
+                .next(10, "after", StartPosition::FromTimestamp(after))
+                .unwrap();
+            assert!(result.0.is_empty());
+            queue.add(&[&[4]]).unwrap();
+            let result = queue.next(10, "after", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![4]], false));
 
-        assert_eq!(queue.path, path);
-        assert_eq!(queue.start_index, 0);
-        assert_eq!(queue.write_index, 0);
-        assert!(queue.write_timestamp > now);
-        assert_eq!(queue.read_indices.is_empty(), true);
-        assert_eq!(queue.empty, true);
-        assert_eq!(queue.len(), 0);
+            queue.next(1, "oldest", StartPosition::Oldest).unwrap();
+            assert_eq!(queue.purge_before(middle).unwrap(), 2);
+            assert_eq!(queue.len(), 2);
+            let result = queue.next(10, "oldest", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![3], vec![4]], true));
+            assert_eq!(queue.purge_before(u64::MAX).unwrap(), 2);
+            assert!(queue.is_empty());
+        });
+    }
 
-        let _ = fs::remove_dir_all(path);
+    #[test]
+    pub fn test_add_with_timestamps() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue
+                .add_with_timestamps(&[&[1], &[2], &[3]], &[100, 100, 200])
+                .unwrap();
+            assert_eq!(
+                queue.read_range(0, 150, 10).unwrap(),
+                vec![vec![1], vec![2]]
+            );
+            assert_eq!(queue.read_range(150, 300, 10).unwrap(), vec![vec![3]]);
+            let result = queue
+                .next(10, "label", StartPosition::FromTimestamp(150))
+                .unwrap();
+            assert_eq!(result, (vec![vec![3]], false));
+
+            let invalid: [(&[&[u8]], &[u64]); 5] = [
+                (&[&[4]], &[300, 400]),
+                (&[&[4], &[5]], &[400, 300]),
+                (&[&[4]], &[150]),
+                (&[&[4]], &[200]),
+                (&[&[4]], &[u64::MAX]),
+            ];
+            for (values, timestamps) in invalid {
+                assert!(matches!(
+                    queue.add_with_timestamps(values, timestamps),
+                    Err(QueueError::InvalidArgument(_))
+                ));
+            }
+            queue
+                .add_with_timestamps(&[&[4], &[5]], &[200, 300])
+                .unwrap();
+            assert_eq!(queue.purge_before(250).unwrap(), 4);
+            let result = queue.next(10, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![5]], true));
+
+            queue.add(&[&[6]]).unwrap();
+            let result = queue.next(10, "label", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![6]], false));
+        });
     }
 
     #[test]
-    pub fn test_new_non_empty() {
-        let path = std::env::temp_dir().join("non-empty");
-        let path = path.to_str().unwrap();
-        let _ = fs::remove_dir_all(path);
+    pub fn test_add_with_keys() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue.add_with_keys(&[(b"a", &[1]), (b"b", &[1])]).unwrap();
+            let result = queue.next(1, "reader", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![1]], false));
 
-        let ttl = Duration::from_secs(1);
-        let values = vec!["a".as_bytes(), "b".as_bytes()];
-        let label = "label";
-        let write_timestamp = {
-            let mut queue = MpmcQueue::new(path, ttl).unwrap();
+            queue
+                .add_with_keys(&[(b"a", &[2]), (b"c", &[1]), (b"a", &[3])])
+                .unwrap();
+            assert_eq!(queue.get_by_key(b"a").unwrap(), Some(vec![3]));
+            assert_eq!(queue.get_by_key(b"x").unwrap(), None);
+            let result = queue.next(10, "reader", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![1], vec![1], vec![3]], false));
+
+            queue.next(0, "slow", StartPosition::Oldest).unwrap();
+            queue.add_with_keys(&[(b"b", &[2])]).unwrap();
+            assert_eq!(queue.len(), 3);
+            let result = queue.next(10, "slow", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![1], vec![3], vec![2]], false));
+
+            queue.reopen().unwrap();
+            let result = queue.next(10, "new", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![1], vec![3], vec![2]], false));
+            assert_eq!(queue.get_by_key(b"b").unwrap(), Some(vec![2]));
+        });
+    }
 
-            queue.add(&values).unwrap();
-            queue.next(1, label, StartPosition::Oldest).unwrap();
+    #[test]
+    pub fn test_start_policy() {
+        let policy = StartPolicy::new(StartPosition::Newest)
+            .with_prefix("replay", StartPosition::Oldest)
+            .with_prefix("replay-live", StartPosition::Newest);
+        assert_eq!(policy.position("replay-1"), StartPosition::Oldest);
+        assert_eq!(policy.position("replay-live-1"), StartPosition::Newest);
+        assert_eq!(policy.position("other"), StartPosition::Newest);
+
+        test(Duration::from_secs(60), |mut queue| {
+            queue.add(&[&[1], &[2]]).unwrap();
+            queue.set_start_policy(Some(policy.clone()));
+            let result = queue.next(10, "other", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![2]], false));
+            let result = queue.next(10, "replay-1", StartPosition::Newest).unwrap();
+            assert_eq!(result, (vec![vec![1], vec![2]], false));
+
+            queue.set_start_policy(None);
+            let result = queue.next(10, "replay-2", StartPosition::Newest).unwrap();
+            assert_eq!(result, (vec![vec![2]], false));
+        });
+    }
 
-            wait_and_expire(&mut queue, ttl.mul(2));
+    #[test]
+    pub fn test_label_groups() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue.add(&[&[1], &[2], &[3], &[4]]).unwrap();
+            queue.join_group("t1", "workers").unwrap();
+            queue.join_group("t2", "workers").unwrap();
+            assert!(queue.join_group("workers", "all").is_err());
+            assert!(queue.join_group("t3", "t1").is_err());
+            assert_eq!(queue.group_of("t1"), Some("workers"));
+
+            let result = queue.next(1, "t1", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![1]], false));
+            let result = queue.next(1, "t2", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![2]], false));
+            let result = queue.next(10, "t1", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![3], vec![4]], false));
+            assert_eq!(queue.get_labels(), vec!["workers".to_string()]);
+
+            assert!(queue.leave_group("t1"));
+            assert!(!queue.leave_group("t1"));
+            let result = queue.next(10, "t1", StartPosition::Oldest).unwrap();
+            assert_eq!(result.0.len(), 4);
+        });
+    }
 
-            queue.add(&values).unwrap();
-            queue.next(1, label, StartPosition::Oldest).unwrap();
+    #[test]
+    pub fn test_add_with_keys_expiration() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue
+                .add_with_keys(&[(b"a", &[1]), (b"b", &[2]), (b"c", &[3])])
+                .unwrap();
+            queue.add_with_keys(&[(b"b", &[4])]).unwrap();
+            let result = queue.next(10, "reader", StartPosition::Oldest).unwrap();
+            // the superseded element is not reported as expired
+            assert_eq!(result, (vec![vec![1], vec![3], vec![4]], false));
 
-            assert_eq!(queue.start_index, 2);
-            assert_eq!(queue.write_index, 4);
-            assert_eq!(
-                queue.read_indices,
-                HashMap::from([(label.to_string(), Reader::new(3, None, false))])
-            );
-            assert_eq!(queue.empty, false);
-            queue.write_timestamp
-        };
+            // emulate that the element of the key "c" expires
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(2))
+                .unwrap();
+            let result = queue.next(10, "other", StartPosition::Oldest).unwrap();
+            assert_eq!(result, (vec![vec![4]], true));
+        });
+    }
 
-        {
-            let queue = MpmcQueue::new(path, ttl).unwrap();
+    #[test]
+    pub fn test_listeners() {
+        let ttl = Duration::from_secs(1);
+        test(ttl, |mut queue| {
+            let events = Arc::new(Mutex::new(Vec::new()));
+            let expired = Arc::new(Mutex::new(Vec::new()));
+            {
+                let events = events.clone();
+                queue.add_listener(move |event| events.lock().push(event.clone()));
+                let expired = expired.clone();
+                queue.on_expire(move |label| expired.lock().push(label.to_string()));
+            }
+
+            queue.add(&[&[1u8], &[2u8]]).unwrap();
+            queue.next(1, "label", StartPosition::Oldest).unwrap();
+            wait_and_expire(&mut queue, ttl.mul(2));
+            assert!(queue.next(1, "label", StartPosition::Oldest).unwrap().1);
+            let values = [[0u8]; INDEX_SPACE as usize + 1];
+            let values = values.iter().map(|v| v.as_slice()).collect::<Vec<_>>();
+            assert!(matches!(queue.add(&values), Err(QueueError::Full)));
 
-            assert_eq!(queue.start_index, 2);
-            assert_eq!(queue.write_index, 4);
-            assert_eq!(queue.write_timestamp, write_timestamp);
             assert_eq!(
-                queue.read_indices,
-                HashMap::from([(label.to_string(), Reader::new(3, None, false))])
+                *events.lock(),
+                vec![
+                    QueueEvent::Push(2),
+                    QueueEvent::Expire("label".to_string()),
+                    QueueEvent::Full
+                ]
             );
-            assert_eq!(queue.empty, false);
-        }
-
-        let _ = fs::remove_dir_all(path);
+            assert_eq!(*expired.lock(), vec!["label".to_string()]);
+        });
     }
 
     #[test]
@@ -473,7 +3322,7 @@ mod tests {
     #[test]
     pub fn test_add_too_big_batch() {
         test(Duration::from_secs(10), |mut queue| {
-            let values = vec!["a".as_bytes(); (MAX_ALLOWED_INDEX + 1) as usize];
+            let values = vec!["a".as_bytes(); (INDEX_SPACE + 1) as usize];
             let result = queue.add(&values);
 
             assert_eq!(result.is_err(), true);
@@ -488,14 +3337,14 @@ mod tests {
     #[test]
     pub fn test_add_full_queue() {
         test(Duration::from_secs(10), |mut queue| {
-            let values = vec!["a".as_bytes(); MAX_ALLOWED_INDEX as usize];
+            let values = vec!["a".as_bytes(); INDEX_SPACE as usize];
             queue.add(&values).unwrap();
 
             assert_eq!(queue.start_index, 0);
             assert_eq!(queue.write_index, 0);
             assert_eq!(queue.read_indices.is_empty(), true);
             assert_eq!(queue.empty, false);
-            assert_eq!(queue.len(), MAX_ALLOWED_INDEX as usize);
+            assert_eq!(queue.len(), INDEX_SPACE as usize);
 
             let result = queue.add(&["b".as_bytes()]);
 
@@ -505,7 +3354,314 @@ mod tests {
             assert_eq!(queue.write_index, 0);
             assert_eq!(queue.read_indices.is_empty(), true);
             assert_eq!(queue.empty, false);
-            assert_eq!(queue.len(), MAX_ALLOWED_INDEX as usize);
+            assert_eq!(queue.len(), INDEX_SPACE as usize);
+        });
+    }
+
+    #[test]
+    pub fn test_add_next_chunked() {
+        let label = "label";
+        let big = (0..10u8).collect::<Vec<_>>();
+        test(Duration::from_secs(10), |mut queue| {
+            queue.set_chunk_size(3);
+            queue.add(&[&big, "a".as_bytes()]).unwrap();
+
+            assert_eq!(queue.write_index, 2);
+            assert_eq!(queue.len(), 2);
+
+            let result = queue.next(2, label, StartPosition::Oldest).unwrap();
+
+            assert_eq!(result.0, vec![big.clone(), "a".as_bytes().to_vec()]);
+            assert_eq!(result.1, false);
+        });
+    }
+
+    #[test]
+    pub fn test_add_next_pairs() {
+        let frame = vec![7u8; 10000];
+        test(Duration::from_secs(10), |mut queue| {
+            queue.add(&[&[1u8]]).unwrap();
+            queue.add_pairs(&[(&[2u8], &frame), (&[3u8], &[])]).unwrap();
+            queue.add_pair(&[4u8], &[5u8, 6]).unwrap();
+
+            assert_eq!(queue.len(), 4);
+            assert_eq!(queue.stats().unwrap().sizes.total, 10006);
+
+            let result = queue
+                .next_meta_only(4, "meta", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result.0, vec![vec![1u8], vec![2u8], vec![3u8], vec![4u8]]);
+
+            let result = queue.next_pairs(4, "pairs", StartPosition::Oldest).unwrap();
+            assert_eq!(
+                result.0,
+                vec![
+                    (vec![1u8], vec![]),
+                    (vec![2u8], frame.clone()),
+                    (vec![3u8], vec![]),
+                    (vec![4u8], vec![5u8, 6])
+                ]
+            );
+            assert_eq!(result.1, false);
+        });
+    }
+
+    #[test]
+    pub fn test_add_next_encrypted() {
+        let label = "label";
+        test(Duration::from_secs(10), |mut queue| {
+            let keyring = Keyring::new(7, &[7u8; KEY_LEN]).unwrap();
+            queue.set_keyring(Some(keyring.clone()));
+            queue.add(&[&[1u8, 2, 3]]).unwrap();
+
+            queue.set_keyring(None);
+            assert!(queue.next(1, label, StartPosition::Oldest).is_err());
+
+            queue.set_keyring(Some(keyring));
+            let result = queue.next(1, label, StartPosition::Oldest).unwrap();
+
+            assert_eq!(result.0, vec![vec![1u8, 2, 3]]);
+            assert_eq!(result.1, false);
+        });
+    }
+
+    #[test]
+    pub fn test_add_next_corrupted() {
+        let label = "label";
+        test(Duration::from_secs(10), |mut queue| {
+            queue.set_checksums(true);
+            queue.add(&[&[1u8, 2, 3], &[4u8, 5, 6]]).unwrap();
+
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            let mut value = queue
+                .db()
+                .unwrap()
+                .get_cf(data_cf, index_to_key(0))
+                .unwrap()
+                .unwrap();
+            *value.last_mut().unwrap() ^= 1;
+            queue
+                .db()
+                .unwrap()
+                .put_cf(data_cf, index_to_key(0), value)
+                .unwrap();
+
+            let err = queue.next(2, label, StartPosition::Oldest).unwrap_err();
+            assert!(matches!(
+                err,
+                QueueError::Corrupted(CorruptedElement { index: 0 })
+            ));
+
+            queue.set_skip_corrupted(true);
+            let result = queue.next(2, label, StartPosition::Oldest).unwrap();
+
+            assert_eq!(result.0, vec![vec![4u8, 5, 6]]);
+            assert_eq!(result.1, false);
+            assert_eq!(queue.take_corrupted(), vec![0]);
+        });
+    }
+
+    #[test]
+    pub fn test_next_exact() {
+        test(Duration::from_secs(60), |mut queue| {
+            queue.set_checksums(true);
+            queue.set_skip_corrupted(true);
+            queue.add(&[&[1u8]]).unwrap();
+            let result = queue
+                .next_exact(2, "reader", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result, (vec![], false));
+
+            // the superseded element doesn't count towards the batch
+            queue.add_with_keys(&[(b"a", &[2u8])]).unwrap();
+            queue.add_with_keys(&[(b"a", &[3u8])]).unwrap();
+            let result = queue
+                .next_exact(3, "reader", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result, (vec![], false));
+            let result = queue
+                .next_exact(2, "reader", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result, (vec![vec![1u8], vec![3u8]], false));
+
+            // neither does the corrupted one
+            queue.add(&[&[4u8], &[5u8]]).unwrap();
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            let mut value = queue
+                .db()
+                .unwrap()
+                .get_cf(data_cf, index_to_key(3))
+                .unwrap()
+                .unwrap();
+            *value.last_mut().unwrap() ^= 1;
+            queue
+                .db()
+                .unwrap()
+                .put_cf(data_cf, index_to_key(3), value)
+                .unwrap();
+            let result = queue
+                .next_exact(2, "reader", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result, (vec![], false));
+            assert!(queue.take_corrupted().is_empty());
+            queue.add(&[&[6u8]]).unwrap();
+            let result = queue
+                .next_exact(2, "reader", StartPosition::Oldest)
+                .unwrap();
+            assert_eq!(result, (vec![vec![5u8], vec![6u8]], false));
+            assert_eq!(queue.take_corrupted(), vec![3]);
+
+            assert!(matches!(
+                queue.next_exact(
+                    queue.index_space() as usize + 1,
+                    "reader",
+                    StartPosition::Oldest
+                ),
+                Err(QueueError::InvalidArgument(_))
+            ));
+        });
+    }
+
+    #[test]
+    pub fn test_verify() {
+        test(Duration::from_secs(10), |mut queue| {
+            assert!(queue.verify().unwrap().is_ok());
+
+            queue.add(&[&[1u8], &[2u8], &[3u8]]).unwrap();
+            queue.next(1, "a", StartPosition::Oldest).unwrap();
+            let report = queue.verify().unwrap();
+            assert!(report.is_ok());
+            assert_eq!(report.elements, 3);
+
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(1))
+                .unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(data_cf, index_to_key(4), [0u8])
+                .unwrap();
+            let reader = Reader::new(5, None, false);
+            let reader_cf = queue.db().unwrap().cf_handle(READER_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(
+                    reader_cf,
+                    "b",
+                    bincode::encode_to_vec(reader.clone(), queue.configuration).unwrap(),
+                )
+                .unwrap();
+            queue.read_indices.insert("b".to_string(), reader);
+
+            let report = queue.verify().unwrap();
+            assert_eq!(
+                report.problems,
+                vec![
+                    Problem::MissingElement { index: 1 },
+                    Problem::UnexpectedElement { index: 4 },
+                    Problem::ReaderOutOfRange {
+                        label: "b".to_string(),
+                        index: 5
+                    },
+                ]
+            );
+        });
+    }
+
+    #[test]
+    pub fn test_open_verified() {
+        let path = std::env::temp_dir().join("open-verified");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+        let ttl = Duration::from_secs(10);
+        {
+            let mut queue = open_queue(path, ttl).unwrap();
+            queue.add(&[&[1u8], &[2u8]]).unwrap();
+            let system_cf = queue.db().unwrap().cf_handle(SYSTEM_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(system_cf, index_to_key(WRITE_INDEX_KEY), 1u64.to_le_bytes())
+                .unwrap();
+        }
+        let err = MpmcQueue::open_verified(path, ttl, Options::default(), false)
+            .err()
+            .unwrap();
+        assert!(matches!(err, QueueError::Unverified { .. }));
+
+        let mut queue = MpmcQueue::open_verified(path, ttl, Options::default(), true).unwrap();
+        assert_eq!(
+            queue.next(2, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![1u8], vec![2u8]], false)
+        );
+
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_repair() {
+        let path = std::env::temp_dir().join("repair");
+        let path = path.to_str().unwrap();
+        let _ = fs::remove_dir_all(path);
+        let ttl = Duration::from_secs(10);
+        {
+            let mut queue = open_queue(path, ttl).unwrap();
+            queue.add(&[&[1u8], &[2u8], &[3u8]]).unwrap();
+            queue.next(1, "a", StartPosition::Oldest).unwrap();
+            queue.next(3, "b", StartPosition::Oldest).unwrap();
+
+            let system_cf = queue.db().unwrap().cf_handle(SYSTEM_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(system_cf, index_to_key(START_INDEX_KEY), 5u64.to_le_bytes())
+                .unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(system_cf, index_to_key(WRITE_INDEX_KEY), 1u64.to_le_bytes())
+                .unwrap();
+            let reader_cf = queue.db().unwrap().cf_handle(READER_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .put_cf(reader_cf, "c", [0xffu8; 3])
+                .unwrap();
+        }
+        MpmcQueue::repair(path, ttl).unwrap();
+        {
+            let mut queue = open_queue(path, ttl).unwrap();
+            assert_eq!((queue.start_index, queue.write_index), (0, 3));
+            assert_eq!(queue.read_indices.get("a").unwrap().index, 1);
+            assert_eq!(queue.read_indices.get("c").unwrap().index, 0);
+            assert!(queue.verify().unwrap().is_ok());
+
+            let result = queue.next(3, "c", StartPosition::Oldest).unwrap();
+            assert_eq!(result.0, vec![vec![1u8], vec![2u8], vec![3u8]]);
+        }
+        let _ = fs::remove_dir_all(path);
+    }
+
+    #[test]
+    pub fn test_add_next_compressed() {
+        let label = "label";
+        let value = vec![1u8; 100];
+        test(Duration::from_secs(10), |mut queue| {
+            queue.set_compression(Compression::Lz4);
+            queue.add(&[&value]).unwrap();
+            queue.set_compression(Compression::None);
+            queue.add(&[&value]).unwrap();
+
+            assert_eq!(queue.format_version, FORMAT_VERSION);
+
+            let result = queue.next(2, label, StartPosition::Oldest).unwrap();
+
+            assert_eq!(result.0, vec![value.clone(), value.clone()]);
+            assert_eq!(result.1, false);
         });
     }
 
@@ -630,7 +3786,7 @@ mod tests {
 
         test(ttl, |mut queue| {
             queue
-                .add(&["value".as_bytes(); (MAX_ALLOWED_INDEX - 1) as usize])
+                .add(&["value".as_bytes(); (INDEX_SPACE - 1) as usize])
                 .unwrap();
             queue.add(&[last_value]).unwrap();
 
@@ -689,9 +3845,7 @@ mod tests {
 
             let write_timestamp = queue.write_timestamp;
 
-            queue
-                .add(&["a".as_bytes(); MAX_ALLOWED_INDEX as usize])
-                .unwrap();
+            queue.add(&["a".as_bytes(); INDEX_SPACE as usize]).unwrap();
 
             assert_eq!(queue.start_index, 2);
             assert_eq!(queue.write_index, 2);
@@ -703,7 +3857,7 @@ mod tests {
                 )])
             );
             assert_eq!(queue.empty, false);
-            assert_eq!(queue.len(), MAX_ALLOWED_INDEX as usize);
+            assert_eq!(queue.len(), INDEX_SPACE as usize);
             assert!(queue.write_timestamp > write_timestamp);
 
             // expire all
@@ -798,9 +3952,7 @@ mod tests {
 
             let write_timestamp = queue.write_timestamp;
 
-            queue
-                .add(&["a".as_bytes(); MAX_ALLOWED_INDEX as usize])
-                .unwrap();
+            queue.add(&["a".as_bytes(); INDEX_SPACE as usize]).unwrap();
 
             assert_eq!(queue.start_index, 2);
             assert_eq!(queue.write_index, 2);
@@ -812,7 +3964,7 @@ mod tests {
                 )])
             );
             assert_eq!(queue.empty, false);
-            assert_eq!(queue.len(), MAX_ALLOWED_INDEX as usize);
+            assert_eq!(queue.len(), INDEX_SPACE as usize);
             assert!(queue.write_timestamp > write_timestamp);
 
             // expire all
@@ -891,9 +4043,10 @@ mod tests {
 
             queue.add(&[value, "b".as_bytes()]).unwrap();
 
-            let data_cf = queue.db.cf_handle(DATA_CF).unwrap();
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
             queue
-                .db
+                .db()
+                .unwrap()
                 .compact_range_cf(data_cf, None::<&[u8]>, None::<&[u8]>);
 
             let result = queue.next(1, label, StartPosition::Oldest).unwrap();
@@ -924,9 +4077,10 @@ mod tests {
 
             queue.add(&["b".as_bytes(), value]).unwrap();
 
-            let data_cf = queue.db.cf_handle(DATA_CF).unwrap();
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
             queue
-                .db
+                .db()
+                .unwrap()
                 .compact_range_cf(data_cf, None::<&[u8]>, None::<&[u8]>);
 
             let result = queue.next(1, label, StartPosition::Newest).unwrap();
@@ -1309,6 +4463,64 @@ mod tests {
         });
     }
 
+    #[test]
+    pub fn test_len_during_expiration() {
+        let ttl = Duration::from_secs(4);
+        test(ttl, |mut queue| {
+            queue.add(&["a".as_bytes()]).unwrap();
+            sleep(ttl.div(2));
+            queue.add(&["b".as_bytes(), "c".as_bytes()]).unwrap();
+            assert_eq!(queue.len(), 3);
+
+            // expire value a, the indices are not actualized
+            wait_and_expire(&mut queue, ttl.mul_f32(0.75));
+            assert_eq!(queue.start_index, 0);
+            assert_eq!(queue.len(), 2);
+            assert_eq!(queue.stats().unwrap().len, 2);
+            assert_eq!(queue.expired, 1);
+
+            // expire all elements
+            wait_and_expire(&mut queue, ttl.div(2));
+            assert_eq!(queue.start_index, 1);
+            assert_eq!(queue.len(), 0);
+            assert!(queue.is_empty());
+            assert_eq!(queue.stats().unwrap().len, 0);
+        });
+    }
+
+    #[test]
+    pub fn test_len_across_key_boundary() {
+        let directory = tempfile::TempDir::new().unwrap();
+        let path = directory.path().to_str().unwrap();
+        let mut queue =
+            MpmcQueue::with_index_space(path, Duration::from_secs(60), 1000, Options::default())
+                .unwrap();
+        queue.add(&[[1u8].as_slice(); 600]).unwrap();
+
+        // the compactions remove the expired elements
+        let expire = |queue: &MpmcQueue, indices: std::ops::Range<u64>| {
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            for index in indices {
+                queue
+                    .db()
+                    .unwrap()
+                    .delete_cf(data_cf, index_to_key(index))
+                    .unwrap();
+            }
+        };
+        expire(&queue, 0..200);
+        queue.actualize_indices().unwrap();
+        assert_eq!(queue.start_index, 200);
+
+        // the little-endian key of 456 is the first one from the key of 200 in the byte order
+        expire(&queue, 200..257);
+        assert_eq!(queue.len(), 343);
+        assert_eq!(queue.stats_snapshot().unwrap().len, 343);
+        queue.actualize_indices().unwrap();
+        assert_eq!(queue.start_index, 257);
+        assert_eq!(queue.len(), 343);
+    }
+
     #[test]
     pub fn test_next_during_expiration_some_elements() {
         let ttl = Duration::from_secs(4);
@@ -1322,8 +4534,12 @@ mod tests {
                 .unwrap();
 
             // emulate that the second value expires right after reading the first value
-            let data_cf = queue.db.cf_handle(DATA_CF).unwrap();
-            queue.db.delete_cf(data_cf, index_to_key(1)).unwrap();
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(1))
+                .unwrap();
 
             let result = queue.next(2, label, StartPosition::Oldest).unwrap();
 
@@ -1356,10 +4572,22 @@ mod tests {
                 .unwrap();
 
             // emulate that the all values expire right after reading the first value
-            let data_cf = queue.db.cf_handle(DATA_CF).unwrap();
-            queue.db.delete_cf(data_cf, index_to_key(1)).unwrap();
-            queue.db.delete_cf(data_cf, index_to_key(2)).unwrap();
-            queue.db.delete_cf(data_cf, index_to_key(3)).unwrap();
+            let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(1))
+                .unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(2))
+                .unwrap();
+            queue
+                .db()
+                .unwrap()
+                .delete_cf(data_cf, index_to_key(3))
+                .unwrap();
 
             let result = queue.next(4, label, StartPosition::Oldest).unwrap();
 
@@ -1434,16 +4662,17 @@ mod tests {
         let directory = tempfile::TempDir::new().unwrap();
         let path = directory.path().to_str().unwrap();
 
-        let queue = MpmcQueue::new(path, ttl).unwrap();
+        let queue = open_queue(path, ttl).unwrap();
 
         f(queue);
     }
 
     fn wait_and_expire(queue: &mut MpmcQueue, duration: Duration) {
         sleep(duration);
-        let data_cf = queue.db.cf_handle(DATA_CF).unwrap();
+        let data_cf = queue.db().unwrap().cf_handle(DATA_CF).unwrap();
         queue
-            .db
+            .db()
+            .unwrap()
             .compact_range_cf(data_cf, None::<&[u8]>, None::<&[u8]>);
     }
 }
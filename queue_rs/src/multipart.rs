@@ -0,0 +1,87 @@
+use crate::error::{QueueError, Result};
+
+// A multi-part element starts with the number of the parts and their lengths, the parts follow
+// in order. The numbers are little endian u32, so a part is limited to 4 GiB.
+const PART_LEN_LEN: usize = 4;
+
+/// Packs the parts into one element, e.g. the metadata and the content of a media frame, see
+/// [`unpack`].
+///
+pub fn pack(parts: &[&[u8]]) -> Result<Vec<u8>> {
+    let header_len = PART_LEN_LEN * (parts.len() + 1);
+    let mut element = Vec::with_capacity(header_len + parts.iter().map(|p| p.len()).sum::<usize>());
+    element.extend_from_slice(&part_len(parts.len())?.to_le_bytes());
+    for part in parts {
+        element.extend_from_slice(&part_len(part.len())?.to_le_bytes());
+    }
+    for part in parts {
+        element.extend_from_slice(part);
+    }
+    Ok(element)
+}
+
+/// Returns the parts of the element packed with [`pack`], they borrow the element.
+pub fn unpack(element: &[u8]) -> Result<Vec<&[u8]>> {
+    let count = read_len(element, 0)?;
+    let mut offset = PART_LEN_LEN
+        .checked_mul(count + 1)
+        .filter(|header_len| *header_len <= element.len())
+        .ok_or_else(|| malformed("the header is truncated"))?;
+    let mut parts = Vec::with_capacity(count);
+    for i in 0..count {
+        let len = read_len(element, PART_LEN_LEN * (i + 1))?;
+        let part = element
+            .get(offset..offset + len)
+            .ok_or_else(|| malformed("a part is truncated"))?;
+        parts.push(part);
+        offset += len;
+    }
+    if offset != element.len() {
+        return Err(malformed("unexpected bytes after the parts"));
+    }
+    Ok(parts)
+}
+
+fn part_len(len: usize) -> Result<u32> {
+    u32::try_from(len).map_err(|_| {
+        QueueError::InvalidArgument(format!("A part can't be longer than {} bytes", u32::MAX))
+    })
+}
+
+fn read_len(element: &[u8], offset: usize) -> Result<usize> {
+    let bytes = element
+        .get(offset..offset + PART_LEN_LEN)
+        .ok_or_else(|| malformed("the header is truncated"))?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+}
+
+fn malformed(reason: &str) -> QueueError {
+    QueueError::Serialization(format!("Malformed multi-part element: {}", reason))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pack_unpack() {
+        let content = vec![7u8; 1000];
+        let element = pack(&[b"meta", &content, b""]).unwrap();
+        assert_eq!(element.len(), 16 + 4 + 1000);
+        assert_eq!(
+            unpack(&element).unwrap(),
+            vec![b"meta".as_slice(), content.as_slice(), b"".as_slice()]
+        );
+        assert_eq!(unpack(&pack(&[]).unwrap()).unwrap(), Vec::<&[u8]>::new());
+    }
+
+    #[test]
+    fn unpack_malformed() {
+        let element = pack(&[b"meta", b"content"]).unwrap();
+        assert!(unpack(&element[..element.len() - 1]).is_err());
+        assert!(unpack(&element[..6]).is_err());
+        assert!(unpack(&[element.as_slice(), b"x"].concat()).is_err());
+        assert!(unpack(&u32::MAX.to_le_bytes()).is_err());
+        assert!(unpack(&[]).is_err());
+    }
+}
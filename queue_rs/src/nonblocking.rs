@@ -1,26 +1,45 @@
+use crate::counters::Counters;
+use crate::error::{QueueError, Result};
+use crate::events::QueueEvent;
 use crate::mpmc;
 use crate::mpmc::StartPosition;
-use anyhow::Result;
-use crossbeam_channel::{Receiver, Sender};
+use crate::trace;
+use crate::utilities::advance_index;
+use crossbeam_channel::{Receiver, SendTimeoutError, Sender, TrySendError};
+use parking_lot::{Mutex, RwLock};
+use std::collections::{BTreeMap, HashMap};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 #[derive(Clone)]
 pub enum Operation {
     Push(Vec<Vec<u8>>),
     Pop(usize),
+    /// Pops the elements until the queue is empty or their payload size reaches the bound.
+    PopAll(Option<u64>),
     Length,
+    IsEmpty,
     PayloadSize,
     DiskSize,
     Stop,
 }
 
 pub enum ResponseVariant {
-    Push(Result<()>),
+    /// The index of the first pushed element and the number of the elements.
+    Push(Result<(u64, usize)>),
     Pop(Result<Vec<Vec<u8>>>),
     Length(usize),
+    IsEmpty(bool),
     Size(Result<usize>),
     Stop,
+    /// The operation was skipped because its deadline passed before it was executed.
+    Timeout,
+    /// The operation was rejected because the queue is closing.
+    Shutdown,
 }
 
 #[derive(Clone)]
@@ -28,6 +47,7 @@ pub enum MpmcOperation {
     Add(Vec<Vec<u8>>),
     Next(usize, String, StartPosition),
     Length,
+    IsEmpty,
     DiskSize,
     GetLabels,
     RemoveLabel(String),
@@ -35,57 +55,951 @@ pub enum MpmcOperation {
 }
 
 pub enum MpmcResponseVariant {
-    Add(Result<()>),
+    /// The index of the first added element and the number of the elements.
+    Add(Result<(u64, usize)>),
     Next(Result<(Vec<Vec<u8>>, bool)>),
     Length(usize),
+    IsEmpty(bool),
     Size(Result<usize>),
     GetLabels(Vec<String>),
     RemoveLabel(Result<bool>),
     Stop,
+    /// The operation was skipped because its deadline passed before it was executed.
+    Timeout,
+    /// The operation was rejected because the queue is closing.
+    Shutdown,
 }
 
-pub struct TypedResponse<T>(Receiver<T>);
+/// How long to wait for the result.
+#[derive(Clone, Copy)]
+enum Wait {
+    No,
+    Timeout(Duration),
+    Forever,
+}
+
+/// The source of the operation result.
+trait ResultSource<T>: Send + Sync {
+    fn is_ready(&self) -> bool;
+
+    /// Returns `None` if the result isn't ready after waiting.
+    fn recv(&self, wait: Wait) -> Result<Option<T>>;
+
+    fn on_ready(&self, f: Box<dyn FnOnce() + Send>);
+}
+
+type ReadyCallbacks = Vec<Box<dyn FnOnce() + Send>>;
+
+/// The callbacks waiting for the result, `None` once the result is sent or can't be sent anymore.
+struct Notifier(Mutex<Option<ReadyCallbacks>>);
+
+impl Notifier {
+    fn new() -> Arc<Self> {
+        Arc::new(Self(Mutex::new(Some(Vec::new()))))
+    }
+
+    fn subscribe(&self, f: Box<dyn FnOnce() + Send>) {
+        let mut callbacks = self.0.lock();
+        match callbacks.as_mut() {
+            Some(callbacks) => callbacks.push(f),
+            None => {
+                drop(callbacks);
+                f();
+            }
+        }
+    }
+
+    fn notify(&self) {
+        let callbacks = self.0.lock().take();
+        for f in callbacks.into_iter().flatten() {
+            f();
+        }
+    }
+}
+
+/// Notifies the waiting callbacks when dropped, i.e. after the result is sent or when the
+/// operation is dropped without the result.
+///
+struct NotifyGuard(Arc<Notifier>);
+
+impl Drop for NotifyGuard {
+    fn drop(&mut self) {
+        self.0.notify();
+    }
+}
+
+/// The result delivered through the channel.
+struct Channel<T> {
+    rx: Receiver<T>,
+    notifier: Arc<Notifier>,
+}
+
+impl<T> Channel<T> {
+    fn new() -> (Responder<T>, Self) {
+        let (tx, rx) = crossbeam_channel::bounded(1);
+        let notifier = Notifier::new();
+        (
+            Responder::Channel(tx, NotifyGuard(notifier.clone())),
+            Self { rx, notifier },
+        )
+    }
+}
+
+impl<T: Send> ResultSource<T> for Channel<T> {
+    fn is_ready(&self) -> bool {
+        !self.rx.is_empty()
+    }
+
+    fn recv(&self, wait: Wait) -> Result<Option<T>> {
+        match wait {
+            Wait::No => match self.rx.try_recv() {
+                Ok(res) => Ok(Some(res)),
+                Err(crossbeam_channel::TryRecvError::Empty) => Ok(None),
+                Err(crossbeam_channel::TryRecvError::Disconnected) => Err(QueueError::Closed),
+            },
+            Wait::Timeout(timeout) => match self.rx.recv_timeout(timeout) {
+                Ok(res) => Ok(Some(res)),
+                Err(crossbeam_channel::RecvTimeoutError::Timeout) => Ok(None),
+                Err(crossbeam_channel::RecvTimeoutError::Disconnected) => Err(QueueError::Closed),
+            },
+            Wait::Forever => Ok(Some(self.rx.recv().map_err(|_| QueueError::Closed)?)),
+        }
+    }
+
+    fn on_ready(&self, f: Box<dyn FnOnce() + Send>) {
+        self.notifier.subscribe(f);
+    }
+}
+
+/// The result transformed by the function.
+struct Transformed<T, F> {
+    source: Box<dyn ResultSource<T>>,
+    // taken when the result is received
+    f: Mutex<Option<F>>,
+}
+
+impl<T, U, F> ResultSource<U> for Transformed<T, F>
+where
+    F: FnOnce(T) -> Result<U> + Send,
+{
+    fn is_ready(&self) -> bool {
+        self.source.is_ready()
+    }
+
+    fn recv(&self, wait: Wait) -> Result<Option<U>> {
+        match self.source.recv(wait)? {
+            Some(res) => {
+                let f = self.f.lock().take().ok_or_else(|| {
+                    QueueError::Internal("The result has already been received.".to_string())
+                })?;
+                Ok(Some(f(res)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn on_ready(&self, f: Box<dyn FnOnce() + Send>) {
+        self.source.on_ready(f);
+    }
+}
+
+pub struct TypedResponse<T>(Box<dyn ResultSource<T>>);
 pub type Response = TypedResponse<ResponseVariant>;
 pub type MpmcResponse = TypedResponse<MpmcResponseVariant>;
 
-impl<T> TypedResponse<T> {
+impl<T: Send + 'static> TypedResponse<T> {
     pub fn is_ready(&self) -> bool {
-        !self.0.is_empty()
+        self.0.is_ready()
     }
 
     pub fn try_get(&self) -> Result<Option<T>> {
-        let res = self.0.try_recv();
-        if let Err(crossbeam_channel::TryRecvError::Empty) = &res {
-            return Ok(None);
-        }
-        Ok(Some(res?))
+        self.0.recv(Wait::No)
     }
 
     pub fn get(&self) -> Result<T> {
-        Ok(self.0.recv()?)
+        Ok(self.0.recv(Wait::Forever)?.unwrap())
+    }
+
+    /// Waits for the result up to `timeout`, returns `None` if it is not ready by then. The
+    /// operation is still executed if the response is dropped afterwards, its result, e.g. the
+    /// popped elements, is discarded.
+    ///
+    pub fn get_timeout(&self, timeout: Duration) -> Result<Option<T>> {
+        self.0.recv(Wait::Timeout(timeout))
+    }
+
+    /// Invokes `f` once the result is ready or can't be received anymore, immediately if it
+    /// already is. The function is invoked by the working thread, so it must not block.
+    ///
+    pub fn on_ready<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.0.on_ready(Box::new(f));
+    }
+
+    /// Returns the response with the result transformed by `f`.
+    pub fn map<U, F>(self, f: F) -> TypedResponse<U>
+    where
+        F: FnOnce(T) -> U + Send + 'static,
+    {
+        self.and_then(|res| Ok(f(res)))
+    }
+
+    /// Returns the response with the result transformed by `f`, the error returned by `f` is
+    /// returned when the result is received.
+    ///
+    pub fn and_then<U, F>(self, f: F) -> TypedResponse<U>
+    where
+        F: FnOnce(T) -> Result<U> + Send + 'static,
+    {
+        TypedResponse(Box::new(Transformed {
+            source: self.0,
+            f: Mutex::new(Some(f)),
+        }))
+    }
+
+    /// Waits for all the responses, returns the results in the same order.
+    pub fn wait_all(responses: Vec<TypedResponse<T>>) -> Result<Vec<T>> {
+        responses.iter().map(|r| r.get()).collect()
     }
 }
 
-type WorkingThread = Option<thread::JoinHandle<Result<()>>>;
-type QueueSender<O, R> = Sender<(O, Sender<R>)>;
-type QueueType<O, R> = (WorkingThread, QueueSender<O, R>);
-pub struct NonBlockingQueueWrapper<O, R>(QueueType<O, R>, O)
+type WorkingThreads = Vec<thread::JoinHandle<Result<()>>>;
+
+/// Delivers the result of the operation.
+enum Responder<R> {
+    Channel(Sender<R>, NotifyGuard),
+    Callback(Box<dyn FnOnce(R) + Send>),
+}
+
+impl<R> Responder<R> {
+    fn send(self, res: R) -> Result<()> {
+        match self {
+            // the guard notifies the callbacks after the result is sent
+            Responder::Channel(tx, _guard) => tx
+                .send(res)
+                .map_err(|_| QueueError::Internal("The response has been dropped.".to_string())),
+            Responder::Callback(callback) => {
+                callback(res);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// The operation sent to the working threads.
+struct Request<O, R> {
+    op: O,
+    // the operation is skipped after the deadline
+    deadline: Option<Instant>,
+    // the key of the operation in the pending operations
+    id: u64,
+    responder: Responder<R>,
+}
+
+type QueueSender<O, R> = Sender<Request<O, R>>;
+// the working threads with the channel they receive operations from
+type Lane<O, R> = (WorkingThreads, QueueSender<O, R>);
+// the first lane receives all operations except writes if the dedicated writer lane exists
+type QueueType<O, R> = Vec<Lane<O, R>>;
+pub struct NonBlockingQueueWrapper<O, R>(QueueType<O, R>, O, Arc<WorkerState>, SendOptions)
 where
     O: Clone + Send + Sync + 'static,
     R: Send + 'static;
 pub type PersistentQueueWithCapacity = NonBlockingQueueWrapper<Operation, ResponseVariant>;
 pub type MpmcQueue = NonBlockingQueueWrapper<MpmcOperation, MpmcResponseVariant>;
 
-fn start_op_loop<O, R, F>(mut f: F, max_inflight_ops: usize) -> (WorkingThread, QueueSender<O, R>)
-where
-    F: FnMut(Receiver<(O, Sender<R>)>) -> Result<()> + Send + 'static,
-    O: Send + Sync + 'static,
-    R: Send + Sync + 'static,
-{
-    let (tx, rx) = crossbeam_channel::bounded::<(O, Sender<R>)>(max_inflight_ops);
-    let handle = thread::spawn(move || f(rx));
+/// Executes the operation on the queue in the working thread.
+pub(crate) fn execute(
+    queue: &crate::PersistentQueueWithCapacity,
+    op: Operation,
+) -> ResponseVariant {
+    match op {
+        Operation::Push(values) => {
+            let value_slices = values.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
+            ResponseVariant::Push(queue.push_indexed(&value_slices))
+        }
+        Operation::Pop(max_elements) => ResponseVariant::Pop(queue.pop(max_elements)),
+        Operation::PopAll(max_bytes) => ResponseVariant::Pop(queue.pop_all(max_bytes)),
+        Operation::Length => ResponseVariant::Length(queue.len()),
+        Operation::IsEmpty => ResponseVariant::IsEmpty(queue.is_empty()),
+        Operation::DiskSize => ResponseVariant::Size(queue.disk_size()),
+        Operation::PayloadSize => ResponseVariant::Size(Ok(queue.payload_size() as usize)),
+        Operation::Stop => ResponseVariant::Stop,
+    }
+}
 
-    (Some(handle), tx)
+/// Executes the operation on the queue in the working thread.
+pub(crate) fn execute_mpmc(queue: &mut mpmc::MpmcQueue, op: MpmcOperation) -> MpmcResponseVariant {
+    match op {
+        MpmcOperation::Add(values) => {
+            let value_slices = values.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
+            MpmcResponseVariant::Add(queue.add_indexed(&value_slices))
+        }
+        MpmcOperation::Next(max_elements, label, start_position) => {
+            MpmcResponseVariant::Next(queue.next(max_elements, label.as_str(), start_position))
+        }
+        MpmcOperation::Length => MpmcResponseVariant::Length(queue.len()),
+        MpmcOperation::IsEmpty => MpmcResponseVariant::IsEmpty(queue.is_empty()),
+        MpmcOperation::DiskSize => MpmcResponseVariant::Size(queue.disk_size()),
+        MpmcOperation::GetLabels => MpmcResponseVariant::GetLabels(queue.get_labels()),
+        MpmcOperation::RemoveLabel(label) => {
+            MpmcResponseVariant::RemoveLabel(queue.remove_label(label.as_str()))
+        }
+        MpmcOperation::Stop => MpmcResponseVariant::Stop,
+    }
+}
+
+/// Limits for merging adjacent queued operations of the same kind (pushes, adds or pops) into a
+/// single queue operation, which reduces the number of RocksDB writes under load. Each merged
+/// operation still gets its own response.
+///
+/// With a non-zero `window` the writes are group-committed: a push (add) waits for the following
+/// ones up to the window, so the pushes sent within it share a single RocksDB write (and a
+/// single sync with [`crate::PersistentQueueWithCapacity::set_sync_writes`]). Their responses
+/// are completed after the shared write.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Coalescing {
+    /// The maximum number of merged operations, `0` or `1` disables merging.
+    pub max_ops: usize,
+    /// The maximum total size of the values of merged pushes (adds).
+    pub max_bytes: usize,
+    /// The time a push (add) waits for the following ones, zero merges only the already queued.
+    pub window: Duration,
+}
+
+impl Default for Coalescing {
+    fn default() -> Self {
+        Self {
+            max_ops: 32,
+            max_bytes: 4 * 1024 * 1024,
+            window: Duration::ZERO,
+        }
+    }
+}
+
+/// What happens when an operation is sent while `max_inflight_ops` operations are already
+/// waiting for the working threads.
+///
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum BackpressureStrategy {
+    /// Waits until there is room for the operation.
+    #[default]
+    Block,
+    /// Fails with [`QueueError::Backpressure`] immediately.
+    FailFast,
+    /// Waits up to the timeout, then fails with [`QueueError::Backpressure`].
+    BlockTimeout(Duration),
+}
+
+/// How the operations are sent to the working threads.
+#[derive(Clone, Copy, Debug, Default)]
+struct SendOptions {
+    op_timeout: Option<Duration>,
+    backpressure: BackpressureStrategy,
+}
+
+/// What happens to the pending operations when the queue is closed.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DrainPolicy {
+    /// The pending operations are executed.
+    #[default]
+    Finish,
+    /// The pending operations are answered with the `Shutdown` response.
+    Reject,
+    /// The pending operations are dropped, their responses fail.
+    Abort,
+}
+
+/// Restarts of a working thread that failed (e.g. panicked). The thread is restarted after the
+/// backoff which doubles with every restart, up to `max_backoff`. The restart counter is reset
+/// when the thread works longer than `max_backoff`.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RestartPolicy {
+    pub max_restarts: usize,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self {
+            max_restarts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+        }
+    }
+}
+
+impl RestartPolicy {
+    fn backoff(&self, restart: usize) -> Duration {
+        let factor = 2u32.saturating_pow(restart.min(u32::MAX as usize) as u32);
+        self.initial_backoff
+            .saturating_mul(factor)
+            .min(self.max_backoff)
+    }
+}
+
+/// The state shared by the wrapper and its working threads.
+#[derive(Default)]
+struct WorkerState {
+    coalescing: Mutex<Coalescing>,
+    // set once the queue is closing
+    closing: Mutex<Option<DrainPolicy>>,
+    restart: Mutex<Option<RestartPolicy>>,
+    restarts: AtomicUsize,
+    // the backoff of reopening the database after the transient errors
+    reopen: Mutex<Option<RestartPolicy>>,
+    // the error the working threads last failed with
+    last_error: Mutex<Option<String>>,
+    // the operations waiting for the working threads by their ids: names and enqueue times
+    pending: Mutex<BTreeMap<u64, (&'static str, Instant)>>,
+    next_id: AtomicU64,
+    // the lock-free mirrors of the length and the payload size of the SPSC queue
+    counters: Option<Arc<Counters>>,
+    // the receivers of the queue events returned by `events()`
+    events: Mutex<Vec<Sender<QueueEvent>>>,
+}
+
+impl WorkerState {
+    /// Removes the received operation from the pending ones and reports its wait.
+    fn dequeued(&self, id: u64) {
+        if let Some((op, queued)) = self.pending.lock().remove(&id) {
+            trace::dequeued(op, queued);
+        }
+    }
+
+    /// Returns the queue listener forwarding the events to the receivers, the dropped
+    /// receivers are removed.
+    ///
+    fn forward_events(self: &Arc<Self>) -> impl Fn(&QueueEvent) + Send + Sync + 'static {
+        let state = self.clone();
+        move |event| {
+            state
+                .events
+                .lock()
+                .retain(|tx| tx.send(event.clone()).is_ok())
+        }
+    }
+}
+
+/// The operations waiting for the working threads.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct PendingOps {
+    /// The number of operations by the operation name.
+    pub counts: HashMap<&'static str, usize>,
+    /// The time the oldest operation has been waiting for.
+    pub oldest_age: Option<Duration>,
+}
+
+/// The name of the operation used in [`PendingOps`].
+pub trait OpName {
+    fn name(&self) -> &'static str;
+}
+
+impl OpName for Operation {
+    fn name(&self) -> &'static str {
+        match self {
+            Operation::Push(_) => "push",
+            Operation::Pop(_) => "pop",
+            Operation::PopAll(_) => "pop_all",
+            Operation::Length => "len",
+            Operation::IsEmpty => "is_empty",
+            Operation::PayloadSize => "payload_size",
+            Operation::DiskSize => "disk_size",
+            Operation::Stop => "stop",
+        }
+    }
+}
+
+impl OpName for MpmcOperation {
+    fn name(&self) -> &'static str {
+        match self {
+            MpmcOperation::Add(_) => "add",
+            MpmcOperation::Next(..) => "next",
+            MpmcOperation::Length => "len",
+            MpmcOperation::IsEmpty => "is_empty",
+            MpmcOperation::DiskSize => "disk_size",
+            MpmcOperation::GetLabels => "get_labels",
+            MpmcOperation::RemoveLabel(_) => "remove_label",
+            MpmcOperation::Stop => "stop",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OpKind {
+    Write,
+    Read,
+}
+
+/// The queue shared by the working threads.
+trait SharedQueue: Send + Sync + 'static {
+    type Op: Clone + Send + Sync + 'static;
+    type Response: Send + Sync + 'static;
+
+    fn is_stop(op: &Self::Op) -> bool;
+
+    fn timeout() -> Self::Response;
+
+    fn shutdown() -> Self::Response;
+
+    /// Executes the operation. Read-only operations run concurrently, the others exclusively.
+    fn execute(queue: &RwLock<Self>, op: Self::Op) -> Self::Response;
+
+    /// Returns the kind and the size of the operation if it can be merged with the adjacent
+    /// operations of the same kind.
+    ///
+    fn coalescing(op: &Self::Op) -> Option<(OpKind, usize)>;
+
+    /// Executes the operations of the same kind as a single one. If it fails, the operations
+    /// are executed one by one.
+    ///
+    fn execute_coalesced(queue: &RwLock<Self>, ops: Vec<Self::Op>) -> Vec<Self::Response>;
+
+    /// Returns `true` if the operation failed with a transient error, see
+    /// [`QueueError::is_transient`].
+    ///
+    fn is_transient(response: &Self::Response) -> bool;
+
+    /// Returns `true` if the elements of the next read are prefetched after the operation.
+    fn prefetches(op: &Self::Op) -> bool;
+
+    /// Loads the elements of the next read into the block cache, see `set_prefetch` of the
+    /// queues.
+    ///
+    fn prefetch(queue: &RwLock<Self>) -> Result<()>;
+
+    fn reopen(&mut self) -> Result<()>;
+}
+
+fn values_size(values: &[Vec<u8>]) -> usize {
+    values.iter().map(|v| v.len()).sum()
+}
+
+/// Splits the index range of the coalesced batch into the ranges of the batches it is made of.
+fn split_range(first: u64, counts: &[usize], index_space: u64) -> Vec<(u64, usize)> {
+    let mut offset = 0;
+    counts
+        .iter()
+        .map(|count| {
+            let range = (advance_index(first, offset, index_space), *count);
+            offset += *count as u64;
+            range
+        })
+        .collect()
+}
+
+impl SharedQueue for crate::PersistentQueueWithCapacity {
+    type Op = Operation;
+    type Response = ResponseVariant;
+
+    fn is_stop(op: &Operation) -> bool {
+        matches!(op, Operation::Stop)
+    }
+
+    fn timeout() -> ResponseVariant {
+        ResponseVariant::Timeout
+    }
+
+    fn shutdown() -> ResponseVariant {
+        ResponseVariant::Shutdown
+    }
+
+    fn execute(queue: &RwLock<Self>, op: Operation) -> ResponseVariant {
+        // pushes and pops are synchronized by the queue itself and run concurrently
+        execute(&queue.read(), op)
+    }
+
+    fn coalescing(op: &Operation) -> Option<(OpKind, usize)> {
+        match op {
+            Operation::Push(values) => Some((OpKind::Write, values_size(values))),
+            Operation::Pop(_) => Some((OpKind::Read, 0)),
+            _ => None,
+        }
+    }
+
+    fn execute_coalesced(queue: &RwLock<Self>, ops: Vec<Operation>) -> Vec<ResponseVariant> {
+        let queue = queue.read();
+        let merged = match &ops[0] {
+            Operation::Push(_) => {
+                let value_slices = ops
+                    .iter()
+                    .flat_map(|op| match op {
+                        Operation::Push(values) => values.as_slice(),
+                        _ => &[],
+                    })
+                    .map(|e| e.as_slice())
+                    .collect::<Vec<_>>();
+                let counts = ops
+                    .iter()
+                    .map(|op| match op {
+                        Operation::Push(values) => values.len(),
+                        _ => 0,
+                    })
+                    .collect::<Vec<_>>();
+                queue.push_indexed(&value_slices).map(|(first, _)| {
+                    split_range(first, &counts, queue.index_space())
+                        .into_iter()
+                        .map(|range| ResponseVariant::Push(Ok(range)))
+                        .collect()
+                })
+            }
+            _ => {
+                let sizes = ops
+                    .iter()
+                    .map(|op| match op {
+                        Operation::Pop(max_elements) => *max_elements,
+                        _ => 0,
+                    })
+                    .collect::<Vec<_>>();
+                let total = sizes.iter().fold(0usize, |acc, n| acc.saturating_add(*n));
+                queue.pop(total).map(|values| {
+                    let mut values = values.into_iter();
+                    sizes
+                        .iter()
+                        .map(|n| ResponseVariant::Pop(Ok(values.by_ref().take(*n).collect())))
+                        .collect()
+                })
+            }
+        };
+        match merged {
+            Ok(responses) => responses,
+            Err(_) => ops.into_iter().map(|op| execute(&queue, op)).collect(),
+        }
+    }
+
+    fn is_transient(response: &ResponseVariant) -> bool {
+        match response {
+            ResponseVariant::Push(Err(e)) | ResponseVariant::Size(Err(e)) => e.is_transient(),
+            ResponseVariant::Pop(Err(e)) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    fn prefetches(op: &Operation) -> bool {
+        matches!(op, Operation::Pop(_) | Operation::PopAll(_))
+    }
+
+    fn prefetch(queue: &RwLock<Self>) -> Result<()> {
+        queue.read().prefetch_next().map(|_| ())
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        crate::PersistentQueueWithCapacity::reopen(self)
+    }
+}
+
+impl SharedQueue for mpmc::MpmcQueue {
+    type Op = MpmcOperation;
+    type Response = MpmcResponseVariant;
+
+    fn is_stop(op: &MpmcOperation) -> bool {
+        matches!(op, MpmcOperation::Stop)
+    }
+
+    fn timeout() -> MpmcResponseVariant {
+        MpmcResponseVariant::Timeout
+    }
+
+    fn shutdown() -> MpmcResponseVariant {
+        MpmcResponseVariant::Shutdown
+    }
+
+    fn execute(queue: &RwLock<Self>, op: MpmcOperation) -> MpmcResponseVariant {
+        match op {
+            MpmcOperation::Length => MpmcResponseVariant::Length(queue.read().len()),
+            MpmcOperation::IsEmpty => MpmcResponseVariant::IsEmpty(queue.read().is_empty()),
+            MpmcOperation::DiskSize => MpmcResponseVariant::Size(queue.read().disk_size()),
+            MpmcOperation::GetLabels => MpmcResponseVariant::GetLabels(queue.read().get_labels()),
+            op => execute_mpmc(&mut queue.write(), op),
+        }
+    }
+
+    fn coalescing(op: &MpmcOperation) -> Option<(OpKind, usize)> {
+        match op {
+            MpmcOperation::Add(values) => Some((OpKind::Write, values_size(values))),
+            _ => None,
+        }
+    }
+
+    fn execute_coalesced(
+        queue: &RwLock<Self>,
+        ops: Vec<MpmcOperation>,
+    ) -> Vec<MpmcResponseVariant> {
+        let mut queue = queue.write();
+        let value_slices = ops
+            .iter()
+            .flat_map(|op| match op {
+                MpmcOperation::Add(values) => values.as_slice(),
+                _ => &[],
+            })
+            .map(|e| e.as_slice())
+            .collect::<Vec<_>>();
+        let counts = ops
+            .iter()
+            .map(|op| match op {
+                MpmcOperation::Add(values) => values.len(),
+                _ => 0,
+            })
+            .collect::<Vec<_>>();
+        match queue.add_indexed(&value_slices) {
+            Ok((first, _)) => split_range(first, &counts, queue.index_space())
+                .into_iter()
+                .map(|range| MpmcResponseVariant::Add(Ok(range)))
+                .collect(),
+            Err(_) => ops
+                .into_iter()
+                .map(|op| execute_mpmc(&mut queue, op))
+                .collect(),
+        }
+    }
+
+    fn is_transient(response: &MpmcResponseVariant) -> bool {
+        match response {
+            MpmcResponseVariant::Add(Err(e)) | MpmcResponseVariant::Size(Err(e)) => {
+                e.is_transient()
+            }
+            MpmcResponseVariant::Next(Err(e)) => e.is_transient(),
+            MpmcResponseVariant::RemoveLabel(Err(e)) => e.is_transient(),
+            _ => false,
+        }
+    }
+
+    fn prefetches(op: &MpmcOperation) -> bool {
+        matches!(op, MpmcOperation::Next(..))
+    }
+
+    fn prefetch(queue: &RwLock<Self>) -> Result<()> {
+        queue.read().prefetch_next().map(|_| ())
+    }
+
+    fn reopen(&mut self) -> Result<()> {
+        mpmc::MpmcQueue::reopen(self)
+    }
+}
+
+type QueueRequest<Q> = Request<<Q as SharedQueue>::Op, <Q as SharedQueue>::Response>;
+
+fn is_stale(deadline: Option<Instant>) -> bool {
+    deadline.map(|d| Instant::now() >= d).unwrap_or(false)
+}
+
+/// Executes the operation, if it fails with a transient error and the reopen policy is set,
+/// reopens the database and retries the operation with the backoff of the policy.
+///
+fn execute_reopening<Q: SharedQueue>(
+    queue: &RwLock<Q>,
+    state: &WorkerState,
+    op: Q::Op,
+) -> Q::Response {
+    let Some(policy) = *state.reopen.lock() else {
+        return Q::execute(queue, op);
+    };
+    let mut response = Q::execute(queue, op.clone());
+    let mut attempt = 0;
+    while Q::is_transient(&response)
+        && attempt < policy.max_restarts
+        && state.closing.lock().is_none()
+    {
+        log::warn!(
+            "The queue operation failed with a transient error, reopening the database in {:?}",
+            policy.backoff(attempt)
+        );
+        thread::sleep(policy.backoff(attempt));
+        attempt += 1;
+        if let Err(e) = queue.write().reopen() {
+            log::error!("Failed to reopen the database of the queue: {}", e);
+            continue;
+        }
+        response = Q::execute(queue, op.clone());
+    }
+    response
+}
+
+/// Runs the operations in the working thread until the stop operation is received.
+fn op_loop<Q: SharedQueue>(
+    queue: &RwLock<Q>,
+    rx: &Receiver<QueueRequest<Q>>,
+    state: &WorkerState,
+) -> Result<()> {
+    // the operation received while collecting the operations to merge
+    let mut next = None;
+    loop {
+        let Request {
+            op,
+            deadline,
+            responder: resp_tx,
+            ..
+        } = match next.take() {
+            Some(request) => request,
+            None => {
+                let request = rx.recv().map_err(|_| QueueError::Closed)?;
+                state.dequeued(request.id);
+                request
+            }
+        };
+        if Q::is_stop(&op) {
+            // nobody may wait for the stop response
+            _ = resp_tx.send(Q::execute(queue, op));
+            break;
+        }
+        match *state.closing.lock() {
+            // the operation is dropped, so its response fails
+            Some(DrainPolicy::Abort) => break,
+            Some(DrainPolicy::Reject) => {
+                _ = resp_tx.send(Q::shutdown());
+                continue;
+            }
+            _ => {}
+        }
+        // the responses are not received if the caller has stopped waiting for them, e.g. after
+        // `get_timeout`, the working thread goes on
+        if is_stale(deadline) {
+            _ = resp_tx.send(Q::timeout());
+            continue;
+        }
+        // the elements are prefetched while the read ones are processed
+        let prefetches = Q::prefetches(&op);
+        let Some((kind, mut size)) = Q::coalescing(&op) else {
+            _ = resp_tx.send(execute_reopening(queue, state, op));
+            if prefetches {
+                prefetch(queue);
+            }
+            continue;
+        };
+
+        let limits = *state.coalescing.lock();
+        // the group commit of the writes waits for the following ones
+        let deadline = (kind == OpKind::Write && !limits.window.is_zero())
+            .then(|| Instant::now() + limits.window);
+        let mut requests = vec![(op, resp_tx)];
+        while requests.len() < limits.max_ops {
+            let received = match deadline {
+                Some(deadline) => rx.recv_deadline(deadline).ok(),
+                None => rx.try_recv().ok(),
+            };
+            let Some(request) = received else {
+                break;
+            };
+            state.dequeued(request.id);
+            if state.closing.lock().is_some() {
+                next = Some(request);
+                break;
+            }
+            if is_stale(request.deadline) {
+                _ = request.responder.send(Q::timeout());
+                continue;
+            }
+            match Q::coalescing(&request.op) {
+                Some((k, s)) if k == kind && size + s <= limits.max_bytes => {
+                    size += s;
+                    requests.push((request.op, request.responder));
+                }
+                _ => {
+                    next = Some(request);
+                    break;
+                }
+            }
+        }
+
+        if requests.len() == 1 {
+            let (op, resp_tx) = requests.pop().unwrap();
+            _ = resp_tx.send(execute_reopening(queue, state, op));
+        } else {
+            let (ops, senders): (Vec<_>, Vec<_>) = requests.into_iter().unzip();
+            // the operations which failed with a transient error are retried one by one
+            let retried = state.reopen.lock().is_some().then(|| ops.clone());
+            let responses = Q::execute_coalesced(queue, ops);
+            for (i, (resp, resp_tx)) in responses.into_iter().zip(senders).enumerate() {
+                let resp = match &retried {
+                    Some(ops) if Q::is_transient(&resp) => {
+                        execute_reopening(queue, state, ops[i].clone())
+                    }
+                    _ => resp,
+                };
+                _ = resp_tx.send(resp);
+            }
+        }
+        if prefetches {
+            prefetch(queue);
+        }
+    }
+    Ok(())
+}
+
+/// Prefetches the elements of the next read, it is a hint, so the failures are only logged.
+fn prefetch<Q: SharedQueue>(queue: &RwLock<Q>) {
+    if let Err(e) = Q::prefetch(queue) {
+        log::warn!("Failed to prefetch the elements of the queue: {}", e);
+    }
+}
+
+/// Runs the operations in the working thread, restarting the loop if it fails and the restart
+/// policy allows it.
+///
+fn run_worker<Q: SharedQueue>(
+    queue: &RwLock<Q>,
+    rx: &Receiver<QueueRequest<Q>>,
+    state: &WorkerState,
+) -> Result<()> {
+    let mut restarts = 0;
+    loop {
+        let started = Instant::now();
+        let res = panic::catch_unwind(AssertUnwindSafe(|| op_loop(queue, rx, state)))
+            .unwrap_or_else(|_| {
+                Err(QueueError::Internal(
+                    "The working thread panicked.".to_string(),
+                ))
+            });
+        let Err(e) = res else {
+            return Ok(());
+        };
+        log::error!("The working thread of the queue failed: {}", e);
+        *state.last_error.lock() = Some(e.to_string());
+        let policy = match *state.restart.lock() {
+            Some(policy) if state.closing.lock().is_none() => policy,
+            _ => return Err(e),
+        };
+        if started.elapsed() > policy.max_backoff {
+            restarts = 0;
+        }
+        if restarts >= policy.max_restarts {
+            return Err(e);
+        }
+        log::warn!(
+            "Restarting the working thread of the queue in {:?}",
+            policy.backoff(restarts)
+        );
+        thread::sleep(policy.backoff(restarts));
+        restarts += 1;
+        state.restarts.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn start_op_loop<Q: SharedQueue>(
+    queue: Arc<RwLock<Q>>,
+    state: Arc<WorkerState>,
+    workers: usize,
+    max_inflight_ops: usize,
+) -> Result<Lane<Q::Op, Q::Response>> {
+    if workers == 0 {
+        return Err(QueueError::InvalidArgument(
+            "workers must be greater than 0".to_string(),
+        ));
+    }
+    let (tx, rx) = crossbeam_channel::bounded::<QueueRequest<Q>>(max_inflight_ops);
+    let handles = (0..workers)
+        .map(|_| {
+            let (queue, rx, state) = (queue.clone(), rx.clone(), state.clone());
+            thread::spawn(move || run_worker(&queue, &rx, &state))
+        })
+        .collect();
+
+    Ok((handles, tx))
 }
 
 impl<O, R> NonBlockingQueueWrapper<O, R>
@@ -94,30 +1008,231 @@ where
     R: Send,
 {
     pub fn is_healthy(&self) -> bool {
-        !self.0 .0.as_ref().map(|t| t.is_finished()).unwrap_or(true)
+        self.0
+            .iter()
+            .all(|(threads, _)| !threads.is_empty() && threads.iter().all(|t| !t.is_finished()))
+    }
+
+    /// Sets the limits for merging adjacent queued operations, see [`Coalescing`].
+    pub fn set_coalescing(&self, coalescing: Coalescing) {
+        *self.2.coalescing.lock() = coalescing;
+    }
+
+    pub fn coalescing(&self) -> Coalescing {
+        *self.2.coalescing.lock()
+    }
+
+    /// Sets the time an operation may wait for a working thread, after which it is skipped and
+    /// answered with the `Timeout` response. Applies to the operations sent afterwards.
+    ///
+    pub fn set_op_timeout(&mut self, timeout: Option<Duration>) {
+        self.3.op_timeout = timeout;
+    }
+
+    pub fn op_timeout(&self) -> Option<Duration> {
+        self.3.op_timeout
+    }
+
+    fn deadline(&self) -> Option<Instant> {
+        self.3.op_timeout.map(|timeout| Instant::now() + timeout)
+    }
+
+    /// Sets what happens when an operation is sent while `max_inflight_ops` operations are
+    /// already waiting, see [`BackpressureStrategy`].
+    ///
+    pub fn set_backpressure(&mut self, backpressure: BackpressureStrategy) {
+        self.3.backpressure = backpressure;
+    }
+
+    pub fn backpressure(&self) -> BackpressureStrategy {
+        self.3.backpressure
+    }
+
+    /// Sets the policy for restarting the failed working threads, `None` (the default) leaves
+    /// the queue unhealthy after a failure.
+    ///
+    pub fn set_restart_policy(&self, policy: Option<RestartPolicy>) {
+        *self.2.restart.lock() = policy;
+    }
+
+    pub fn restart_policy(&self) -> Option<RestartPolicy> {
+        *self.2.restart.lock()
+    }
+
+    /// Sets the policy for reopening the database when an operation fails with a transient
+    /// error (see [`QueueError::is_transient`]), the operation is retried after the database is
+    /// reopened. `None` (the default) returns the errors. The `max_restarts` of the policy
+    /// limits the attempts per operation.
+    ///
+    pub fn set_reopen_policy(&self, policy: Option<RestartPolicy>) {
+        *self.2.reopen.lock() = policy;
+    }
+
+    pub fn reopen_policy(&self) -> Option<RestartPolicy> {
+        *self.2.reopen.lock()
+    }
+
+    /// Returns the number of times the working threads have been restarted.
+    pub fn restarts(&self) -> usize {
+        self.2.restarts.load(Ordering::Relaxed)
+    }
+
+    /// Returns the error the working threads last failed with, including the failures they have
+    /// been restarted after.
+    ///
+    pub fn last_error(&self) -> Option<String> {
+        self.2.last_error.lock().clone()
+    }
+
+    /// Returns the receiver of the queue events, see [`QueueEvent`]. The events are sent by the
+    /// working threads without waiting, so the receiver must be drained or dropped.
+    ///
+    pub fn events(&self) -> Receiver<QueueEvent> {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        self.2.events.lock().push(tx);
+        rx
+    }
+
+    /// Returns the number of working threads.
+    pub fn workers(&self) -> usize {
+        self.0.iter().map(|(threads, _)| threads.len()).sum()
+    }
+
+    fn reader(&self) -> &QueueSender<O, R> {
+        &self.0[0].1
+    }
+
+    fn writer(&self) -> &QueueSender<O, R> {
+        &self.0[self.0.len() - 1].1
     }
 
     pub fn inflight_ops(&self) -> Result<usize> {
         if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
+            return Err(QueueError::Closed);
         }
 
-        Ok(self.0 .1.len())
+        Ok(self.0.iter().map(|(_, tx)| tx.len()).sum())
+    }
+
+    /// Stops the working threads, the pending operations are handled according to the policy.
+    /// Returns the error the working threads failed with, if any.
+    ///
+    pub fn close(mut self, policy: DrainPolicy) -> Result<()> {
+        self.shutdown(policy)
     }
 
-    fn shutdown(&mut self) -> Result<()> {
-        if self.is_healthy() {
-            let (tx, rx) = crossbeam_channel::bounded(1);
-            self.0 .1.send((self.1.clone(), tx))?;
-            rx.recv()?;
-            let thread_opt = self.0 .0.take();
-            if let Some(thread) = thread_opt {
-                thread.join().expect("Failed to join thread.")?;
+    fn shutdown(&mut self, policy: DrainPolicy) -> Result<()> {
+        *self.2.closing.lock() = Some(policy);
+        let mut res = Ok(());
+        for (threads, sender) in self.0.iter_mut() {
+            let threads = std::mem::take(threads);
+            // every working thread stops after receiving its own stop operation
+            let running = threads.iter().filter(|t| !t.is_finished()).count();
+            for _ in 0..running {
+                let (responder, _) = Channel::new();
+                let stop = Request {
+                    op: self.1.clone(),
+                    deadline: None,
+                    id: 0,
+                    responder,
+                };
+                // the threads that have already exited don't need it
+                _ = match policy {
+                    DrainPolicy::Abort => sender.try_send(stop).is_ok(),
+                    _ => sender.send(stop).is_ok(),
+                };
+            }
+            for thread in threads {
+                let thread_res = thread.join().unwrap_or_else(|_| {
+                    Err(QueueError::Internal(
+                        "The working thread panicked.".to_string(),
+                    ))
+                });
+                if res.is_ok() {
+                    res = thread_res;
+                }
             }
         }
-        Ok(())
+        res
+    }
+}
+
+impl<O, R> NonBlockingQueueWrapper<O, R>
+where
+    O: OpName + Clone + Send + Sync,
+    R: Send,
+{
+    /// Returns the operations waiting for the working threads.
+    pub fn pending_ops(&self) -> PendingOps {
+        let pending = self.2.pending.lock();
+        let mut counts = HashMap::new();
+        for (name, _) in pending.values() {
+            *counts.entry(*name).or_insert(0) += 1;
+        }
+        PendingOps {
+            counts,
+            oldest_age: pending.values().next().map(|(_, queued)| queued.elapsed()),
+        }
+    }
+
+    fn submit(&self, write: bool, op: O, responder: Responder<R>) -> Result<()> {
+        if !self.is_healthy() {
+            return Err(QueueError::Closed);
+        }
+
+        // the ids start from 1, 0 is used by the stop operations
+        let id = self.2.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        self.2
+            .pending
+            .lock()
+            .insert(id, (op.name(), Instant::now()));
+        let request = Request {
+            op,
+            deadline: self.deadline(),
+            id,
+            responder,
+        };
+        let sender = if write { self.writer() } else { self.reader() };
+        let res = match self.3.backpressure {
+            BackpressureStrategy::Block => sender.send(request).map_err(|_| QueueError::Closed),
+            BackpressureStrategy::FailFast => sender.try_send(request).map_err(|e| match e {
+                TrySendError::Full(_) => QueueError::Backpressure,
+                TrySendError::Disconnected(_) => QueueError::Closed,
+            }),
+            BackpressureStrategy::BlockTimeout(timeout) => {
+                sender.send_timeout(request, timeout).map_err(|e| match e {
+                    SendTimeoutError::Timeout(_) => QueueError::Backpressure,
+                    SendTimeoutError::Disconnected(_) => QueueError::Closed,
+                })
+            }
+        };
+        if res.is_err() {
+            self.2.pending.lock().remove(&id);
+        }
+        res
+    }
+
+    fn call(&self, write: bool, op: O) -> Result<TypedResponse<R>> {
+        let (responder, channel) = Channel::new();
+        self.submit(write, op, responder)?;
+        Ok(TypedResponse(Box::new(channel)))
+    }
+
+    /// Returns the response which is ready without going through the working threads.
+    fn ready(&self, res: R) -> Result<TypedResponse<R>> {
+        if !self.is_healthy() {
+            return Err(QueueError::Closed);
+        }
+        let (responder, channel) = Channel::new();
+        _ = responder.send(res);
+        Ok(TypedResponse(Box::new(channel)))
+    }
+
+    fn call_with_callback<F>(&self, write: bool, op: O, callback: F) -> Result<()>
+    where
+        F: FnOnce(R) + Send + 'static,
+    {
+        self.submit(write, op, Responder::Callback(Box::new(callback)))
     }
 }
 
@@ -127,201 +1242,226 @@ where
     R: Send,
 {
     fn drop(&mut self) {
-        self.shutdown().unwrap();
+        // the errors can't be reported from here, use `close` to get them
+        _ = self.shutdown(DrainPolicy::Finish);
     }
 }
 
 impl PersistentQueueWithCapacity {
-    pub fn new(
-        path: &str,
+    pub fn new<P: AsRef<Path>>(
+        path: P,
         max_elements: usize,
         max_inflight_ops: usize,
         db_options: rocksdb::Options,
     ) -> Result<Self> {
-        let mut queue =
-            crate::PersistentQueueWithCapacity::new(path, max_elements, db_options).unwrap();
-        let f = move |rx: Receiver<(Operation, Sender<ResponseVariant>)>| {
-            loop {
-                match rx.recv() {
-                    Ok((Operation::Push(values), resp_tx)) => {
-                        let value_slices = values.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
-                        let resp = queue.push(&value_slices);
-                        resp_tx.send(ResponseVariant::Push(resp))?;
-                    }
-                    Ok((Operation::Pop(max_elements), resp_tx)) => {
-                        let resp = queue.pop(max_elements);
-                        resp_tx.send(ResponseVariant::Pop(resp))?;
-                    }
-                    Ok((Operation::Length, resp_tx)) => {
-                        let resp = queue.len();
-                        resp_tx.send(ResponseVariant::Length(resp))?;
-                    }
-                    Ok((Operation::DiskSize, resp_tx)) => {
-                        let resp = queue.disk_size();
-                        resp_tx.send(ResponseVariant::Size(resp))?;
-                    }
-                    Ok((Operation::PayloadSize, resp_tx)) => {
-                        let resp = queue.payload_size();
-                        resp_tx.send(ResponseVariant::Size(Ok(resp as usize)))?;
-                    }
-                    Ok((Operation::Stop, resp_tx)) => {
-                        resp_tx.send(ResponseVariant::Stop)?;
-                        break;
-                    }
-                    Err(e) => return Err(anyhow::anyhow!("Error receiving operation: {}", e)),
-                }
-            }
-            Ok(())
-        };
-        let (handle, tx) = start_op_loop(f, max_inflight_ops);
-        Ok(Self((handle, tx), Operation::Stop))
+        let queue = crate::PersistentQueueWithCapacity::new(path, max_elements, db_options)?;
+        Self::from_queue(queue, max_inflight_ops)
+    }
+
+    /// Wraps an already opened and configured queue.
+    pub fn from_queue(
+        queue: crate::PersistentQueueWithCapacity,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        Self::from_queue_with_workers(queue, 1, max_inflight_ops)
+    }
+
+    /// Wraps an already opened and configured queue operated by `workers` threads. Read-only
+    /// operations (`len`, `disk_size`, `payload_size`) run concurrently and are not delayed by
+    /// a slow push or pop. With more than one thread, operations may complete in a different
+    /// order than they were sent.
+    ///
+    pub fn from_queue_with_workers(
+        queue: crate::PersistentQueueWithCapacity,
+        workers: usize,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        let state = Arc::new(WorkerState {
+            counters: Some(queue.counters()),
+            ..Default::default()
+        });
+        queue.add_listener(state.forward_events());
+        let lane = start_op_loop(
+            Arc::new(RwLock::new(queue)),
+            state.clone(),
+            workers,
+            max_inflight_ops,
+        )?;
+        Ok(Self(
+            vec![lane],
+            Operation::Stop,
+            state,
+            SendOptions::default(),
+        ))
     }
 
+    /// Wraps an already opened and configured queue. Pushes are executed by the dedicated
+    /// writer thread, the other operations by `readers` threads, so that pops are not queued
+    /// behind large pushes. Both the readers and the writer accept up to `max_inflight_ops`
+    /// operations.
+    ///
+    pub fn from_queue_with_writer(
+        queue: crate::PersistentQueueWithCapacity,
+        readers: usize,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        let state = Arc::new(WorkerState {
+            counters: Some(queue.counters()),
+            ..Default::default()
+        });
+        queue.add_listener(state.forward_events());
+        let queue = Arc::new(RwLock::new(queue));
+        let reader = start_op_loop(queue.clone(), state.clone(), readers, max_inflight_ops)?;
+        let writer = start_op_loop(queue, state.clone(), 1, max_inflight_ops)?;
+        Ok(Self(
+            vec![reader, writer],
+            Operation::Stop,
+            state,
+            SendOptions::default(),
+        ))
+    }
+
+    fn counters(&self) -> &Counters {
+        // set by all the constructors of the SPSC queue
+        self.2.counters.as_deref().unwrap()
+    }
+
+    /// Returns the ready response with the length, it doesn't wait for the working threads,
+    /// so operations sent before but not completed yet are not taken into account.
+    ///
     pub fn len(&self) -> Result<Response> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
+        self.ready(ResponseVariant::Length(self.counters().len()))
+    }
 
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((Operation::Length, tx))?;
-        Ok(TypedResponse(rx))
+    /// Returns the ready response, see [`Self::len`].
+    pub fn is_empty(&self) -> Result<Response> {
+        self.ready(ResponseVariant::IsEmpty(self.counters().is_empty()))
     }
 
     pub fn disk_size(&self) -> Result<Response> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((Operation::DiskSize, tx))?;
-        Ok(TypedResponse(rx))
+        self.call(false, Operation::DiskSize)
     }
 
+    /// Returns the ready response, see [`Self::len`].
     pub fn payload_size(&self) -> Result<Response> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((Operation::PayloadSize, tx))?;
-        Ok(TypedResponse(rx))
+        self.ready(ResponseVariant::Size(Ok(
+            self.counters().payload_size() as usize
+        )))
     }
 
     pub fn push(&self, values: &[&[u8]]) -> Result<Response> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((
+        self.call(
+            true,
             Operation::Push(values.iter().map(|e| e.to_vec()).collect()),
-            tx,
-        ))?;
-        Ok(TypedResponse(rx))
+        )
     }
 
     pub fn pop(&self, max_elements: usize) -> Result<Response> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
+        self.call(false, Operation::Pop(max_elements))
+    }
 
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((Operation::Pop(max_elements), tx))?;
-        Ok(TypedResponse(rx))
+    /// Pops the elements until the queue is empty, see
+    /// [`crate::PersistentQueueWithCapacity::pop_all`]. The result is [`ResponseVariant::Pop`].
+    ///
+    pub fn pop_all(&self, max_bytes: Option<u64>) -> Result<Response> {
+        self.call(false, Operation::PopAll(max_bytes))
+    }
+
+    /// Pushes the values, the result is passed to the callback in the working thread.
+    pub fn push_with_callback<F>(&self, values: &[&[u8]], callback: F) -> Result<()>
+    where
+        F: FnOnce(ResponseVariant) + Send + 'static,
+    {
+        let op = Operation::Push(values.iter().map(|e| e.to_vec()).collect());
+        self.call_with_callback(true, op, callback)
+    }
+
+    /// Pops the values, the result is passed to the callback in the working thread.
+    pub fn pop_with_callback<F>(&self, max_elements: usize, callback: F) -> Result<()>
+    where
+        F: FnOnce(ResponseVariant) + Send + 'static,
+    {
+        self.call_with_callback(false, Operation::Pop(max_elements), callback)
     }
 }
 
 impl MpmcQueue {
-    pub fn new(path: &str, ttl: Duration, max_inflight_ops: usize) -> Result<Self> {
-        let mut queue = mpmc::MpmcQueue::new(path, ttl)?;
-        let f = move |rx: Receiver<(MpmcOperation, Sender<MpmcResponseVariant>)>| {
-            loop {
-                match rx.recv() {
-                    Ok((MpmcOperation::Add(values), resp_tx)) => {
-                        let value_slices = values.iter().map(|e| e.as_slice()).collect::<Vec<_>>();
-                        let resp = queue.add(&value_slices);
-                        resp_tx.send(MpmcResponseVariant::Add(resp))?;
-                    }
-                    Ok((MpmcOperation::Next(max_elements, label, start_position), resp_tx)) => {
-                        let resp = queue.next(max_elements, label.as_str(), start_position);
-                        resp_tx.send(MpmcResponseVariant::Next(resp))?;
-                    }
-                    Ok((MpmcOperation::Length, resp_tx)) => {
-                        let resp = queue.len();
-                        resp_tx.send(MpmcResponseVariant::Length(resp))?;
-                    }
-                    Ok((MpmcOperation::DiskSize, resp_tx)) => {
-                        let resp = queue.disk_size();
-                        resp_tx.send(MpmcResponseVariant::Size(resp))?;
-                    }
-                    Ok((MpmcOperation::GetLabels, resp_tx)) => {
-                        let resp = queue.get_labels();
-                        resp_tx.send(MpmcResponseVariant::GetLabels(resp))?;
-                    }
-                    Ok((MpmcOperation::RemoveLabel(label), resp_tx)) => {
-                        let resp = queue.remove_label(label.as_str());
-                        resp_tx.send(MpmcResponseVariant::RemoveLabel(resp))?;
-                    }
-                    Ok((MpmcOperation::Stop, resp_tx)) => {
-                        resp_tx.send(MpmcResponseVariant::Stop)?;
-                        break;
-                    }
-                    Err(e) => return Err(anyhow::anyhow!("Error receiving operation: {}", e)),
-                }
-            }
-            Ok(())
-        };
-        let (handle, tx) = start_op_loop(f, max_inflight_ops);
-        Ok(Self((handle, tx), MpmcOperation::Stop))
+    pub fn new<P: AsRef<Path>>(path: P, ttl: Duration, max_inflight_ops: usize) -> Result<Self> {
+        let queue = mpmc::MpmcQueue::new(path, ttl)?;
+        Self::from_queue(queue, max_inflight_ops)
     }
 
-    pub fn disk_size(&self) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
+    /// Wraps an already opened and configured queue.
+    pub fn from_queue(queue: mpmc::MpmcQueue, max_inflight_ops: usize) -> Result<Self> {
+        Self::from_queue_with_workers(queue, 1, max_inflight_ops)
+    }
 
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((MpmcOperation::DiskSize, tx))?;
-        Ok(TypedResponse(rx))
+    /// Wraps an already opened and configured queue operated by `workers` threads. Read-only
+    /// operations (`len`, `disk_size`, `get_labels`) run concurrently and are not delayed by
+    /// a slow add or next. With more than one thread, operations may complete in a different
+    /// order than they were sent.
+    ///
+    pub fn from_queue_with_workers(
+        queue: mpmc::MpmcQueue,
+        workers: usize,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        let state = Arc::new(WorkerState::default());
+        queue.add_listener(state.forward_events());
+        let lane = start_op_loop(
+            Arc::new(RwLock::new(queue)),
+            state.clone(),
+            workers,
+            max_inflight_ops,
+        )?;
+        Ok(Self(
+            vec![lane],
+            MpmcOperation::Stop,
+            state,
+            SendOptions::default(),
+        ))
+    }
+
+    /// Wraps an already opened and configured queue. Adds are executed by the dedicated
+    /// writer thread, the other operations by `readers` threads, so that reads are not queued
+    /// behind large adds. Both the readers and the writer accept up to `max_inflight_ops`
+    /// operations.
+    ///
+    pub fn from_queue_with_writer(
+        queue: mpmc::MpmcQueue,
+        readers: usize,
+        max_inflight_ops: usize,
+    ) -> Result<Self> {
+        let state = Arc::new(WorkerState::default());
+        queue.add_listener(state.forward_events());
+        let queue = Arc::new(RwLock::new(queue));
+        let reader = start_op_loop(queue.clone(), state.clone(), readers, max_inflight_ops)?;
+        let writer = start_op_loop(queue, state.clone(), 1, max_inflight_ops)?;
+        Ok(Self(
+            vec![reader, writer],
+            MpmcOperation::Stop,
+            state,
+            SendOptions::default(),
+        ))
+    }
+
+    pub fn disk_size(&self) -> Result<MpmcResponse> {
+        self.call(false, MpmcOperation::DiskSize)
     }
 
     pub fn len(&self) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
+        self.call(false, MpmcOperation::Length)
+    }
 
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((MpmcOperation::Length, tx))?;
-        Ok(TypedResponse(rx))
+    pub fn is_empty(&self) -> Result<MpmcResponse> {
+        self.call(false, MpmcOperation::IsEmpty)
     }
 
     pub fn add(&self, values: &[&[u8]]) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((
+        self.call(
+            true,
             MpmcOperation::Add(values.iter().map(|e| e.to_vec()).collect()),
-            tx,
-        ))?;
-        Ok(TypedResponse(rx))
+        )
     }
 
     pub fn next(
@@ -330,52 +1470,53 @@ impl MpmcQueue {
         label: &str,
         start_position: StartPosition,
     ) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((
+        self.call(
+            false,
             MpmcOperation::Next(max_elts, label.to_string(), start_position),
-            tx,
-        ))?;
-        Ok(TypedResponse(rx))
+        )
     }
 
     pub fn get_labels(&self) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
-
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0 .1.send((MpmcOperation::GetLabels, tx))?;
-        Ok(TypedResponse(rx))
+        self.call(false, MpmcOperation::GetLabels)
     }
 
     pub fn remove_label(&self, label: &str) -> Result<MpmcResponse> {
-        if !self.is_healthy() {
-            return Err(anyhow::anyhow!(
-                "Queue is unhealthy: cannot use it anymore."
-            ));
-        }
+        self.call(false, MpmcOperation::RemoveLabel(label.to_string()))
+    }
 
-        let (tx, rx) = crossbeam_channel::bounded(1);
-        self.0
-             .1
-            .send((MpmcOperation::RemoveLabel(label.to_string()), tx))?;
-        Ok(TypedResponse(rx))
+    /// Adds the values, the result is passed to the callback in the working thread.
+    pub fn add_with_callback<F>(&self, values: &[&[u8]], callback: F) -> Result<()>
+    where
+        F: FnOnce(MpmcResponseVariant) + Send + 'static,
+    {
+        let op = MpmcOperation::Add(values.iter().map(|e| e.to_vec()).collect());
+        self.call_with_callback(true, op, callback)
+    }
+
+    /// Reads the values, the result is passed to the callback in the working thread.
+    pub fn next_with_callback<F>(
+        &self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+        callback: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(MpmcResponseVariant) + Send + 'static,
+    {
+        let op = MpmcOperation::Next(max_elts, label.to_string(), start_position);
+        self.call_with_callback(false, op, callback)
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::error::{QueueError, Result};
+    use crate::events::QueueEvent;
     use crate::mpmc;
     use crate::mpmc::StartPosition;
-    use std::time::Duration;
+    use std::thread;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn persistent_queue_fresh_healthy() {
@@ -390,6 +1531,31 @@ mod tests {
         _ = crate::PersistentQueueWithCapacity::remove_db(&path);
     }
 
+    #[test]
+    fn persistent_queue_open_twice() {
+        let path = "/tmp/test_open_twice".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let _queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                1,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let err = super::PersistentQueueWithCapacity::new(
+                &path,
+                1,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .err()
+            .unwrap();
+            assert!(matches!(err, QueueError::InUse { .. }));
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
     #[test]
     fn persistent_queue_push_pop() {
         let path = "/tmp/test_push_pop".to_string();
@@ -403,19 +1569,29 @@ mod tests {
         assert!(matches!(resp, super::ResponseVariant::Length(0)));
 
         let resp = queue.push(&[&[1u8, 2u8, 3u8]]).unwrap().get().unwrap();
-        assert!(matches!(resp, super::ResponseVariant::Push(Ok(()))));
+        assert!(matches!(resp, super::ResponseVariant::Push(Ok((0, 1)))));
 
         let resp = queue.payload_size().unwrap().get().unwrap();
         assert!(matches!(resp, super::ResponseVariant::Size(Ok(3))));
 
         let resp = queue.len().unwrap().get().unwrap();
         assert!(matches!(resp, super::ResponseVariant::Length(1)));
+        let resp = queue.is_empty().unwrap().get().unwrap();
+        assert!(matches!(resp, super::ResponseVariant::IsEmpty(false)));
         let resp = queue.pop(1).unwrap().get().unwrap();
         assert!(
             matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![1u8, 2u8, 3u8]])
         );
         let resp = queue.len().unwrap().get().unwrap();
         assert!(matches!(resp, super::ResponseVariant::Length(0)));
+        let resp = queue.is_empty().unwrap().get().unwrap();
+        assert!(matches!(resp, super::ResponseVariant::IsEmpty(true)));
+
+        queue.push(&[&[1u8], &[2u8]]).unwrap().get().unwrap();
+        let resp = queue.pop_all(None).unwrap().get().unwrap();
+        assert!(
+            matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![1u8], vec![2u8]])
+        );
         _ = crate::PersistentQueueWithCapacity::remove_db(&path);
     }
 
@@ -439,6 +1615,8 @@ mod tests {
         assert!(queue.is_healthy());
         let resp = queue.len().unwrap().get().unwrap();
         assert!(matches!(resp, super::MpmcResponseVariant::Length(0)));
+        let resp = queue.is_empty().unwrap().get().unwrap();
+        assert!(matches!(resp, super::MpmcResponseVariant::IsEmpty(true)));
         _ = mpmc::MpmcQueue::remove_db(&path);
     }
 
@@ -453,7 +1631,9 @@ mod tests {
         assert!(matches!(resp, super::MpmcResponseVariant::Length(0)));
 
         let resp = queue.add(&[&[1u8, 2u8, 3u8]]).unwrap().get().unwrap();
-        assert!(matches!(resp, super::MpmcResponseVariant::Add(Ok(()))));
+        assert!(matches!(resp, super::MpmcResponseVariant::Add(Ok((0, 1)))));
+        let resp = queue.is_empty().unwrap().get().unwrap();
+        assert!(matches!(resp, super::MpmcResponseVariant::IsEmpty(false)));
 
         let resp = queue.len().unwrap().get().unwrap();
         assert!(matches!(resp, super::MpmcResponseVariant::Length(1)));
@@ -470,6 +1650,643 @@ mod tests {
         _ = mpmc::MpmcQueue::remove_db(&path);
     }
 
+    #[test]
+    fn persistent_queue_workers() {
+        let path = "/tmp/test_workers".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue =
+                crate::PersistentQueueWithCapacity::new(&path, 5, rocksdb::Options::default())
+                    .unwrap();
+            let queue = super::PersistentQueueWithCapacity::from_queue_with_workers(queue, 4, 1000)
+                .unwrap();
+            assert!(queue.is_healthy());
+            assert_eq!(queue.workers(), 4);
+
+            let responses = (0..5u8)
+                .map(|i| queue.push(&[&[i]]).unwrap())
+                .collect::<Vec<_>>();
+            for resp in responses {
+                assert!(matches!(
+                    resp.get().unwrap(),
+                    super::ResponseVariant::Push(Ok(_))
+                ));
+            }
+            let resp = queue.len().unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Length(5)));
+            let resp = queue.pop(10).unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v.len() == 5));
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_writer() {
+        let path = "/tmp/test_writer".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue =
+                crate::PersistentQueueWithCapacity::new(&path, 5, rocksdb::Options::default())
+                    .unwrap();
+            let queue =
+                super::PersistentQueueWithCapacity::from_queue_with_writer(queue, 1, 1000).unwrap();
+            assert!(queue.is_healthy());
+            assert_eq!(queue.workers(), 2);
+
+            let resp = queue.push(&[&[1u8], &[2u8]]).unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Push(Ok(_))));
+            let resp = queue.len().unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Length(2)));
+            let resp = queue.pop(5).unwrap().get().unwrap();
+            assert!(
+                matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![1u8], vec![2u8]])
+            );
+            assert_eq!(queue.inflight_ops().unwrap(), 0);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_op_timeout() {
+        let path = "/tmp/test_op_timeout".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            queue.set_op_timeout(Some(Duration::ZERO));
+            let resp = queue.push(&[&[1u8]]).unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Timeout));
+
+            queue.set_op_timeout(None);
+            assert_eq!(queue.op_timeout(), None);
+            let resp = queue
+                .len()
+                .unwrap()
+                .get_timeout(Duration::from_secs(10))
+                .unwrap();
+            assert!(matches!(resp, Some(super::ResponseVariant::Length(0))));
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_close() {
+        let path = "/tmp/test_close".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let push = queue.push(&[&[1u8]]).unwrap();
+            queue.close(super::DrainPolicy::Finish).unwrap();
+            assert!(matches!(
+                push.get().unwrap(),
+                super::ResponseVariant::Push(Ok(_))
+            ));
+
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let resp = queue.len().unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Length(1)));
+            queue.close(super::DrainPolicy::Reject).unwrap();
+
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            queue.close(super::DrainPolicy::Abort).unwrap();
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn mpmc_queue_close_reject() {
+        let path = "/tmp/test_mpmc_close_reject".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = super::MpmcQueue::new(&path, Duration::from_secs(10), 1000).unwrap();
+            let responses = (0..5)
+                .map(|_| queue.add(&[&[1u8]]).unwrap())
+                .collect::<Vec<_>>();
+            queue.close(super::DrainPolicy::Reject).unwrap();
+            // the operations executed before closing succeed, the rest are rejected
+            for resp in responses {
+                assert!(matches!(
+                    resp.get().unwrap(),
+                    super::MpmcResponseVariant::Add(Ok(_)) | super::MpmcResponseVariant::Shutdown
+                ));
+            }
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_restart() {
+        let path = "/tmp/test_restart".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            assert_eq!(queue.restart_policy(), None);
+            queue.set_restart_policy(Some(super::RestartPolicy {
+                max_restarts: 1,
+                initial_backoff: Duration::ZERO,
+                max_backoff: Duration::ZERO,
+            }));
+
+            // the working thread fails if the callback panics
+            queue
+                .push_with_callback(&[&[1]], |_| panic!("The callback failed."))
+                .unwrap();
+
+            // the element pushed before the panic is popped by the restarted thread
+            let resp = queue.pop(1).unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![1]]));
+            assert!(queue.is_healthy());
+            assert_eq!(queue.restarts(), 1);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_dropped_response() {
+        let path = "/tmp/test_dropped_response".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            queue.push(&[&[1]]).unwrap().get().unwrap();
+
+            // the callback blocks the working thread until the pop response is dropped
+            let (entered_tx, entered_rx) = crossbeam_channel::bounded(0);
+            let (release_tx, release_rx) = crossbeam_channel::bounded::<()>(0);
+            queue
+                .push_with_callback(&[&[2]], move |_| {
+                    entered_tx.send(()).unwrap();
+                    _ = release_rx.recv();
+                })
+                .unwrap();
+            entered_rx.recv().unwrap();
+            drop(queue.pop(1).unwrap());
+            drop(release_tx);
+
+            let resp = queue.pop(1).unwrap().get().unwrap();
+            assert!(matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![2]]));
+            assert!(queue.is_healthy());
+            assert_eq!(queue.restarts(), 0);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_last_error() {
+        let path = "/tmp/test_last_error".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            assert_eq!(queue.last_error(), None);
+
+            // the working thread fails if the callback panics
+            queue
+                .pop_with_callback(1, |_| panic!("The callback failed."))
+                .unwrap();
+            let deadline = Instant::now() + Duration::from_secs(10);
+            while queue.is_healthy() && Instant::now() < deadline {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(!queue.is_healthy());
+            assert_eq!(
+                queue.last_error().as_deref(),
+                Some("The working thread panicked.")
+            );
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_events() {
+        let path = "/tmp/test_events".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                1,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let events = queue.events();
+            drop(queue.events());
+
+            queue.push(&[&[1u8]]).unwrap().get().unwrap();
+            queue.push(&[&[2u8]]).unwrap().get().unwrap();
+            assert_eq!(
+                events.try_iter().collect::<Vec<_>>(),
+                vec![QueueEvent::Push(1), QueueEvent::Full]
+            );
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn restart_backoff() {
+        let policy = super::RestartPolicy {
+            max_restarts: 10,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(1),
+        };
+        assert_eq!(policy.backoff(0), Duration::from_millis(100));
+        assert_eq!(policy.backoff(2), Duration::from_millis(400));
+        assert_eq!(policy.backoff(4), Duration::from_secs(1));
+        assert_eq!(policy.backoff(usize::MAX), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn persistent_queue_on_ready() {
+        let path = "/tmp/test_on_ready".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let (tx, rx) = crossbeam_channel::unbounded();
+            let resp = queue.push(&[&[1u8, 2u8]]).unwrap();
+            let push_tx = tx.clone();
+            resp.on_ready(move || push_tx.send("push").unwrap());
+            assert_eq!(rx.recv().unwrap(), "push");
+            assert!(resp.is_ready());
+            assert!(matches!(
+                resp.get().unwrap(),
+                super::ResponseVariant::Push(Ok(_))
+            ));
+
+            // the callback is invoked immediately when the result is already there
+            let resp = queue.len().unwrap();
+            resp.get_timeout(Duration::from_secs(1)).unwrap();
+            resp.on_ready(move || tx.send("len").unwrap());
+            assert_eq!(rx.try_recv().unwrap(), "len");
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_callbacks() {
+        let path = "/tmp/test_callbacks".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let (tx, rx) = crossbeam_channel::unbounded();
+            let push_tx = tx.clone();
+            queue
+                .push_with_callback(&[&[1u8]], move |resp| {
+                    push_tx
+                        .send(matches!(resp, super::ResponseVariant::Push(Ok(_))))
+                        .unwrap();
+                })
+                .unwrap();
+            queue
+                .pop_with_callback(1, move |resp| {
+                    tx.send(
+                        matches!(resp, super::ResponseVariant::Pop(Ok(v)) if v == vec![vec![1u8]]),
+                    )
+                    .unwrap();
+                })
+                .unwrap();
+            assert!(rx.recv().unwrap());
+            assert!(rx.recv().unwrap());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn mpmc_queue_callbacks() {
+        let path = "/tmp/test_mpmc_callbacks".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = super::MpmcQueue::new(&path, Duration::from_secs(10), 1000).unwrap();
+            let (tx, rx) = crossbeam_channel::unbounded();
+            let add_tx = tx.clone();
+            queue
+                .add_with_callback(&[&[1u8]], move |resp| {
+                    add_tx
+                        .send(matches!(resp, super::MpmcResponseVariant::Add(Ok(_))))
+                        .unwrap();
+                })
+                .unwrap();
+            queue
+                .next_with_callback(1, "label", StartPosition::Oldest, move |resp| {
+                    tx.send(matches!(resp, super::MpmcResponseVariant::Next(Ok((v, false))) if v == vec![vec![1u8]]))
+                        .unwrap();
+                })
+                .unwrap();
+            assert!(rx.recv().unwrap());
+            assert!(rx.recv().unwrap());
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_pending_ops() {
+        let path = "/tmp/test_pending_ops".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                3,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            assert_eq!(queue.pending_ops(), super::PendingOps::default());
+            queue.set_coalescing(super::Coalescing {
+                max_ops: 1,
+                max_bytes: 0,
+                ..Default::default()
+            });
+
+            // the working thread is blocked by the callback
+            let (tx, rx) = crossbeam_channel::bounded::<()>(0);
+            queue
+                .pop_with_callback(1, move |_| {
+                    _ = rx.recv();
+                })
+                .unwrap();
+            let push = queue.push(&[&[1u8]]).unwrap();
+            let size = queue.disk_size().unwrap();
+            let pending = queue.pending_ops();
+            assert_eq!(pending.counts.get("push"), Some(&1));
+            assert_eq!(pending.counts.get("disk_size"), Some(&1));
+            assert!(pending.oldest_age.is_some());
+
+            tx.send(()).unwrap();
+            push.get().unwrap();
+            size.get().unwrap();
+            assert_eq!(queue.pending_ops(), super::PendingOps::default());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_response_combinators() {
+        let path = "/tmp/test_response_combinators".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                5,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            let pushes = (0..3u8)
+                .map(|i| {
+                    queue.push(&[&[i]]).unwrap().and_then(|resp| match resp {
+                        super::ResponseVariant::Push(res) => res,
+                        _ => Err(QueueError::Internal("Unexpected response".to_string())),
+                    })
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(super::TypedResponse::wait_all(pushes).unwrap().len(), 3);
+
+            let len = queue.len().unwrap().map(|resp| match resp {
+                super::ResponseVariant::Length(len) => len,
+                _ => 0,
+            });
+            assert_eq!(len.get().unwrap(), 3);
+            assert!(len.try_get().is_err());
+
+            let pop = queue
+                .pop(5)
+                .unwrap()
+                .and_then(|_| -> Result<()> { Err(QueueError::Internal("failed".to_string())) });
+            assert!(pop.get().is_err());
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_backpressure() {
+        let path = "/tmp/test_backpressure".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue =
+                super::PersistentQueueWithCapacity::new(&path, 3, 1, rocksdb::Options::default())
+                    .unwrap();
+            assert_eq!(queue.backpressure(), super::BackpressureStrategy::Block);
+            queue.set_coalescing(super::Coalescing {
+                max_ops: 1,
+                max_bytes: 0,
+                ..Default::default()
+            });
+
+            // the working thread is blocked by the callback, the next operation fills the channel
+            let (tx, rx) = crossbeam_channel::bounded::<()>(0);
+            queue
+                .pop_with_callback(1, move |_| {
+                    _ = rx.recv();
+                })
+                .unwrap();
+            while queue.inflight_ops().unwrap() > 0 {
+                std::thread::yield_now();
+            }
+            let size = queue.disk_size().unwrap();
+
+            queue.set_backpressure(super::BackpressureStrategy::FailFast);
+            let err = queue.disk_size().err().unwrap();
+            assert!(matches!(err, QueueError::Backpressure));
+
+            queue.set_backpressure(super::BackpressureStrategy::BlockTimeout(
+                Duration::from_millis(10),
+            ));
+            let err = queue.disk_size().err().unwrap();
+            assert!(matches!(err, QueueError::Backpressure));
+            assert_eq!(queue.pending_ops().counts.get("disk_size"), Some(&1));
+            // the length doesn't go through the working thread
+            assert!(matches!(
+                queue.len().unwrap().get().unwrap(),
+                super::ResponseVariant::Length(0)
+            ));
+
+            tx.send(()).unwrap();
+            assert!(matches!(
+                size.get().unwrap(),
+                super::ResponseVariant::Size(Ok(_))
+            ));
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_coalescing() {
+        let path = "/tmp/test_coalescing".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                5,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            assert_eq!(queue.coalescing(), super::Coalescing::default());
+            queue.set_coalescing(super::Coalescing {
+                max_ops: 8,
+                max_bytes: 1024,
+                ..Default::default()
+            });
+
+            // merged or not, the operations behave as if executed one by one
+            let pushes = [[[1u8], [2u8]], [[3u8], [4u8]], [[5u8], [6u8]]]
+                .iter()
+                .map(|values| queue.push(&[&values[0], &values[1]]).unwrap())
+                .collect::<Vec<_>>();
+            let pushes = pushes
+                .into_iter()
+                .map(|resp| match resp.get().unwrap() {
+                    super::ResponseVariant::Push(res) => res.is_ok(),
+                    _ => panic!("Unexpected response"),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(pushes, vec![true, true, false]);
+
+            let pops = [1, 2, 5]
+                .iter()
+                .map(|n| queue.pop(*n).unwrap())
+                .collect::<Vec<_>>();
+            let pops = pops
+                .into_iter()
+                .map(|resp| match resp.get().unwrap() {
+                    super::ResponseVariant::Pop(res) => res.unwrap(),
+                    _ => panic!("Unexpected response"),
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(
+                pops,
+                vec![vec![vec![1u8]], vec![vec![2u8], vec![3u8]], vec![vec![4u8]]]
+            );
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn persistent_queue_group_commit() {
+        let path = "/tmp/test_group_commit".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = super::PersistentQueueWithCapacity::new(
+                &path,
+                6,
+                1000,
+                rocksdb::Options::default(),
+            )
+            .unwrap();
+            queue.set_coalescing(super::Coalescing {
+                window: Duration::from_millis(200),
+                ..Default::default()
+            });
+            let events = queue.events();
+
+            // the pushes sent within the window are written together
+            let pushes = (0..3u8)
+                .map(|i| queue.push(&[&[i]]).unwrap())
+                .collect::<Vec<_>>();
+            for (i, resp) in pushes.into_iter().enumerate() {
+                assert!(matches!(
+                    resp.get().unwrap(),
+                    super::ResponseVariant::Push(Ok((first, 1))) if first == i as u64
+                ));
+            }
+            assert_eq!(
+                events.try_iter().collect::<Vec<_>>(),
+                vec![QueueEvent::Push(3)]
+            );
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn mpmc_queue_workers() {
+        let path = "/tmp/test_mpmc_workers".to_string();
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let queue = mpmc::MpmcQueue::new(&path, Duration::from_secs(10)).unwrap();
+            assert!(super::MpmcQueue::from_queue_with_workers(queue, 0, 1000).is_err());
+
+            let queue = mpmc::MpmcQueue::new(&path, Duration::from_secs(10)).unwrap();
+            let queue = super::MpmcQueue::from_queue_with_workers(queue, 2, 1000).unwrap();
+            assert_eq!(queue.workers(), 2);
+            let resp = queue.add(&[&[1u8], &[2u8]]).unwrap().get().unwrap();
+            assert!(matches!(resp, super::MpmcResponseVariant::Add(Ok((0, 2)))));
+            let resp = queue
+                .next(1, "label", StartPosition::Oldest)
+                .unwrap()
+                .get()
+                .unwrap();
+            assert!(
+                matches!(resp, super::MpmcResponseVariant::Next(Ok((v, false))) if v.len() == 1)
+            );
+        }
+        {
+            let queue = mpmc::MpmcQueue::new(&path, Duration::from_secs(10)).unwrap();
+            let queue = super::MpmcQueue::from_queue_with_writer(queue, 2, 1000).unwrap();
+            assert_eq!(queue.workers(), 3);
+            let resp = queue.add(&[&[3u8]]).unwrap().get().unwrap();
+            assert!(matches!(resp, super::MpmcResponseVariant::Add(Ok((2, 1)))));
+            let resp = queue
+                .next(5, "label", StartPosition::Oldest)
+                .unwrap()
+                .get()
+                .unwrap();
+            assert!(
+                matches!(resp, super::MpmcResponseVariant::Next(Ok((v, false))) if v.len() == 2)
+            );
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+
     #[test]
     fn mpmc_queue_size() {
         let path = "/tmp/test_mpmc_size".to_string();
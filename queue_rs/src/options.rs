@@ -0,0 +1,580 @@
+use crate::alerts::DiskAlerts;
+use crate::codec::Compression;
+use crate::compaction::{self, EvictionFilter};
+use crate::error::{QueueError, Result};
+use crate::limiter::RateLimit;
+use crate::mpmc::{ReaderPersistence, RetentionGuard, StartPolicy};
+use crate::resources::SharedResources;
+use rocksdb::{BlockBasedOptions, Cache, DBCompressionType, Env, Options};
+use std::fmt;
+use std::str::FromStr;
+
+// the RocksDB defaults of the rate limiter
+const RATE_LIMITER_REFILL_MICROS: i64 = 100_000;
+const RATE_LIMITER_FAIRNESS: i32 = 10;
+
+const DB_COMPRESSIONS: [(&str, DBCompressionType); 7] = [
+    ("none", DBCompressionType::None),
+    ("snappy", DBCompressionType::Snappy),
+    ("zlib", DBCompressionType::Zlib),
+    ("bz2", DBCompressionType::Bz2),
+    ("lz4", DBCompressionType::Lz4),
+    ("lz4hc", DBCompressionType::Lz4hc),
+    ("zstd", DBCompressionType::Zstd),
+];
+
+/// The prefix of the environment variables overriding the options, see
+/// [`QueueOptions::with_env_overrides`].
+///
+pub const ENV_PREFIX: &str = "ROCKSQ_";
+
+/// Returns the value of the override `name` returned by `lookup`.
+pub(crate) fn override_value<T, F>(lookup: &F, name: &str) -> Result<Option<T>>
+where
+    T: FromStr,
+    F: Fn(&str) -> Option<String>,
+{
+    lookup(name)
+        .map(|value| {
+            value.trim().parse().map_err(|_| {
+                QueueError::Config(format!("Invalid value of the override {}: {}", name, value))
+            })
+        })
+        .transpose()
+}
+
+/// Returns the boolean override `name` returned by `lookup`, `1` and `0` are accepted too.
+pub(crate) fn override_flag<F>(lookup: &F, name: &str) -> Result<Option<bool>>
+where
+    F: Fn(&str) -> Option<String>,
+{
+    lookup(name)
+        .map(|value| match value.trim().to_ascii_lowercase().as_str() {
+            "1" | "true" => Ok(true),
+            "0" | "false" => Ok(false),
+            _ => Err(QueueError::Config(format!(
+                "Invalid value of the override {}: {}",
+                name, value
+            ))),
+        })
+        .transpose()
+}
+
+/// Returns the RocksDB compression by its name: `none`, `snappy`, `zlib`, `bz2`, `lz4`,
+/// `lz4hc` or `zstd`.
+///
+pub fn db_compression(name: &str) -> Result<DBCompressionType> {
+    DB_COMPRESSIONS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, c)| *c)
+        .ok_or_else(|| {
+            QueueError::InvalidArgument(format!(
+                "Unknown compression: {}, expected one of: {}",
+                name,
+                DB_COMPRESSIONS.map(|(n, _)| n).join(", ")
+            ))
+        })
+}
+
+/// The typed options of a queue, see [`crate::PersistentQueueWithCapacity::open`] and
+/// [`crate::mpmc::MpmcQueue::open`]. The options which are not set keep the RocksDB defaults
+/// and the defaults of the queue.
+///
+/// The RocksDB options can be passed as is with [`Self::db_options`], the typed options are set
+/// on top of them, e.g. to tune the knobs which are not covered here.
+///
+/// ```
+/// use queue_rs::options::QueueOptions;
+/// use rocksdb::DBCompressionType;
+///
+/// let options = QueueOptions::default()
+///     .write_buffer_size(64 * 1024 * 1024)
+///     .compression(DBCompressionType::Lz4)
+///     .sync_writes(true);
+/// ```
+///
+#[derive(Clone, Default)]
+pub struct QueueOptions {
+    db_options: Option<Options>,
+    write_buffer_size: Option<usize>,
+    max_write_buffers: Option<i32>,
+    compression: Option<DBCompressionType>,
+    block_cache_size: Option<usize>,
+    enable_blob_files: bool,
+    min_blob_size: Option<u64>,
+    blob_file_size: Option<u64>,
+    enable_statistics: bool,
+    background_write_bytes_per_sec: Option<i64>,
+    auto_tune_background_writes: bool,
+    low_priority_compactions: bool,
+    resources: Option<SharedResources>,
+    pub(crate) element_compression: Compression,
+    pub(crate) checksums: bool,
+    pub(crate) sync_writes: bool,
+    pub(crate) push_rate_limit: Option<RateLimit>,
+    pub(crate) pop_rate_limit: Option<RateLimit>,
+    pub(crate) verify_on_open: bool,
+    pub(crate) repair_on_open: bool,
+    pub(crate) index_space: Option<u64>,
+    pub(crate) eviction_filter: Option<EvictionFilter>,
+    pub(crate) strict_ttl: bool,
+    pub(crate) retention_guard: Option<RetentionGuard>,
+    pub(crate) prefetch: usize,
+    pub(crate) reader_persistence: ReaderPersistence,
+    pub(crate) start_policy: Option<StartPolicy>,
+    pub(crate) disk_alerts: Option<DiskAlerts>,
+}
+
+impl fmt::Debug for QueueOptions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("QueueOptions")
+            .field("write_buffer_size", &self.write_buffer_size)
+            .field("max_write_buffers", &self.max_write_buffers)
+            .field("block_cache_size", &self.block_cache_size)
+            .field("enable_blob_files", &self.enable_blob_files)
+            .field("element_compression", &self.element_compression)
+            .field("sync_writes", &self.sync_writes)
+            .field("verify_on_open", &self.verify_on_open)
+            .field("repair_on_open", &self.repair_on_open)
+            .field("index_space", &self.index_space)
+            .field("eviction_filter", &self.eviction_filter.is_some())
+            .field("strict_ttl", &self.strict_ttl)
+            .field("retention_guard", &self.retention_guard)
+            .field("prefetch", &self.prefetch)
+            .field("reader_persistence", &self.reader_persistence)
+            .field("start_policy", &self.start_policy)
+            .field("disk_alerts", &self.disk_alerts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl QueueOptions {
+    /// The RocksDB options the typed options are set on, for the knobs which are not covered
+    /// by them. The options required by the queue are set on top of all of them.
+    ///
+    pub fn db_options(mut self, db_options: Options) -> Self {
+        self.db_options = Some(db_options);
+        self
+    }
+
+    /// The size in bytes of a memtable.
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buffer_size = Some(size);
+        self
+    }
+
+    /// The maximum number of memtables, both active and immutable.
+    pub fn max_write_buffers(mut self, number: i32) -> Self {
+        self.max_write_buffers = Some(number);
+        self
+    }
+
+    /// The compression of the stored data by RocksDB, see [`Self::element_compression`] for the
+    /// compression of the single elements.
+    ///
+    pub fn compression(mut self, compression: DBCompressionType) -> Self {
+        self.compression = Some(compression);
+        self
+    }
+
+    /// The size in bytes of the block cache of the queue.
+    pub fn block_cache_size(mut self, size: usize) -> Self {
+        self.block_cache_size = Some(size);
+        self
+    }
+
+    /// Stores the values from `min_blob_size` bytes in the blob files of `blob_file_size` bytes,
+    /// `None` keeps the RocksDB defaults.
+    ///
+    pub fn blob_files(mut self, min_blob_size: Option<u64>, blob_file_size: Option<u64>) -> Self {
+        self.enable_blob_files = true;
+        self.min_blob_size = min_blob_size;
+        self.blob_file_size = blob_file_size;
+        self
+    }
+
+    /// Collects the RocksDB statistics reported by `db_stats`.
+    pub fn enable_statistics(mut self, enable: bool) -> Self {
+        self.enable_statistics = enable;
+        self
+    }
+
+    /// Limits the rate of the flush and compaction writes of RocksDB to `bytes_per_sec`. If
+    /// `auto_tune` is set, the rate is tuned by RocksDB up to the limit.
+    ///
+    pub fn background_write_rate(mut self, bytes_per_sec: i64, auto_tune: bool) -> Self {
+        self.background_write_bytes_per_sec = Some(bytes_per_sec);
+        self.auto_tune_background_writes = auto_tune;
+        self
+    }
+
+    /// Lowers the IO and CPU priority of the compaction threads. The threads are shared by the
+    /// queues of the process, so the priority is lowered for all of them.
+    ///
+    pub fn low_priority_compactions(mut self, low_priority: bool) -> Self {
+        self.low_priority_compactions = low_priority;
+        self
+    }
+
+    /// The block cache and the background threads shared with other queues, the shared cache
+    /// replaces the one of [`Self::block_cache_size`].
+    ///
+    pub fn resources(mut self, resources: SharedResources) -> Self {
+        self.resources = Some(resources);
+        self
+    }
+
+    /// The compression applied to the pushed elements.
+    pub fn element_compression(mut self, compression: Compression) -> Self {
+        self.element_compression = compression;
+        self
+    }
+
+    /// Stores the CRC32C checksum with the pushed elements, from the first push to the empty
+    /// queue, see `set_checksums` of the queues.
+    ///
+    pub fn checksums(mut self, checksums: bool) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
+    /// Syncs every write to disk before it is acknowledged.
+    pub fn sync_writes(mut self, sync_writes: bool) -> Self {
+        self.sync_writes = sync_writes;
+        self
+    }
+
+    pub fn push_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.push_rate_limit = Some(limit);
+        self
+    }
+
+    pub fn pop_rate_limit(mut self, limit: RateLimit) -> Self {
+        self.pop_rate_limit = Some(limit);
+        self
+    }
+
+    /// Opens the queue with the RocksDB paranoid checks and verifies it, a queue failing the
+    /// verification is repaired if `repair` is set, see `open_verified` of the queues.
+    ///
+    pub fn verify_on_open(mut self, repair: bool) -> Self {
+        self.verify_on_open = true;
+        self.repair_on_open = repair;
+        self
+    }
+
+    /// The number of the indices the elements of a new queue are stored under, see
+    /// `with_index_space` of the queues. Opening an existing queue with a different one fails.
+    ///
+    pub fn index_space(mut self, index_space: u64) -> Self {
+        self.index_space = Some(index_space);
+        self
+    }
+
+    /// Never returns the elements of the MPMC queue which have outlived the TTL but are not
+    /// removed by the compactions yet, see `set_strict_ttl` of the MPMC queue.
+    ///
+    pub fn strict_ttl(mut self, strict_ttl: bool) -> Self {
+        self.strict_ttl = strict_ttl;
+        self
+    }
+
+    /// Prefetches the entries after the read ones into the block cache, see `set_prefetch` of
+    /// the queues.
+    ///
+    pub fn prefetch(mut self, entries: usize) -> Self {
+        self.prefetch = entries;
+        self
+    }
+
+    /// Sets when the positions of the readers of the MPMC queue are written, see
+    /// `set_reader_persistence` of the MPMC queue.
+    ///
+    pub fn reader_persistence(mut self, persistence: ReaderPersistence) -> Self {
+        self.reader_persistence = persistence;
+        self
+    }
+
+    /// Sets the start positions of the new readers of the MPMC queue by their labels, see
+    /// `set_start_policy` of the MPMC queue.
+    ///
+    pub fn start_policy(mut self, policy: StartPolicy) -> Self {
+        self.start_policy = Some(policy);
+        self
+    }
+
+    /// Notifies the listeners of the queue when its disk usage reaches the thresholds, see
+    /// `set_disk_alerts` of the queues.
+    ///
+    pub fn disk_alerts(mut self, alerts: DiskAlerts) -> Self {
+        self.disk_alerts = Some(alerts);
+        self
+    }
+
+    /// Protects the elements of the MPMC queue not read by the active readers from the
+    /// expiration, see `set_retention_guard` of the MPMC queue.
+    ///
+    pub fn retention_guard(mut self, guard: RetentionGuard) -> Self {
+        self.retention_guard = Some(guard);
+        self
+    }
+
+    /// Evicts the elements the filter returns `true` for when RocksDB compacts them, e.g. to
+    /// apply the retention rules per message class on top of the TTL. The eviction happens at
+    /// an unknown time, so the filter must return the same result for an element every time.
+    /// The evicted elements are skipped by the consumers and count towards the length of the
+    /// persistent queue and its payload size until the read index passes them. The encrypted
+    /// elements are never evicted; the chunked elements, the payloads of the paired ones and
+    /// the elements of the queues created by older versions must not be filtered.
+    ///
+    pub fn eviction_filter<F>(mut self, filter: F) -> Self
+    where
+        F: Fn(&[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.eviction_filter = Some(std::sync::Arc::new(filter));
+        self
+    }
+
+    /// Returns the RocksDB options built from the typed options.
+    pub fn build_db_options(&self) -> Result<Options> {
+        let mut opts = self.db_options.clone().unwrap_or_default();
+        if let Some(size) = self.write_buffer_size {
+            opts.set_write_buffer_size(size);
+        }
+        if let Some(number) = self.max_write_buffers {
+            opts.set_max_write_buffer_number(number);
+        }
+        if let Some(compression) = self.compression {
+            opts.set_compression_type(compression);
+        }
+        if let Some(size) = self.block_cache_size {
+            let mut table_opts = BlockBasedOptions::default();
+            table_opts.set_block_cache(&Cache::new_lru_cache(size));
+            opts.set_block_based_table_factory(&table_opts);
+        }
+        if self.enable_blob_files {
+            opts.set_enable_blob_files(true);
+        }
+        if let Some(size) = self.min_blob_size {
+            opts.set_min_blob_size(size);
+        }
+        if let Some(size) = self.blob_file_size {
+            opts.set_blob_file_size(size);
+        }
+        if self.enable_statistics {
+            opts.enable_statistics();
+        }
+        if let Some(rate) = self.background_write_bytes_per_sec {
+            if rate <= 0 {
+                return Err(QueueError::InvalidArgument(format!(
+                    "The background write rate must be positive, got {}",
+                    rate
+                )));
+            }
+            if self.auto_tune_background_writes {
+                opts.set_auto_tuned_ratelimiter(
+                    rate,
+                    RATE_LIMITER_REFILL_MICROS,
+                    RATE_LIMITER_FAIRNESS,
+                );
+            } else {
+                opts.set_ratelimiter(rate, RATE_LIMITER_REFILL_MICROS, RATE_LIMITER_FAIRNESS);
+            }
+        }
+        if self.low_priority_compactions {
+            let mut env = Env::new()?;
+            env.lower_thread_pool_io_priority();
+            env.lower_thread_pool_cpu_priority();
+            opts.set_env(&env);
+        }
+        if let Some(resources) = &self.resources {
+            resources.apply(&mut opts);
+        }
+        if let Some(filter) = &self.eviction_filter {
+            compaction::set_eviction_filter(&mut opts, filter.clone());
+        }
+        Ok(opts)
+    }
+
+    /// Overrides the options with the `ROCKSQ_*` environment variables, e.g. to tune a queue per
+    /// deployment without changing its code or configuration file:
+    ///
+    /// - `ROCKSQ_WRITE_BUFFER_SIZE`, `ROCKSQ_MAX_WRITE_BUFFERS`, `ROCKSQ_BLOCK_CACHE_SIZE`,
+    /// - `ROCKSQ_COMPRESSION` (see [`db_compression`]),
+    /// - `ROCKSQ_SYNC_WRITES`, `ROCKSQ_CHECKSUMS`, `ROCKSQ_ENABLE_STATISTICS`,
+    /// - `ROCKSQ_BACKGROUND_WRITE_BYTES_PER_SEC`, `ROCKSQ_LOW_PRIORITY_COMPACTIONS`,
+    /// - `ROCKSQ_VERIFY_ON_OPEN`, `ROCKSQ_REPAIR_ON_OPEN`.
+    ///
+    /// The variables which are not set keep the options, the invalid values fail with
+    /// [`QueueError::Config`].
+    ///
+    pub fn with_env_overrides(self) -> Result<Self> {
+        self.with_overrides(|name| std::env::var(format!("{}{}", ENV_PREFIX, name)).ok())
+    }
+
+    /// Overrides the options with the values returned by `lookup` for the names of the
+    /// environment variables without the prefix, see [`Self::with_env_overrides`].
+    ///
+    pub fn with_overrides<F>(mut self, lookup: F) -> Result<Self>
+    where
+        F: Fn(&str) -> Option<String>,
+    {
+        if let Some(size) = override_value(&lookup, "WRITE_BUFFER_SIZE")? {
+            self.write_buffer_size = Some(size);
+        }
+        if let Some(number) = override_value(&lookup, "MAX_WRITE_BUFFERS")? {
+            self.max_write_buffers = Some(number);
+        }
+        if let Some(size) = override_value(&lookup, "BLOCK_CACHE_SIZE")? {
+            self.block_cache_size = Some(size);
+        }
+        if let Some(name) = lookup("COMPRESSION") {
+            self.compression = Some(db_compression(name.trim())?);
+        }
+        if let Some(rate) = override_value(&lookup, "BACKGROUND_WRITE_BYTES_PER_SEC")? {
+            self.background_write_bytes_per_sec = Some(rate);
+        }
+        for (name, flag) in [
+            ("SYNC_WRITES", &mut self.sync_writes),
+            ("CHECKSUMS", &mut self.checksums),
+            ("ENABLE_STATISTICS", &mut self.enable_statistics),
+            (
+                "LOW_PRIORITY_COMPACTIONS",
+                &mut self.low_priority_compactions,
+            ),
+            ("VERIFY_ON_OPEN", &mut self.verify_on_open),
+            ("REPAIR_ON_OPEN", &mut self.repair_on_open),
+        ] {
+            if let Some(value) = override_flag(&lookup, name)? {
+                *flag = value;
+            }
+        }
+        Ok(self)
+    }
+}
+
+/// How a queue is removed, see [`crate::PersistentQueueWithCapacity::remove_db_with`].
+#[derive(Clone, Default)]
+pub struct RemoveOptions {
+    pub(crate) db_options: Option<Options>,
+    pub(crate) force: bool,
+    pub(crate) secure_wipe: bool,
+}
+
+impl RemoveOptions {
+    /// The RocksDB options the queue was created with, e.g. with the custom paths of the WAL or
+    /// of the data files, so all of them are removed.
+    ///
+    pub fn db_options(mut self, db_options: Options) -> Self {
+        self.db_options = Some(db_options);
+        self
+    }
+
+    /// Removes the queue even if it is open, e.g. by another process, which loses its data.
+    pub fn force(mut self, force: bool) -> Self {
+        self.force = force;
+        self
+    }
+
+    /// Overwrites the data files (the SST, blob and WAL files) with zeros and syncs them before
+    /// they are unlinked, so the elements can't be recovered from the freed blocks. The copies
+    /// made by the file system or the storage (e.g. by copy-on-write or wear leveling) are not
+    /// wiped.
+    ///
+    pub fn secure_wipe(mut self, secure_wipe: bool) -> Self {
+        self.secure_wipe = secure_wipe;
+        self
+    }
+}
+
+impl From<Options> for QueueOptions {
+    fn from(db_options: Options) -> Self {
+        Self::default().db_options(db_options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QueueOptions;
+    use crate::codec::Compression;
+    use crate::error::QueueError;
+    use crate::limiter::{RateLimit, RateLimitStrategy};
+    use crate::mpmc::{MpmcQueue, StartPosition};
+    use crate::PersistentQueueWithCapacity;
+    use rocksdb::DBCompressionType;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    #[test]
+    fn open_with_options() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let limit = RateLimit {
+            elements_per_sec: Some(1000.0),
+            bytes_per_sec: None,
+            strategy: RateLimitStrategy::FailFast,
+        };
+        let options = QueueOptions::default()
+            .write_buffer_size(1024 * 1024)
+            .block_cache_size(1024 * 1024)
+            .element_compression(Compression::Lz4)
+            .checksums(true)
+            .sync_writes(true)
+            .push_rate_limit(limit);
+
+        let path = dir.path().join("queue");
+        let queue = PersistentQueueWithCapacity::open(path.to_str().unwrap(), 5, &options).unwrap();
+        assert_eq!(queue.compression(), Compression::Lz4);
+        assert!(queue.checksums());
+        assert!(queue.sync_writes());
+        assert_eq!(queue.push_rate_limit(), Some(limit));
+        queue.push(&[&[1u8]]).unwrap();
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![1u8]]);
+
+        let path = dir.path().join("mpmc");
+        let mut mpmc =
+            MpmcQueue::open(path.to_str().unwrap(), Duration::from_secs(60), &options).unwrap();
+        assert!(mpmc.sync_writes());
+        mpmc.add(&[&[2u8]]).unwrap();
+        assert_eq!(
+            mpmc.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![2u8]], false)
+        );
+    }
+
+    #[test]
+    fn invalid_background_write_rate() {
+        let options = QueueOptions::default().background_write_rate(0, false);
+        assert!(matches!(
+            options.build_db_options(),
+            Err(QueueError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn overrides() {
+        let vars = HashMap::from([
+            ("WRITE_BUFFER_SIZE", "1048576"),
+            ("COMPRESSION", "lz4"),
+            ("SYNC_WRITES", "1"),
+            ("VERIFY_ON_OPEN", "true"),
+        ]);
+        let lookup = |name: &str| vars.get(name).map(|v| v.to_string());
+        let options = QueueOptions::default()
+            .checksums(true)
+            .with_overrides(lookup)
+            .unwrap();
+        assert_eq!(options.write_buffer_size, Some(1024 * 1024));
+        assert_eq!(options.compression, Some(DBCompressionType::Lz4));
+        assert!(options.sync_writes);
+        assert!(options.verify_on_open);
+        assert!(!options.repair_on_open);
+        assert!(options.checksums);
+
+        let invalid = |name: &str| (name == "SYNC_WRITES").then(|| "yes".to_string());
+        assert!(matches!(
+            QueueOptions::default().with_overrides(invalid),
+            Err(QueueError::Config(_))
+        ));
+    }
+}
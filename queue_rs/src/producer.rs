@@ -0,0 +1,170 @@
+use crate::error::{QueueError, Result};
+use crossbeam_channel::{Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Limits for accumulating the pushes of [`Producer`] handles into a single queue push, i.e. a
+/// single RocksDB write.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GroupCommit {
+    /// The time the first push of a group waits for the following ones.
+    pub max_delay: Duration,
+    /// The total size of the values after which the group is written without waiting.
+    pub max_bytes: usize,
+}
+
+impl Default for GroupCommit {
+    fn default() -> Self {
+        Self {
+            max_delay: Duration::from_millis(1),
+            max_bytes: 1024 * 1024,
+        }
+    }
+}
+
+struct PushRequest {
+    values: Vec<Vec<u8>>,
+    reply: Sender<Result<()>>,
+}
+
+struct Committer {
+    // taken on drop to stop the committing thread
+    tx: Option<Sender<PushRequest>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Drop for Committer {
+    fn drop(&mut self) {
+        drop(self.tx.take());
+        if let Some(thread) = self.thread.take() {
+            _ = thread.join();
+        }
+    }
+}
+
+/// A cloneable handle pushing to [`crate::PersistentQueueWithCapacity`] from many threads. The
+/// concurrent pushes are accumulated by the committing thread according to [`GroupCommit`] and
+/// written together, so the producers don't contend for the queue. Each push returns once its
+/// values are written. The committing thread stops when the last handle is dropped.
+///
+#[derive(Clone)]
+pub struct Producer(Arc<Committer>);
+
+impl Producer {
+    pub(crate) fn new(
+        queue: Arc<crate::PersistentQueueWithCapacity>,
+        group_commit: GroupCommit,
+    ) -> Self {
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let thread = thread::spawn(move || commit_loop(&queue, &rx, group_commit));
+        Self(Arc::new(Committer {
+            tx: Some(tx),
+            thread: Some(thread),
+        }))
+    }
+
+    pub fn push(&self, values: &[&[u8]]) -> Result<()> {
+        let (reply, res) = crossbeam_channel::bounded(1);
+        let request = PushRequest {
+            values: values.iter().map(|v| v.to_vec()).collect(),
+            reply,
+        };
+        // the sender is taken only when the last handle is dropped
+        let tx = self.0.tx.as_ref().unwrap();
+        tx.send(request).map_err(|_| QueueError::Closed)?;
+        res.recv().map_err(|_| QueueError::Closed)?
+    }
+}
+
+fn commit_loop(
+    queue: &crate::PersistentQueueWithCapacity,
+    rx: &Receiver<PushRequest>,
+    group_commit: GroupCommit,
+) {
+    while let Ok(first) = rx.recv() {
+        let deadline = Instant::now() + group_commit.max_delay;
+        let mut size = first.values.iter().map(|v| v.len()).sum::<usize>();
+        let mut requests = vec![first];
+        while size < group_commit.max_bytes {
+            let Ok(request) = rx.recv_deadline(deadline) else {
+                break;
+            };
+            size += request.values.iter().map(|v| v.len()).sum::<usize>();
+            requests.push(request);
+        }
+        commit(queue, requests);
+    }
+}
+
+/// Writes the requests together, if it fails (e.g. the queue is full) each request is written
+/// separately to get its own result.
+///
+fn commit(queue: &crate::PersistentQueueWithCapacity, requests: Vec<PushRequest>) {
+    if requests.len() > 1 {
+        let values = requests
+            .iter()
+            .flat_map(|r| r.values.iter().map(|v| v.as_slice()))
+            .collect::<Vec<_>>();
+        if queue.push(&values).is_ok() {
+            for request in requests {
+                _ = request.reply.send(Ok(()));
+            }
+            return;
+        }
+    }
+    for request in requests {
+        let values = request
+            .values
+            .iter()
+            .map(|v| v.as_slice())
+            .collect::<Vec<_>>();
+        _ = request.reply.send(queue.push(&values));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GroupCommit;
+    use crate::blocking::PersistentQueueWithCapacity;
+    use crate::error::QueueError;
+    use rocksdb::Options;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn concurrent_producers() {
+        let path = "/tmp/test_producer".to_string();
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let queue = PersistentQueueWithCapacity::new(&path, 6, Options::default()).unwrap();
+            let producer = queue.producer(GroupCommit {
+                max_delay: Duration::from_millis(50),
+                max_bytes: 1024,
+            });
+            let threads = (0..3u8)
+                .map(|i| {
+                    let producer = producer.clone();
+                    thread::spawn(move || producer.push(&[&[i], &[i]]))
+                })
+                .collect::<Vec<_>>();
+            for thread in threads {
+                thread.join().unwrap().unwrap();
+            }
+            assert!(matches!(producer.push(&[&[3]]), Err(QueueError::Full)));
+
+            let mut values = queue.pop(6).unwrap();
+            values.sort();
+            assert_eq!(
+                values,
+                vec![vec![0], vec![0], vec![1], vec![1], vec![2], vec![2]]
+            );
+
+            // the committing thread holds the queue until the last handle is dropped
+            drop(producer);
+            queue.close().unwrap();
+        }
+        _ = PersistentQueueWithCapacity::remove_db(&path);
+    }
+}
@@ -0,0 +1,261 @@
+use crate::error::{QueueError, Result};
+use crate::mpmc::StartPosition;
+use crate::nonblocking::{MpmcResponseVariant, ResponseVariant};
+use crate::{blocking, mpmc, nonblocking};
+
+/// The producing side of a queue. Implemented by all queue types, so the code adding elements
+/// can be written once regardless of the backend.
+///
+pub trait QueueProducer {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()>;
+}
+
+/// The consuming side of a queue. Implemented by the persistent queues directly and by
+/// [`LabeledConsumer`] for MPMC queues.
+///
+pub trait QueueConsumer {
+    /// Retrieves up to `max_elts` elements.
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>>;
+}
+
+/// A queue with multiple consumers identified by labels.
+pub trait LabeledQueue {
+    /// Retrieves up to `max_elts` elements for the consumer with the label. Returns the
+    /// elements and whether some elements have expired before being read.
+    ///
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)>;
+}
+
+/// The consumer of a [`LabeledQueue`] with the fixed label. Elements expired before being read
+/// are silently skipped.
+///
+pub struct LabeledConsumer<Q> {
+    queue: Q,
+    label: String,
+    start_position: StartPosition,
+}
+
+impl<Q> LabeledConsumer<Q> {
+    pub fn new(queue: Q, label: &str, start_position: StartPosition) -> Self {
+        Self {
+            queue,
+            label: label.to_string(),
+            start_position,
+        }
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn into_inner(self) -> Q {
+        self.queue
+    }
+}
+
+impl<Q: LabeledQueue> QueueConsumer for LabeledConsumer<Q> {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        Ok(self
+            .queue
+            .next(max_elts, &self.label, self.start_position)?
+            .0)
+    }
+}
+
+impl<Q: QueueProducer> QueueProducer for LabeledConsumer<Q> {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        self.queue.push(values)
+    }
+}
+
+impl<Q: QueueProducer + ?Sized> QueueProducer for &mut Q {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        (**self).push(values)
+    }
+}
+
+impl<Q: QueueConsumer + ?Sized> QueueConsumer for &mut Q {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        (**self).pop(max_elts)
+    }
+}
+
+impl<Q: LabeledQueue + ?Sized> LabeledQueue for &mut Q {
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        (**self).next(max_elts, label, start_position)
+    }
+}
+
+impl QueueProducer for crate::PersistentQueueWithCapacity {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        crate::PersistentQueueWithCapacity::push(self, values)
+    }
+}
+
+impl QueueConsumer for crate::PersistentQueueWithCapacity {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        crate::PersistentQueueWithCapacity::pop(self, max_elts)
+    }
+}
+
+impl QueueProducer for blocking::PersistentQueueWithCapacity {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        blocking::PersistentQueueWithCapacity::push(self, values)
+    }
+}
+
+impl QueueConsumer for blocking::PersistentQueueWithCapacity {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        blocking::PersistentQueueWithCapacity::pop(self, max_elts)
+    }
+}
+
+impl QueueProducer for nonblocking::PersistentQueueWithCapacity {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        match nonblocking::PersistentQueueWithCapacity::push(self, values)?.get()? {
+            ResponseVariant::Push(res) => res.map(|_| ()),
+            _ => Err(QueueError::Internal("Unexpected response".to_string())),
+        }
+    }
+}
+
+impl QueueConsumer for nonblocking::PersistentQueueWithCapacity {
+    fn pop(&mut self, max_elts: usize) -> Result<Vec<Vec<u8>>> {
+        match nonblocking::PersistentQueueWithCapacity::pop(self, max_elts)?.get()? {
+            ResponseVariant::Pop(res) => res,
+            _ => Err(QueueError::Internal("Unexpected response".to_string())),
+        }
+    }
+}
+
+impl QueueProducer for mpmc::MpmcQueue {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        self.add(values)
+    }
+}
+
+impl LabeledQueue for mpmc::MpmcQueue {
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        mpmc::MpmcQueue::next(self, max_elts, label, start_position)
+    }
+}
+
+impl QueueProducer for blocking::MpmcQueue {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        self.add(values)
+    }
+}
+
+impl LabeledQueue for blocking::MpmcQueue {
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        blocking::MpmcQueue::next(self, max_elts, label, start_position)
+    }
+}
+
+impl QueueProducer for nonblocking::MpmcQueue {
+    fn push(&mut self, values: &[&[u8]]) -> Result<()> {
+        match self.add(values)?.get()? {
+            MpmcResponseVariant::Add(res) => res.map(|_| ()),
+            _ => Err(QueueError::Internal("Unexpected response".to_string())),
+        }
+    }
+}
+
+impl LabeledQueue for nonblocking::MpmcQueue {
+    fn next(
+        &mut self,
+        max_elts: usize,
+        label: &str,
+        start_position: StartPosition,
+    ) -> Result<(Vec<Vec<u8>>, bool)> {
+        match nonblocking::MpmcQueue::next(self, max_elts, label, start_position)?.get()? {
+            MpmcResponseVariant::Next(res) => res,
+            _ => Err(QueueError::Internal("Unexpected response".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rocksdb::Options;
+    use std::time::Duration;
+
+    fn produce_consume<Q: QueueProducer + QueueConsumer>(queue: &mut Q) {
+        queue.push(&[&[1u8], &[2u8]]).unwrap();
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![1u8]]);
+        assert_eq!(queue.pop(2).unwrap(), vec![vec![2u8]]);
+        assert!(queue.pop(1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_persistent_queue() {
+        let path = "/tmp/test_queue_traits_persistent".to_string();
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+        {
+            let mut queue =
+                crate::PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+            produce_consume(&mut queue);
+        }
+        {
+            let mut queue =
+                blocking::PersistentQueueWithCapacity::new(&path, 3, Options::default()).unwrap();
+            produce_consume(&mut queue);
+        }
+        {
+            let mut queue =
+                nonblocking::PersistentQueueWithCapacity::new(&path, 3, 10, Options::default())
+                    .unwrap();
+            produce_consume(&mut queue);
+        }
+        _ = crate::PersistentQueueWithCapacity::remove_db(&path);
+    }
+
+    #[test]
+    fn test_mpmc_queue() {
+        let path = "/tmp/test_queue_traits_mpmc".to_string();
+        let ttl = Duration::from_secs(10);
+        _ = mpmc::MpmcQueue::remove_db(&path);
+        {
+            let mut queue = mpmc::MpmcQueue::new(&path, ttl).unwrap();
+            let mut consumer = LabeledConsumer::new(&mut queue, "a", StartPosition::Oldest);
+            produce_consume(&mut consumer);
+            assert_eq!(consumer.label(), "a");
+        }
+        {
+            let queue = blocking::MpmcQueue::new(&path, ttl).unwrap();
+            let mut consumer = LabeledConsumer::new(queue.clone(), "b", StartPosition::Newest);
+            consumer.push(&[&[3u8]]).unwrap();
+            assert_eq!(consumer.pop(1).unwrap(), vec![vec![3u8]]);
+        }
+        {
+            let queue = nonblocking::MpmcQueue::new(&path, ttl, 10).unwrap();
+            let mut consumer = LabeledConsumer::new(queue, "c", StartPosition::Oldest);
+            assert_eq!(
+                consumer.pop(10).unwrap(),
+                vec![vec![1u8], vec![2u8], vec![3u8]]
+            );
+        }
+        _ = mpmc::MpmcQueue::remove_db(&path);
+    }
+}
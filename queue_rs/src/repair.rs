@@ -0,0 +1,109 @@
+use crate::error::Result;
+use crate::utilities::{index_to_key, key_to_index, next_index, u64_from_byte_vec};
+use crate::U64_BYTE_LEN;
+use rocksdb::{ColumnFamily, IteratorMode, Options, DB};
+use std::path::Path;
+
+/// Opens the database, running the RocksDB repair (e.g. for a damaged manifest) if the first
+/// attempt fails.
+///
+pub fn open_or_repair<F>(db_opts: &Options, path: &Path, open: F) -> Result<DB>
+where
+    F: Fn() -> Result<DB, rocksdb::Error>,
+{
+    match open() {
+        Ok(db) => Ok(db),
+        Err(e) => {
+            log::warn!(
+                "Failed to open the queue at {}, repairing it: {}",
+                path.display(),
+                e
+            );
+            DB::repair(db_opts, path)?;
+            Ok(open()?)
+        }
+    }
+}
+
+/// Reads the stored index or counter, malformed values are treated as missing.
+pub fn read_cell(db: &DB, cf: Option<&ColumnFamily>, cell: u64) -> Result<Option<u64>> {
+    let value = match cf {
+        Some(cf) => db.get_cf(cf, index_to_key(cell))?,
+        None => db.get(index_to_key(cell))?,
+    };
+    Ok(value
+        .filter(|v| v.len() == U64_BYTE_LEN)
+        .map(|v| u64_from_byte_vec(&v)))
+}
+
+/// The elements actually stored in the database.
+pub struct StoredIndices {
+    /// Element indices in ascending order.
+    pub indices: Vec<u64>,
+    pub chunked: bool,
+}
+
+/// Collects the indices of the elements, the keys outside of the index space (e.g. the system
+/// cells) are skipped.
+///
+pub fn scan(db: &DB, cf: Option<&ColumnFamily>, index_space: u64) -> Result<StoredIndices> {
+    let iterator = match cf {
+        Some(cf) => db.iterator_cf(cf, IteratorMode::Start),
+        None => db.iterator(IteratorMode::Start),
+    };
+    let mut indices = Vec::new();
+    let mut chunked = false;
+    for item in iterator {
+        let (key, _) = item?;
+        if key.len() != U64_BYTE_LEN {
+            chunked = true;
+            continue;
+        }
+        let index = key_to_index(&key);
+        if index < index_space {
+            indices.push(index);
+        }
+    }
+    // keys are little endian, so the iteration order is not the numeric one
+    indices.sort_unstable();
+    Ok(StoredIndices { indices, chunked })
+}
+
+/// Reconstructs the range `[start, end)` occupied by the elements. The elements are expected
+/// to occupy a contiguous range of the circular index space, so the range begins after the
+/// largest gap between the elements. `start` is used if all indices are occupied and the range
+/// can begin at any of them. Returns `None` if there are no elements.
+///
+pub fn index_range(indices: &[u64], start: u64, index_space: u64) -> Option<(u64, u64)> {
+    let (first, last) = (*indices.first()?, *indices.last()?);
+    if indices.len() as u64 == index_space {
+        let start = if start < index_space { start } else { 0 };
+        return Some((start, start));
+    }
+
+    // the gap wrapping around the end of the index space
+    let mut range = (first, next_index(last, index_space));
+    let mut largest_gap = index_space - last - 1 + first;
+    for pair in indices.windows(2) {
+        let gap = pair[1] - pair[0] - 1;
+        if gap > largest_gap {
+            largest_gap = gap;
+            range = (pair[1], next_index(pair[0], index_space));
+        }
+    }
+    Some(range)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_index_range() {
+        assert_eq!(index_range(&[], 3, 6), None);
+        assert_eq!(index_range(&[1, 2, 3], 0, 6), Some((1, 4)));
+        assert_eq!(index_range(&[3, 4, 5], 0, 6), Some((3, 0)));
+        assert_eq!(index_range(&[0, 4, 5], 0, 6), Some((4, 1)));
+        assert_eq!(index_range(&[0, 1, 2, 3, 4, 5], 2, 6), Some((2, 2)));
+    }
+}
@@ -0,0 +1,90 @@
+use crate::error::Result;
+use rocksdb::{BlockBasedOptions, Cache, Env, Options};
+
+/// The RocksDB resources shared by the queues opened with them: the block cache and the pool of
+/// the background threads (flushes and compactions). The queues opened with their own options
+/// allocate a block cache and background threads each, which adds up when many queues are
+/// hosted by one process.
+///
+#[derive(Clone)]
+pub struct SharedResources {
+    cache: Option<Cache>,
+    env: Env,
+    max_open_files: Option<i32>,
+}
+
+impl SharedResources {
+    /// Creates the resources: the block cache of `cache_size` bytes (`None` keeps the cache of
+    /// each queue), `background_threads` threads shared by the flushes and the compactions of
+    /// all the queues and the limit of the files each queue keeps open.
+    ///
+    pub fn new(
+        cache_size: Option<usize>,
+        background_threads: Option<i32>,
+        max_open_files: Option<i32>,
+    ) -> Result<Self> {
+        let mut env = Env::new()?;
+        if let Some(threads) = background_threads {
+            env.set_background_threads(threads);
+            env.set_high_priority_background_threads(threads);
+        }
+        Ok(Self {
+            cache: cache_size.map(Cache::new_lru_cache),
+            env,
+            max_open_files,
+        })
+    }
+
+    /// Sets the resources on the options of a queue, the block cache replaces the one set on the
+    /// options.
+    ///
+    pub fn apply(&self, opts: &mut Options) {
+        opts.set_env(&self.env);
+        if let Some(cache) = &self.cache {
+            let mut table_opts = BlockBasedOptions::default();
+            table_opts.set_block_cache(cache);
+            opts.set_block_based_table_factory(&table_opts);
+        }
+        if let Some(files) = self.max_open_files {
+            opts.set_max_open_files(files);
+        }
+    }
+
+    /// Returns the memory used by the shared block cache in bytes.
+    pub fn cache_usage(&self) -> usize {
+        self.cache.as_ref().map_or(0, Cache::get_usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SharedResources;
+    use crate::mpmc::{MpmcQueue, StartPosition};
+    use crate::PersistentQueueWithCapacity;
+    use rocksdb::Options;
+    use std::time::Duration;
+
+    #[test]
+    fn shared_by_queues() {
+        let resources = SharedResources::new(Some(1024 * 1024), Some(2), Some(64)).unwrap();
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("queue");
+        let mut opts = Options::default();
+        resources.apply(&mut opts);
+        let queue = PersistentQueueWithCapacity::new(path.to_str().unwrap(), 5, opts).unwrap();
+
+        let path = dir.path().join("mpmc");
+        let mut opts = Options::default();
+        resources.apply(&mut opts);
+        let mut mpmc =
+            MpmcQueue::with_options(path.to_str().unwrap(), Duration::from_secs(60), opts).unwrap();
+
+        queue.push(&[&[1u8]]).unwrap();
+        mpmc.add(&[&[2u8]]).unwrap();
+        assert_eq!(queue.pop(1).unwrap(), vec![vec![1u8]]);
+        assert_eq!(
+            mpmc.next(1, "label", StartPosition::Oldest).unwrap(),
+            (vec![vec![2u8]], false)
+        );
+    }
+}
@@ -0,0 +1,301 @@
+use crate::alerts::DiskThreshold;
+use crate::error::Result;
+use bincode::{Decode, Encode};
+use rocksdb::{ColumnFamily, DB};
+use std::collections::HashMap;
+
+/// The state of [`crate::PersistentQueueWithCapacity`], see
+/// [`crate::PersistentQueueWithCapacity::stats`].
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    pub len: usize,
+    pub payload_size: u64,
+    pub disk_size: usize,
+    /// The number of the elements pushed since the queue was opened.
+    pub pushed: u64,
+    /// The number of the elements popped since the queue was opened.
+    pub popped: u64,
+    pub sizes: SizeStats,
+    /// The disk usage thresholds the queue has reached, see [`crate::alerts::DiskAlerts`].
+    pub disk_alerts: Vec<DiskThreshold>,
+}
+
+/// The state of [`crate::mpmc::MpmcQueue`], see [`crate::mpmc::MpmcQueue::stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MpmcQueueStats {
+    pub len: usize,
+    pub disk_size: usize,
+    /// The time of the last add in nanoseconds since the epoch, `None` if the queue is empty.
+    pub newest_timestamp: Option<u64>,
+    /// The number of the elements added since the queue was opened.
+    pub added: u64,
+    /// The number of the elements expired since the queue was opened.
+    pub expired: u64,
+    /// The indices of the next elements of the readers by their labels.
+    pub labels: HashMap<String, u64>,
+    pub sizes: SizeStats,
+    /// The disk usage thresholds the queue has reached, see [`crate::alerts::DiskAlerts`].
+    pub disk_alerts: Vec<DiskThreshold>,
+}
+
+/// The position of a reader in [`StatsSnapshot`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReaderPosition {
+    /// The index of the next element of the reader.
+    pub index: u64,
+    /// The number of the elements the reader hasn't read.
+    pub lag: usize,
+}
+
+/// The state of a queue read against a single RocksDB snapshot, so the numbers are consistent
+/// with each other, e.g. the length and the lags of the readers, see
+/// [`crate::PersistentQueueWithCapacity::stats_snapshot`] and
+/// [`crate::mpmc::MpmcQueue::stats_snapshot`].
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StatsSnapshot {
+    pub len: usize,
+    /// The payload size of the persistent queue, `None` for the MPMC queue not tracking it.
+    pub payload_size: Option<u64>,
+    /// The index of the oldest element, the read index of the persistent queue.
+    pub start_index: u64,
+    pub write_index: u64,
+    /// The positions of the readers of the MPMC queue by their labels.
+    pub labels: HashMap<String, ReaderPosition>,
+}
+
+/// The number of the buckets of [`SizeStats::histogram`].
+pub const SIZE_BUCKETS: usize = 32;
+
+/// The distribution of the payload sizes of the elements pushed to a queue since it was
+/// created, it is stored in the system column family and updated by every push. The bucket `i`
+/// of the histogram counts the sizes of `i` significant bits, i.e. from `2^(i-1)` to `2^i - 1`,
+/// the last one counts the larger sizes as well.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq, Encode, Decode)]
+pub struct SizeStats {
+    pub count: u64,
+    pub min: u64,
+    pub max: u64,
+    pub total: u64,
+    pub histogram: [u64; SIZE_BUCKETS],
+}
+
+impl SizeStats {
+    pub(crate) fn add(&mut self, size: u64) {
+        if self.count == 0 || size < self.min {
+            self.min = size;
+        }
+        self.max = self.max.max(size);
+        self.count += 1;
+        self.total = self.total.saturating_add(size);
+        let bucket = (u64::BITS - size.leading_zeros()) as usize;
+        self.histogram[bucket.min(SIZE_BUCKETS - 1)] += 1;
+    }
+
+    /// Returns the average size, `None` if no elements were pushed.
+    pub fn avg(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total as f64 / self.count as f64)
+    }
+
+    pub(crate) fn to_bytes(&self) -> Result<Vec<u8>> {
+        Ok(bincode::encode_to_vec(self, bincode::config::standard())?)
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::decode_from_slice(bytes, bincode::config::standard())?.0)
+    }
+}
+
+/// The RocksDB internals of a queue, e.g. to find out why the writes stall. The tickers are
+/// collected only if the statistics are enabled with `Options::enable_statistics`, otherwise
+/// they are `None`. The compaction properties are of the column family of the elements.
+///
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DbStats {
+    pub compaction_pending: bool,
+    pub running_compactions: u64,
+    pub pending_compaction_bytes: u64,
+    pub write_stopped: bool,
+    /// The rate in bytes per second the writes are delayed to, `0` if they are not delayed.
+    pub delayed_write_rate: u64,
+    pub memtables_size: u64,
+    pub block_cache_usage: u64,
+    pub estimated_keys: u64,
+    /// The total time in microseconds the writes have been stalled.
+    pub stall_micros: Option<u64>,
+    pub block_cache_hits: Option<u64>,
+    pub block_cache_misses: Option<u64>,
+}
+
+impl DbStats {
+    /// Reads the properties of the database, `cf` is the column family of the elements.
+    pub(crate) fn read(db: &DB, cf: Option<&ColumnFamily>) -> Result<Self> {
+        let property = |name: &str| -> Result<u64> {
+            let value = match cf {
+                Some(cf) => db.property_int_value_cf(cf, name)?,
+                None => db.property_int_value(name)?,
+            };
+            Ok(value.unwrap_or(0))
+        };
+        let statistics = db.property_value("rocksdb.options-statistics")?;
+        let ticker = |name: &str| statistics.as_deref().and_then(|s| ticker(s, name));
+        Ok(Self {
+            compaction_pending: property("rocksdb.compaction-pending")? > 0,
+            running_compactions: property("rocksdb.num-running-compactions")?,
+            pending_compaction_bytes: property("rocksdb.estimate-pending-compaction-bytes")?,
+            write_stopped: property("rocksdb.is-write-stopped")? > 0,
+            delayed_write_rate: property("rocksdb.actual-delayed-write-rate")?,
+            memtables_size: property("rocksdb.cur-size-all-mem-tables")?,
+            block_cache_usage: property("rocksdb.block-cache-usage")?,
+            estimated_keys: property("rocksdb.estimate-num-keys")?,
+            stall_micros: ticker("rocksdb.stall.micros"),
+            block_cache_hits: ticker("rocksdb.block.cache.hit"),
+            block_cache_misses: ticker("rocksdb.block.cache.miss"),
+        })
+    }
+
+    /// Returns the share of the block cache lookups which are hits, `None` without the
+    /// statistics or lookups.
+    ///
+    pub fn block_cache_hit_rate(&self) -> Option<f64> {
+        let hits = self.block_cache_hits?;
+        let lookups = hits + self.block_cache_misses?;
+        (lookups > 0).then(|| hits as f64 / lookups as f64)
+    }
+}
+
+/// The number of the LSM levels of RocksDB.
+const NUM_LEVELS: usize = 7;
+/// The stored bytes of an element besides the payload: the key, the sequence number and the
+/// type of the RocksDB internal key and the element header.
+///
+const ELEMENT_OVERHEAD: u64 = 24;
+
+/// The observed storage cost of the elements, it predicts the disk usage of a queue.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct StorageModel {
+    /// The uncompressed bytes per stored byte of the bottommost level with files, `1` until the
+    /// elements are flushed.
+    ///
+    compression_ratio: f64,
+    /// The bytes of the SST files per byte of live data, `1` until the elements are flushed.
+    space_amplification: f64,
+}
+
+impl StorageModel {
+    /// Reads the properties of the database, `cf` is the column family of the elements.
+    pub(crate) fn read(db: &DB, cf: Option<&ColumnFamily>) -> Result<Self> {
+        let property = |name: &str| -> Result<Option<String>> {
+            Ok(match cf {
+                Some(cf) => db.property_value_cf(cf, name)?,
+                None => db.property_value(name)?,
+            })
+        };
+        let mut compression_ratio = 1.0;
+        for level in 0..NUM_LEVELS {
+            let ratio = property(&format!("rocksdb.compression-ratio-at-level{}", level))?
+                .and_then(|v| v.trim().parse::<f64>().ok());
+            // the levels without files have the ratio of -1
+            if let Some(ratio) = ratio.filter(|r| *r > 0.0) {
+                compression_ratio = ratio;
+            }
+        }
+        let int_property = |name: &str| -> Result<u64> {
+            Ok(property(name)?.and_then(|v| v.parse().ok()).unwrap_or(0))
+        };
+        let sst_size = int_property("rocksdb.total-sst-files-size")?;
+        let live_size = int_property("rocksdb.estimate-live-data-size")?;
+        let space_amplification = if sst_size > 0 && live_size > 0 {
+            (sst_size as f64 / live_size as f64).max(1.0)
+        } else {
+            1.0
+        };
+        Ok(Self {
+            compression_ratio,
+            space_amplification,
+        })
+    }
+
+    fn element_size(&self, avg_size: u64) -> f64 {
+        (avg_size + ELEMENT_OVERHEAD) as f64 / self.compression_ratio * self.space_amplification
+    }
+
+    /// Returns the disk bytes the elements take.
+    pub(crate) fn disk_for(&self, elements: u64, avg_size: u64) -> u64 {
+        (elements as f64 * self.element_size(avg_size)).ceil() as u64
+    }
+
+    /// Returns the number of the elements fitting in the disk bytes.
+    pub(crate) fn elements_for(&self, disk_size: u64, avg_size: u64) -> u64 {
+        (disk_size as f64 / self.element_size(avg_size)) as u64
+    }
+}
+
+/// Finds the ticker in the statistics dump, its lines look like `rocksdb.stall.micros COUNT : 5`.
+fn ticker(statistics: &str, name: &str) -> Option<u64> {
+    statistics.lines().find_map(|line| {
+        let (ticker, value) = line.split_once(" COUNT : ")?;
+        if ticker == name {
+            value.trim().parse().ok()
+        } else {
+            None
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ticker, DbStats, SizeStats, StorageModel, SIZE_BUCKETS};
+
+    #[test]
+    fn tickers() {
+        let statistics = "rocksdb.block.cache.hit COUNT : 30\n\
+                          rocksdb.block.cache.miss COUNT : 10\n\
+                          rocksdb.db.get.micros P50 : 1.0 P95 : 2.0 P99 : 3.0 COUNT : 7 SUM : 9\n";
+        assert_eq!(ticker(statistics, "rocksdb.block.cache.hit"), Some(30));
+        assert_eq!(ticker(statistics, "rocksdb.block.cache"), None);
+        assert_eq!(ticker(statistics, "rocksdb.stall.micros"), None);
+
+        let stats = DbStats {
+            block_cache_hits: ticker(statistics, "rocksdb.block.cache.hit"),
+            block_cache_misses: ticker(statistics, "rocksdb.block.cache.miss"),
+            ..Default::default()
+        };
+        assert_eq!(stats.block_cache_hit_rate(), Some(0.75));
+        assert_eq!(DbStats::default().block_cache_hit_rate(), None);
+    }
+
+    #[test]
+    fn sizes() {
+        let mut sizes = SizeStats::default();
+        assert_eq!(sizes.avg(), None);
+        for size in [0, 1, 5, 6, u64::MAX] {
+            sizes.add(size);
+        }
+        assert_eq!((sizes.count, sizes.min, sizes.max), (5, 0, u64::MAX));
+        assert_eq!(&sizes.histogram[..4], &[1, 1, 0, 2]);
+        assert_eq!(sizes.histogram[SIZE_BUCKETS - 1], 1);
+
+        let mut sizes = SizeStats::default();
+        sizes.add(2);
+        sizes.add(4);
+        assert_eq!(sizes.avg(), Some(3.0));
+        assert_eq!(
+            SizeStats::from_bytes(&sizes.to_bytes().unwrap()).unwrap(),
+            sizes
+        );
+    }
+
+    #[test]
+    fn storage_model() {
+        let model = StorageModel {
+            compression_ratio: 2.0,
+            space_amplification: 1.5,
+        };
+        assert_eq!(model.disk_for(1000, 76), 75000);
+        assert_eq!(model.elements_for(75000, 76), 1000);
+        assert_eq!(model.disk_for(0, 76), 0);
+    }
+}
@@ -0,0 +1,47 @@
+use std::time::Instant;
+
+/// The span of a queue operation, it is entered until dropped. Without the `tracing` feature it
+/// does nothing.
+///
+pub(crate) struct OpSpan(#[cfg(feature = "tracing")] tracing::span::EnteredSpan);
+
+impl OpSpan {
+    /// Enters the span of the operation, the elements and their size are recorded later.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn enter(op: &'static str) -> Self {
+        Self(
+            tracing::debug_span!(
+                "rocksq",
+                op,
+                elements = tracing::field::Empty,
+                bytes = tracing::field::Empty
+            )
+            .entered(),
+        )
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn enter(_op: &'static str) -> Self {
+        Self()
+    }
+
+    /// Records the number of the pushed or popped elements and their payload size.
+    #[cfg(feature = "tracing")]
+    pub(crate) fn record(&self, elements: usize, bytes: u64) {
+        self.0.record("elements", elements);
+        self.0.record("bytes", bytes);
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn record(&self, _elements: usize, _bytes: u64) {}
+}
+
+/// Reports the time the operation waited in the channel of the working threads.
+#[cfg(feature = "tracing")]
+pub(crate) fn dequeued(op: &'static str, queued: Instant) {
+    let wait_micros = queued.elapsed().as_micros() as u64;
+    tracing::debug!(op, wait_micros, "rocksq operation dequeued");
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn dequeued(_op: &'static str, _queued: Instant) {}
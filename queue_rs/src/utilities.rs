@@ -1,5 +1,42 @@
-use crate::{MAX_ALLOWED_INDEX, U64_BYTE_LEN};
+use crate::error::{QueueError, Result};
+use crate::U64_BYTE_LEN;
 use chrono::Utc;
+use rocksdb::{
+    ColumnFamily, ErrorKind, MergeOperands, Options, ReadOptions, SnapshotWithThreadMode, DB,
+};
+use std::path::Path;
+
+/// The name of the merge operator of the counters, see [`merge_counter`].
+pub const COUNTER_MERGE_OPERATOR: &str = "rocksq.counter";
+
+/// The values from this size are stored in the blob files of the payload column families.
+const PAYLOAD_MIN_BLOB_SIZE: u64 = 4096;
+
+/// The most indices read at once by [`first_stored`].
+const MAX_PROBE_BATCH: u64 = 1024;
+
+/// Returns the options of the column family of the payloads of the paired elements, the large
+/// payloads are stored in the blob files, so the compactions don't rewrite them.
+///
+pub fn payload_cf_options() -> Options {
+    let mut opts = Options::default();
+    opts.set_enable_blob_files(true);
+    opts.set_min_blob_size(PAYLOAD_MIN_BLOB_SIZE);
+    opts
+}
+
+/// Converts the error of opening or destroying the database at `path`, the lock errors mean that
+/// another handle still holds it.
+///
+pub fn db_error(path: &Path, e: rocksdb::Error) -> QueueError {
+    if e.kind() == ErrorKind::IOError && e.to_string().contains("LOCK") {
+        QueueError::InUse {
+            path: path.to_path_buf(),
+        }
+    } else {
+        e.into()
+    }
+}
 
 pub fn u64_from_byte_vec(v: &[u8]) -> u64 {
     let mut buf = [0u8; U64_BYTE_LEN];
@@ -11,28 +48,133 @@ pub fn index_to_key(index: u64) -> [u8; U64_BYTE_LEN] {
     index.to_le_bytes()
 }
 
-pub fn key_to_index(v: Box<[u8]>) -> u64 {
+pub fn key_to_index(v: &[u8]) -> u64 {
     let mut buf = [0u8; U64_BYTE_LEN];
-    buf.copy_from_slice(&v);
+    // chunk keys are prefixed with the element key
+    buf.copy_from_slice(&v[..U64_BYTE_LEN]);
     u64::from_le_bytes(buf)
 }
 
-pub fn next_index(index: u64) -> u64 {
+/// Returns the index following `index` in the circular index space of `index_space` indices.
+pub fn next_index(index: u64, index_space: u64) -> u64 {
     let mut next = index + 1;
-    if next == MAX_ALLOWED_INDEX {
+    if next == index_space {
         next = 0;
     }
     next
 }
 
-pub fn previous_index(index: u64) -> u64 {
+/// Returns the index `n` indices after `index` in the circular index space, `n` is less than
+/// `index_space`.
+///
+pub fn advance_index(index: u64, n: u64, index_space: u64) -> u64 {
+    let left = index_space - index;
+    if n < left {
+        index + n
+    } else {
+        n - left
+    }
+}
+
+/// Reads the `count` elements from the index, so their blocks are loaded into the block cache
+/// before they are read. The keys follow the circular index space, not the byte order, of the
+/// chunked elements only the first chunks are read. Returns the indices of the found elements.
+///
+pub fn prefetch(
+    db: &DB,
+    cf: Option<&ColumnFamily>,
+    from: u64,
+    count: usize,
+    index_space: u64,
+) -> Result<Vec<u64>> {
+    let mut indices = Vec::with_capacity(count);
+    let mut index = from;
+    for _ in 0..count.min(index_space as usize) {
+        indices.push(index);
+        index = next_index(index, index_space);
+    }
+    let keys = indices.iter().map(|index| index_to_key(*index));
+    let values = match cf {
+        Some(cf) => db.multi_get_cf(keys.map(|key| (cf, key))),
+        None => db.multi_get(keys),
+    };
+    let mut found = Vec::with_capacity(indices.len());
+    for (index, value) in indices.into_iter().zip(values) {
+        if value?.is_some() {
+            found.push(index);
+        }
+    }
+    Ok(found)
+}
+
+/// Returns the first index stored in the column family among the `count` indices from `from`.
+/// The keys are little-endian, so their byte order is not the numeric one and the indices are
+/// probed in the circular index space instead of seeking, in batches growing from one index, as
+/// the first one is usually stored.
+///
+pub fn first_stored(
+    db: &DB,
+    cf: &ColumnFamily,
+    snapshot: Option<&SnapshotWithThreadMode<DB>>,
+    from: u64,
+    count: u64,
+    index_space: u64,
+) -> Result<Option<u64>> {
+    let mut read_opts = ReadOptions::default();
+    if let Some(snapshot) = snapshot {
+        read_opts.set_snapshot(snapshot);
+    }
+    let mut index = from;
+    let mut remaining = count;
+    let mut batch = 1;
+    while remaining > 0 {
+        let n = remaining.min(batch);
+        let mut indices = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            indices.push(index);
+            index = next_index(index, index_space);
+        }
+        let keys = indices.iter().map(|index| (cf, index_to_key(*index)));
+        for (index, value) in indices.iter().zip(db.multi_get_cf_opt(keys, &read_opts)) {
+            if value?.is_some() {
+                return Ok(Some(*index));
+            }
+        }
+        remaining -= n;
+        batch = (batch * 2).min(MAX_PROBE_BATCH);
+    }
+    Ok(None)
+}
+
+pub fn previous_index(index: u64, index_space: u64) -> u64 {
     if index == 0 {
-        MAX_ALLOWED_INDEX - 1
+        index_space - 1
     } else {
         index - 1
     }
 }
 
+/// Returns the merge operand adding `delta` to a counter.
+pub fn counter_delta(delta: i64) -> [u8; U64_BYTE_LEN] {
+    delta.to_le_bytes()
+}
+
+/// Adds the deltas to the little-endian `u64` counter. The deltas are added with wrapping in
+/// two's complement, so the operator is associative and partially merged operands stay deltas.
+///
+pub fn merge_counter(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let value = operands
+        .iter()
+        .fold(existing.map_or(0, u64_from_byte_vec), |acc, delta| {
+            acc.wrapping_add(u64_from_byte_vec(delta))
+        });
+    Some(value.to_le_bytes().to_vec())
+}
+
 pub fn current_timestamp() -> u64 {
     Utc::now().timestamp_nanos_opt().unwrap() as u64
 }
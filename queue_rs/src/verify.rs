@@ -0,0 +1,141 @@
+use crate::chunking;
+use crate::codec::Codec;
+use crate::error::{QueueError, Result};
+use crate::utilities::{key_to_index, next_index};
+use crate::U64_BYTE_LEN;
+use rocksdb::{ColumnFamily, IteratorMode, DB};
+
+/// A violated queue invariant found by `verify()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Problem {
+    /// The stored cursor is outside of the index space.
+    IndexOutOfRange { cursor: &'static str, index: u64 },
+    /// The element between the read (start) and the write index is missing.
+    MissingElement { index: u64 },
+    /// The element fails the checksum verification.
+    CorruptedElement { index: u64 },
+    /// The element can't be decoded (e.g. it is encrypted with a key missing in the keyring).
+    UndecodableElement { index: u64, error: String },
+    /// The element is stored outside of the range between the read (start) and the write index.
+    UnexpectedElement { index: u64 },
+    /// The stored payload size doesn't match the total size of the elements.
+    PayloadSizeMismatch { stored: u64, actual: u64 },
+    /// The reader position is outside of the range between the start and the write index.
+    ReaderOutOfRange { label: String, index: u64 },
+}
+
+/// The result of the queue verification.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct VerificationReport {
+    /// The number of elements checked.
+    pub elements: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl VerificationReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub(crate) fn check_index(&mut self, cursor: &'static str, index: u64, index_space: u64) {
+        if index >= index_space {
+            self.problems
+                .push(Problem::IndexOutOfRange { cursor, index });
+        }
+    }
+}
+
+/// Returns `true` if the index is in the range of the stored elements `[start, end)`.
+pub(crate) fn in_range(index: u64, start: u64, end: u64, empty: bool) -> bool {
+    if empty {
+        false
+    } else if start < end {
+        index >= start && index < end
+    } else {
+        // the range wraps around the index space or covers all of it
+        index >= start || index < end
+    }
+}
+
+pub(crate) struct ElementsScan<'a> {
+    pub db: &'a DB,
+    pub cf: Option<&'a ColumnFamily>,
+    pub codec: &'a Codec,
+    pub format_version: u64,
+    pub checksums: bool,
+    pub chunked: bool,
+    pub index_space: u64,
+}
+
+impl ElementsScan<'_> {
+    /// Checks the elements in the range `[start, end)` and looks for elements outside of it.
+    /// Returns the total payload size of the decoded elements and whether all elements have
+    /// been decoded.
+    ///
+    pub fn run(
+        &self,
+        start: u64,
+        end: u64,
+        empty: bool,
+        report: &mut VerificationReport,
+    ) -> Result<(u64, bool)> {
+        let mut payload_size = 0;
+        let mut complete = true;
+
+        if !empty {
+            let mut index = start;
+            loop {
+                report.elements += 1;
+                match chunking::get_element(self.db, self.cf, index, self.chunked)? {
+                    None => {
+                        report.problems.push(Problem::MissingElement { index });
+                        complete = false;
+                    }
+                    Some(e) => {
+                        match self.codec.decode_versioned(
+                            e.value,
+                            index,
+                            self.format_version,
+                            self.checksums,
+                        ) {
+                            Ok(value) => payload_size += value.len() as u64,
+                            Err(QueueError::Corrupted(_)) => {
+                                report.problems.push(Problem::CorruptedElement { index });
+                                complete = false;
+                            }
+                            Err(e) => {
+                                report.problems.push(Problem::UndecodableElement {
+                                    index,
+                                    error: e.to_string(),
+                                });
+                                complete = false;
+                            }
+                        }
+                    }
+                }
+                index = next_index(index, self.index_space);
+                if index == end {
+                    break;
+                }
+            }
+        }
+
+        let iterator = match self.cf {
+            Some(cf) => self.db.iterator_cf(cf, IteratorMode::Start),
+            None => self.db.iterator(IteratorMode::Start),
+        };
+        for item in iterator {
+            let (key, _) = item?;
+            // chunk sub-keys are checked together with the element
+            if key.len() != U64_BYTE_LEN {
+                continue;
+            }
+            let index = key_to_index(&key);
+            if index < self.index_space && !in_range(index, start, end, empty) {
+                report.problems.push(Problem::UnexpectedElement { index });
+            }
+        }
+
+        Ok((payload_size, complete))
+    }
+}